@@ -14,6 +14,8 @@ export class Transcription {
     if (!sequence) {
       sequence = [];
     }
+    // segments are sent again when a session is resumed
+    sequence = sequence.filter((s) => s.segment_number !== segment.segment_number);
     sequence.push(segment);
     this.sequences[position] = sequence;
   }
@@ -91,10 +91,19 @@ export function goToCompare(asset_id, lang) {
     return;
   }
   if (confirm("Are you sure you are done?")) {
-    document.location = "/compare/" + asset_id + "/" + state.uuid + "/" + lang;
+    // keeps ?curriculum= so that the comparison links to the next exercise
+    document.location = "/compare/" + asset_id + "/" + state.uuid + "/" + lang + window.location.search;
   }
 }
 
+// tells the server the learner starts rendering a segment of a consecutive session, or stops with null
+export function markRendition(segment) {
+  if (!state.theWebsocket || state.theWebsocket.readyState !== WebSocket.OPEN) {
+    return;
+  }
+  state.theWebsocket.send(JSON.stringify({ rendition: segment }));
+}
+
 function connectToServer(callbackFunction) {
   if (state.theWebsocket !== undefined) {
     console.log("state.theWebsocket not false: " + state.theWebsocket);
@@ -146,15 +155,26 @@ function getWebSocketUri() {
   let chat_path =
       "/chat?lang=" + lang + "&rate=" + sampleRate + "&uuid=" + state.uuid;
   if(resource) {
-    chat_path += "&resource=" + encodeURI(resource);
+    chat_path += "&resource=" + encodeURIComponent(resource);
+  }
+  let mode = document.getElementById("mode");
+  if (mode && mode.value) {
+    chat_path += "&mode=" + mode.value;
   }
 
-  let websocket_uri =
-    window.location.protocol === "https:"
-      ? "wss://" + window.location.host + chat_path
-      : "ws://localhost:3030" + chat_path;
+  return getWebSocketBaseUri() + chat_path;
+}
+
+function getWebSocketBaseUri() {
+  return window.location.protocol === "https:"
+    ? "wss://" + window.location.host
+    : window.location.protocol === "http:"
+    ? "ws://" + window.location.host
+    : "ws://localhost:3030";
+}
 
-  return websocket_uri;
+function getResumeUri() {
+  return getWebSocketBaseUri() + "/chat?resume=" + state.uuid;
 }
 
 function getAppBaseUri() {
@@ -184,6 +204,10 @@ function updateProgress(percentage) {
 
 let sendfunction = function (e) {
   var left = e.inputBuffer.getChannelData(0);
+  if (state.theWebsocket && state.theWebsocket.readyState === WebSocket.CONNECTING) {
+    // resuming after the connection dropped; this bit of audio is lost
+    return;
+  }
   try {
     state.theWebsocket.send(left);
   } catch (e) {
@@ -253,7 +277,15 @@ function initWebSocket(websocket_uri) {
       console.log("connected");
     };
 
-    ws.onclose = function () {
+    ws.onclose = function (e) {
+      if (!e.wasClean && state.uuid && state.processor) {
+        // the connection dropped while recording: carry on with the same session
+        console.log(`Connection lost, resuming session ${state.uuid}`);
+        setTimeout(() => {
+          state.theWebsocket = initWebSocket(getResumeUri());
+        }, 1000);
+        return;
+      }
       disconnect();
     };
 
@@ -0,0 +1,27 @@
+-- The sessions' metadata and transcripts; their recordings stay on disk.
+-- Columns holding JSON are never NULL, a missing value being 'null'.
+CREATE TABLE sessions (
+    uuid TEXT PRIMARY KEY NOT NULL,
+    language TEXT NOT NULL,
+    resource TEXT,
+    sample_rate INTEGER NOT NULL,
+    recording_file TEXT,
+    transcript_file TEXT,
+    recording_deleted_at TEXT,
+    client TEXT,
+    user TEXT,
+    language_detected BOOLEAN NOT NULL,
+    diarize BOOLEAN NOT NULL,
+    mode TEXT NOT NULL,
+    renditions TEXT NOT NULL,
+    backend TEXT NOT NULL,
+    model TEXT,
+    codec TEXT NOT NULL,
+    close_reason TEXT NOT NULL,
+    rating TEXT NOT NULL,
+    sequence_lengths TEXT NOT NULL,
+    segments TEXT NOT NULL,
+    transcript TEXT,
+    updated_at TEXT NOT NULL,
+    created_at TEXT NOT NULL
+);
@@ -0,0 +1,2 @@
+-- For looking sessions up by resource and language, newest first.
+CREATE INDEX sessions_resource_lang_created ON sessions (resource, language, created_at);
@@ -0,0 +1,179 @@
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::process::Command;
+
+use crate::error::{Er, E};
+use crate::metadata::Metadata;
+use crate::session::SessionData;
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct WordTimestamp {
+    pub word: String,
+    pub start: f32,
+    pub end: f32,
+}
+
+fn alignment_path(metadata: &Metadata, lang: &str) -> String {
+    format!("{}/alignment_{}.json", metadata.enclosing_directory, lang)
+}
+
+/**
+ * Runs `FORCE_ALIGN_CMD <audio> <text> <output>` to write the alignment
+ * sidecar. The command may contain extra arguments, separated by spaces.
+ */
+fn force_align(metadata: &Metadata, lang: &str, command: &str) -> E<()> {
+    let text = metadata.text_path(lang).ok_or(Er::new(format!(
+        "No text for lang {} in resource {}",
+        lang, metadata.enclosing_directory
+    )))?;
+    let audio = format!("{}/{}", metadata.enclosing_directory, metadata.audio);
+    run_aligner(command, &audio, &text, &alignment_path(metadata, lang))
+}
+
+/**
+ * Runs the aligner, which writes to `<output>.partial`, renamed to
+ * `output` once it has succeeded so that no one reads it half written.
+ */
+fn run_aligner(command: &str, audio: &str, text: &str, output: &str) -> E<()> {
+    let mut args = command.split_whitespace();
+    let program = args
+        .next()
+        .ok_or(Er::new("FORCE_ALIGN_CMD is empty".to_string()))?;
+    log::debug!("Running {} to align {} with {}", command, audio, text);
+    let partial = format!("{}.partial", output);
+    let status = Command::new(program)
+        .args(args)
+        .arg(audio)
+        .arg(text)
+        .arg(&partial)
+        .status()?;
+    if !status.success() {
+        let _ = std::fs::remove_file(&partial);
+        return Err(Er::new(format!("{} failed with {}", command, status)));
+    }
+    std::fs::rename(partial, output)?;
+    Ok(())
+}
+
+fn read_timestamps(path: &str) -> E<Option<Vec<WordTimestamp>>> {
+    if !Path::new(path).exists() {
+        return Ok(None);
+    }
+    let f = std::fs::File::open(path)?;
+    let reader = std::io::BufReader::new(f);
+    Ok(Some(serde_json::from_reader(reader)?))
+}
+
+/// Reads `alignment_<lang>.json` from the resource directory, if there is one.
+pub fn read_alignment(metadata: &Metadata, lang: &str) -> E<Option<Vec<WordTimestamp>>> {
+    read_timestamps(&alignment_path(metadata, lang))
+}
+
+/**
+ * Returns the forced alignment of the narration in `lang`, reading it
+ * from `alignment_<lang>.json` in the resource directory. When that is
+ * missing and `FORCE_ALIGN_CMD` is set it is generated first, otherwise
+ * `None` is returned.
+ */
+pub fn word_timestamps(resource_path: &String, lang: &str) -> E<Option<Vec<WordTimestamp>>> {
+    let metadata = Metadata::from_resource_path(resource_path)?;
+    if !Path::new(&alignment_path(&metadata, lang)).exists() {
+        match std::env::var("FORCE_ALIGN_CMD") {
+            Ok(command) => force_align(&metadata, lang, &command)?,
+            Err(_) => return Ok(None),
+        }
+    }
+    read_alignment(&metadata, lang)
+}
+
+/**
+ * The forced alignment of a session's recording with its transcript,
+ * kept as `alignment_<lang>.json` next to the recording and made with
+ * `FORCE_ALIGN_CMD` like that of a resource.
+ */
+pub fn session_word_timestamps(session: &SessionData, lang: &str) -> E<Option<Vec<WordTimestamp>>> {
+    let (recording, transcript) = match (&session.recording_file, &session.transcript_file) {
+        (Some(recording), Some(transcript)) => (recording, transcript),
+        _ => return Ok(None),
+    };
+    let directory = session
+        .directory()
+        .ok_or(Er::new(format!("No directory for recording {}", recording)))?;
+    let path = format!("{}/alignment_{}.json", directory, lang);
+    if !Path::new(&path).exists() {
+        match std::env::var("FORCE_ALIGN_CMD") {
+            Ok(command) => run_aligner(&command, recording, transcript, &path)?,
+            Err(_) => return Ok(None),
+        }
+    }
+    read_timestamps(&path)
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct AudioSegment {
+    pub sentence_index: usize,
+    pub start_secs: f32,
+    pub end_secs: f32,
+    pub text: String,
+}
+
+/**
+ * Where each sentence of `text` is in its audio. With word timestamps
+ * for every word a sentence runs from the start of its first word to the
+ * end of its last, otherwise the times are estimated by assuming speech
+ * runs at an even rate of characters through `duration` seconds.
+ */
+pub fn audio_segments(
+    text: &str,
+    timestamps: Option<&[WordTimestamp]>,
+    duration: f32,
+) -> Vec<AudioSegment> {
+    let sentences = crate::difficulty::sentences(text);
+    let word_counts: Vec<usize> = sentences
+        .iter()
+        .map(|s| crate::compare::words(s).len())
+        .collect();
+    let timestamps = timestamps.filter(|t| t.len() >= word_counts.iter().sum());
+
+    let characters = text.chars().count().max(1) as f32;
+    let mut byte_offset = 0;
+    let mut first_word = 0;
+    sentences
+        .into_iter()
+        .zip(word_counts)
+        .enumerate()
+        .map(|(sentence_index, (sentence, count))| {
+            let (start_secs, end_secs) = match timestamps {
+                Some(timestamps) => (
+                    timestamps[first_word].start,
+                    timestamps[first_word + count - 1].end,
+                ),
+                None => {
+                    let start = byte_offset + text[byte_offset..].find(&sentence).unwrap_or(0);
+                    let end = start + sentence.len();
+                    byte_offset = end;
+                    let at = |offset: usize| text[..offset].chars().count() as f32 / characters;
+                    (at(start) * duration, at(end) * duration)
+                }
+            };
+            first_word += count;
+            AudioSegment {
+                sentence_index,
+                start_secs,
+                end_secs,
+                text: sentence,
+            }
+        })
+        .collect()
+}
+
+/// Where each sentence of a session's transcript is in its recording.
+pub fn session_audio_segments(session: &SessionData, lang: &str) -> E<Vec<AudioSegment>> {
+    let text = session.transcript()?;
+    let timestamps = session_word_timestamps(session, lang)?;
+    let duration = match &session.recording_file {
+        Some(filename) => crate::audio::duration_seconds(filename)?,
+        None => 0.0,
+    };
+    Ok(audio_segments(&text, timestamps.as_deref(), duration))
+}
@@ -1,66 +1,297 @@
-use crate::error::E;
+use crate::audio::{Codec, StreamDecoder};
+use crate::compare::ComparisonMode;
+use crate::config::Config;
+use crate::consecutive::Mode;
+use crate::curriculum::CurriculumStep;
+use crate::error::{reject, Er, Kind, E};
+use crate::export::ExportFormat;
+use crate::library::{LibraryEntry, LibraryQuery};
 use crate::metadata::Metadata;
-use crate::session::{get_sessions, mark_session_for_closure_uuid, user_connected, SessionData};
+use crate::session::{
+    get_sessions, mark_session_for_closure_uuid, user_connected, CloseReason, Page, SelfRating,
+    SessionData, SessionFilter, SELF_RATING_RANGE,
+};
+use crate::transcribe::Backend;
 use askama::Template; // bring trait in scope
-use bytes::Bytes;
+use bytes::{Buf, Bytes};
+use futures_util::{StreamExt, TryStreamExt};
 use rust_embed::RustEmbed;
+use serde::Serialize;
+use serde_json::json;
 use std::collections::HashMap;
-use std::io::Read;
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
-use urlencoding::decode;
-use warp::reply::Json;
-use warp::{http::Response, Filter};
+use std::net::IpAddr;
+use std::path::Path;
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast::error::RecvError;
+use urlencoding::{decode, encode};
+use warp::reply::{Json, Reply};
+use warp::{
+    http::{Response, StatusCode},
+    Filter,
+};
 use warp_range::{filter_range, get_range};
 
 #[derive(Template)]
 #[template(path = "index.html", escape = "none")]
 pub struct Index {
     sessions: Vec<SessionData>,
+    page: usize,
+    pages: usize,
+    /// the filters as a query string to put before `page=`, ending in `&` if there are any
+    query: String,
+    /// who is logged in, when there are user accounts
+    user: Option<String>,
 }
 
-pub async fn index() -> std::result::Result<impl warp::Reply, warp::Rejection> {
-    let mut sessions = get_sessions().await.ok_or(warp::reject::reject())?;
-    sessions.sort_by(|a, b| {
-        a.created_at
-            .partial_cmp(&b.created_at)
-            .expect("Unexpected error in comparison")
-    });
+/// How many sessions a page of the session list shows unless asked otherwise.
+pub const SESSIONS_PER_PAGE: usize = 20;
+/// The most sessions a page of the session list can show.
+pub const MAX_SESSIONS_PER_PAGE: usize = 100;
+
+/**
+ * The filters and page asked for with `lang`, `resource`, `status`, `page`
+ * and `per_page`, with the filters as a query string without the page.
+ */
+pub fn session_query(
+    params: &HashMap<String, String>,
+) -> std::result::Result<(SessionFilter, Page, String), warp::Rejection> {
+    let number = |name: &str, default: usize| match params.get(name) {
+        Some(n) => n.parse::<usize>().ok().filter(|n| *n > 0).ok_or_else(|| {
+            reject(
+                Kind::BadRequest,
+                format!("{} must be a whole number from 1, not {}", name, n),
+            )
+        }),
+        None => Ok(default),
+    };
+    let page = Page {
+        page: number("page", 1)?,
+        per_page: number("per_page", SESSIONS_PER_PAGE)?.min(MAX_SESSIONS_PER_PAGE),
+    };
+    if let Some(status) = params.get("status") {
+        if !["open", "closing", "finished"].contains(&status.as_str()) {
+            return Err(reject(
+                Kind::BadRequest,
+                format!("status must be open, closing or finished, not {}", status),
+            ));
+        }
+    }
+    let filter = SessionFilter {
+        lang: params.get("lang").cloned(),
+        resource: params.get("resource").cloned(),
+        status: params.get("status").cloned(),
+        user: None,
+    };
+    let mut query = String::new();
+    for name in ["lang", "resource", "status", "per_page"] {
+        if let Some(value) = params.get(name) {
+            query.push_str(&format!("{}={}&", name, encode(value)));
+        }
+    }
+    Ok((filter, page, query))
+}
+
+/**
+ * The list of sessions. With user accounts only the logged in user's
+ * sessions are listed, and those who aren't logged in are sent to log in.
+ */
+pub async fn index(
+    params: HashMap<String, String>,
+    user: Option<String>,
+) -> std::result::Result<warp::reply::Response, warp::Rejection> {
+    let (mut filter, page, query) = session_query(&params)?;
+    if crate::users::enabled() {
+        match user {
+            Some(ref user) => filter.user = Some(Some(user.clone())),
+            None => {
+                return Ok(
+                    warp::redirect::see_other(warp::http::Uri::from_static("/login.html"))
+                        .into_response(),
+                )
+            }
+        }
+    }
+    let sessions = get_sessions(&filter, Some(page)).await;
 
-    let template = Index { sessions };
+    let template = Index {
+        sessions: sessions.sessions,
+        page: sessions.page,
+        pages: sessions.pages,
+        query,
+        user,
+    };
 
-    Ok(warp::reply::html(template.render().unwrap()))
+    Ok(warp::reply::html(render(&template)?).into_response())
 }
 
-#[derive(Template)]
+#[derive(Template, Serialize)]
 #[template(path = "practice.html", escape = "none")]
 pub struct PracticeData {
     metadata: Metadata,
+    /// as it appears in URLs
     resource_path: String,
+    /// as it is stored in the session
+    resource: String,
     lang: String,
+    related: Vec<crate::metadata::Related>,
+    history: Vec<crate::session::Attempt>,
+    /// which the player can switch between
+    tracks: Vec<crate::metadata::AudioTrack>,
+    /// whether the resource is a video, which the page shows in a video player
+    video: bool,
+    /// where the resource is in the curriculum the learner is working through
+    curriculum: Option<CurriculumStep>,
+    /// whether the page plays the resource segment by segment, for consecutive interpreting
+    mode: Mode,
 }
 
-pub async fn practice(
+/// How many past attempts the practice page and its `history` show by default.
+pub const HISTORY_LIMIT: usize = 10;
+
+/// A page from its template, answering 500 if it can't be rendered.
+fn render(template: &impl Template) -> std::result::Result<String, warp::Rejection> {
+    template.render().map_err(|e| {
+        log::error!("Error rendering page: {:?}", e);
+        crate::error::reject_error(&e)
+    })
+}
+
+/// A path parameter with its URL encoding undone, answering 400 if that isn't valid UTF-8.
+fn decode_path(path: &str) -> std::result::Result<String, warp::Rejection> {
+    decode(path).map(|p| p.into_owned()).map_err(|e| {
+        reject(
+            Kind::BadRequest,
+            format!("{} is not a valid path: {}", path, e),
+        )
+    })
+}
+
+/// Where the resource is in the curriculum `id`, answering 404 if there is no such curriculum.
+fn curriculum_step(
+    id: Option<&String>,
+    resource: &str,
+    lang: &str,
+) -> std::result::Result<Option<CurriculumStep>, warp::Rejection> {
+    let Some(id) = id else {
+        return Ok(None);
+    };
+    let curriculum = crate::curriculum::get(id).map_err(|e| {
+        log::error!("Error loading curriculum {}: {}", id, e);
+        crate::error::reject_error(&*e)
+    })?;
+    Ok(crate::curriculum::step(&curriculum, resource, lang))
+}
+
+/**
+ * What the practice page shows of a resource, in `lang`, to `client`, as
+ * an exercise of the curriculum called `curriculum` if it is given. The
+ * `curriculum` and `mode` are taken from `params`.
+ */
+pub async fn practice_data(
     resource_path: String,
     lang: String,
-) -> std::result::Result<impl warp::Reply, warp::Rejection> {
-    let metadata = match Metadata::from_resource_path(
-        &decode(&resource_path)
-            .expect("invalide URL encoding")
-            .into_owned(),
-    ) {
+    user: Option<String>,
+    client: Option<String>,
+    params: &HashMap<String, String>,
+) -> std::result::Result<PracticeData, warp::Rejection> {
+    let decoded = decode_path(&resource_path)?;
+    let mode = match params.get("mode") {
+        Some(name) => Mode::from_name(name).map_err(|e| crate::error::reject_error(&*e))?,
+        None => Mode::default(),
+    };
+    let curriculum = params.get("curriculum");
+    let curriculum = curriculum_step(curriculum, &decoded, &lang)?;
+    if let Err(e) = crate::tts::ensure_audio_blocking(&decoded).await {
+        log::error!("Couldn't synthesize the audio of {}: {}", decoded, e);
+        return Err(crate::error::reject_error(&e));
+    }
+    let metadata = match Metadata::from_resource_path(&decoded) {
         Ok(m) => m,
         Err(e) => {
             log::error!("Error loading metadata in practise: {:?}", e);
-            return Err(warp::reject::not_found());
+            return Err(crate::error::reject_as(Kind::ResourceNotFound, &*e));
+        }
+    };
+    let related = match crate::metadata::related(&decoded) {
+        Ok(related) => related
+            .into_iter()
+            .filter(|r| r.languages.contains(&lang))
+            .map(|r| crate::metadata::Related {
+                resource_path: encode(&r.resource_path).into_owned(),
+                ..r
+            })
+            .collect(),
+        Err(e) => {
+            log::warn!("Couldn't find resources related to {}: {:?}", decoded, e);
+            vec![]
         }
     };
-    let template = PracticeData {
+    let history = crate::session::history(
+        &decoded,
+        &lang,
+        user.as_deref(),
+        client.as_deref(),
+        HISTORY_LIMIT,
+    )
+    .await
+    .map_err(|e| {
+        log::error!("Error finding the practice history of {}: {:?}", decoded, e);
+        crate::error::reject_error(&*e)
+    })?;
+    let tracks = metadata.tracks();
+    let video = metadata.is_video();
+    Ok(PracticeData {
         metadata,
         resource_path,
+        resource: decoded,
         lang,
-    };
+        related,
+        history,
+        tracks,
+        video,
+        curriculum,
+        mode,
+    })
+}
 
-    Ok(warp::reply::html(template.render().unwrap()))
+pub async fn practice(
+    resource_path: String,
+    lang: String,
+    user: Option<String>,
+    client: Option<String>,
+    params: HashMap<String, String>,
+) -> std::result::Result<impl warp::Reply, warp::Rejection> {
+    let template = practice_data(resource_path, lang, user, client, &params).await?;
+    Ok(warp::reply::html(render(&template)?))
+}
+
+/// A session's transcript in `format`, as a file to download.
+fn export_transcript(
+    session: &SessionData,
+    format: ExportFormat,
+) -> std::result::Result<Response<String>, warp::Rejection> {
+    let body = crate::export::export(session, format).map_err(|e| {
+        log::error!("Error exporting transcript: {:?}", e);
+        crate::error::reject_error(&*e)
+    })?;
+    Response::builder()
+        .header(
+            "Content-Type",
+            format!("{}; charset=utf-8", format.content_type()),
+        )
+        .header(
+            "Content-Disposition",
+            format!(
+                "attachment; filename=\"{}.{}\"",
+                session.uuid,
+                format.extension()
+            ),
+        )
+        .body(body)
+        .map_err(|e| {
+            log::error!("Error making response: {:?}", e);
+            crate::error::reject_error(&e)
+        })
 }
 
 #[derive(Template)]
@@ -69,56 +300,702 @@ pub struct Comparison {
     resource: String,
     uuid: String,
     lang: String,
+    /// where the session's exercise is in the curriculum, to go on to the next one
+    curriculum: Option<CurriculumStep>,
+    /// whether the session was practised segment by segment, to compare it so
+    consecutive: bool,
+    /// whether the sentences are aligned by meaning as well
+    semantic: bool,
+}
+
+/// The `mode` a comparison was asked for in, by default words; others give 400.
+fn comparison_mode(
+    params: &HashMap<String, String>,
+) -> std::result::Result<ComparisonMode, warp::Rejection> {
+    match params.get("mode") {
+        Some(name) => ComparisonMode::from_name(name).map_err(|e| crate::error::reject_error(&*e)),
+        None => Ok(ComparisonMode::default()),
+    }
 }
 
 pub async fn compare(
     resource_path: String,
     uuid: String,
     lang: String,
+    curriculum: Option<String>,
+    mode: ComparisonMode,
+    user: Option<String>,
 ) -> std::result::Result<impl warp::Reply, warp::Rejection> {
+    let curriculum = curriculum_step(curriculum.as_ref(), &resource_path, &lang)?;
+    let consecutive = owned_session(&uuid, &user).await?.mode == Mode::Consecutive;
     let template = match crate::compare::get_comparison(&resource_path, &uuid, &lang).await {
         Ok(c) => Comparison {
             resource: c.resource,
             uuid: c.uuid,
             lang: c.lang,
+            curriculum,
+            consecutive,
+            semantic: mode == ComparisonMode::Semantic,
         },
         Err(e) => {
             log::error!("Couldn't get transcript for uuid {}: {:?}", uuid, e);
-            return Err(warp::reject::reject());
+            return Err(crate::error::reject_as(Kind::SessionNotFound, &*e));
         }
     };
-    Ok(warp::reply::html(template.render().unwrap()))
+    Ok(warp::reply::html(render(&template)?))
+}
+
+/// The resource library, searched and filtered as `/api/v1/resources` is.
+#[derive(Template)]
+#[template(path = "library.html", escape = "html")]
+pub struct Library {
+    entries: Vec<LibraryEntry>,
+    q: String,
+    lang: String,
+    tag: String,
+    difficulty: String,
+    /// what can be filtered by
+    tags: Vec<String>,
+    languages: Vec<String>,
+    levels: Vec<String>,
+}
+
+/**
+ * The resources found by the query `params` make, which are all of them
+ * when it asks for nothing, and every resource to choose filters from.
+ */
+async fn search_library(
+    params: &HashMap<String, String>,
+) -> std::result::Result<(Vec<LibraryEntry>, Vec<LibraryEntry>), warp::Rejection> {
+    let query = LibraryQuery::from_params(params).map_err(|e| crate::error::reject_error(&*e))?;
+    match tokio::task::spawn_blocking(|| crate::library::entries().map_err(|e| Er::from_error(&*e)))
+        .await
+    {
+        Ok(Ok(entries)) => Ok((
+            entries
+                .iter()
+                .filter(|e| query.matches(e))
+                .cloned()
+                .collect(),
+            entries,
+        )),
+        Ok(Err(e)) => {
+            log::error!("Error in library: {}", e);
+            Err(crate::error::reject_as(Kind::NotFound, &e))
+        }
+        Err(e) => {
+            log::error!("Error in library: {:?}", e);
+            Err(crate::error::reject_error(&e))
+        }
+    }
+}
+
+async fn library(
+    params: HashMap<String, String>,
+) -> std::result::Result<impl Reply, warp::Rejection> {
+    let (found, entries) = search_library(&params).await?;
+    let param = |name: &str| params.get(name).cloned().unwrap_or_default();
+    let template = Library {
+        entries: found,
+        q: param("q"),
+        lang: param("lang"),
+        tag: param("tag"),
+        difficulty: param("difficulty").to_uppercase(),
+        tags: crate::library::tags(&entries),
+        languages: crate::library::languages(&entries),
+        levels: crate::library::CEFR_LEVELS.map(String::from).to_vec(),
+    };
+    Ok(warp::reply::html(render(&template)?))
+}
+
+/// A learner's progress over their finished sessions.
+#[derive(Template)]
+#[template(path = "stats.html", escape = "html")]
+pub struct StatsPage {
+    stats: crate::stats::Stats,
+}
+
+/// The `since_days`, `resource` and `lang` of `params` as a query, answering 400 if they don't make one.
+fn stats_query(
+    params: &HashMap<String, String>,
+) -> std::result::Result<crate::stats::StatsQuery, warp::Rejection> {
+    crate::stats::StatsQuery::from_params(params).map_err(|e| crate::error::reject_error(&*e))
+}
+
+/**
+ * The progress of the logged in user, or while there are no user
+ * accounts, of whoever connects from `client`.
+ */
+async fn stats_page(
+    params: HashMap<String, String>,
+    user: Option<String>,
+    client: Option<String>,
+) -> std::result::Result<warp::reply::Response, warp::Rejection> {
+    let query = stats_query(&params)?;
+    if crate::users::enabled() && user.is_none() {
+        return Ok(
+            warp::redirect::see_other(warp::http::Uri::from_static("/login.html")).into_response(),
+        );
+    }
+    let template = StatsPage {
+        stats: crate::stats::stats(user.as_deref(), client.as_deref(), &query).await,
+    };
+    Ok(warp::reply::html(render(&template)?).into_response())
+}
+
+/// The admin dashboard, which follows `/events` once it has been given the API token.
+#[derive(Template)]
+#[template(path = "dashboard.html", escape = "none")]
+pub struct Dashboard {}
+
+/**
+ * The sessions going on as server-sent events: a `snapshot` with the
+ * overview of each, then a `session` event for everything other than
+ * audio arriving which happens in any session. A subscriber which falls
+ * too far behind is sent `lagged` with how many events it missed, and
+ * should reconnect for a new snapshot.
+ */
+async fn session_events() -> impl Reply {
+    let (overviews, receiver) = crate::session::subscribe_events().await;
+    let snapshot = futures_util::stream::once(async move {
+        warp::sse::Event::default()
+            .event("snapshot")
+            .json_data(&overviews)
+    });
+    let events = futures_util::stream::unfold(receiver, |mut receiver| async move {
+        let event = match receiver.recv().await {
+            Ok(event) => warp::sse::Event::default()
+                .event("session")
+                .json_data(&event),
+            Err(RecvError::Lagged(missed)) => Ok(warp::sse::Event::default()
+                .event("lagged")
+                .data(missed.to_string())),
+            Err(RecvError::Closed) => return None,
+        };
+        Some((event, receiver))
+    });
+    warp::sse::reply(warp::sse::keep_alive().stream(snapshot.chain(events)))
 }
 
+/**
+ * A `Range` header for a file of `size` bytes which get_range can serve,
+ * with a range running past the end cut short, or `None` if none of the
+ * range is in the file.
+ */
+fn clamp_range(range_header: Option<String>, size: u64) -> Option<Option<String>> {
+    let Some(range) = range_header else {
+        return Some(None);
+    };
+    let (start, end) = range.trim().strip_prefix("bytes=")?.split_once('-')?;
+    let (start, end) = match (start.trim(), end.trim()) {
+        ("", "") => return None,
+        // the last `end` bytes
+        ("", end) => (size.saturating_sub(end.parse().ok()?), size.checked_sub(1)?),
+        (start, "") => (start.parse().ok()?, size.checked_sub(1)?),
+        (start, end) => (
+            start.parse().ok()?,
+            end.parse::<u64>().ok()?.min(size.checked_sub(1)?),
+        ),
+    };
+    (start <= end).then(|| Some(format!("bytes={}-{}", start, end)))
+}
+
+/**
+ * Serves a session's recording as it is, or transcoded to `format` when
+ * one is asked for, streamed from disk with `Range` requests served as
+ * parts. A session still being recorded can't be transcoded, as its
+ * recording isn't finished.
+ */
 pub async fn download_audio(
     uuid: String,
+    format: Option<String>,
+    range_header: Option<String>,
 ) -> std::result::Result<impl warp::Reply, warp::Rejection> {
-    let session_id = crate::session::find_session_with_uuid(&uuid).await.unwrap();
-    let session = crate::session::get_session(&session_id).await.unwrap();
-    let content_path = session.recording_file.unwrap();
+    let session = session_for_uuid(&uuid).await?;
+    if session.recording_deleted_at.is_some() {
+        return Err(recording_deleted(&uuid));
+    }
+    let mut content_path = session.recording_file.ok_or_else(|| no_recording(&uuid))?;
     log::debug!("content_path is {}", content_path);
-    let mut f = std::fs::File::open(content_path.clone()).unwrap();
-    let metadata = std::fs::metadata(&content_path).expect("unable to read metadata");
-    let mut buffer = vec![0; metadata.len() as usize];
-    let _ = f.read(&mut buffer).expect("buffer overflow");
-    let b: Bytes = Bytes::from(buffer);
-    let response = match Response::builder()
-        .header(
-            "Content-Disposition",
-            format!("attachment; filename=\"{}.wav\"", uuid),
-        )
-        .body(b)
+    let stored = if content_path.ends_with(".flac") {
+        ("flac", "audio/flac")
+    } else {
+        ("wav", "audio/wav")
+    };
+    let (extension, content_type) = match format.as_deref() {
+        None => stored,
+        Some(format) if format == stored.0 => stored,
+        Some(format) => {
+            let Some((extension, _, content_type)) = crate::audio::TRANSCODE_FORMATS
+                .iter()
+                .find(|(f, _, _)| *f == format)
+            else {
+                return Err(reject(
+                    Kind::BadRequest,
+                    format!("Recordings can't be converted to {}", format),
+                ));
+            };
+            if session.valid {
+                return Err(reject(
+                    Kind::SessionNotReady,
+                    "The session is still being recorded".to_string(),
+                ));
+            }
+            let recording = content_path.clone();
+            let format = format.to_string();
+            content_path = match tokio::task::spawn_blocking(move || {
+                crate::audio::transcode(&recording, &format).map_err(|e| Er::from_error(&*e))
+            })
+            .await
+            {
+                Ok(Ok(transcoded)) => transcoded,
+                Ok(Err(e)) => {
+                    log::error!("Error transcoding {}: {}", content_path, e);
+                    return Err(warp::reject::custom(e));
+                }
+                Err(e) => {
+                    log::error!("Error transcoding {}: {:?}", content_path, e);
+                    return Err(crate::error::reject_error(&e));
+                }
+            };
+            (*extension, *content_type)
+        }
+    };
+    let disposition = format!("attachment; filename=\"{}.{}\"", uuid, extension);
+    Ok(warp::reply::with_header(
+        serve_file(&content_path, content_type, range_header).await?,
+        "Content-Disposition",
+        disposition,
+    ))
+}
+
+/**
+ * Streams a file from disk as `content_type`, serving `Range` requests as
+ * parts so that players can seek, with ranges running past the end cut
+ * short and those outside the file answered with 416.
+ */
+async fn serve_file(
+    filename: &str,
+    content_type: &str,
+    range_header: Option<String>,
+) -> std::result::Result<warp::reply::Response, warp::Rejection> {
+    // get_range can't tell a missing file from a broken one, and can't serve an empty one
+    let size = match tokio::fs::metadata(filename).await {
+        Ok(metadata) => metadata.len(),
+        Err(e) => {
+            log::error!("Error reading {}: {:?}", filename, e);
+            return Err(crate::error::reject_error(&e));
+        }
+    };
+    let Some(range_header) = clamp_range(range_header, size) else {
+        return Response::builder()
+            .status(StatusCode::RANGE_NOT_SATISFIABLE)
+            .header("Content-Range", format!("bytes */{}", size))
+            .body(warp::hyper::Body::empty())
+            .map_err(|e| crate::error::reject_error(&e));
+    };
+    if size == 0 {
+        return Response::builder()
+            .header("Content-Type", content_type)
+            .header("Content-Length", 0)
+            .body(warp::hyper::Body::empty())
+            .map_err(|e| crate::error::reject_error(&e));
+    }
+    Ok(get_range(range_header, filename, content_type)
+        .await?
+        .into_response())
+}
+
+const REPROCESS_TIMEOUT_SECONDS: u64 = 600;
+
+/// Recordings of sessions left alone for this long are compressed by `/admin/vacuum`.
+pub const VACUUM_OLDER_THAN_DAYS: i64 = 30;
+
+/// How far back `/admin/resource-usage` looks unless asked otherwise.
+pub const RESOURCE_USAGE_SINCE_DAYS: i64 = 30;
+
+/**
+ * The time the parameter `name` counts back in days from now, or `default`
+ * days without it, answering 400 if it isn't a number of days from 0 or
+ * goes back further than a date can.
+ */
+fn days_ago(
+    params: &HashMap<String, String>,
+    name: &str,
+    default: i64,
+) -> std::result::Result<chrono::DateTime<chrono::Utc>, warp::Rejection> {
+    let days = match params.get(name) {
+        Some(days) => days.parse::<i64>().ok().filter(|d| *d >= 0),
+        None => Some(default),
+    };
+    days.and_then(chrono::TimeDelta::try_days)
+        .and_then(|days| chrono::Utc::now().checked_sub_signed(days))
+        .ok_or_else(|| {
+            reject(
+                Kind::BadRequest,
+                format!(
+                    "{} must be a whole number of days from 0, not {}",
+                    name,
+                    params.get(name).map(String::as_str).unwrap_or_default()
+                ),
+            )
+        })
+}
+
+/// The largest recording `/upload` takes.
+pub const MAX_UPLOAD_BYTES: u64 = 200 * 1024 * 1024;
+
+/// The parts of a `multipart/form-data` body, as their names, filenames and contents.
+async fn read_form(
+    mut form: warp::multipart::FormData,
+) -> std::result::Result<Vec<(String, Option<String>, Vec<u8>)>, warp::Rejection> {
+    let mut parts = vec![];
+    while let Some(part) = form
+        .try_next()
+        .await
+        .map_err(|e| reject(Kind::BadRequest, e.to_string()))?
     {
-        Ok(b) => b,
+        let name = part.name().to_string();
+        let filename = part.filename().map(|f| f.to_string());
+        let mut data = vec![];
+        let mut stream = part.stream();
+        while let Some(chunk) = stream
+            .try_next()
+            .await
+            .map_err(|e| reject(Kind::BadRequest, e.to_string()))?
+        {
+            data.extend_from_slice(chunk.chunk());
+        }
+        parts.push((name, filename, data));
+    }
+    Ok(parts)
+}
+
+/**
+ * Makes a session from a recording sent as `multipart/form-data`, with
+ * the recording as `audio` and optionally `lang`, `resource_path`, `backend`,
+ * `model` and `diarize`, and answers with its UUID so its status can be followed like any other.
+ */
+pub async fn upload(
+    form: warp::multipart::FormData,
+    client: Option<String>,
+    user: Option<String>,
+    base_url: Option<String>,
+) -> std::result::Result<impl warp::Reply, warp::Rejection> {
+    crate::transcribe::scheduler::check_capacity().map_err(|e| crate::error::reject_error(&*e))?;
+    let mut fields: HashMap<String, Vec<u8>> = read_form(form)
+        .await?
+        .into_iter()
+        .map(|(name, _, data)| (name, data))
+        .collect();
+    let text = |name: &str| {
+        fields
+            .get(name)
+            .map(|value| String::from_utf8_lossy(value).trim().to_string())
+            .filter(|value| !value.is_empty())
+    };
+    let lang = text("lang").unwrap_or("de".to_string());
+    let resource = text("resource_path");
+    if let Some(resource) = &resource {
+        Metadata::from_resource_path(resource)
+            .map_err(|e| crate::error::reject_as(Kind::ResourceNotFound, &*e))?;
+    }
+    let backend = match text("backend") {
+        Some(name) => Backend::from_name(&name).map_err(|e| crate::error::reject_error(&*e))?,
+        None => Backend::configured(),
+    };
+    backend
+        .check_language(&lang)
+        .map_err(|e| crate::error::reject_error(&*e))?;
+    let model = text("model");
+    if let Some(model) = &model {
+        crate::models::check(model, backend).map_err(|e| crate::error::reject_error(&*e))?;
+    }
+    let diarize = text("diarize").is_some_and(|v| v == "true");
+    let audio = fields
+        .remove("audio")
+        .ok_or_else(|| reject(Kind::BadRequest, "No audio was uploaded".to_string()))?;
+    match crate::session::upload_session(
+        audio, lang, resource, client, user, base_url, backend, model, diarize,
+    )
+    .await
+    {
+        Ok(uuid) => Ok(warp::reply::json(&json!({ "uuid": uuid.to_string() }))),
         Err(e) => {
-            log::error!("Error making response: {:?}", e);
-            return Err(warp::reject::not_found());
+            log::error!("Error in upload: {:?}", e);
+            Err(crate::error::reject_error(&*e))
         }
+    }
+}
+
+/**
+ * Adds a resource bundle sent as `multipart/form-data`, with its path as
+ * `resource_path`, its `metadata.json` as `metadata` and each file the
+ * metadata names uploaded under that filename, answering with its summary.
+ */
+pub async fn add_resource(
+    form: warp::multipart::FormData,
+) -> std::result::Result<impl warp::Reply, warp::Rejection> {
+    let mut resource_path = None;
+    let mut metadata = None;
+    let mut files = HashMap::new();
+    for (name, filename, data) in read_form(form).await? {
+        match (name.as_str(), filename) {
+            ("resource_path", _) => {
+                resource_path = Some(String::from_utf8_lossy(&data).trim().to_string())
+            }
+            ("metadata", _) => metadata = Some(data),
+            (_, Some(filename)) => {
+                files.insert(filename, data);
+            }
+            (_, None) => {
+                return Err(reject(
+                    Kind::BadRequest,
+                    format!("{} is neither a file nor a known field", name),
+                ))
+            }
+        }
+    }
+    let resource_path = resource_path
+        .ok_or_else(|| reject(Kind::BadRequest, "No resource_path was given".to_string()))?;
+    let metadata =
+        metadata.ok_or_else(|| reject(Kind::BadRequest, "No metadata was given".to_string()))?;
+    match tokio::task::spawn_blocking(move || {
+        crate::resources::create(&resource_path, &metadata, files)
+            .map(|m| m.to_summary(resource_path))
+            .map_err(|e| Er::from_error(&*e))
+    })
+    .await
+    {
+        Ok(Ok(summary)) => Ok(warp::reply::with_status(
+            warp::reply::json(&summary),
+            StatusCode::CREATED,
+        )),
+        Ok(Err(e)) => {
+            log::error!("Error adding resource: {}", e);
+            Err(crate::error::reject_error(&e))
+        }
+        Err(e) => {
+            log::error!("Error adding resource: {:?}", e);
+            Err(crate::error::reject_error(&e))
+        }
+    }
+}
+
+/**
+ * Transcribes a session again from its recording and waits for it to
+ * finish, answering with the new word error rate. If that takes too long,
+ * 202 is returned and `/status` can be polled instead.
+ */
+pub async fn reprocess_session(
+    uuid: String,
+) -> std::result::Result<impl warp::Reply, warp::Rejection> {
+    let session = session_for_uuid(&uuid).await?;
+    let session_id = session.id;
+    let recording_exists = match &session.recording_file {
+        Some(filename) => Path::new(filename).exists(),
+        None => false,
+    };
+    if !recording_exists || session.valid {
+        return Ok(warp::reply::with_status(
+            warp::reply::json(&json!({ "uuid": uuid, "wer": null })),
+            StatusCode::CONFLICT,
+        ));
+    }
+    if let Err(e) = crate::session::reprocess_session(session_id).await {
+        log::error!("Error reprocessing session {}: {:?}", uuid, e);
+        return Err(crate::error::reject_error(&*e));
+    }
+    let started = Instant::now();
+    while crate::session::get_session(&session_id)
+        .await
+        .map(|s| s.valid)
+        .unwrap_or(false)
+    {
+        if started.elapsed() > Duration::from_secs(REPROCESS_TIMEOUT_SECONDS) {
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&json!({ "uuid": uuid, "wer": null })),
+                StatusCode::ACCEPTED,
+            ));
+        }
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+    let wer = match session.resource {
+        Some(resource) => {
+            match crate::compare::score(resource, uuid.clone(), session.language).await {
+                Ok(score) => Some(score.wer),
+                Err(e) => {
+                    log::error!("Error scoring reprocessed session {}: {:?}", uuid, e);
+                    None
+                }
+            }
+        }
+        None => None,
     };
-    Ok(response)
+    Ok(warp::reply::with_status(
+        warp::reply::json(&json!({ "uuid": uuid, "wer": wer })),
+        StatusCode::OK,
+    ))
 }
 
-pub async fn get_resource_filename(resource_path: String) -> E<String> {
+/**
+ * A page of the sessions the request's filters ask for. With user accounts
+ * only those of the logged in user, or the anonymous ones for those who
+ * aren't logged in, are listed.
+ */
+async fn session_list(
+    params: HashMap<String, String>,
+    user: Option<String>,
+) -> std::result::Result<Json, warp::Rejection> {
+    let (mut filter, page, _) = session_query(&params)?;
+    if crate::users::enabled() {
+        filter.user = Some(user);
+    }
+    let sessions = get_sessions(&filter, Some(page)).await;
+    Ok(warp::reply::json(&crate::session::SessionPage {
+        sessions: sessions.sessions.iter().map(|s| s.info()).collect(),
+        page: sessions.page,
+        per_page: sessions.per_page,
+        total: sessions.total,
+        pages: sessions.pages,
+    }))
+}
+
+/// The session with the UUID `uuid`, or a rejection saying why there is none.
+async fn session_for_uuid(uuid: &String) -> std::result::Result<SessionData, warp::Rejection> {
+    if uuid::Uuid::parse_str(uuid).is_err() {
+        return Err(reject(Kind::BadUuid, format!("{} is not a UUID", uuid)));
+    }
+    match crate::session::find_session_with_uuid(uuid).await {
+        Some(session_id) => crate::session::get_session(&session_id).await,
+        None => None,
+    }
+    .ok_or_else(|| reject(Kind::SessionNotFound, format!("No session {}", uuid)))
+}
+
+/**
+ * The session `uuid` if `user` may see it, as `users::check_owner` decides.
+ * Every route finding a session by its UUID for a learner goes through it.
+ */
+async fn owned_session(
+    uuid: &String,
+    user: &Option<String>,
+) -> std::result::Result<SessionData, warp::Rejection> {
+    let session = session_for_uuid(uuid).await?;
+    crate::users::check_owner(&session, user)?;
+    Ok(session)
+}
+
+fn no_recording(uuid: &String) -> warp::Rejection {
+    reject(Kind::NotFound, format!("Session {} has no recording", uuid))
+}
+
+fn recording_deleted(uuid: &String) -> warp::Rejection {
+    reject(
+        Kind::RecordingDeleted,
+        format!("The recording of session {} has been deleted", uuid),
+    )
+}
+
+/**
+ * Answers every rejected request with a JSON body saying what went wrong,
+ * like `{"error":"session_not_found","detail":"No session ..."}`.
+ */
+async fn handle_rejection(
+    err: warp::Rejection,
+) -> std::result::Result<impl warp::Reply, std::convert::Infallible> {
+    let (status, error, detail) = if err.find::<crate::auth::Unauthorized>().is_some() {
+        (
+            StatusCode::UNAUTHORIZED,
+            "unauthorized",
+            "The admin routes need an Authorization: Bearer header with the API token".to_string(),
+        )
+    } else if err.find::<crate::users::LoginFailed>().is_some() {
+        (
+            StatusCode::UNAUTHORIZED,
+            "login_failed",
+            "The name or password is wrong".to_string(),
+        )
+    } else if err.find::<crate::auth::Forbidden>().is_some() {
+        (
+            StatusCode::FORBIDDEN,
+            "forbidden",
+            "The URL's signature is missing, wrong or has expired".to_string(),
+        )
+    } else if let Some(e) = err.find::<warp::cors::CorsForbidden>() {
+        (StatusCode::FORBIDDEN, "cors_forbidden", e.to_string())
+    } else if err
+        .find::<crate::rate_limit::TooManyConnections>()
+        .is_some()
+    {
+        (
+            StatusCode::TOO_MANY_REQUESTS,
+            "too_many_connections",
+            "Too many connections are open from this address".to_string(),
+        )
+    } else if let Some(er) = err.find::<Er>() {
+        match er.kind() {
+            Some(kind) => (kind.status(), kind.code(), er.message().to_string()),
+            None => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "internal_error",
+                er.message().to_string(),
+            ),
+        }
+    } else if err.is_not_found() {
+        (
+            StatusCode::NOT_FOUND,
+            "not_found",
+            "Nothing is served here".to_string(),
+        )
+    } else if let Some(e) = err.find::<warp::reject::MethodNotAllowed>() {
+        (
+            StatusCode::METHOD_NOT_ALLOWED,
+            "method_not_allowed",
+            e.to_string(),
+        )
+    } else if let Some(e) = err.find::<warp::reject::InvalidQuery>() {
+        (StatusCode::BAD_REQUEST, "bad_request", e.to_string())
+    } else if let Some(e) = err.find::<warp::body::BodyDeserializeError>() {
+        (StatusCode::BAD_REQUEST, "bad_request", e.to_string())
+    } else if let Some(e) = err.find::<warp::reject::MissingHeader>() {
+        (StatusCode::BAD_REQUEST, "bad_request", e.to_string())
+    } else if let Some(e) = err.find::<warp::reject::InvalidHeader>() {
+        (StatusCode::BAD_REQUEST, "bad_request", e.to_string())
+    } else if let Some(e) = err.find::<warp::reject::UnsupportedMediaType>() {
+        (
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "unsupported_media_type",
+            e.to_string(),
+        )
+    } else if let Some(e) = err.find::<warp::reject::PayloadTooLarge>() {
+        (
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "payload_too_large",
+            e.to_string(),
+        )
+    } else {
+        log::error!("Unhandled rejection: {:?}", err);
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "internal_error",
+            "Internal error".to_string(),
+        )
+    };
+    Ok(warp::reply::with_status(
+        warp::reply::json(&json!({ "error": error, "detail": detail })),
+        status,
+    ))
+}
+
+/// The full path to the resource's audio, or to its track in `track` if one is asked for.
+pub async fn get_resource_filename(resource_path: String, track: Option<String>) -> E<String> {
+    if track.is_none() {
+        if let Err(e) = crate::tts::ensure_audio_blocking(&resource_path).await {
+            log::error!("Couldn't synthesize the audio of {}: {}", resource_path, e);
+            return Err(Box::new(e));
+        }
+    }
     let metadata = match Metadata::from_resource_path(&resource_path) {
         Ok(m) => m,
         Err(e) => {
@@ -127,150 +1004,1945 @@ pub async fn get_resource_filename(resource_path: String) -> E<String> {
                 e,
                 resource_path
             );
-            return Err(crate::error::Er::new(e.to_string()));
+            return Err(e);
         }
     };
-    let content_path = format!("{}/{}", metadata.enclosing_directory, metadata.audio);
+    let content_path = metadata.audio_path(track.as_deref()).ok_or_else(|| {
+        Er::with_kind(
+            Kind::NotFound,
+            format!(
+                "Resource {} has no audio track in {}",
+                resource_path,
+                track.unwrap_or_default()
+            ),
+        )
+    })?;
     log::debug!("content_path is {}", content_path);
     Ok(content_path)
 }
 
-pub async fn serve() {
+/// The slowest and fastest speeds `audio-speed` will play a resource at.
+pub const SPEED_RANGE: (f32, f32) = (0.25, 2.0);
+/// Speeds are rounded to a multiple of this, so that only so many copies of a resource's audio are made.
+pub const SPEED_STEP: f32 = 0.05;
+
+/// The speed `factor` asks for, rounded to SPEED_STEP, if it is a number within SPEED_RANGE.
+fn speed(factor: &str) -> Option<f32> {
+    let speed = (factor.parse::<f32>().ok()? / SPEED_STEP).round() * SPEED_STEP;
+    (SPEED_RANGE.0..=SPEED_RANGE.1)
+        .contains(&speed)
+        .then_some(speed)
+}
+
+/**
+ * Returns the name of a copy of the resource's audio played at `speed`,
+ * making it the first time it's asked for.
+ */
+pub fn get_speed_filename(resource_path: String, speed: f32) -> E<String> {
+    let metadata = Metadata::from_resource_path(&resource_path)?;
+    let filename = format!(
+        "{}/audio_speed_{:.2}.wav",
+        metadata.enclosing_directory, speed
+    );
+    if !Path::new(&filename).exists() {
+        let (samples, sample_rate) = crate::audio::read_audio(&format!(
+            "{}/{}",
+            metadata.enclosing_directory, metadata.audio
+        ))?;
+        log::debug!("Making {}", filename);
+        let stretched = crate::audio::time_stretch(&samples, sample_rate, speed);
+        crate::audio::write_wav(&filename, &stretched, sample_rate)?;
+    }
+    Ok(filename)
+}
+
+/// The FFT size spectrograms use unless asked for another.
+pub const DEFAULT_FFT_SIZE: usize = 512;
+/// The smallest and largest FFT sizes spectrograms can be made with.
+pub const FFT_SIZE_RANGE: (usize, usize) = (64, 8192);
+
+/// Reads the `fft_size` parameter, which must be a power of two within FFT_SIZE_RANGE.
+fn fft_size(params: &HashMap<String, String>) -> Option<usize> {
+    match params.get("fft_size") {
+        Some(size) => size.parse::<usize>().ok().filter(|size| {
+            size.is_power_of_two() && (FFT_SIZE_RANGE.0..=FFT_SIZE_RANGE.1).contains(size)
+        }),
+        None => Some(DEFAULT_FFT_SIZE),
+    }
+}
+
+fn bad_fft_size() -> warp::Rejection {
+    reject(
+        Kind::BadRequest,
+        format!(
+            "fft_size must be a power of two between {} and {}",
+            FFT_SIZE_RANGE.0, FFT_SIZE_RANGE.1
+        ),
+    )
+}
+
+/// The `segments` a sentiment arc is asked for, answering 400 if it isn't a whole number from 1.
+fn sentiment_segments(
+    params: &HashMap<String, String>,
+) -> std::result::Result<usize, warp::Rejection> {
+    match params.get("segments") {
+        Some(segments) => segments.parse().ok().filter(|s| *s > 0).ok_or_else(|| {
+            reject(
+                Kind::BadRequest,
+                format!("segments must be a whole number from 1, not {}", segments),
+            )
+        }),
+        None => Ok(crate::sentiment::DEFAULT_SEGMENTS),
+    }
+}
+
+/**
+ * The spectrogram of a resource's audio, which is kept gzipped as
+ * `spectrogram_<fft_size>.json.gz` in the resource directory once it has
+ * been made, and made again when the audio is newer.
+ */
+pub fn get_spectrogram(resource_path: String, fft_size: usize) -> E<crate::audio::Spectrogram> {
+    let metadata = Metadata::from_resource_path(&resource_path)?;
+    let filename = format!(
+        "{}/spectrogram_{}.json.gz",
+        metadata.enclosing_directory, fft_size
+    );
+    let audio = format!("{}/{}", metadata.enclosing_directory, metadata.audio);
+    let modified = |f: &str| std::fs::metadata(f).and_then(|m| m.modified()).ok();
+    if modified(&filename).is_some() && modified(&filename) >= modified(&audio) {
+        let cached = std::fs::File::open(&filename)?;
+        let reader = std::io::BufReader::new(flate2::read::GzDecoder::new(cached));
+        match serde_json::from_reader(reader) {
+            Ok(spectrogram) => return Ok(spectrogram),
+            Err(e) => log::warn!("Making {} again, as it can't be read: {}", filename, e),
+        }
+    }
+    let (samples, sample_rate) = crate::audio::read_audio(&audio)?;
+    log::debug!("Making {}", filename);
+    let spectrogram = crate::audio::spectrogram(&samples, sample_rate, fft_size);
+    let partial = format!("{}.partial", filename);
+    let mut writer = flate2::write::GzEncoder::new(
+        std::io::BufWriter::new(std::fs::File::create(&partial)?),
+        flate2::Compression::default(),
+    );
+    serde_json::to_writer(&mut writer, &spectrogram)?;
+    writer.finish()?.into_inner()?;
+    std::fs::rename(partial, filename)?;
+    Ok(spectrogram)
+}
+
+/**
+ * The chapters of a resource's audio, which are kept as `chapters.json`
+ * in the resource directory once they have been found.
+ */
+pub fn get_chapters(resource_path: &String) -> E<Vec<crate::audio::Chapter>> {
+    let metadata = Metadata::from_resource_path(resource_path)?;
+    if !metadata.cue_points.is_empty() {
+        let audio = format!("{}/{}", metadata.enclosing_directory, metadata.audio);
+        // only WAV and FLAC files say how long they are, others are decoded
+        let duration = match (
+            crate::audio::duration_seconds(&audio),
+            metadata.duration_secs,
+        ) {
+            (Ok(duration), _) | (Err(_), Some(duration)) => duration,
+            (Err(_), None) => {
+                let (samples, sample_rate) = crate::audio::read_audio(&audio)?;
+                samples.len() as f32 / sample_rate as f32
+            }
+        };
+        return Ok(crate::audio::chapters_at(&metadata.cue_points, duration));
+    }
+    let filename = format!("{}/chapters.json", metadata.enclosing_directory);
+    if let Ok(cached) = std::fs::read_to_string(&filename) {
+        return Ok(serde_json::from_str(&cached)?);
+    }
+    let (samples, sample_rate) = crate::audio::read_audio(&format!(
+        "{}/{}",
+        metadata.enclosing_directory, metadata.audio
+    ))?;
+    log::debug!("Making {}", filename);
+    let chapters = crate::audio::chapters(&samples, sample_rate);
+    let partial = format!("{}.partial", filename);
+    std::fs::write(&partial, serde_json::to_string(&chapters)?)?;
+    std::fs::rename(partial, filename)?;
+    Ok(chapters)
+}
+
+/**
+ * Returns the name of the audio of one chapter of a resource, cut out as
+ * `chapter_<index>.wav` the first time it's asked for, or None if there
+ * is no such chapter.
+ */
+pub fn get_chapter_filename(resource_path: String, index: usize) -> E<Option<String>> {
+    let Some(chapter) = get_chapters(&resource_path)?.into_iter().nth(index) else {
+        return Ok(None);
+    };
+    let metadata = Metadata::from_resource_path(&resource_path)?;
+    let filename = format!("{}/chapter_{}.wav", metadata.enclosing_directory, index);
+    if !Path::new(&filename).exists() {
+        let (samples, sample_rate) = crate::audio::read_audio(&format!(
+            "{}/{}",
+            metadata.enclosing_directory, metadata.audio
+        ))?;
+        log::debug!("Making {}", filename);
+        let at = |secs: f32| ((secs * sample_rate as f32) as usize).min(samples.len());
+        crate::audio::write_wav(
+            &filename,
+            &samples[at(chapter.start_secs)..at(chapter.end_secs)],
+            sample_rate,
+        )?;
+    }
+    Ok(Some(filename))
+}
+
+pub async fn serve(config: &Config) {
     let chat = warp::path("chat")
         .and(warp::query::<HashMap<String, String>>())
         .and(warp::ws())
-        .map(move |params: HashMap<String, String>, ws: warp::ws::Ws| {
-            let lang: String = (params.get("lang").unwrap_or(&"de".to_string())).clone();
-            let resource: Option<String> = params.get("resource").cloned();
-            let sample_rate: u32 = match params.get("rate") {
-                Some(rate) => rate.to_string(),
-                None => "44100".to_string(),
-            }
-            .parse()
-            .unwrap();
-            ws.on_upgrade(move |socket| user_connected(socket, lang, sample_rate, resource))
-        });
-
-    let close = warp::post().and(warp::path!("close" / String).and_then(|uuid| async move {
-        mark_session_for_closure_uuid(uuid).await;
-        Ok::<&str, warp::Rejection>("foo")
-    }));
-
-    let practice = warp::get().and(
-        warp::path!("practice" / String / String)
-            .and_then(|directory, lang| async move { practice(directory, lang).await }),
-    );
+        .and(crate::middleware::client_addr())
+        .and(crate::rate_limit::limit())
+        .and(crate::users::current_user())
+        .and(crate::middleware::base_url())
+        .and_then(
+            move |params: HashMap<String, String>,
+                  ws: warp::ws::Ws,
+                  addr: Option<IpAddr>,
+                  guard: crate::rate_limit::ConnectionGuard,
+                  user: Option<String>,
+                  base_url: Option<String>| async move {
+                crate::shutdown::check().map_err(|e| crate::error::reject_error(&*e))?;
+                if let Some(uuid) = params.get("resume") {
+                    let session = owned_session(uuid, &user).await?;
+                    if !session.valid || session.close_reason.is_some() {
+                        return Err(reject(
+                            Kind::SessionClosed,
+                            format!("Session {} has been closed and can't be resumed", uuid),
+                        ));
+                    }
+                    // the audio goes on as it started, whatever this request says
+                    let decoder = match session.codec {
+                        Codec::Pcm => None,
+                        Codec::Opus => {
+                            Some(StreamDecoder::start(session.sample_rate).map_err(|e| {
+                                log::error!("Couldn't start decoding: {:?}", e);
+                                crate::error::reject_error(&*e)
+                            })?)
+                        }
+                    };
+                    return Ok(ws
+                        .on_upgrade(move |socket| async move {
+                            let _guard = guard;
+                            crate::session::user_resumed(socket, session.id, decoder).await
+                        })
+                        .into_response());
+                }
+                crate::transcribe::scheduler::check_capacity()
+                    .map_err(|e| crate::error::reject_error(&*e))?;
+                let lang: String = (params.get("lang").unwrap_or(&"de".to_string())).clone();
+                let resource: Option<String> = params.get("resource").cloned();
+                let sample_rate: u32 = match params.get("rate") {
+                    Some(rate) => rate
+                        .parse()
+                        .ok()
+                        .filter(|r| {
+                            (crate::audio::MIN_SAMPLE_RATE..=crate::audio::MAX_SAMPLE_RATE)
+                                .contains(r)
+                        })
+                        .ok_or_else(|| {
+                            reject(
+                                Kind::BadRequest,
+                                format!(
+                                    "rate must be a number of samples a second from {} to {}, not {}",
+                                    crate::audio::MIN_SAMPLE_RATE,
+                                    crate::audio::MAX_SAMPLE_RATE,
+                                    rate
+                                ),
+                            )
+                        })?,
+                    None => 44100,
+                };
+                let client = addr.map(|a| a.to_string());
+                let backend = match params.get("backend") {
+                    Some(name) => {
+                        Backend::from_name(name).map_err(|e| crate::error::reject_error(&*e))?
+                    }
+                    None => Backend::configured(),
+                };
+                backend
+                    .check_language(&lang)
+                    .map_err(|e| crate::error::reject_error(&*e))?;
+                let model = params.get("model").cloned();
+                if let Some(model) = &model {
+                    crate::models::check(model, backend)
+                        .map_err(|e| crate::error::reject_error(&*e))?;
+                }
+                let diarize = params.get("diarize").is_some_and(|v| v == "true");
+                let mode = match params.get("mode") {
+                    Some(name) => {
+                        Mode::from_name(name).map_err(|e| crate::error::reject_error(&*e))?
+                    }
+                    None => Mode::default(),
+                };
+                // compressed audio is decoded as it comes; without a codec it is raw samples
+                let decoder = match params.get("codec") {
+                    Some(name) => match Codec::from_name(name)
+                        .map_err(|e| crate::error::reject_error(&*e))?
+                    {
+                        Codec::Pcm => None,
+                        Codec::Opus => Some(StreamDecoder::start(sample_rate).map_err(|e| {
+                            log::error!("Couldn't start decoding: {:?}", e);
+                            crate::error::reject_error(&*e)
+                        })?),
+                    },
+                    None => None,
+                };
+                Ok::<_, warp::Rejection>(
+                    ws.on_upgrade(move |socket| async move {
+                        // the connection counts against the address until the session ends
+                        let _guard = guard;
+                        user_connected(
+                            socket,
+                            lang,
+                            sample_rate,
+                            resource,
+                            client,
+                            user,
+                            base_url,
+                            backend,
+                            model,
+                            diarize,
+                            mode,
+                            decoder,
+                        )
+                        .await
+                    })
+                    .into_response(),
+                )
+            },
+        );
+
+    let close = warp::path!("close" / String)
+        .and(warp::post())
+        .and(crate::users::current_user())
+        .and_then(|uuid: String, user| async move {
+            // closing a session which isn't there is nothing to do, as it always was
+            if let Ok(session) = session_for_uuid(&uuid).await {
+                crate::users::check_owner(&session, &user)?;
+            }
+            mark_session_for_closure_uuid(uuid, CloseReason::ClientRequested).await;
+            Ok::<&str, warp::Rejection>("foo")
+        });
+
+    let reprocess_session = warp::path!("admin" / "reprocess-session" / String)
+        .and(warp::post())
+        .and(crate::auth::admin())
+        .and_then(|uuid| async move { crate::api::reprocess_session(uuid).await });
+
+    let vacuum = warp::path!("admin" / "vacuum")
+        .and(warp::post())
+        .and(crate::auth::admin())
+        .and(warp::query::<HashMap<String, String>>())
+        .and_then(|params: HashMap<String, String>| async move {
+            let cutoff = days_ago(&params, "older_than_days", VACUUM_OLDER_THAN_DAYS)?;
+            Ok::<_, warp::Rejection>(warp::reply::json(&crate::session::vacuum(cutoff).await))
+        });
+
+    let close_session = warp::path!("admin" / "close-session" / String)
+        .and(warp::post())
+        .and(crate::auth::admin())
+        .and_then(|uuid: String| async move {
+            let session = session_for_uuid(&uuid).await?;
+            if !session.valid {
+                return Ok::<StatusCode, warp::Rejection>(StatusCode::CONFLICT);
+            }
+            crate::session::mark_session_for_closure(session.id, CloseReason::AdminForced).await;
+            Ok(StatusCode::NO_CONTENT)
+        });
+
+    let invalidate_cache = warp::path!("admin" / "cache" / "invalidate")
+        .and(warp::post())
+        .and(crate::auth::admin())
+        .and(warp::query::<HashMap<String, String>>())
+        .map(|params: HashMap<String, String>| {
+            let invalidated = crate::metadata::invalidate_cache(params.get("resource"));
+            warp::reply::json(&json!({ "invalidated": invalidated }))
+        });
+
+    let export_config = warp::path!("admin" / "export-config")
+        .and(warp::get())
+        .and(crate::auth::admin())
+        .map(|| warp::reply::json(&crate::config::export()));
+
+    let events = warp::path!("events")
+        .and(warp::get())
+        .and(crate::auth::admin())
+        .then(session_events);
+
+    let library = warp::path!("library")
+        .and(warp::get())
+        .and(warp::query::<HashMap<String, String>>())
+        .and_then(library);
+
+    let stats = warp::path!("stats")
+        .and(warp::get())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(crate::users::current_user())
+        .and(crate::middleware::client_addr())
+        .and_then(|params, user, addr: Option<IpAddr>| async move {
+            stats_page(params, user, addr.map(|a| a.to_string())).await
+        });
+
+    let dashboard = warp::path!("admin" / "dashboard")
+        .and(warp::get())
+        .and_then(|| async { Ok::<_, warp::Rejection>(warp::reply::html(render(&Dashboard {})?)) });
+
+    let metrics = warp::path!("metrics")
+        .and(warp::get())
+        .and(crate::auth::admin())
+        .then(|| async {
+            warp::reply::with_header(
+                crate::metrics::render().await,
+                "Content-Type",
+                crate::metrics::CONTENT_TYPE,
+            )
+        });
 
-    let serve_resource = warp::get().and(
-        warp::path!("serve_resource" / String)
-            .and(filter_range())
-            .and_then(|resource_path: String, range_header| async move {
+    let resource_usage = warp::path!("admin" / "resource-usage")
+        .and(warp::get())
+        .and(crate::auth::admin())
+        .and(warp::query::<HashMap<String, String>>())
+        .and_then(|params: HashMap<String, String>| async move {
+            let cutoff = days_ago(&params, "since_days", RESOURCE_USAGE_SINCE_DAYS)?;
+            Ok::<_, warp::Rejection>(warp::reply::json(
+                &crate::session::resource_usage(cutoff).await,
+            ))
+        });
+
+    let practice = warp::path!("practice" / String / String)
+        .and(warp::get())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(crate::users::current_user())
+        .and(crate::middleware::client_addr())
+        .and_then(
+            |directory,
+             lang,
+             params: HashMap<String, String>,
+             user: Option<String>,
+             addr: Option<IpAddr>| async move {
+                practice(directory, lang, user, addr.map(|a| a.to_string()), params).await
+            },
+        );
+
+    let serve_resource = warp::path!("serve_resource" / String)
+        .and(warp::get())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(filter_range())
+        .and_then(
+            |resource_path: String, params: HashMap<String, String>, range_header| async move {
                 let filename = get_resource_filename(
-                    decode(&resource_path)
-                        .expect("Invalid source path in serve_resource")
-                        .into_owned(),
+                    decode_path(&resource_path)?,
+                    params.get("track").cloned(),
                 )
                 .await
-                .unwrap();
-                let mime_type = mime_guess::from_path(&filename).first().unwrap();
-                log::debug!("Found MIME type {}", mime_type.as_ref());
-                get_range(range_header, &filename, mime_type.as_ref()).await
-            }),
-    );
+                .map_err(|e| crate::error::reject_as(Kind::ResourceNotFound, &*e))?;
+                let media_type = crate::metadata::media_type(&filename);
+                log::debug!("Found MIME type {}", media_type);
+                serve_file(&filename, &media_type, range_header).await
+            },
+        );
 
-    let status = warp::path!("status" / String).and_then(|uuid| async move {
-        match crate::session::find_session_with_uuid(&uuid).await {
-            Some(session_id) => match crate::session::get_session(&session_id).await {
-                Some(session) => {
-                    Ok::<Json, warp::Rejection>(warp::reply::json(&session.status().unwrap()))
-                }
-                None => Err(warp::reject::not_found()),
+    let audio_speed = warp::path!("resource" / String / "audio-speed" / String)
+        .and(warp::get())
+        .and(filter_range())
+        .and_then(
+            |resource_path: String, factor: String, range_header| async move {
+                let speed = match speed(&factor) {
+                    Some(speed) => speed,
+                    None => {
+                        return Err(reject(
+                            Kind::BadRequest,
+                            format!(
+                                "Speed must be between {} and {}",
+                                SPEED_RANGE.0, SPEED_RANGE.1
+                            ),
+                        ))
+                    }
+                };
+                let resource_path = decode_path(&resource_path)?;
+                let filename = match tokio::task::spawn_blocking(move || {
+                    get_speed_filename(resource_path, speed).map_err(|e| Er::from_error(&*e))
+                })
+                .await
+                {
+                    Ok(Ok(filename)) => filename,
+                    Ok(Err(e)) => {
+                        log::error!("Error in audio-speed: {}", e);
+                        return Err(crate::error::reject_as(Kind::NotFound, &e));
+                    }
+                    Err(e) => {
+                        log::error!("Error in audio-speed: {:?}", e);
+                        return Err(crate::error::reject_error(&e));
+                    }
+                };
+                get_range(range_header, &filename, "audio/wav").await
             },
-            None => Err(warp::reject::not_found()),
-        }
-    });
+        );
 
-    let compare = warp::get()
-        .and(warp::path!("compare" / String / String / String))
-        .and_then(|resource_path: String, uuid, lang| async move {
-            match compare(
-                decode(&resource_path)
-                    .expect("Invalid URL encoding in compare")
-                    .into_owned(),
-                uuid,
-                lang,
+    let status = warp::path!("status" / String)
+        .and(crate::users::current_user())
+        .and_then(|uuid, user| async move {
+            let session = owned_session(&uuid, &user).await?;
+            match session.status() {
+                Ok(status) => Ok::<Json, warp::Rejection>(warp::reply::json(&status)),
+                Err(e) => {
+                    log::error!("Error in status: {:?}", e);
+                    Err(crate::error::reject_error(&*e))
+                }
+            }
+        });
+
+    let status_ws = warp::path!("status-ws" / String)
+        .and(warp::ws())
+        .and(crate::users::current_user())
+        .and_then(|uuid: String, ws: warp::ws::Ws, user| async move {
+            let session_id = owned_session(&uuid, &user).await?.id;
+            Ok::<_, warp::Rejection>(
+                ws.on_upgrade(move |socket| crate::session::status_connected(socket, session_id)),
             )
+        });
+
+    let export = warp::path!("export" / String)
+        .and(warp::get())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(crate::users::current_user())
+        .and_then(
+            |uuid: String, params: HashMap<String, String>, user| async move {
+                let session = owned_session(&uuid, &user).await?;
+                if session.valid {
+                    return Err(reject(
+                        Kind::SessionNotReady,
+                        "The session is still being recorded".to_string(),
+                    ));
+                }
+                let lang = params
+                    .get("lang")
+                    .cloned()
+                    .unwrap_or(session.language.clone());
+                let parts = crate::bundle::bundle(&session, &lang).await.map_err(|e| {
+                    log::error!("Error bundling session {}: {:?}", uuid, e);
+                    crate::error::reject_error(&*e)
+                })?;
+                let body = warp::hyper::Body::wrap_stream(futures_util::stream::iter(
+                    parts.into_iter().map(Ok::<_, std::convert::Infallible>),
+                ));
+                Response::builder()
+                    .header("Content-Type", "application/zip")
+                    .header(
+                        "Content-Disposition",
+                        format!("attachment; filename=\"{}.zip\"", uuid),
+                    )
+                    .body(body)
+                    .map_err(|e| crate::error::reject_error(&e))
+            },
+        );
+
+    let replay = warp::path!("replay" / String)
+        .and(warp::get())
+        .and(crate::users::current_user())
+        .and_then(|uuid, user| async move {
+            let session = owned_session(&uuid, &user).await?;
+            if !session.valid {
+                return Ok::<_, warp::Rejection>(
+                    warp::reply::json(&session.events_from(0)).into_response(),
+                );
+            }
+            let body = warp::hyper::Body::wrap_stream(crate::session::replay_stream(session.id));
+            let response = Response::builder()
+                .header("Content-Type", "application/x-ndjson")
+                .body(body)
+                .map_err(|e| crate::error::reject_error(&e))?;
+            Ok(response)
+        });
+
+    let score = warp::path!("score" / String / String)
+        .and(warp::get())
+        .and(crate::users::current_user())
+        .and_then(|uuid, lang: String, user| async move {
+            let session = owned_session(&uuid, &user).await?;
+            match crate::compare::session_score(&session, &lang) {
+                Ok(score) => Ok(warp::reply::json(&score)),
+                Err(e) => {
+                    log::error!("Error in score: {:?}", e);
+                    Err(crate::error::reject_as(Kind::NotFound, &*e))
+                }
+            }
+        });
+
+    let score_card = warp::path!("session" / String / "score-card")
+        .and(warp::get())
+        .and(crate::users::current_user())
+        .and_then(|uuid, user| async move {
+            let session = owned_session(&uuid, &user).await?;
+            match crate::scoring::score_card(&session).await {
+                Ok(card) => Ok(warp::reply::json(&card)),
+                Err(e) => {
+                    log::error!("Error in score-card: {:?}", e);
+                    Err(crate::error::reject_error(&*e))
+                }
+            }
+        });
+
+    let audio_quality = warp::path!("session" / String / "audio-quality")
+        .and(warp::get())
+        .and(crate::users::current_user())
+        .and_then(|uuid, user| async move {
+            let filename = owned_session(&uuid, &user)
+                .await?
+                .recording_file
+                .ok_or_else(|| no_recording(&uuid))?;
+            let analysed = filename.clone();
+            match tokio::task::spawn_blocking(move || {
+                crate::audio::read_audio(&analysed)
+                    .map(|(samples, sample_rate)| crate::audio::quality(&samples, sample_rate))
+                    .map_err(|e| Er::from_error(&*e))
+            })
+            .await
+            {
+                Ok(Ok(quality)) => Ok(warp::reply::json(&quality)),
+                Ok(Err(e)) => {
+                    log::error!("Error reading {} in audio-quality: {}", filename, e);
+                    Err(crate::error::reject_as(Kind::NotFound, &e))
+                }
+                Err(e) => {
+                    log::error!("Error analysing {} in audio-quality: {:?}", filename, e);
+                    Err(crate::error::reject_error(&e))
+                }
+            }
+        });
+
+    let audio_segments = warp::path!("session" / String / "audio-segments" / String)
+        .and(warp::get())
+        .and(crate::users::current_user())
+        .and_then(|uuid, lang: String, user| async move {
+            let session = owned_session(&uuid, &user).await?;
+            if session.language != lang {
+                return Err(reject(
+                    Kind::NotFound,
+                    format!("Session {} was not in {}", uuid, lang),
+                ));
+            }
+            match tokio::task::spawn_blocking(move || {
+                crate::alignment::session_audio_segments(&session, &lang)
+                    .map_err(|e| Er::from_error(&*e))
+            })
+            .await
+            {
+                Ok(Ok(segments)) => Ok(warp::reply::json(&segments)),
+                Ok(Err(e)) => {
+                    log::error!("Error in audio-segments: {}", e);
+                    Err(crate::error::reject_as(Kind::NotFound, &e))
+                }
+                Err(e) => {
+                    log::error!("Error in audio-segments: {:?}", e);
+                    Err(crate::error::reject_error(&e))
+                }
+            }
+        });
+
+    let rate = warp::path!("session" / String / "rate")
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(crate::users::current_user())
+        .and_then(|uuid: String, rating: SelfRating, user| async move {
+            let session_id = owned_session(&uuid, &user).await?.id;
+            if !(SELF_RATING_RANGE.0..=SELF_RATING_RANGE.1).contains(&rating.self_rating) {
+                return Err(reject(
+                    Kind::BadRequest,
+                    format!(
+                        "self_rating must be between {} and {}",
+                        SELF_RATING_RANGE.0, SELF_RATING_RANGE.1
+                    ),
+                ));
+            }
+            match crate::session::rate_session(session_id, rating.clone()).await {
+                Ok(()) => Ok(warp::reply::json(&rating)),
+                Err(e) => {
+                    log::error!("Error rating session {}: {:?}", uuid, e);
+                    Err(crate::error::reject_error(&*e))
+                }
+            }
+        });
+
+    let compare_visual = warp::path!("session" / String / "compare_visual")
+        .and(warp::get())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(crate::users::current_user())
+        .and_then(|uuid, params: HashMap<String, String>, user| async move {
+            let session = owned_session(&uuid, &user).await?;
+            let lang = params.get("lang").unwrap_or(&session.language).clone();
+            match crate::compare::session_compare_table(&session, &lang) {
+                Ok(table) => Ok(warp::reply::with_header(
+                    table,
+                    "Content-Type",
+                    "text/html; charset=utf-8",
+                )),
+                Err(e) => {
+                    log::error!("Error in compare_visual: {:?}", e);
+                    Err(crate::error::reject_as(Kind::NotFound, &*e))
+                }
+            }
+        })
+        .with(warp::cors().allow_any_origin().allow_method("GET"));
+
+    let compare = warp::path!("compare" / String / String / String)
+        .and(warp::get())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(crate::users::current_user())
+        .and_then(
+            |resource_path: String, uuid, lang, params: HashMap<String, String>, user| async move {
+                let curriculum = params.get("curriculum").cloned();
+                let mode = comparison_mode(&params)?;
+                match compare(
+                    decode_path(&resource_path)?,
+                    uuid,
+                    lang,
+                    curriculum,
+                    mode,
+                    user,
+                )
+                .await
+                {
+                    Ok(x) => Ok(x),
+                    Err(e) => {
+                        log::error!("Error in compare: {:?}", e);
+                        Err(e)
+                    }
+                }
+            },
+        );
+
+    let changes =
+        warp::path!("changes" / String / String / String)
+            .and(warp::get())
+            .and(warp::query::<HashMap<String, String>>())
+            .and(crate::users::current_user())
+            .and_then(
+                |resource_path: String,
+                 uuid: String,
+                 lang,
+                 params: HashMap<String, String>,
+                 user| async move {
+                    owned_session(&uuid, &user).await?;
+                    let resource_path = decode_path(&resource_path)?;
+                    let include_bleu = params.get("include_bleu").is_some_and(|v| v == "true");
+                    let mode = comparison_mode(&params)?;
+                    // the bare array clients expect, unless something only the report has is asked for
+                    if !(include_bleu
+                        || mode != crate::compare::ComparisonMode::Words
+                        || params.get("report").is_some_and(|v| v == "true"))
+                    {
+                        return match crate::compare::changes(resource_path, uuid, lang).await {
+                            Ok(changes) => Ok(warp::reply::json(&changes)),
+                            Err(e) => {
+                                log::error!("Error in changes: {:?}", e);
+                                Err(crate::error::reject_error(&*e))
+                            }
+                        };
+                    }
+                    match crate::compare::report(resource_path, uuid, lang, include_bleu, mode)
+                        .await
+                    {
+                        Ok(report) => Ok(warp::reply::json(&report)),
+                        Err(e) => {
+                            log::error!("Error in changes: {:?}", e);
+                            Err(crate::error::reject_error(&*e))
+                        }
+                    }
+                },
+            );
+
+    let word_timestamps =
+        warp::path!("resource" / String / "narration" / String / "word-timestamps")
+            .and(warp::get())
+            .and_then(|resource_path: String, lang: String| async move {
+                let resource_path = decode_path(&resource_path)?;
+                let aligned = lang.clone();
+                // the forced aligner may run, for as long as it takes
+                match tokio::task::spawn_blocking(move || {
+                    crate::alignment::word_timestamps(&resource_path, &aligned)
+                        .map_err(|e| Er::from_error(&*e))
+                })
+                .await
+                {
+                    Ok(Ok(Some(timestamps))) => Ok(warp::reply::json(&timestamps)),
+                    Ok(Ok(None)) => Err(reject(
+                        Kind::NotFound,
+                        format!("No narration in {} to align", lang),
+                    )),
+                    Ok(Err(e)) => {
+                        log::error!("Error in word-timestamps: {}", e);
+                        Err(crate::error::reject_error(&e))
+                    }
+                    Err(e) => {
+                        log::error!("Error in word-timestamps: {:?}", e);
+                        Err(crate::error::reject_error(&e))
+                    }
+                }
+            });
+
+    let history = warp::path!("practice" / String / String / "history")
+        .and(warp::get())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(crate::users::current_user())
+        .and(crate::middleware::client_addr())
+        .and_then(
+            |resource_path: String,
+             lang: String,
+             params: HashMap<String, String>,
+             user: Option<String>,
+             addr: Option<IpAddr>| async move {
+                let limit = match params.get("limit") {
+                    Some(l) => l.parse::<usize>().map_err(|_| {
+                        reject(
+                            Kind::BadRequest,
+                            format!("limit must be a number, not {}", l),
+                        )
+                    })?,
+                    None => HISTORY_LIMIT,
+                };
+                let resource = decode_path(&resource_path)?;
+                let client = addr.map(|a| a.to_string());
+                let history = crate::session::history(
+                    &resource,
+                    &lang,
+                    user.as_deref(),
+                    client.as_deref(),
+                    limit,
+                )
+                .await
+                .map_err(|e| {
+                    log::error!("Error in practice history: {:?}", e);
+                    crate::error::reject_error(&*e)
+                })?;
+                Ok::<_, warp::Rejection>(warp::reply::json(&history))
+            },
+        );
+
+    let progress = warp::path!("history" / String / String)
+        .and(warp::get())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(crate::users::current_user())
+        .and_then(
+            |resource_path: String,
+             lang: String,
+             params: HashMap<String, String>,
+             user: Option<String>| async move {
+                let limit = match params.get("limit") {
+                    Some(l) => Some(l.parse::<usize>().map_err(|_| {
+                        reject(
+                            Kind::BadRequest,
+                            format!("limit must be a number, not {}", l),
+                        )
+                    })?),
+                    None => None,
+                };
+                let since = match params.get("since") {
+                    Some(s) => Some(
+                        chrono::DateTime::parse_from_rfc3339(s)
+                            .map_err(|_| {
+                                reject(
+                                    Kind::BadRequest,
+                                    format!("since must be an ISO 8601 time, not {}", s),
+                                )
+                            })?
+                            .with_timezone(&chrono::Utc),
+                    ),
+                    None => None,
+                };
+                let resource = decode_path(&resource_path)?;
+                // with user accounts only the user's own, as `session_list` lists them
+                let user = crate::users::enabled().then_some(user);
+                let progress = crate::session::progress(&resource, &lang, since, limit, user)
+                    .await
+                    .map_err(|e| {
+                        log::error!("Error in history: {:?}", e);
+                        crate::error::reject_error(&*e)
+                    })?;
+                Ok::<_, warp::Rejection>(warp::reply::json(&progress))
+            },
+        );
+
+    let resource_sentiment_arc =
+        warp::path!("resource" / String / "transcript" / String / "sentiment-arc")
+            .and(warp::get())
+            .and(warp::query::<HashMap<String, String>>())
+            .and_then(
+                |resource_path: String, lang: String, params: HashMap<String, String>| async move {
+                    let segments = sentiment_segments(&params)?;
+                    match crate::sentiment::resource_sentiment_arc(
+                        &decode_path(&resource_path)?,
+                        &lang,
+                        segments,
+                    ) {
+                        Ok(arc) => Ok(warp::reply::json(&arc)),
+                        Err(e) => {
+                            log::error!("Error in sentiment-arc: {:?}", e);
+                            Err(crate::error::reject_as(Kind::NotFound, &*e))
+                        }
+                    }
+                },
+            );
+
+    let ngrams = warp::path!("resource" / String / "transcript" / String / "ngrams")
+        .and(warp::get())
+        .and(warp::query::<HashMap<String, String>>())
+        .and_then(
+            |resource_path: String, lang: String, params: HashMap<String, String>| async move {
+                let number = |name: &str, default: usize| match params.get(name) {
+                    Some(value) => value.parse::<usize>().ok(),
+                    None => Some(default),
+                };
+                let (Some(n), Some(top_k), Some(min_count)) = (
+                    number("n", crate::ngrams::DEFAULT_N)
+                        .filter(|n| (1..=crate::ngrams::MAX_N).contains(n)),
+                    number("top_k", crate::ngrams::DEFAULT_TOP_K),
+                    number("min_count", crate::ngrams::DEFAULT_MIN_COUNT),
+                ) else {
+                    return Err(reject(
+                        Kind::BadRequest,
+                        format!(
+                            "n must be between 1 and {}, and top_k and min_count must be numbers",
+                            crate::ngrams::MAX_N
+                        ),
+                    ));
+                };
+                let exclude_stopwords = params
+                    .get("exclude_stopwords")
+                    .map(|e| e == "true")
+                    .unwrap_or(false);
+                match crate::ngrams::resource_ngrams(
+                    &decode_path(&resource_path)?,
+                    &lang,
+                    n,
+                    top_k,
+                    min_count,
+                    exclude_stopwords,
+                ) {
+                    Ok(ngrams) => Ok(warp::reply::json(&ngrams)),
+                    Err(e) => {
+                        log::error!("Error in ngrams: {:?}", e);
+                        Err(crate::error::reject_as(Kind::NotFound, &*e))
+                    }
+                }
+            },
+        );
+
+    let session_sentiment_arc = warp::path!("session" / String / "sentiment-arc")
+        .and(warp::get())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(crate::users::current_user())
+        .and_then(|uuid, params: HashMap<String, String>, user| async move {
+            let segments = sentiment_segments(&params)?;
+            let session = owned_session(&uuid, &user).await?;
+            match session.transcript() {
+                Ok(transcript) => Ok(warp::reply::json(&crate::sentiment::sentiment_arc(
+                    &transcript,
+                    &crate::sentiment::load_lexicon(&session.language),
+                    segments,
+                ))),
+                Err(e) => {
+                    log::error!("Error in session sentiment-arc: {:?}", e);
+                    Err(crate::error::reject_as(Kind::NotFound, &*e))
+                }
+            }
+        });
+
+    let gap_fill = warp::path!("resource" / String / "transcript" / String / "gap-fill")
+        .and(warp::get())
+        .and(warp::query::<HashMap<String, String>>())
+        .and_then(
+            |resource_path: String, lang: String, params: HashMap<String, String>| async move {
+                let pos = crate::gapfill::parts_of_speech(
+                    params
+                        .get("pos")
+                        .map(|p| p.as_str())
+                        .unwrap_or(crate::gapfill::DEFAULT_PARTS_OF_SPEECH),
+                );
+                let count = match params.get("count") {
+                    Some(count) => count.parse().map_err(|_| {
+                        reject(
+                            Kind::BadRequest,
+                            format!("count must be a whole number, not {}", count),
+                        )
+                    })?,
+                    None => crate::gapfill::DEFAULT_GAPS,
+                };
+                let seed = params
+                    .get("seed")
+                    .and_then(|s| s.parse().ok())
+                    .unwrap_or_else(rand::random);
+                match crate::gapfill::resource_gap_fill(
+                    &decode_path(&resource_path)?,
+                    &lang,
+                    &pos,
+                    count,
+                    seed,
+                ) {
+                    Ok(exercise) => Ok(warp::reply::json(&exercise)),
+                    Err(e) => {
+                        log::error!("Error in gap-fill: {:?}", e);
+                        Err(crate::error::reject_as(Kind::NotFound, &*e))
+                    }
+                }
+            },
+        );
+
+    let gap_fill_check =
+        warp::path!("resource" / String / "transcript" / String / "gap-fill" / "check")
+            .and(warp::post())
+            .and(warp::body::json())
+            .and_then(
+                |resource_path: String, lang: String, answers: crate::gapfill::Answers| async move {
+                    let pos = crate::gapfill::parts_of_speech(
+                        answers
+                            .pos
+                            .as_deref()
+                            .unwrap_or(crate::gapfill::DEFAULT_PARTS_OF_SPEECH),
+                    );
+                    match crate::gapfill::resource_gap_fill(
+                        &decode_path(&resource_path)?,
+                        &lang,
+                        &pos,
+                        answers.count.unwrap_or(crate::gapfill::DEFAULT_GAPS),
+                        answers.seed,
+                    ) {
+                        Ok(exercise) => Ok(warp::reply::json(&crate::gapfill::check(
+                            &exercise,
+                            &answers.answers,
+                        ))),
+                        Err(e) => {
+                            log::error!("Error in gap-fill check: {:?}", e);
+                            Err(crate::error::reject_as(Kind::NotFound, &*e))
+                        }
+                    }
+                },
+            );
+
+    let difficulty_estimate = warp::path!("resource" / String / "difficulty" / "estimate")
+        .and(warp::get())
+        .and(warp::query::<HashMap<String, String>>())
+        .and_then(
+            |resource_path: String, params: HashMap<String, String>| async move {
+                match crate::difficulty::estimate(&decode_path(&resource_path)?, params.get("lang"))
+                {
+                    Ok(estimate) => Ok(warp::reply::json(&estimate)),
+                    Err(e) => {
+                        log::error!("Error in difficulty estimate: {:?}", e);
+                        Err(crate::error::reject_as(Kind::NotFound, &*e))
+                    }
+                }
+            },
+        );
+
+    let difficulty_components = warp::path!("resource" / String / "difficulty" / "components")
+        .and(warp::get())
+        .and(warp::query::<HashMap<String, String>>())
+        .and_then(
+            |resource_path: String, params: HashMap<String, String>| async move {
+                match crate::difficulty::resource_components(
+                    &decode_path(&resource_path)?,
+                    params.get("lang"),
+                ) {
+                    Ok(components) => Ok(warp::reply::json(&components)),
+                    Err(e) => {
+                        log::error!("Error in difficulty components: {:?}", e);
+                        Err(crate::error::reject_as(Kind::NotFound, &*e))
+                    }
+                }
+            },
+        );
+
+    let resources = warp::path!("resources")
+        .and(warp::get())
+        .and_then(|| async move {
+            match tokio::task::spawn_blocking(|| {
+                crate::metadata::resource_summaries().map_err(|e| Er::from_error(&*e))
+            })
             .await
             {
-                Ok(x) => Ok(x),
+                Ok(Ok(resources)) => Ok(warp::reply::json(&resources)),
+                Ok(Err(e)) => {
+                    log::error!("Error in resources: {}", e);
+                    Err(crate::error::reject_as(Kind::NotFound, &e))
+                }
                 Err(e) => {
-                    log::error!("Error in compare: {:?}", e);
-                    Err(warp::reject())
+                    log::error!("Error in resources: {:?}", e);
+                    Err(crate::error::reject_error(&e))
                 }
             }
         });
 
-    let changes = warp::get()
-        .and(warp::path!("changes" / String / String / String))
-        .and_then(|resource_path: String, uuid, lang| async move {
-            match crate::compare::changes(decode(&resource_path).expect("Invlude URL encoding in changes").into_owned(), uuid, lang).await {
-                Ok(x) => {
-                    let changes = x.clone();
-                    let reply = warp::reply::json(&changes);
-                    Ok(reply)
+    let api_resources = warp::path!("api" / "resources")
+        .and(warp::get())
+        .and_then(|| async move {
+            match tokio::task::spawn_blocking(|| {
+                crate::resources::list().map_err(|e| Er::from_error(&*e))
+            })
+            .await
+            {
+                Ok(Ok(resources)) => Ok(warp::reply::json(&resources)),
+                Ok(Err(e)) => {
+                    log::error!("Error in api/resources: {}", e);
+                    Err(crate::error::reject_as(Kind::NotFound, &e))
                 }
                 Err(e) => {
-                    log::error!("Error in changes: {:?}", e);
-                    Err(warp::reject())
+                    log::error!("Error in api/resources: {:?}", e);
+                    Err(crate::error::reject_error(&e))
                 }
             }
         });
 
-    let recording = warp::get()
-        .and(warp::path!("recording" / String))
-        .and_then(|uuid| async { download_audio(uuid).await });
+    let add_resource = warp::path!("api" / "resources")
+        .and(warp::post())
+        .and(crate::auth::admin())
+        .and(warp::multipart::form().max_length(MAX_UPLOAD_BYTES))
+        .and_then(add_resource);
+
+    let delete_resource = warp::path!("api" / "resources" / String)
+        .and(warp::delete())
+        .and(crate::auth::admin())
+        .and_then(|resource_path: String| async move {
+            let resource = decode_path(&resource_path)?;
+            crate::resources::delete(&resource).map_err(|e| {
+                log::error!("Error deleting resource {}: {:?}", resource, e);
+                crate::error::reject_error(&*e)
+            })?;
+            Ok::<_, warp::Rejection>(StatusCode::NO_CONTENT)
+        });
 
-    let assets_dir = std::env::var("ASSETS_DIR").unwrap_or("../assets".to_string());
-    let assets = warp::get()
-        .and(warp::path("assets"))
+    let related = warp::path!("resource" / String / "related")
+        .and(warp::get())
+        .and_then(|resource_path: String| async move {
+            match crate::metadata::related(&decode_path(&resource_path)?) {
+                Ok(related) => Ok(warp::reply::json(&related)),
+                Err(e) => {
+                    log::error!("Error in related: {:?}", e);
+                    Err(crate::error::reject_as(Kind::NotFound, &*e))
+                }
+            }
+        });
+
+    let resource_sessions = warp::path!("resource" / String / "sessions")
+        .and(warp::get())
+        .and(crate::users::current_user())
+        .and_then(|resource_path: String, user: Option<String>| async move {
+            let resource = decode_path(&resource_path)?;
+            // with user accounts only the user's own, or the anonymous ones, as `session_list` lists them
+            let sessions: Vec<crate::session::SessionInfo> =
+                crate::session::get_sessions_by_resource(&resource)
+                    .await
+                    .iter()
+                    .filter(|s| !crate::users::enabled() || s.user == user)
+                    .map(|s| s.info())
+                    .collect();
+            Ok::<_, warp::Rejection>(warp::reply::json(&sessions))
+        });
+
+    let validate = warp::path!("resource" / String / "validate")
+        .and(warp::get())
+        .and_then(|resource_path: String| async move {
+            Ok::<_, warp::Rejection>(warp::reply::json(&crate::metadata::validate_resource(
+                &decode_path(&resource_path)?,
+            )))
+        });
+
+    let speaker_turns = warp::path!("resource" / String / "speaker-turns" / String)
+        .and(warp::get())
+        .and_then(|resource_path: String, lang: String| async move {
+            let resource_path = decode_path(&resource_path)?;
+            let split = lang.clone();
+            // finding the turns may run the forced aligner
+            match tokio::task::spawn_blocking(move || {
+                crate::compare::speaker_turns(&resource_path, &split)
+                    .map_err(|e| Er::from_error(&*e))
+            })
+            .await
+            {
+                Ok(Ok(Some(turns))) => Ok(warp::reply::json(&turns)),
+                Ok(Ok(None)) => Err(reject(
+                    Kind::NotFound,
+                    format!("No speaker turns in {}", lang),
+                )),
+                Ok(Err(e)) => {
+                    log::error!("Error in speaker-turns: {}", e);
+                    Err(crate::error::reject_error(&e))
+                }
+                Err(e) => {
+                    log::error!("Error in speaker-turns: {:?}", e);
+                    Err(crate::error::reject_error(&e))
+                }
+            }
+        });
+
+    let turn_scores = warp::path!("changes" / String / String / String / "speaker-turns")
+        .and(warp::get())
+        .and(crate::users::current_user())
+        .and_then(
+            |resource_path: String, uuid: String, lang, user| async move {
+                owned_session(&uuid, &user).await?;
+                match crate::compare::turn_scores(decode_path(&resource_path)?, uuid, lang).await {
+                    Ok(Some(scores)) => Ok(warp::reply::json(&scores)),
+                    Ok(None) => Err(reject(
+                        Kind::NotFound,
+                        "No speaker turns to score".to_string(),
+                    )),
+                    Err(e) => {
+                        log::error!("Error in speaker-turns: {:?}", e);
+                        Err(crate::error::reject_error(&*e))
+                    }
+                }
+            },
+        );
+
+    let segment_comparisons = warp::path!("changes" / String / String / String / "consecutive")
+        .and(warp::get())
+        .and(crate::users::current_user())
+        .and_then(
+            |resource_path: String, uuid: String, lang: String, user| async move {
+                let resource_path = decode_path(&resource_path)?;
+                let session = owned_session(&uuid, &user).await?;
+                match tokio::task::spawn_blocking(move || {
+                    crate::consecutive::compare(&resource_path, &session, &lang)
+                        .map_err(|e| Er::from_error(&*e))
+                })
+                .await
+                {
+                    Ok(Ok(segments)) => Ok(warp::reply::json(&segments)),
+                    Ok(Err(e)) => {
+                        log::error!("Error in consecutive changes: {}", e);
+                        Err(crate::error::reject_error(&e))
+                    }
+                    Err(e) => {
+                        log::error!("Error in consecutive changes: {:?}", e);
+                        Err(crate::error::reject_error(&e))
+                    }
+                }
+            },
+        );
+
+    let trimmed_recording = warp::path!("recording" / String / "trim")
+        .and(warp::get())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(crate::users::current_user())
+        .and_then(
+            |uuid: String, params: HashMap<String, String>, user| async move {
+                if !crate::auth::verify_signed_url(&format!("/recording/{}", uuid), &params) {
+                    return Err(warp::reject::custom(crate::auth::Forbidden));
+                }
+                let threshold_db = match params.get("threshold_db") {
+                    Some(t) => t.parse::<f32>().map_err(|_| {
+                        reject(
+                            Kind::BadRequest,
+                            format!("threshold_db must be a number of decibels, not {}", t),
+                        )
+                    })?,
+                    None => crate::audio::DEFAULT_TRIM_THRESHOLD_DB,
+                };
+                let session = owned_session(&uuid, &user).await?;
+                if session.recording_deleted_at.is_some() {
+                    return Err(recording_deleted(&uuid));
+                }
+                let filename = session.recording_file.ok_or_else(|| no_recording(&uuid))?;
+                let trimmed = filename.clone();
+                let missing = format!("Session {} has no recording", uuid);
+                match tokio::task::spawn_blocking(move || {
+                    if !Path::new(&trimmed).exists() {
+                        return Err(*Er::with_kind(Kind::NotFound, missing));
+                    }
+                    crate::audio::trim_silence(&trimmed, threshold_db)
+                        .map_err(|e| Er::from_error(&*e))
+                })
+                .await
+                {
+                    Ok(Ok(wav)) => Response::builder()
+                        .header("Content-Type", "audio/wav")
+                        .header(
+                            "Content-Disposition",
+                            format!("attachment; filename=\"{}_trimmed.wav\"", uuid),
+                        )
+                        .body(Bytes::from(wav))
+                        .map_err(|e| {
+                            log::error!("Error making response: {:?}", e);
+                            crate::error::reject_error(&e)
+                        }),
+                    Ok(Err(e)) => {
+                        log::error!("Error trimming {}: {}", filename, e);
+                        Err(crate::error::reject_error(&e))
+                    }
+                    Err(e) => {
+                        log::error!("Error trimming {}: {:?}", filename, e);
+                        Err(crate::error::reject_error(&e))
+                    }
+                }
+            },
+        );
+
+    let audio_chapters = warp::path!("resource" / String / "audio-chapters")
+        .and(warp::get())
+        .and_then(|resource_path: String| async move {
+            let resource_path = decode_path(&resource_path)?;
+            match tokio::task::spawn_blocking(move || {
+                get_chapters(&resource_path).map_err(|e| Er::from_error(&*e))
+            })
+            .await
+            {
+                Ok(Ok(chapters)) => Ok(warp::reply::json(&chapters)),
+                Ok(Err(e)) => {
+                    log::error!("Error in audio-chapters: {}", e);
+                    Err(crate::error::reject_as(Kind::NotFound, &e))
+                }
+                Err(e) => {
+                    log::error!("Error in audio-chapters: {:?}", e);
+                    Err(crate::error::reject_error(&e))
+                }
+            }
+        });
+
+    let audio_chapter = warp::path!("resource" / String / "audio" / "chapters" / usize)
+        .and(warp::get())
+        .and(filter_range())
+        .and_then(
+            |resource_path: String, index: usize, range_header| async move {
+                let resource_path = decode_path(&resource_path)?;
+                let filename = match tokio::task::spawn_blocking(move || {
+                    get_chapter_filename(resource_path, index).map_err(|e| Er::from_error(&*e))
+                })
+                .await
+                {
+                    Ok(Ok(Some(filename))) => filename,
+                    Ok(Ok(None)) => {
+                        return Err(reject(Kind::NotFound, format!("No chapter {}", index)))
+                    }
+                    Ok(Err(e)) => {
+                        log::error!("Error in audio chapter: {}", e);
+                        return Err(crate::error::reject_as(Kind::NotFound, &e));
+                    }
+                    Err(e) => {
+                        log::error!("Error in audio chapter: {:?}", e);
+                        return Err(crate::error::reject_error(&e));
+                    }
+                };
+                get_range(range_header, &filename, "audio/wav").await
+            },
+        );
+
+    let resource_spectrogram = warp::path!("resource" / String / "audio" / "spectrogram")
+        .and(warp::get())
+        .and(warp::query::<HashMap<String, String>>())
+        .and_then(
+            |resource_path: String, params: HashMap<String, String>| async move {
+                let Some(fft_size) = fft_size(&params) else {
+                    return Err(bad_fft_size());
+                };
+                let resource_path = decode_path(&resource_path)?;
+                match tokio::task::spawn_blocking(move || {
+                    get_spectrogram(resource_path, fft_size).map_err(|e| Er::from_error(&*e))
+                })
+                .await
+                {
+                    Ok(Ok(spectrogram)) => Ok(warp::reply::json(&spectrogram)),
+                    Ok(Err(e)) => {
+                        log::error!("Error in spectrogram: {}", e);
+                        Err(crate::error::reject_as(Kind::NotFound, &e))
+                    }
+                    Err(e) => {
+                        log::error!("Error in spectrogram: {:?}", e);
+                        Err(crate::error::reject_error(&e))
+                    }
+                }
+            },
+        );
+
+    let session_spectrogram = warp::path!("session" / String / "spectrogram")
+        .and(warp::get())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(crate::users::current_user())
+        .and_then(|uuid, params: HashMap<String, String>, user| async move {
+            let Some(fft_size) = fft_size(&params) else {
+                return Err(bad_fft_size());
+            };
+            let filename = owned_session(&uuid, &user)
+                .await?
+                .recording_file
+                .ok_or_else(|| no_recording(&uuid))?;
+            let analysed = filename.clone();
+            match tokio::task::spawn_blocking(move || {
+                crate::audio::read_audio(&analysed)
+                    .map(|(samples, sample_rate)| {
+                        crate::audio::spectrogram(&samples, sample_rate, fft_size)
+                    })
+                    .map_err(|e| Er::from_error(&*e))
+            })
+            .await
+            {
+                Ok(Ok(spectrogram)) => Ok(warp::reply::json(&spectrogram)),
+                Ok(Err(e)) => {
+                    log::error!("Error reading {} in spectrogram: {}", filename, e);
+                    Err(crate::error::reject_as(Kind::NotFound, &e))
+                }
+                Err(e) => {
+                    log::error!("Error analysing {} in spectrogram: {:?}", filename, e);
+                    Err(crate::error::reject_error(&e))
+                }
+            }
+        });
+
+    let pronunciation_report = warp::path!("session" / String / "pronunciation-report" / String)
+        .and(warp::get())
+        .and(crate::users::current_user())
+        .and_then(|uuid, lang: String, user| async move {
+            let session = owned_session(&uuid, &user).await?;
+            match crate::pronunciation::pronunciation_report(&session, &lang).await {
+                Ok(report) => Ok(warp::reply::json(&report)),
+                Err(e) => {
+                    log::error!("Error in pronunciation-report: {:?}", e);
+                    Err(crate::error::reject_as(Kind::NotFound, &*e))
+                }
+            }
+        });
+
+    let recording = warp::path!("recording" / String)
+        .and(warp::get())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(crate::users::current_user())
+        .and(filter_range())
+        .and_then(
+            |uuid: String, params: HashMap<String, String>, user, range_header| async move {
+                if !crate::auth::verify_signed_url(&format!("/recording/{}", uuid), &params) {
+                    return Err(warp::reject::custom(crate::auth::Forbidden));
+                }
+                owned_session(&uuid, &user).await?;
+                download_audio(uuid, params.get("format").cloned(), range_header).await
+            },
+        );
+
+    let delete_recording = warp::path!("recording" / String)
+        .and(warp::delete())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(crate::users::current_user())
+        .and(crate::auth::is_admin())
+        .and_then(
+            |uuid: String, params: HashMap<String, String>, user, admin| async move {
+                if !crate::auth::verify_signed_url(&format!("/recording/{}", uuid), &params) {
+                    return Err(warp::reject::custom(crate::auth::Forbidden));
+                }
+                let session = session_for_uuid(&uuid).await?;
+                // signed URLs are handed out with every listing, so they only add to the permission
+                crate::users::check_can_delete(&session, &user, admin)?;
+                if session.recording_deleted_at.is_some() {
+                    return Err(recording_deleted(&uuid));
+                }
+                if session.valid {
+                    return Err(reject(
+                        Kind::SessionNotReady,
+                        "The session is still being recorded".to_string(),
+                    ));
+                }
+                crate::session::delete_recording(session.id)
+                    .await
+                    .map_err(|e| {
+                        log::error!("Error deleting the recording of {}: {:?}", uuid, e);
+                        crate::error::reject_error(&*e)
+                    })?;
+                Ok(StatusCode::NO_CONTENT)
+            },
+        );
+
+    let api_session = warp::path!("api" / "session" / String)
+        .and(warp::get())
+        .and(crate::users::current_user())
+        .and_then(|uuid, user| async move {
+            let session = owned_session(&uuid, &user).await?;
+            Ok::<Json, warp::Rejection>(warp::reply::json(&session.info()))
+        });
+
+    let api_sessions = warp::path!("api" / "sessions")
+        .and(warp::get())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(crate::users::current_user())
+        .and_then(session_list);
+
+    // The JSON API for other frontends, answering errors like every route does.
+    let v1_sessions = warp::path!("api" / "v1" / "sessions")
+        .and(warp::get())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(crate::users::current_user())
+        .and_then(session_list);
+
+    let v1_session = warp::path!("api" / "v1" / "sessions" / String)
+        .and(warp::get())
+        .and(crate::users::current_user())
+        .and_then(|uuid, user| async move {
+            let session = owned_session(&uuid, &user).await?;
+            Ok::<Json, warp::Rejection>(warp::reply::json(&session.info()))
+        });
+
+    // `/api/v1/session/:uuid` as well, as deleting was first asked for under that name
+    let v1_delete_session = warp::path!("api" / "v1" / "sessions" / String)
+        .or(warp::path!("api" / "v1" / "session" / String))
+        .unify()
+        .and(warp::delete())
+        .and(crate::users::current_user())
+        .and(crate::auth::is_admin())
+        .and_then(|uuid: String, user, admin| async move {
+            let session = session_for_uuid(&uuid).await?;
+            crate::users::check_can_delete(&session, &user, admin)?;
+            crate::session::delete_session(session.id)
+                .await
+                .map_err(|e| {
+                    log::error!("Error deleting session {}: {:?}", uuid, e);
+                    crate::error::reject_error(&*e)
+                })?;
+            Ok::<_, warp::Rejection>(StatusCode::NO_CONTENT)
+        });
+
+    let v1_status = warp::path!("api" / "v1" / "sessions" / String / "status")
+        .and(warp::get())
+        .and(crate::users::current_user())
+        .and_then(|uuid, user| async move {
+            let session = owned_session(&uuid, &user).await?;
+            let status = session.status().map_err(|e| {
+                log::error!("Error in status: {:?}", e);
+                crate::error::reject_error(&*e)
+            })?;
+            Ok::<Json, warp::Rejection>(warp::reply::json(&status))
+        });
+
+    let v1_resources = warp::path!("api" / "v1" / "resources")
+        .and(warp::get())
+        .and(warp::query::<HashMap<String, String>>())
+        .and_then(|params: HashMap<String, String>| async move {
+            let (found, _) = search_library(&params).await?;
+            Ok::<Json, warp::Rejection>(warp::reply::json(&found))
+        });
+
+    let v1_resource = warp::path!("api" / "v1" / "resources" / String / String)
+        .and(warp::get())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(crate::users::current_user())
+        .and(crate::middleware::client_addr())
+        .and_then(
+            |resource_path,
+             lang,
+             params: HashMap<String, String>,
+             user: Option<String>,
+             addr: Option<IpAddr>| async move {
+                let client = addr.map(|a| a.to_string());
+                let practice = practice_data(resource_path, lang, user, client, &params).await?;
+                Ok::<Json, warp::Rejection>(warp::reply::json(&practice))
+            },
+        );
+
+    let v1_curricula = warp::path!("api" / "v1" / "curricula")
+        .and(warp::get())
+        .and_then(|| async move {
+            match crate::curriculum::list() {
+                Ok(curricula) => Ok(warp::reply::json(&curricula)),
+                Err(e) => {
+                    log::error!("Error listing curricula: {}", e);
+                    Err(crate::error::reject_error(&*e))
+                }
+            }
+        });
+
+    let v1_curriculum = warp::path!("api" / "v1" / "curricula" / String)
+        .and(warp::get())
+        .and(crate::users::current_user())
+        .and(crate::middleware::client_addr())
+        .and_then(
+            |id: String, user: Option<String>, addr: Option<IpAddr>| async move {
+                let curriculum = crate::curriculum::get(&id).map_err(|e| {
+                    log::error!("Error loading curriculum {}: {}", id, e);
+                    crate::error::reject_error(&*e)
+                })?;
+                let client = addr.map(|a| a.to_string());
+                let progress =
+                    crate::curriculum::progress(&curriculum, user.as_deref(), client.as_deref())
+                        .await;
+                Ok::<Json, warp::Rejection>(warp::reply::json(&progress))
+            },
+        );
+
+    let v1_comparison =
+        warp::path!("api" / "v1" / "comparisons" / String / String / String)
+            .and(warp::get())
+            .and(warp::query::<HashMap<String, String>>())
+            .and(crate::users::current_user())
+            .and_then(
+                |resource_path: String,
+                 uuid: String,
+                 lang,
+                 params: HashMap<String, String>,
+                 user| async move {
+                    owned_session(&uuid, &user).await?;
+                    let report = crate::compare::report(
+                        decode_path(&resource_path)?,
+                        uuid,
+                        lang,
+                        params.get("include_bleu").is_some_and(|v| v == "true"),
+                        comparison_mode(&params)?,
+                    )
+                    .await
+                    .map_err(|e| {
+                        log::error!("Error in comparison: {:?}", e);
+                        crate::error::reject_error(&*e)
+                    })?;
+                    Ok::<Json, warp::Rejection>(warp::reply::json(&report))
+                },
+            );
+
+    let v1_stats = warp::path!("api" / "v1" / "stats" / "user" / String)
+        .and(warp::get())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(crate::users::current_user())
+        .and_then(
+            |id: String, params: HashMap<String, String>, user: Option<String>| async move {
+                let query = stats_query(&params)?;
+                if !crate::users::enabled() {
+                    return Err(reject(
+                        Kind::NotFound,
+                        "There are no user accounts on this server".to_string(),
+                    ));
+                }
+                if user.as_ref() != Some(&id) {
+                    return Err(reject(
+                        Kind::NotOwner,
+                        format!("Only {} can see their statistics", id),
+                    ));
+                }
+                let stats = crate::stats::stats(Some(&id), None, &query).await;
+                Ok::<Json, warp::Rejection>(warp::reply::json(&stats))
+            },
+        );
+
+    let v1_score = warp::path!("api" / "v1" / "score" / String / String / String)
+        .and(warp::get())
+        .and(crate::users::current_user())
+        .and_then(
+            |resource_path: String, uuid: String, lang, user| async move {
+                let session = owned_session(&uuid, &user).await?;
+                let resource_path = decode_path(&resource_path)?;
+                let report = crate::scoring::session_accuracy(&session, &resource_path, &lang)
+                    .map_err(|e| {
+                        log::error!("Error in score: {:?}", e);
+                        crate::error::reject_error(&*e)
+                    })?;
+                Ok::<Json, warp::Rejection>(warp::reply::json(&report))
+            },
+        );
+
+    let assets_dir = config.assets_dir.clone();
+    let assets = warp::path("assets")
+        .and(warp::get())
         .and(warp::fs::dir(assets_dir));
 
-    let transcript = warp::path!("transcript" / String).and_then(|uuid| async move {
-        match crate::session::find_session_with_uuid(&uuid).await {
-            Some(session_id) => match crate::session::get_session(&session_id).await {
-                Some(session) => Ok(session.transcript().unwrap()),
-                None => Err(warp::reject::not_found()),
+    let transcript = warp::path!("transcript" / String)
+        .and(warp::query::<HashMap<String, String>>())
+        .and(crate::users::current_user())
+        .and_then(
+            |uuid: String, params: HashMap<String, String>, user| async move {
+                let session = owned_session(&uuid, &user).await?;
+                // without a format it is the bare text, as it always was
+                let Some(format) = params.get("format") else {
+                    return session
+                        .transcript()
+                        .map(|t| t.into_response())
+                        .map_err(|e| {
+                            log::error!("Error in transcript: {:?}", e);
+                            crate::error::reject_error(&*e)
+                        });
+                };
+                let format =
+                    ExportFormat::from_name(format).map_err(|e| crate::error::reject_error(&*e))?;
+                export_transcript(&session, format).map(|r| r.into_response())
             },
-            None => Err(warp::reject::not_found()),
-        }
-    });
+        );
+
+    // the paths the subtitles had before transcripts could be exported in other formats
+    let subtitles = warp::path!("transcript" / String / String)
+        .and(warp::get())
+        .and(crate::users::current_user())
+        .and_then(|uuid: String, format: String, user| async move {
+            let format = match format.as_str() {
+                "srt" => ExportFormat::Srt,
+                "vtt" => ExportFormat::Vtt,
+                _ => {
+                    return Err(reject(
+                        Kind::NotFound,
+                        format!("No subtitles as {}", format),
+                    ))
+                }
+            };
+            let session = owned_session(&uuid, &user).await?;
+            export_transcript(&session, format)
+        });
+
+    let upload = warp::path!("upload")
+        .and(warp::post())
+        .and(warp::multipart::form().max_length(MAX_UPLOAD_BYTES))
+        .and(crate::middleware::client_addr())
+        .and(crate::users::current_user())
+        .and(crate::middleware::base_url())
+        .and_then(|form, addr: Option<IpAddr>, user, base_url| async move {
+            upload(form, addr.map(|a| a.to_string()), user, base_url).await
+        });
+
+    let v1_transcribe = warp::path!("api" / "v1" / "transcribe")
+        .and(warp::post())
+        .and(warp::multipart::form().max_length(MAX_UPLOAD_BYTES))
+        .and(crate::middleware::client_addr())
+        .and(crate::users::current_user())
+        .and(crate::middleware::base_url())
+        .and_then(|form, addr: Option<IpAddr>, user, base_url| async move {
+            let reply =
+                crate::api::upload(form, addr.map(|a| a.to_string()), user, base_url).await?;
+            // the transcription goes on after the answer
+            Ok::<_, warp::Rejection>(warp::reply::with_status(reply, StatusCode::ACCEPTED))
+        });
+
+    let login = warp::path!("login")
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(crate::middleware::client_addr())
+        .and_then(
+            |credentials: crate::users::Credentials, addr: Option<IpAddr>| async move {
+                let name = credentials.name.clone();
+                // hashing the password takes a while
+                let token = match tokio::task::spawn_blocking(move || {
+                    crate::users::login(&credentials, addr).map_err(|e| Er::from_error(&*e))
+                })
+                .await
+                {
+                    Ok(Ok(token)) => token,
+                    Ok(Err(e)) if e.kind().is_some() => return Err(warp::reject::custom(e)),
+                    Ok(Err(e)) => {
+                        log::error!("Error logging in: {:?}", e);
+                        return Err(warp::reject::custom(e));
+                    }
+                    Err(e) => {
+                        log::error!("Error logging in: {:?}", e);
+                        return Err(crate::error::reject_error(&e));
+                    }
+                }
+                .ok_or_else(|| warp::reject::custom(crate::users::LoginFailed))?;
+                Ok::<_, warp::Rejection>(warp::reply::with_header(
+                    warp::reply::json(&json!({ "user": name })),
+                    "Set-Cookie",
+                    format!(
+                        "{}={}; Path=/; Max-Age={}; HttpOnly; SameSite=Lax",
+                        crate::users::LOGIN_COOKIE,
+                        token,
+                        crate::users::LOGIN_SECONDS
+                    ),
+                ))
+            },
+        );
+
+    let logout = warp::path!("logout")
+        .and(warp::post())
+        .and(warp::cookie::optional::<String>(crate::users::LOGIN_COOKIE))
+        .map(|token: Option<String>| {
+            if let Some(token) = token {
+                crate::users::logout(&token);
+            }
+            warp::reply::with_header(
+                StatusCode::NO_CONTENT,
+                "Set-Cookie",
+                format!("{}=; Path=/; Max-Age=0", crate::users::LOGIN_COOKIE),
+            )
+        });
 
-    let index = warp::path::end().and_then(|| async move { crate::api::index().await });
+    let set_user = warp::path!("admin" / "users")
+        .and(warp::post())
+        .and(crate::auth::admin())
+        .and(warp::body::json())
+        .and_then(|credentials: crate::users::Credentials| async move {
+            let name = credentials.name.clone();
+            // hashing the password takes a while
+            match tokio::task::spawn_blocking(move || {
+                crate::users::set_user(&credentials).map_err(|e| Er::from_error(&*e))
+            })
+            .await
+            {
+                Ok(Ok(())) => (),
+                Ok(Err(e)) => {
+                    log::error!("Error setting user {}: {:?}", name, e);
+                    return Err(warp::reject::custom(e));
+                }
+                Err(e) => {
+                    log::error!("Error setting user {}: {:?}", name, e);
+                    return Err(crate::error::reject_error(&e));
+                }
+            }
+            Ok::<_, warp::Rejection>(StatusCode::NO_CONTENT)
+        });
+
+    let index = warp::path::end()
+        .and(warp::get())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(crate::users::current_user())
+        .and_then(|params, user| async move { crate::api::index(params, user).await });
 
     #[derive(RustEmbed)]
     #[folder = "../client"]
     struct StaticContent;
     let static_content_serve = warp_embed::embed(&StaticContent);
 
-    let routes = index
-        .or(assets)
+    // Boxed in groups, as a single chain of every route is too deep a type to compile.
+    let resource_routes = assets
+        .or(add_resource)
+        .or(api_resources)
+        .or(audio_chapter)
+        .or(audio_chapters)
+        .or(audio_speed)
+        .or(difficulty_estimate)
+        .or(delete_resource)
+        .or(difficulty_components)
+        .or(gap_fill)
+        .or(gap_fill_check)
+        .or(history)
+        .or(library)
+        .or(progress)
+        .or(ngrams)
+        .or(practice)
+        .or(related)
+        .or(resources)
+        .or(resource_sentiment_arc)
+        .or(resource_sessions)
+        .or(resource_spectrogram)
+        .or(serve_resource)
+        .or(speaker_turns)
+        .or(stats)
+        .or(validate)
+        .or(word_timestamps)
+        .boxed();
+    let session_routes = api_session
+        .or(api_sessions)
+        .or(audio_quality)
+        .or(audio_segments)
         .or(changes)
         .or(chat)
         .or(close)
         .or(compare)
-        .or(practice)
+        .or(compare_visual)
+        .or(pronunciation_report)
+        .or(rate)
+        .or(trimmed_recording)
         .or(recording)
-        .or(serve_resource)
+        .or(delete_recording)
+        .or(replay)
+        .or(export)
+        .or(score)
+        .or(score_card)
+        .or(segment_comparisons)
+        .or(session_sentiment_arc)
+        .or(session_spectrogram)
         .or(status)
+        .or(status_ws)
+        .or(login)
+        .or(logout)
+        .or(subtitles)
+        .or(transcript)
+        .or(turn_scores)
+        .or(upload)
+        .boxed();
+    let admin_routes = close_session
+        .or(dashboard)
+        .or(events)
+        .or(set_user)
+        .or(export_config)
+        .or(invalidate_cache)
+        .or(metrics)
+        .or(reprocess_session)
+        .or(resource_usage)
+        .or(vacuum)
+        .boxed();
+    let api_v1_routes = v1_comparison
+        .or(v1_curricula)
+        .or(v1_curriculum)
+        .or(v1_score)
+        .or(v1_stats)
+        .or(v1_resource)
+        .or(v1_resources)
+        .or(v1_session)
+        .or(v1_delete_session)
+        .or(v1_sessions)
+        .or(v1_status)
+        .or(v1_transcribe)
+        .boxed();
+
+    let routes = index
+        .or(resource_routes)
+        .or(session_routes)
+        .or(admin_routes)
+        .or(api_v1_routes)
         .or(static_content_serve)
-        .or(transcript);
+        .map(warp::reply::Reply::into_response)
+        .boxed();
+    let routes = match crate::middleware::cors(config) {
+        Some(cors) => routes
+            .with(cors)
+            .map(warp::reply::Reply::into_response)
+            .boxed(),
+        None => routes,
+    };
+    let routes = routes
+        .recover(handle_rejection)
+        .with(crate::middleware::security_headers(config))
+        .with(warp::log::custom(|info| {
+            crate::metrics::http_request(
+                info.method().as_str(),
+                info.path(),
+                info.status().as_u16(),
+                info.elapsed(),
+            )
+        }))
+        .map(warp::reply::Reply::into_response)
+        .boxed();
     log::debug!("Starting server");
-    let listen;
-    if let Ok(x) = std::env::var(" LISTEN") {
-        listen = x.parse().unwrap();
-    } else {
-        listen = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 3030);
+    let listen = config.listen;
+    let (stopped, stopped_rx) = tokio::sync::oneshot::channel();
+    let shutdown = async {
+        crate::shutdown::shut_down().await;
+        let _ = stopped.send(());
     };
-
-    warp::serve(routes).run(listen).await;
+    let server = async {
+        match &config.tls {
+            Some(tls) => {
+                if let Err(e) = crate::tls::serve(routes, listen, tls, shutdown).await {
+                    log::error!("{}", e);
+                    std::process::exit(1);
+                }
+            }
+            None => {
+                let (_, server) = warp::serve(routes).bind_with_graceful_shutdown(listen, shutdown);
+                server.await;
+            }
+        }
+    };
+    // connections such as idle websockets would otherwise keep the server from stopping
+    let linger = async {
+        if stopped_rx.await.is_ok() {
+            tokio::time::sleep(Duration::from_secs(
+                crate::shutdown::SHUTDOWN_LINGER_SECONDS,
+            ))
+            .await;
+        } else {
+            std::future::pending::<()>().await;
+        }
+    };
+    tokio::select! {
+        _ = server => (),
+        _ = linger => log::info!("Closing the connections still open"),
+    }
 }
@@ -0,0 +1,855 @@
+use hound::{SampleFormat, WavReader, WavSpec, WavWriter};
+use rustfft::num_complex::Complex;
+use rustfft::FftPlanner;
+use serde::{Deserialize, Serialize};
+use std::io::Read;
+
+use crate::error::{Er, Kind, E};
+use crate::translate::{SILENCE_AMPLITUDE_THRESHOLD, SILENCE_TIME_MILLISECONDS};
+
+/**
+ * Reads a WAV file into mono samples in the range [-1, 1], whatever the
+ * sample format. Multi-channel files are mixed down. Returns the samples
+ * and the sample rate.
+ */
+pub fn read_wav(filename: &str) -> E<(Vec<f32>, u32)> {
+    let mut reader = WavReader::open(filename)?;
+    let spec = reader.spec();
+    let samples: Vec<f32> = match spec.sample_format {
+        SampleFormat::Float => reader.samples::<f32>().collect::<Result<_, _>>()?,
+        SampleFormat::Int => {
+            let scale = (1i64 << (spec.bits_per_sample - 1)) as f32;
+            reader
+                .samples::<i32>()
+                .map(|s| s.map(|s| s as f32 / scale))
+                .collect::<Result<_, _>>()?
+        }
+    };
+    let channels = spec.channels as usize;
+    let mono = if channels > 1 {
+        samples
+            .chunks(channels)
+            .map(|frame| frame.iter().sum::<f32>() / channels as f32)
+            .collect()
+    } else {
+        samples
+    };
+    Ok((mono, spec.sample_rate))
+}
+
+/// Mono samples as a 16 bit PCM WAV file.
+pub fn wav_bytes(samples: &[f32], sample_rate: u32) -> E<Vec<u8>> {
+    let spec = WavSpec {
+        channels: 1,
+        sample_rate,
+        bits_per_sample: 16,
+        sample_format: SampleFormat::Int,
+    };
+    let mut wav = std::io::Cursor::new(vec![]);
+    let mut writer = WavWriter::new(&mut wav, spec)?;
+    for sample in samples {
+        writer.write_sample((sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16)?;
+    }
+    writer.finalize()?;
+    Ok(wav.into_inner())
+}
+
+/**
+ * Writes mono samples as 16 bit PCM. The file is written under a
+ * temporary name and then renamed, so that nobody reads it half-written.
+ */
+pub fn write_wav(filename: &str, samples: &[f32], sample_rate: u32) -> E<()> {
+    let spec = WavSpec {
+        channels: 1,
+        sample_rate,
+        bits_per_sample: 16,
+        sample_format: SampleFormat::Int,
+    };
+    let partial = format!("{}.partial", filename);
+    let mut writer = WavWriter::create(&partial, spec)?;
+    for sample in samples {
+        writer.write_sample((sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16)?;
+    }
+    writer.finalize()?;
+    std::fs::rename(partial, filename)?;
+    Ok(())
+}
+
+/// The sample rate the soundtracks of videos and other files ffmpeg reads are decoded at.
+pub const DECODE_SAMPLE_RATE: u32 = 44100;
+
+/**
+ * Reads a WAV or, going by its extension, FLAC file like `read_wav`.
+ * Anything else which isn't WAV, like the soundtrack of a video or an
+ * MP3, is decoded with `decode_file` at DECODE_SAMPLE_RATE.
+ */
+pub fn read_audio(filename: &str) -> E<(Vec<f32>, u32)> {
+    if !is_flac(filename) {
+        return match read_wav(filename) {
+            Err(_) if !filename.ends_with(".wav") => Ok((
+                decode_file(filename, DECODE_SAMPLE_RATE)?,
+                DECODE_SAMPLE_RATE,
+            )),
+            read => read,
+        };
+    }
+    let (samples, sample_rate, bits_per_sample) = crate::flac::decode(&std::fs::read(filename)?)?;
+    let scale = (1i64 << (bits_per_sample - 1)) as f32;
+    Ok((
+        samples.iter().map(|s| *s as f32 / scale).collect(),
+        sample_rate,
+    ))
+}
+
+fn is_flac(filename: &str) -> bool {
+    filename.ends_with(".flac")
+}
+
+/// The length of a WAV or FLAC file in seconds, read from its header.
+pub fn duration_seconds(filename: &str) -> E<f32> {
+    if is_flac(filename) {
+        let mut header = vec![];
+        std::fs::File::open(filename)?
+            .take(4096)
+            .read_to_end(&mut header)?;
+        let (sample_rate, _, samples) = crate::flac::stream_info(&header)?;
+        return Ok(samples as f32 / sample_rate as f32);
+    }
+    let reader = WavReader::open(filename)?;
+    Ok(reader.duration() as f32 / reader.spec().sample_rate as f32)
+}
+
+/// Samples quieter than this are silence to `trim_silence`, unless told otherwise.
+pub const DEFAULT_TRIM_THRESHOLD_DB: f32 = -45.0;
+
+/**
+ * Returns a WAV file of the part of a recording between the first and the
+ * last sample louder than `threshold_db` (relative to full scale), in the
+ * same sample format as the recording. The recording itself is left
+ * alone. FLAC recordings are returned as integer WAV files.
+ */
+pub fn trim_silence(filename: &str, threshold_db: f32) -> E<Vec<u8>> {
+    let threshold = 10f32.powf(threshold_db / 20.0);
+    let (samples, sample_rate) = read_audio(filename)?;
+    let (start, end) = match (
+        samples.iter().position(|s| s.abs() > threshold),
+        samples.iter().rposition(|s| s.abs() > threshold),
+    ) {
+        (Some(first), Some(last)) => (first, last + 1),
+        _ => (0, 0),
+    };
+
+    let mut trimmed = std::io::Cursor::new(vec![]);
+    if is_flac(filename) {
+        let (samples, _, bits_per_sample) = crate::flac::decode(&std::fs::read(filename)?)?;
+        let spec = WavSpec {
+            channels: 1,
+            sample_rate,
+            bits_per_sample: bits_per_sample as u16,
+            sample_format: SampleFormat::Int,
+        };
+        let mut writer = WavWriter::new(&mut trimmed, spec)?;
+        for sample in &samples[start..end] {
+            writer.write_sample(*sample)?;
+        }
+        writer.finalize()?;
+        return Ok(trimmed.into_inner());
+    }
+
+    let mut reader = WavReader::open(filename)?;
+    let spec = reader.spec();
+    reader.seek(start as u32)?;
+    let count = (end - start) * spec.channels as usize;
+    let mut writer = WavWriter::new(&mut trimmed, spec)?;
+    match spec.sample_format {
+        SampleFormat::Float => {
+            for sample in reader.samples::<f32>().take(count) {
+                writer.write_sample(sample?)?;
+            }
+        }
+        SampleFormat::Int => {
+            for sample in reader.samples::<i32>().take(count) {
+                writer.write_sample(sample?)?;
+            }
+        }
+    }
+    writer.finalize()?;
+    Ok(trimmed.into_inner())
+}
+
+/// Spectrograms have at most this many frames, long audio's being spaced further apart rather than averaged.
+pub const SPECTROGRAM_MAX_FRAMES: usize = 1000;
+/// The quietest level a spectrogram shows, in dB relative to full scale.
+pub const SPECTROGRAM_FLOOR_DB: f32 = -120.0;
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Spectrogram {
+    pub fft_size: usize,
+    /// samples between the starts of successive frames
+    pub hop: usize,
+    pub sample_rate: u32,
+    /// width of each frequency bin
+    pub bin_hz: f32,
+    /// time between successive frames
+    pub frame_secs: f32,
+    /// the level of each of the `fft_size / 2 + 1` bins of each frame, in whole dB
+    pub frames: Vec<Vec<i16>>,
+}
+
+/**
+ * The short-time Fourier transform of the samples, with Hann windows of
+ * `fft_size` samples, which should be a power of two. Frames overlap by
+ * half, or are spaced further apart for long audio to keep to
+ * SPECTROGRAM_MAX_FRAMES, leaving out the samples between them.
+ */
+pub fn spectrogram(samples: &[f32], sample_rate: u32, fft_size: usize) -> Spectrogram {
+    let hop = (fft_size / 2).max(samples.len().div_ceil(SPECTROGRAM_MAX_FRAMES));
+    let window: Vec<f32> = (0..fft_size)
+        .map(|i| 0.5 - 0.5 * (2.0 * std::f32::consts::PI * i as f32 / fft_size as f32).cos())
+        .collect();
+    let gain = 2.0 / window.iter().sum::<f32>();
+    let fft = FftPlanner::<f32>::new().plan_fft_forward(fft_size);
+
+    let mut frames = vec![];
+    let mut buffer = vec![Complex::default(); fft_size];
+    let mut start = 0;
+    while start < samples.len() {
+        for (i, value) in buffer.iter_mut().enumerate() {
+            let sample = samples.get(start + i).cloned().unwrap_or(0.0);
+            *value = Complex::new(sample * window[i], 0.0);
+        }
+        fft.process(&mut buffer);
+        frames.push(
+            buffer[..=fft_size / 2]
+                .iter()
+                .map(|bin| {
+                    (20.0 * (bin.norm() * gain).max(1e-7).log10())
+                        .max(SPECTROGRAM_FLOOR_DB)
+                        .round() as i16
+                })
+                .collect(),
+        );
+        start += hop;
+    }
+    Spectrogram {
+        fft_size,
+        hop,
+        sample_rate,
+        bin_hz: sample_rate as f32 / fft_size as f32,
+        frame_secs: hop as f32 / sample_rate as f32,
+        frames,
+    }
+}
+
+/// Float samples are stored in FLAC with this many bits.
+pub const FLAC_FLOAT_BITS: u32 = 24;
+/// Session recordings are stored as integer samples of this many bits, which FLAC holds exactly.
+pub const RECORDING_BITS: u16 = 24;
+
+/// A sample in the range [-1, 1] as an integer of `bits` bits, scaled as `read_wav` scales them back.
+pub fn int_sample(sample: f32, bits: u16) -> i32 {
+    let scale = (1i64 << (bits - 1)) as f32;
+    (sample * scale).round().clamp(-scale, scale - 1.0) as i32
+}
+
+/**
+ * Compresses a mono WAV file to FLAC and deletes it, returning the number
+ * of bytes saved. Integer samples are kept exactly. Float samples, as
+ * older session recordings have, are only compressed when each of them is a
+ * FLAC_FLOAT_BITS bit fraction, as those of a 16 or 24 bit microphone
+ * are, and otherwise the WAV file is kept. The FLAC file is decoded again
+ * and checked against the WAV file's samples before the WAV file is
+ * removed.
+ */
+pub fn wav_to_flac(wav: &str, flac: &str) -> E<u64> {
+    let mut reader = WavReader::open(wav)?;
+    let spec = reader.spec();
+    if spec.channels != 1 {
+        return Err(Er::new(format!("{} is not mono", wav)));
+    }
+    let float_scale = (1i64 << (FLAC_FLOAT_BITS - 1)) as f32;
+    let (samples, floats, bits_per_sample): (Vec<i32>, Option<Vec<f32>>, u32) =
+        match spec.sample_format {
+            SampleFormat::Int if spec.bits_per_sample <= 24 => (
+                reader.samples::<i32>().collect::<Result<_, _>>()?,
+                None,
+                spec.bits_per_sample as u32,
+            ),
+            SampleFormat::Int => {
+                return Err(Er::new(format!(
+                    "{} has {} bit samples, more than FLAC can hold",
+                    wav, spec.bits_per_sample
+                )))
+            }
+            SampleFormat::Float => {
+                let floats: Vec<f32> = reader.samples::<f32>().collect::<Result<_, _>>()?;
+                let largest = float_scale as i64 - 1;
+                let samples = floats
+                    .iter()
+                    .map(|s| ((s * float_scale) as i64).clamp(-largest - 1, largest) as i32)
+                    .collect();
+                (samples, Some(floats), FLAC_FLOAT_BITS)
+            }
+        };
+    let encoded = crate::flac::encode(&samples, spec.sample_rate, bits_per_sample);
+    let (decoded, sample_rate, decoded_bits) = crate::flac::decode(&encoded)?;
+    let exact = sample_rate == spec.sample_rate
+        && decoded_bits == bits_per_sample
+        && match &floats {
+            Some(floats) => {
+                decoded.len() == floats.len()
+                    && decoded
+                        .iter()
+                        .zip(floats)
+                        .all(|(d, f)| *d as f32 / float_scale == *f)
+            }
+            None => decoded == samples,
+        };
+    if !exact {
+        return Err(Er::new(format!(
+            "{} can't be stored in FLAC without losing detail",
+            wav
+        )));
+    }
+    let partial = format!("{}.partial", flac);
+    std::fs::write(&partial, &encoded)?;
+    std::fs::rename(partial, flac)?;
+    let saved = std::fs::metadata(wav)?
+        .len()
+        .saturating_sub(encoded.len() as u64);
+    std::fs::remove_file(wav)?;
+    Ok(saved)
+}
+
+/**
+ * Mean absolute amplitude of each SILENCE_TIME_MILLISECONDS window, the
+ * same measure `translate::find_silence` uses.
+ */
+pub fn window_levels(samples: &[f32], sample_rate: u32) -> Vec<f32> {
+    let window = (sample_rate as usize * SILENCE_TIME_MILLISECONDS / 1000).max(1);
+    samples
+        .chunks(window)
+        .map(|w| w.iter().map(|s| s.abs()).sum::<f32>() / w.len() as f32)
+        .collect()
+}
+
+/**
+ * The fraction of time spent pausing between the first and the last
+ * window with speech in it.
+ */
+pub fn pause_ratio(samples: &[f32], sample_rate: u32) -> f32 {
+    let levels = window_levels(samples, sample_rate);
+    let voiced = |l: f32| l > SILENCE_AMPLITUDE_THRESHOLD;
+    let first = levels.iter().position(|&l| voiced(l));
+    let last = levels.iter().rposition(|&l| voiced(l));
+    match (first, last) {
+        (Some(first), Some(last)) => {
+            let speech = &levels[first..=last];
+            let pauses = speech.iter().filter(|&&l| !voiced(l)).count();
+            pauses as f32 / speech.len() as f32
+        }
+        _ => 1.0,
+    }
+}
+
+/// Silences at least this long between a learner's words count as pauses.
+pub const PAUSE_SECONDS: f32 = 1.0;
+
+/**
+ * How many times the speaker stops for PAUSE_SECONDS or more between the
+ * first and the last window with speech in it.
+ */
+pub fn pause_count(samples: &[f32], sample_rate: u32) -> usize {
+    let window_secs = SILENCE_TIME_MILLISECONDS as f32 / 1000.0;
+    let levels = window_levels(samples, sample_rate);
+    let voiced = |l: &f32| *l > SILENCE_AMPLITUDE_THRESHOLD;
+    let (Some(first), Some(last)) = (
+        levels.iter().position(voiced),
+        levels.iter().rposition(voiced),
+    ) else {
+        return 0;
+    };
+    levels[first..=last]
+        .split(voiced)
+        .filter(|silence| silence.len() as f32 * window_secs >= PAUSE_SECONDS)
+        .count()
+}
+
+/// The command decoding and transcoding audio unless `FFMPEG` names another.
+pub const DEFAULT_FFMPEG: &str = "ffmpeg";
+
+/// The formats recordings can be transcoded to, with their codecs and content types.
+pub const TRANSCODE_FORMATS: [(&str, &str, &str); 2] = [
+    ("mp3", "libmp3lame", "audio/mpeg"),
+    ("ogg", "libvorbis", "audio/ogg"),
+];
+
+/**
+ * Transcodes a recording to `format`, one of TRANSCODE_FORMATS, with the
+ * command named by `FFMPEG`, by default `ffmpeg`. The result is kept
+ * beside the recording with the format as its extension, and its name is
+ * returned.
+ */
+pub fn transcode(filename: &str, format: &str) -> E<String> {
+    let (_, codec, _) = TRANSCODE_FORMATS
+        .iter()
+        .find(|(f, _, _)| *f == format)
+        .ok_or(Er::new(format!("Can't transcode to {}", format)))?;
+    let stem = filename
+        .rsplit_once('.')
+        .map(|(stem, _)| stem)
+        .unwrap_or(filename);
+    let transcoded = format!("{}.{}", stem, format);
+    if std::path::Path::new(&transcoded).exists() {
+        return Ok(transcoded);
+    }
+    let command = std::env::var("FFMPEG").unwrap_or(DEFAULT_FFMPEG.to_string());
+    let partial = format!("{}.partial", transcoded);
+    log::debug!("Transcoding {} to {}", filename, format);
+    let output = std::process::Command::new(&command)
+        .args(["-y", "-loglevel", "error", "-i", filename, "-c:a", codec])
+        .args(["-f", format, &partial])
+        .output()
+        .map_err(|e| Er::new(format!("Couldn't run {}: {}", command, e)))?;
+    if !output.status.success() {
+        let _ = std::fs::remove_file(&partial);
+        return Err(Er::new(format!(
+            "{} failed with {}: {}",
+            command,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    std::fs::rename(partial, &transcoded)?;
+    Ok(transcoded)
+}
+
+/**
+ * Decodes a recording in any format `FFMPEG`, by default `ffmpeg`, can
+ * read, such as MP3 or Opus, to mono float samples at `sample_rate`.
+ */
+pub fn decode_file(filename: &str, sample_rate: u32) -> E<Vec<f32>> {
+    let command = std::env::var("FFMPEG").unwrap_or(DEFAULT_FFMPEG.to_string());
+    let output = std::process::Command::new(&command)
+        .args(["-loglevel", "error", "-i", filename])
+        .args(["-f", "f32le", "-ac", "1", "-ar", &sample_rate.to_string()])
+        .arg("pipe:1")
+        .output()
+        .map_err(|e| Er::new(format!("Couldn't run {}: {}", command, e)))?;
+    if !output.status.success() {
+        return Err(Er::new(format!(
+            "{} failed with {}: {}",
+            command,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(output
+        .stdout
+        .chunks_exact(4)
+        .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .collect())
+}
+
+/// How audio is sent over the `/chat` websocket.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize, sqlx::Type)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(type_name = "TEXT", rename_all = "lowercase")]
+pub enum Codec {
+    /// mono 32 bit float samples, little endian
+    #[default]
+    Pcm,
+    /// Opus in WebM or Ogg, as browsers' MediaRecorder makes it
+    Opus,
+}
+
+impl Codec {
+    pub fn from_name(name: &str) -> E<Codec> {
+        match name {
+            "pcm" => Ok(Codec::Pcm),
+            "opus" => Ok(Codec::Opus),
+            _ => Err(Er::with_kind(
+                Kind::BadRequest,
+                format!("There is no codec {}, only pcm and opus", name),
+            )),
+        }
+    }
+}
+
+/**
+ * Decodes a compressed stream as it arrives, feeding it to `FFMPEG`,
+ * by default `ffmpeg`, which gives back mono float samples at the
+ * session's sample rate to go down the same path as uncompressed audio.
+ */
+pub struct StreamDecoder {
+    child: tokio::process::Child,
+    stdin: Option<tokio::process::ChildStdin>,
+    stdout: Option<tokio::process::ChildStdout>,
+}
+
+impl StreamDecoder {
+    pub fn start(sample_rate: u32) -> E<Self> {
+        let command = std::env::var("FFMPEG").unwrap_or(DEFAULT_FFMPEG.to_string());
+        let mut child = tokio::process::Command::new(&command)
+            .args(["-loglevel", "error", "-i", "pipe:0"])
+            .args(["-f", "f32le", "-ac", "1", "-ar", &sample_rate.to_string()])
+            .arg("pipe:1")
+            .stdin(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|e| Er::new(format!("Couldn't run {}: {}", command, e)))?;
+        Ok(Self {
+            stdin: child.stdin.take(),
+            stdout: child.stdout.take(),
+            child,
+        })
+    }
+
+    /// The decoded samples' bytes as they come, which can be taken once.
+    pub fn output(&mut self) -> Option<tokio::process::ChildStdout> {
+        self.stdout.take()
+    }
+
+    pub async fn write(&mut self, data: &[u8]) -> E<()> {
+        use tokio::io::AsyncWriteExt;
+        match self.stdin.as_mut() {
+            Some(stdin) => Ok(stdin.write_all(data).await?),
+            None => Err(Er::new("The decoder has been finished".to_string())),
+        }
+    }
+
+    /// Ends the stream, and waits for the last of it to be decoded.
+    pub async fn finish(mut self) -> E<()> {
+        drop(self.stdin.take());
+        let status = self.child.wait().await?;
+        if !status.success() {
+            return Err(Er::new(format!("Decoding failed with {}", status)));
+        }
+        Ok(())
+    }
+}
+
+/// Pauses at least this long in a narration separate its chapters.
+pub const CHAPTER_PAUSE_SECONDS: f32 = 2.0;
+/// Chapters are at least this long, apart from the last.
+pub const MIN_CHAPTER_SECONDS: f32 = 20.0;
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Chapter {
+    pub index: usize,
+    pub start_secs: f32,
+    pub end_secs: f32,
+}
+
+/**
+ * Divides audio into chapters at pauses of CHAPTER_PAUSE_SECONDS or more,
+ * cutting in the middle of each pause, but leaving out any pause which
+ * would give a chapter shorter than MIN_CHAPTER_SECONDS.
+ */
+pub fn chapters(samples: &[f32], sample_rate: u32) -> Vec<Chapter> {
+    let window_secs = SILENCE_TIME_MILLISECONDS as f32 / 1000.0;
+    let levels = window_levels(samples, sample_rate);
+    let voiced = |l: &f32| *l > SILENCE_AMPLITUDE_THRESHOLD;
+    let duration = samples.len() as f32 / sample_rate as f32;
+    let (Some(first), Some(last)) = (
+        levels.iter().position(voiced),
+        levels.iter().rposition(voiced),
+    ) else {
+        return vec![Chapter {
+            index: 0,
+            start_secs: 0.0,
+            end_secs: duration,
+        }];
+    };
+
+    let mut cuts = vec![];
+    let mut pause_start = None;
+    for (i, level) in levels.iter().enumerate().take(last + 1).skip(first) {
+        match (voiced(level), pause_start) {
+            (false, None) => pause_start = Some(i),
+            (true, Some(start)) => {
+                if (i - start) as f32 * window_secs >= CHAPTER_PAUSE_SECONDS {
+                    cuts.push((start + i) as f32 / 2.0 * window_secs);
+                }
+                pause_start = None;
+            }
+            _ => (),
+        }
+    }
+
+    let mut chapters: Vec<Chapter> = vec![];
+    let mut start_secs = 0.0;
+    for cut in cuts {
+        if cut - start_secs >= MIN_CHAPTER_SECONDS {
+            chapters.push(Chapter {
+                index: chapters.len(),
+                start_secs,
+                end_secs: cut,
+            });
+            start_secs = cut;
+        }
+    }
+    chapters.push(Chapter {
+        index: chapters.len(),
+        start_secs,
+        end_secs: duration,
+    });
+    chapters
+}
+
+/**
+ * Divides audio lasting `duration` seconds into chapters at the cue
+ * points, leaving out those which aren't inside it.
+ */
+pub fn chapters_at(cue_points: &[f32], duration: f32) -> Vec<Chapter> {
+    let mut cuts: Vec<f32> = cue_points
+        .iter()
+        .copied()
+        .filter(|cut| *cut > 0.0 && *cut < duration)
+        .collect();
+    cuts.sort_by(f32::total_cmp);
+    cuts.dedup();
+    let mut starts = vec![0.0];
+    starts.extend(&cuts);
+    cuts.push(duration);
+    starts
+        .into_iter()
+        .zip(cuts)
+        .enumerate()
+        .map(|(index, (start_secs, end_secs))| Chapter {
+            index,
+            start_secs,
+            end_secs,
+        })
+        .collect()
+}
+
+/// Samples at or beyond full scale of 16 bit PCM count as clipped.
+pub const CLIPPING_LEVEL: f32 = 32767.0 / 32768.0;
+
+fn rms(samples: &[f32]) -> f32 {
+    if samples.is_empty() {
+        return 0.0;
+    }
+    (samples.iter().map(|s| s * s).sum::<f32>() / samples.len() as f32).sqrt()
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct AudioQuality {
+    pub snr_db: Option<f32>,
+    pub clipping_pct: f32,
+    pub quality_label: String,
+}
+
+/**
+ * Estimates the signal to noise ratio, taking the RMS of the silent
+ * windows as the noise floor and that of the others as the signal, and
+ * how many samples are clipped. The noise floor is never taken to be
+ * below one step of 16 bit PCM, so digital silence gives about 90dB. If
+ * there are no silent or no voiced windows the SNR is unknown, and a
+ * recording without voiced windows is labelled silent.
+ */
+pub fn quality(samples: &[f32], sample_rate: u32) -> AudioQuality {
+    let window = (sample_rate as usize * SILENCE_TIME_MILLISECONDS / 1000).max(1);
+    let (silent, voiced): (Vec<&[f32]>, Vec<&[f32]>) = samples.chunks(window).partition(|w| {
+        w.iter().map(|s| s.abs()).sum::<f32>() / w.len() as f32 <= SILENCE_AMPLITUDE_THRESHOLD
+    });
+    let snr_db = if silent.is_empty() || voiced.is_empty() {
+        None
+    } else {
+        let noise = rms(&silent.concat()).max(1.0 / 32768.0);
+        let signal = rms(&voiced.concat());
+        Some(20.0 * (signal / noise).log10())
+    };
+    let clipped = samples.iter().filter(|s| s.abs() >= CLIPPING_LEVEL).count();
+    let clipping_pct = if samples.is_empty() {
+        0.0
+    } else {
+        100.0 * clipped as f32 / samples.len() as f32
+    };
+    let quality_label = match snr_db {
+        _ if clipping_pct > 1.0 => "clipping",
+        // nothing was said, so there's no telling how well it would be heard
+        None if voiced.is_empty() => "silent",
+        Some(snr) if snr < 10.0 => "poor",
+        Some(snr) if snr < 20.0 => "fair",
+        _ => "good",
+    }
+    .to_string();
+    AudioQuality {
+        snr_db,
+        clipping_pct,
+        quality_label,
+    }
+}
+
+/// Length of the frames `time_stretch` cuts the audio into.
+pub const STRETCH_FRAME_MILLISECONDS: usize = 40;
+/// How far `time_stretch` may move a frame to line it up with the last one.
+pub const STRETCH_TOLERANCE_MILLISECONDS: usize = 10;
+
+/**
+ * Changes the speed of speech without changing its pitch, using WSOLA
+ * (waveform similarity overlap-add). Frames are taken from the input
+ * `speed` times further apart than they are laid down in the output,
+ * each one shifted, within a tolerance, to where it best continues the
+ * frame before it, and cross-faded with a Hann window.
+ */
+pub fn time_stretch(samples: &[f32], sample_rate: u32, speed: f32) -> Vec<f32> {
+    let frame = (sample_rate as usize * STRETCH_FRAME_MILLISECONDS / 1000).max(4) & !1;
+    let hop = frame / 2;
+    let tolerance = sample_rate as usize * STRETCH_TOLERANCE_MILLISECONDS / 1000;
+    let window: Vec<f32> = (0..frame)
+        .map(|i| 0.5 - 0.5 * (2.0 * std::f32::consts::PI * i as f32 / frame as f32).cos())
+        .collect();
+
+    let mut output = vec![0f32; (samples.len() as f32 / speed) as usize + frame];
+    let mut previous = 0usize;
+    let mut k = 0usize;
+    loop {
+        let nominal = (k as f32 * hop as f32 * speed) as usize;
+        if nominal + frame > samples.len() || k * hop + frame > output.len() {
+            break;
+        }
+        let position = if k == 0 {
+            0
+        } else {
+            // the frame which would naturally have followed the last one
+            let natural = &samples[(previous + hop).min(samples.len() - frame)..][..hop];
+            let similarity = |candidate: usize| -> f32 {
+                natural
+                    .iter()
+                    .zip(&samples[candidate..candidate + hop])
+                    .step_by(4)
+                    .map(|(x, y)| x * y)
+                    .sum()
+            };
+            let lowest = nominal.saturating_sub(tolerance);
+            let highest = (nominal + tolerance).min(samples.len() - frame);
+            (lowest..=highest)
+                .map(|candidate| (candidate, similarity(candidate)))
+                .max_by(|a, b| a.1.total_cmp(&b.1))
+                .map(|(candidate, _)| candidate)
+                .unwrap_or(nominal)
+        };
+        for (i, w) in window.iter().enumerate() {
+            output[k * hop + i] += w * samples[position + i];
+        }
+        previous = position;
+        k += 1;
+    }
+    output.truncate(k * hop + hop);
+    output
+}
+
+/// The lowest `rate` sessions may stream at.
+pub const MIN_SAMPLE_RATE: u32 = 8000;
+/// The highest `rate` sessions may stream at.
+pub const MAX_SAMPLE_RATE: u32 = 192000;
+/// Length of the windows `preprocess` tells speech from silence in.
+pub const VAD_WINDOW_MILLISECONDS: usize = 30;
+/// How much silence `preprocess` leaves around the speech it trims to, so that words aren't cut off.
+pub const TRIM_PADDING_MILLISECONDS: usize = 200;
+
+/**
+ * How the audio of a session is prepared before it is transcribed, as
+ * `[preprocessing]` in the config file. It is always resampled to
+ * `TRANSCRIBE_SAMPLE_RATE`.
+ */
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Preprocessing {
+    /// brings the speech to `target_dbfs`, so that quiet microphones are heard
+    pub normalize: bool,
+    /// the RMS level of the speech, relative to full scale
+    pub target_dbfs: f32,
+    /// the most the audio is amplified, so that noise isn't turned up into speech
+    pub max_gain_db: f32,
+    /// cuts the silence before and after the speech in each piece
+    pub trim_silence: bool,
+    /// windows quieter than this, relative to full scale, are silence
+    pub silence_threshold_db: f32,
+}
+
+impl Default for Preprocessing {
+    fn default() -> Self {
+        Self {
+            normalize: true,
+            target_dbfs: -20.0,
+            max_gain_db: 30.0,
+            trim_silence: false,
+            silence_threshold_db: DEFAULT_TRIM_THRESHOLD_DB,
+        }
+    }
+}
+
+/// A piece of audio ready to be transcribed, and how much was cut from its start.
+pub struct Preprocessed {
+    pub samples: Vec<f32>,
+    pub trimmed_secs: f32,
+}
+
+fn db(level: f32) -> f32 {
+    20.0 * level.max(f32::MIN_POSITIVE).log10()
+}
+
+/**
+ * Resamples a piece of a session from `sample_rate` to
+ * `TRANSCRIBE_SAMPLE_RATE`, then, as `settings` say, trims it to the
+ * windows louder than `silence_threshold_db` with TRIM_PADDING_MILLISECONDS
+ * around them, and amplifies or attenuates it so that the RMS of those
+ * windows is `target_dbfs`, by at most `max_gain_db` and never so much
+ * that it clips. A piece with no speech in it is trimmed to nothing.
+ */
+pub fn preprocess(samples: &[f32], sample_rate: u32, settings: &Preprocessing) -> E<Preprocessed> {
+    let rate = crate::transcribe::TRANSCRIBE_SAMPLE_RATE;
+    let mut samples = match sample_rate {
+        _ if samples.is_empty() => vec![],
+        r if r == rate => samples.to_vec(),
+        r => crate::translate::resample_to(samples, r as f64, rate as f64)?,
+    };
+
+    let window = (rate as usize * VAD_WINDOW_MILLISECONDS / 1000).max(1);
+    let threshold = 10f32.powf(settings.silence_threshold_db / 20.0);
+    let voiced: Vec<bool> = samples.chunks(window).map(|w| rms(w) > threshold).collect();
+    // measured before trimming so that the windows line up
+    let speech: Vec<f32> = samples
+        .chunks(window)
+        .zip(&voiced)
+        .filter(|(_, voiced)| **voiced)
+        .flat_map(|(w, _)| w.iter().copied())
+        .collect();
+    let level = rms(if speech.is_empty() { &samples } else { &speech });
+
+    let mut trimmed_secs = 0.0;
+    if settings.trim_silence {
+        let padding = rate as usize * TRIM_PADDING_MILLISECONDS / 1000;
+        let (start, end) = match (
+            voiced.iter().position(|v| *v),
+            voiced.iter().rposition(|v| *v),
+        ) {
+            (Some(first), Some(last)) => (
+                (first * window).saturating_sub(padding),
+                ((last + 1) * window + padding).min(samples.len()),
+            ),
+            _ => (samples.len(), samples.len()),
+        };
+        samples.truncate(end);
+        samples.drain(..start);
+        trimmed_secs = start as f32 / rate as f32;
+    }
+
+    if settings.normalize && level > 0.0 {
+        let peak = samples.iter().fold(0f32, |peak, s| peak.max(s.abs()));
+        let gain_db = (settings.target_dbfs - db(level))
+            .min(settings.max_gain_db)
+            .min(db(CLIPPING_LEVEL) - db(peak));
+        let gain = 10f32.powf(gain_db / 20.0);
+        samples.iter_mut().for_each(|s| *s *= gain);
+    }
+    Ok(Preprocessed {
+        samples,
+        trimmed_secs,
+    })
+}
@@ -0,0 +1,137 @@
+use chrono::Utc;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use warp::{Filter, Rejection};
+
+#[derive(Debug)]
+pub struct Unauthorized;
+
+impl warp::reject::Reject for Unauthorized {}
+
+#[derive(Debug)]
+pub struct Forbidden;
+
+impl warp::reject::Reject for Forbidden {}
+
+/// How long signed URLs are valid for, unless `URL_SIGNING_TTL_SECONDS` says otherwise.
+pub const DEFAULT_URL_SIGNING_TTL_SECONDS: i64 = 3600;
+
+/// Whether `header` is `Bearer <TERPLOUNGE_API_TOKEN>`, which it never is while that isn't set.
+fn is_admin_header(header: &Option<String>) -> bool {
+    match std::env::var("TERPLOUNGE_API_TOKEN") {
+        Ok(token) => !token.is_empty() && header.as_deref() == Some(&format!("Bearer {}", token)),
+        Err(_) => false,
+    }
+}
+
+/**
+ * Only lets through requests with an `Authorization: Bearer <token>`
+ * header matching `TERPLOUNGE_API_TOKEN`. When that isn't set, the admin
+ * routes are closed to everyone.
+ */
+pub fn admin() -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::header::optional::<String>("authorization")
+        .and_then(|header: Option<String>| async move {
+            match is_admin_header(&header) {
+                true => Ok(()),
+                false => Err(warp::reject::custom(Unauthorized)),
+            }
+        })
+        .untuple_one()
+}
+
+/// Whether the request is made with the admin token, for routes open to others as well.
+pub fn is_admin() -> impl Filter<Extract = (bool,), Error = Rejection> + Clone {
+    warp::header::optional::<String>("authorization").map(|header| is_admin_header(&header))
+}
+
+pub fn hmac_sha256(key: &[u8], message: &[u8]) -> Vec<u8> {
+    const BLOCK_SIZE: usize = 64;
+    let mut key = if key.len() > BLOCK_SIZE {
+        Sha256::digest(key).to_vec()
+    } else {
+        key.to_vec()
+    };
+    key.resize(BLOCK_SIZE, 0);
+    let inner_pad: Vec<u8> = key.iter().map(|b| b ^ 0x36).collect();
+    let outer_pad: Vec<u8> = key.iter().map(|b| b ^ 0x5c).collect();
+    let inner = Sha256::new()
+        .chain_update(inner_pad)
+        .chain_update(message)
+        .finalize();
+    Sha256::new()
+        .chain_update(outer_pad)
+        .chain_update(inner)
+        .finalize()
+        .to_vec()
+}
+
+/// The key to sign URLs with, when `SIGNED_URLS=1` and `URL_SIGNING_KEY` is set.
+fn signing_key() -> Option<String> {
+    if std::env::var("SIGNED_URLS").ok()? != "1" {
+        return None;
+    }
+    std::env::var("URL_SIGNING_KEY")
+        .ok()
+        .filter(|key| !key.is_empty())
+}
+
+fn signature(key: &str, path: &str, expires: i64) -> String {
+    hmac_sha256(
+        key.as_bytes(),
+        format!("{}?exp={}", path, expires).as_bytes(),
+    )
+    .iter()
+    .map(|b| format!("{:02x}", b))
+    .collect()
+}
+
+/**
+ * Returns `path`, with an expiry time and an HMAC-SHA256 signature of
+ * the two added as `exp` and `sig` query parameters when URL signing is
+ * turned on.
+ */
+pub fn signed_url(path: &str) -> String {
+    match signing_key() {
+        Some(key) => {
+            let ttl = std::env::var("URL_SIGNING_TTL_SECONDS")
+                .ok()
+                .and_then(|ttl| ttl.parse().ok())
+                .unwrap_or(DEFAULT_URL_SIGNING_TTL_SECONDS);
+            let expires = Utc::now().timestamp() + ttl;
+            format!(
+                "{}?exp={}&sig={}",
+                path,
+                expires,
+                signature(&key, path, expires)
+            )
+        }
+        None => path.to_string(),
+    }
+}
+
+/**
+ * Checks the `exp` and `sig` parameters of a request for `path` made
+ * with a URL from `signed_url`. Anything goes when URL signing is off.
+ */
+pub fn verify_signed_url(path: &str, params: &HashMap<String, String>) -> bool {
+    let Some(key) = signing_key() else {
+        return true;
+    };
+    let (Some(expires), Some(sig)) = (
+        params.get("exp").and_then(|exp| exp.parse::<i64>().ok()),
+        params.get("sig"),
+    ) else {
+        return false;
+    };
+    if expires < Utc::now().timestamp() {
+        return false;
+    }
+    let expected = signature(&key, path, expires);
+    expected.len() == sig.len()
+        && expected
+            .bytes()
+            .zip(sig.bytes())
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
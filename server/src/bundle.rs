@@ -0,0 +1,211 @@
+use bytes::Bytes;
+use chrono::{Datelike, Timelike, Utc};
+
+use crate::error::{Er, E};
+use crate::export::ExportFormat;
+use crate::session::SessionData;
+
+/// The CRC-32 of each byte, which `crc32` looks up instead of working through its bits.
+const CRC_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in bytes {
+        crc = (crc >> 8) ^ CRC_TABLE[((crc ^ *byte as u32) & 0xff) as usize];
+    }
+    !crc
+}
+
+/**
+ * Writes a ZIP archive an entry at a time, so each can be sent as soon as
+ * it is ready. Entries are stored as they are, since recordings hardly
+ * compress and the texts are small.
+ */
+struct ZipWriter {
+    /// the central directory record of each entry written so far
+    directory: Vec<u8>,
+    entries: u16,
+    offset: u32,
+    time: u16,
+    date: u16,
+}
+
+impl ZipWriter {
+    fn new() -> Self {
+        let now = Utc::now();
+        Self {
+            directory: vec![],
+            entries: 0,
+            offset: 0,
+            time: ((now.hour() << 11) | (now.minute() << 5) | (now.second() / 2)) as u16,
+            date: (((now.year() - 1980).max(0) as u32) << 9 | now.month() << 5 | now.day()) as u16,
+        }
+    }
+
+    /// The fields the local header and the central directory have in common.
+    fn common_header(&self, crc: u32, size: u32, name: &str) -> Vec<u8> {
+        let mut header = vec![];
+        header.extend_from_slice(&20u16.to_le_bytes()); // version needed
+        header.extend_from_slice(&0x0800u16.to_le_bytes()); // the name is UTF-8
+        header.extend_from_slice(&0u16.to_le_bytes()); // stored
+        header.extend_from_slice(&self.time.to_le_bytes());
+        header.extend_from_slice(&self.date.to_le_bytes());
+        header.extend_from_slice(&crc.to_le_bytes());
+        header.extend_from_slice(&size.to_le_bytes());
+        header.extend_from_slice(&size.to_le_bytes());
+        header.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        header.extend_from_slice(&0u16.to_le_bytes()); // no extra field
+        header
+    }
+
+    /// The local header of a file in the archive, which its contents follow.
+    fn entry(&mut self, name: &str, data: &[u8]) -> E<Vec<u8>> {
+        let size: u32 = data
+            .len()
+            .try_into()
+            .map_err(|_| Er::new(format!("{} is too big for a ZIP file", name)))?;
+        let crc = crc32(data);
+        let common = self.common_header(crc, size, name);
+
+        let mut local = 0x0403_4b50u32.to_le_bytes().to_vec();
+        local.extend_from_slice(&common);
+        local.extend_from_slice(name.as_bytes());
+
+        self.directory
+            .extend_from_slice(&0x0201_4b50u32.to_le_bytes());
+        self.directory.extend_from_slice(&20u16.to_le_bytes()); // version made by
+        self.directory.extend_from_slice(&common);
+        self.directory.extend_from_slice(&[0; 6]); // comment length, disk and internal attributes
+        self.directory.extend_from_slice(&0u32.to_le_bytes()); // external attributes
+        self.directory.extend_from_slice(&self.offset.to_le_bytes());
+        self.directory.extend_from_slice(name.as_bytes());
+
+        self.entries += 1;
+        self.offset = u32::try_from(local.len() + data.len())
+            .ok()
+            .and_then(|len| self.offset.checked_add(len))
+            .ok_or(Er::new("The bundle is too big for a ZIP file".to_string()))?;
+        Ok(local)
+    }
+
+    /// The central directory, which ends the archive.
+    fn finish(self) -> Vec<u8> {
+        let mut end = self.directory;
+        let size = end.len() as u32;
+        end.extend_from_slice(&0x0605_4b50u32.to_le_bytes());
+        end.extend_from_slice(&[0; 4]); // this is the only disk
+        end.extend_from_slice(&self.entries.to_le_bytes());
+        end.extend_from_slice(&self.entries.to_le_bytes());
+        end.extend_from_slice(&size.to_le_bytes());
+        end.extend_from_slice(&self.offset.to_le_bytes());
+        end.extend_from_slice(&0u16.to_le_bytes()); // no comment
+        end
+    }
+}
+
+/// The recording of a session as a WAV file, decoding it first if it has been vacuumed to FLAC.
+fn recording_wav(filename: &str) -> E<Vec<u8>> {
+    if filename.ends_with(".wav") {
+        return Ok(std::fs::read(filename)?);
+    }
+    let (samples, sample_rate) = crate::audio::read_audio(filename)?;
+    crate::audio::wav_bytes(&samples, sample_rate)
+}
+
+/**
+ * The files of a session's bundle, named as they are in the archive: its
+ * transcript as text and subtitles, and when its resource has a text in
+ * `lang` that reference, the comparison with it and its score. The
+ * recording is left to `recording_wav`, as it has to be read from disk.
+ */
+async fn texts(session: &SessionData, lang: &String) -> E<Vec<(String, Vec<u8>)>> {
+    let mut files = vec![
+        (
+            "transcript.txt".to_string(),
+            crate::export::export(session, ExportFormat::Txt)?.into_bytes(),
+        ),
+        (
+            "transcript.srt".to_string(),
+            crate::export::export(session, ExportFormat::Srt)?.into_bytes(),
+        ),
+    ];
+    let Some(resource) = session.resource.clone() else {
+        return Ok(files);
+    };
+    let reference = match crate::compare::get_translation(&resource, lang) {
+        Ok(reference) => reference,
+        Err(e) => {
+            log::debug!("The bundle of {} has no reference: {}", session.uuid, e);
+            return Ok(files);
+        }
+    };
+    let report = crate::compare::report(
+        resource,
+        session.uuid.to_string(),
+        lang.clone(),
+        true,
+        crate::compare::ComparisonMode::Words,
+    )
+    .await?;
+    let score = crate::scoring::accuracy_report(&reference, &session.transcript()?);
+    files.push((format!("reference_{}.txt", lang), reference.into_bytes()));
+    files.push((
+        "comparison.json".to_string(),
+        serde_json::to_vec_pretty(&report)?,
+    ));
+    files.push(("score.json".to_string(), serde_json::to_vec_pretty(&score)?));
+    Ok(files)
+}
+
+/// The parts of the archive of `files`, after the recording in `recording_file` if there is one.
+fn archive(recording_file: Option<String>, mut files: Vec<(String, Vec<u8>)>) -> E<Vec<Bytes>> {
+    if let Some(filename) = recording_file {
+        files.insert(0, ("recording.wav".to_string(), recording_wav(&filename)?));
+    }
+    let mut zip = ZipWriter::new();
+    let mut parts = vec![];
+    for (name, data) in files {
+        parts.push(Bytes::from(zip.entry(&name, &data)?));
+        parts.push(Bytes::from(data));
+    }
+    parts.push(Bytes::from(zip.finish()));
+    Ok(parts)
+}
+
+/**
+ * The parts of a ZIP archive of what a session produced, to hand in: the
+ * recording, unless it has been deleted, the transcript, and the
+ * reference text in `lang` with the comparison and score report. Each
+ * entry is two parts, its header and its contents, and the last part is
+ * the archive's directory. The recording is read and the archive put
+ * together off the async runtime.
+ */
+pub async fn bundle(session: &SessionData, lang: &String) -> E<Vec<Bytes>> {
+    let files = texts(session, lang).await?;
+    let recording_file = match session.recording_deleted_at {
+        None => session.recording_file.clone(),
+        Some(_) => None,
+    };
+    Ok(tokio::task::spawn_blocking(move || {
+        archive(recording_file, files).map_err(|e| Er::from_error(&*e))
+    })
+    .await??)
+}
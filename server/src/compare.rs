@@ -1,22 +1,25 @@
-use crate::error::{Er, E};
+use crate::alignment::WordTimestamp;
+use crate::error::{Er, Kind, E};
 use crate::metadata::Metadata;
 use crate::session::find_session_with_uuid;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use serde_json::json;
-use similar::{ChangeTag, TextDiff};
+use similar::{capture_diff_slices, Algorithm, ChangeTag, DiffOp, TextDiff};
+use std::collections::{HashMap, HashSet};
 use std::fs;
 
-fn get_translation(resource_path: &String, lang: &String) -> E<String> {
+pub fn get_translation(resource_path: &String, lang: &String) -> E<String> {
     let metadata = Metadata::from_resource_path(resource_path)?;
     let source_path = format!(
         "{}/{}",
         metadata.enclosing_directory,
-        metadata.translations.get(lang).unwrap_or_else(|| {
-            panic!(
+        metadata.translations.get(lang).ok_or(Er::with_kind(
+            Kind::NotFound,
+            format!(
                 "Translation not found for resource {} and lang {}",
                 resource_path, lang
-            );
-        })
+            )
+        ))?
     );
     let source = fs::read_to_string(source_path.clone())?;
     Ok(source)
@@ -40,35 +43,961 @@ pub async fn get_comparison(resource_path: &str, uuid: &str, lang: &str) -> E<Co
 pub struct Change {
     pub change_type: String,
     pub content: String,
+    /// where in the recording the words are, or for words missing from it where they should have been
+    pub start_secs: Option<f32>,
+    pub end_secs: Option<f32>,
+    /// who said the words, in diarized sessions
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub speaker: Option<String>,
 }
 
-pub async fn changes(resource_path: String, uuid: String, lang: String) -> E<Vec<Change>> {
-    let source = get_translation(&resource_path, &lang)?;
-    let session_id = find_session_with_uuid(&uuid)
-        .await
-        .expect("Session not found");
+async fn get_session(uuid: &String) -> E<crate::session::SessionData> {
+    let session_id = find_session_with_uuid(uuid).await.ok_or(Er::with_kind(
+        Kind::SessionNotFound,
+        format!("No session {}", uuid),
+    ))?;
 
-    let session = match crate::session::get_session(&session_id).await {
-        Some(s) => s,
-        None => return Err(Er::new(format!("Session {} not found", session_id))),
-    };
+    match crate::session::get_session(&session_id).await {
+        Some(s) => Ok(s),
+        None => Err(Er::new(format!("Session {} not found", session_id))),
+    }
+}
 
+async fn get_transcript(uuid: &String) -> E<String> {
+    get_session(uuid).await?.transcript()
+}
+
+/**
+ * The word by word differences between the transcript of session `uuid`
+ * and the reference text in `lang`. Words of the transcript are timed
+ * where they were said, and words missing from it at the end of the
+ * transcript's word before them.
+ */
+pub async fn changes(resource_path: String, uuid: String, lang: String) -> E<Vec<Change>> {
+    let source = get_translation(&resource_path, &lang)?;
+    let session = get_session(&uuid).await?;
     let dest = session.transcript()?;
+    let mut word_times = session.word_times().into_iter();
+    let mut word_speakers = session.word_speakers().into_iter();
+    let mut last_end = None;
+    let mut last_speaker = None;
 
     log::debug!("Comparing");
 
     let diff = TextDiff::configure().diff_words(dest.as_str(), source.as_str());
     let changes: Vec<Change> = diff
         .iter_all_changes()
-        .map(|x| Change {
-            change_type: match x.tag() {
-                ChangeTag::Equal => "equal".to_string(),
-                ChangeTag::Delete => "delete".to_string(),
-                ChangeTag::Insert => "insert".to_string(),
-            },
-            content: x.value().to_string(),
+        .map(|x| {
+            let (times, speaker) = match x.tag() {
+                _ if x.value().trim().is_empty() => (None, None),
+                ChangeTag::Insert => (last_end.map(|end| (end, end)), last_speaker.clone()),
+                ChangeTag::Equal | ChangeTag::Delete => {
+                    let times = word_times.next().flatten();
+                    last_end = times.map(|(_, end)| end).or(last_end);
+                    last_speaker = word_speakers.next().flatten().or(last_speaker.clone());
+                    (times, last_speaker.clone())
+                }
+            };
+            Change {
+                change_type: match x.tag() {
+                    ChangeTag::Equal => "equal".to_string(),
+                    ChangeTag::Delete => "delete".to_string(),
+                    ChangeTag::Insert => "insert".to_string(),
+                },
+                content: x.value().to_string(),
+                start_secs: times.map(|(start, _)| start),
+                end_secs: times.map(|(_, end)| end),
+                speaker,
+            }
         })
         .collect();
-    log::trace!("Changes: {}", json!(changes).to_string());
+    log::trace!("Changes: {}", json!(changes));
     Ok(changes)
 }
+
+/// Splits text into lowercase words with surrounding punctuation removed.
+pub fn words(text: &str) -> Vec<String> {
+    text.split_whitespace()
+        .map(|w| {
+            w.trim_matches(|c: char| !c.is_alphanumeric())
+                .to_lowercase()
+        })
+        .filter(|w| !w.is_empty())
+        .collect()
+}
+
+/**
+ * Estimates the syllables in a word by counting its groups of vowels.
+ * Every word has at least one.
+ */
+pub fn syllables(word: &str) -> usize {
+    let is_vowel = |c: char| "aeiouyäöüéèêëàâáíìîïóòôúùûœæå".contains(c);
+    let mut count = 0;
+    let mut previous_vowel = false;
+    for c in word.to_lowercase().chars() {
+        let vowel = is_vowel(c);
+        if vowel && !previous_vowel {
+            count += 1;
+        }
+        previous_vowel = vowel;
+    }
+    count.max(1)
+}
+
+/// Words with more syllables than this are worth listening to again.
+pub const LONG_WORD_SYLLABLES: u8 = 4;
+
+/**
+ * The words of the transcript with more than `threshold_syllables`
+ * syllables, with their positions among its words. These are the ones
+ * easiest to rush through.
+ */
+pub fn highlight_long_words(transcript: &str, threshold_syllables: u8) -> Vec<(usize, String)> {
+    words(transcript)
+        .into_iter()
+        .enumerate()
+        .filter(|(_, word)| syllables(word) > threshold_syllables as usize)
+        .collect()
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Score {
+    pub wer: f32,
+    pub insertions: usize,
+    pub deletions: usize,
+    pub substitutions: usize,
+    pub reference_words: usize,
+    pub hypothesis_words: usize,
+}
+
+/**
+ * Word error rate of `hypothesis` against `reference`. A replaced run of
+ * words counts as substitutions for the overlap, and the rest as
+ * insertions or deletions.
+ */
+pub fn word_error_rate(reference: &str, hypothesis: &str) -> Score {
+    let reference = words(reference);
+    let hypothesis = words(hypothesis);
+    let mut insertions = 0;
+    let mut deletions = 0;
+    let mut substitutions = 0;
+    for op in capture_diff_slices(Algorithm::Myers, &reference, &hypothesis) {
+        match op {
+            DiffOp::Equal { .. } => (),
+            DiffOp::Delete { old_len, .. } => deletions += old_len,
+            DiffOp::Insert { new_len, .. } => insertions += new_len,
+            DiffOp::Replace {
+                old_len, new_len, ..
+            } => {
+                substitutions += old_len.min(new_len);
+                deletions += old_len.saturating_sub(new_len);
+                insertions += new_len.saturating_sub(old_len);
+            }
+        }
+    }
+    let errors = (insertions + deletions + substitutions) as f32;
+    Score {
+        wer: if reference.is_empty() {
+            errors
+        } else {
+            errors / reference.len() as f32
+        },
+        insertions,
+        deletions,
+        substitutions,
+        reference_words: reference.len(),
+        hypothesis_words: hypothesis.len(),
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Substitution {
+    pub expected: String,
+    pub heard: String,
+}
+
+/**
+ * Reference words which the hypothesis replaced one for one with another
+ * word, which often means they were misheard or mispronounced.
+ */
+pub fn substitutions(reference: &str, hypothesis: &str) -> Vec<Substitution> {
+    let reference = words(reference);
+    let hypothesis = words(hypothesis);
+    capture_diff_slices(Algorithm::Myers, &reference, &hypothesis)
+        .into_iter()
+        .filter_map(|op| match op {
+            DiffOp::Replace {
+                old_index,
+                old_len,
+                new_index,
+                new_len,
+            } if old_len == new_len => Some((old_index, new_index, old_len)),
+            _ => None,
+        })
+        .flat_map(|(old_index, new_index, len)| {
+            (0..len).map(move |i| (old_index + i, new_index + i))
+        })
+        .map(|(old, new)| Substitution {
+            expected: reference[old].clone(),
+            heard: hypothesis[new].clone(),
+        })
+        .collect()
+}
+
+/// How a reference word and the word heard in its place were aligned.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Alignment {
+    Equal,
+    /// a reference word which wasn't heard
+    Deletion,
+    Substitution,
+    /// a word heard which isn't in the reference
+    Insertion,
+}
+
+#[derive(Clone, Debug)]
+pub struct WordPair {
+    pub alignment: Alignment,
+    pub reference: Option<String>,
+    pub heard: Option<String>,
+}
+
+/**
+ * Aligns the words of the hypothesis with those of the reference, pairing
+ * each reference word with the word heard in its place, if any, in the
+ * same way the word error rate counts errors.
+ */
+pub fn align_words(reference: &str, hypothesis: &str) -> Vec<WordPair> {
+    let reference = words(reference);
+    let hypothesis = words(hypothesis);
+    let pair = |alignment, old: Option<usize>, new: Option<usize>| WordPair {
+        alignment,
+        reference: old.map(|i| reference[i].clone()),
+        heard: new.map(|i| hypothesis[i].clone()),
+    };
+    let mut pairs = vec![];
+    for op in capture_diff_slices(Algorithm::Myers, &reference, &hypothesis) {
+        match op {
+            DiffOp::Equal {
+                old_index,
+                new_index,
+                len,
+            } => pairs.extend(
+                (0..len).map(|i| pair(Alignment::Equal, Some(old_index + i), Some(new_index + i))),
+            ),
+            DiffOp::Delete {
+                old_index, old_len, ..
+            } => pairs
+                .extend((0..old_len).map(|i| pair(Alignment::Deletion, Some(old_index + i), None))),
+            DiffOp::Insert {
+                new_index, new_len, ..
+            } => pairs.extend(
+                (0..new_len).map(|i| pair(Alignment::Insertion, None, Some(new_index + i))),
+            ),
+            DiffOp::Replace {
+                old_index,
+                old_len,
+                new_index,
+                new_len,
+            } => {
+                for i in 0..old_len.max(new_len) {
+                    let old = (i < old_len).then_some(old_index + i);
+                    let new = (i < new_len).then_some(new_index + i);
+                    let alignment = match (old, new) {
+                        (Some(_), Some(_)) => Alignment::Substitution,
+                        (Some(_), None) => Alignment::Deletion,
+                        _ => Alignment::Insertion,
+                    };
+                    pairs.push(pair(alignment, old, new));
+                }
+            }
+        }
+    }
+    pairs
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/**
+ * An HTML table with a row for each pair of aligned words, coloured by
+ * how they were aligned, for embedding in other pages. It is styled
+ * inline, as the page it ends up in won't have our stylesheet.
+ */
+pub fn compare_table(pairs: &[WordPair]) -> String {
+    let mut html = String::from(
+        "<table class=\"terplounge-compare\">\n<tr><th>Reference</th><th>Heard</th></tr>\n",
+    );
+    for pair in pairs {
+        let (class, colour) = match pair.alignment {
+            Alignment::Equal => ("equal", "#c8f0c8"),
+            Alignment::Deletion => ("deletion", "#f4c2c2"),
+            Alignment::Substitution => ("substitution", "#f8efb0"),
+            Alignment::Insertion => ("insertion", "#c2d8f4"),
+        };
+        html.push_str(&format!(
+            "<tr class=\"{}\" style=\"background-color: {}\"><td>{}</td><td>{}</td></tr>\n",
+            class,
+            colour,
+            escape_html(pair.reference.as_deref().unwrap_or_default()),
+            escape_html(pair.heard.as_deref().unwrap_or_default()),
+        ));
+    }
+    html.push_str("</table>\n");
+    html
+}
+
+/// The comparison table of a session's transcript with the reference translation in `lang`.
+pub fn session_compare_table(session: &crate::session::SessionData, lang: &String) -> E<String> {
+    let resource = session
+        .resource
+        .as_ref()
+        .ok_or(Er::new(format!("Session {} has no resource", session.uuid)))?;
+    let reference = get_translation(resource, lang)?;
+    Ok(compare_table(&align_words(
+        &reference,
+        &session.transcript()?,
+    )))
+}
+
+pub async fn score(resource_path: String, uuid: String, lang: String) -> E<Score> {
+    let source = get_translation(&resource_path, &lang)?;
+    let dest = get_transcript(&uuid).await?;
+    Ok(word_error_rate(&source, &dest))
+}
+
+/**
+ * The word error rate of a session in `lang` against its resource's
+ * reference. Once the session has finished its score is kept with it, so
+ * asking again is cheap, until the session is reprocessed.
+ */
+pub fn session_score(session: &crate::session::SessionData, lang: &String) -> E<Score> {
+    if let Some(score) = session.scores.lock().unwrap().get(lang) {
+        return Ok(score.clone());
+    }
+    let resource = session.resource.as_ref().ok_or(Er::with_kind(
+        Kind::ResourceNotFound,
+        format!("Session {} has no resource to score against", session.uuid),
+    ))?;
+    let score = word_error_rate(&get_translation(resource, lang)?, &session.transcript()?);
+    if !session.valid {
+        session
+            .scores
+            .lock()
+            .unwrap()
+            .insert(lang.clone(), score.clone());
+    }
+    Ok(score)
+}
+
+/// The longest n-grams `/changes` counts towards its BLEU score.
+pub const BLEU_MAX_N: usize = 4;
+
+#[derive(Clone, Debug, Serialize)]
+pub struct BleuScore {
+    pub individual_precisions: Vec<f32>,
+    pub brevity_penalty: f32,
+    pub bleu: f32,
+}
+
+fn n_grams(words: &[String], n: usize) -> HashMap<&[String], usize> {
+    let mut counts = HashMap::new();
+    for gram in words.windows(n) {
+        *counts.entry(gram).or_insert(0) += 1;
+    }
+    counts
+}
+
+/**
+ * BLEU score of `hypothesis` against a single `reference`: the geometric
+ * mean of the clipped 1- to `max_n`-gram precisions, scaled down by the
+ * brevity penalty if the hypothesis is shorter than the reference. If
+ * any of the precisions is zero so is the score.
+ */
+pub fn n_gram_overlap(reference: &str, hypothesis: &str, max_n: usize) -> BleuScore {
+    let reference = words(reference);
+    let hypothesis = words(hypothesis);
+    let individual_precisions: Vec<f32> = (1..=max_n)
+        .map(|n| {
+            let reference_counts = n_grams(&reference, n);
+            let hypothesis_counts = n_grams(&hypothesis, n);
+            let total: usize = hypothesis_counts.values().sum();
+            if total == 0 {
+                return 0.0;
+            }
+            let matches: usize = hypothesis_counts
+                .iter()
+                .map(|(gram, count)| (*count).min(*reference_counts.get(gram).unwrap_or(&0)))
+                .sum();
+            matches as f32 / total as f32
+        })
+        .collect();
+    let brevity_penalty = if hypothesis.is_empty() {
+        0.0
+    } else if hypothesis.len() >= reference.len() {
+        1.0
+    } else {
+        (1.0 - reference.len() as f32 / hypothesis.len() as f32).exp()
+    };
+    let bleu = if individual_precisions.is_empty() || individual_precisions.contains(&0.0) {
+        0.0
+    } else {
+        let log_mean = individual_precisions.iter().map(|p| p.ln()).sum::<f32>()
+            / individual_precisions.len() as f32;
+        brevity_penalty * log_mean.exp()
+    };
+    BleuScore {
+        individual_precisions,
+        brevity_penalty,
+        bleu,
+    }
+}
+
+/**
+ * How closely the times the session's words were spoken at follow those
+ * of the same words in the reference, from 0 for no relation to 1 for
+ * perfect timing. The words are matched up as for the word error rate and
+ * the score is the correlation of their start times, so a steady lag
+ * behind the reference, as interpreting involves, doesn't count against
+ * it. Fewer than two matched words give 0.
+ */
+pub fn time_alignment_score(
+    reference_timestamps: &[(String, f32)],
+    session_timestamps: &[(String, f32)],
+) -> f32 {
+    let normalize = |timestamps: &[(String, f32)]| -> Vec<String> {
+        timestamps
+            .iter()
+            .map(|(word, _)| words(word).concat())
+            .collect()
+    };
+    let reference = normalize(reference_timestamps);
+    let session = normalize(session_timestamps);
+    let pairs: Vec<(f32, f32)> = capture_diff_slices(Algorithm::Myers, &reference, &session)
+        .into_iter()
+        .filter_map(|op| match op {
+            DiffOp::Equal {
+                old_index,
+                new_index,
+                len,
+            } => Some((old_index, new_index, len)),
+            _ => None,
+        })
+        .flat_map(|(old_index, new_index, len)| {
+            (0..len).map(move |i| {
+                (
+                    reference_timestamps[old_index + i].1,
+                    session_timestamps[new_index + i].1,
+                )
+            })
+        })
+        .collect();
+    if pairs.len() < 2 {
+        return 0.0;
+    }
+
+    let n = pairs.len() as f32;
+    let mean_reference = pairs.iter().map(|(r, _)| r).sum::<f32>() / n;
+    let mean_session = pairs.iter().map(|(_, s)| s).sum::<f32>() / n;
+    let mut covariance = 0.0;
+    let mut variance_reference = 0.0;
+    let mut variance_session = 0.0;
+    for (r, s) in &pairs {
+        covariance += (r - mean_reference) * (s - mean_session);
+        variance_reference += (r - mean_reference).powi(2);
+        variance_session += (s - mean_session).powi(2);
+    }
+    if variance_reference == 0.0 || variance_session == 0.0 {
+        return 0.0;
+    }
+    (covariance / (variance_reference * variance_session).sqrt()).clamp(0.0, 1.0)
+}
+
+/**
+ * The time alignment score of a session against the narration of the
+ * resource in `lang`, if there are word timestamps for both.
+ */
+fn timing_score(
+    resource_path: &String,
+    session: &crate::session::SessionData,
+    lang: &str,
+) -> E<Option<f32>> {
+    let start_times = |timestamps: Vec<WordTimestamp>| -> Vec<(String, f32)> {
+        timestamps.into_iter().map(|t| (t.word, t.start)).collect()
+    };
+    let Some(reference) = crate::alignment::word_timestamps(resource_path, lang)? else {
+        return Ok(None);
+    };
+    let Some(heard) = crate::alignment::session_word_timestamps(session, lang)? else {
+        return Ok(None);
+    };
+    Ok(Some(time_alignment_score(
+        &start_times(reference),
+        &start_times(heard),
+    )))
+}
+
+/// How `/compare` and `/changes` compare a transcript with its reference.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ComparisonMode {
+    /// the word by word differences, and nothing else
+    #[default]
+    Words,
+    /// the word by word differences with the sentences aligned by meaning
+    Semantic,
+}
+
+impl ComparisonMode {
+    pub fn from_name(name: &str) -> E<ComparisonMode> {
+        match name {
+            "words" => Ok(ComparisonMode::Words),
+            "semantic" => Ok(ComparisonMode::Semantic),
+            _ => Err(Er::with_kind(
+                Kind::BadRequest,
+                format!("There is no mode {}, only words and semantic", name),
+            )),
+        }
+    }
+}
+
+/// Sentences aligned with each other less alike than this are taken to be an omission and an addition.
+pub const MIN_SENTENCE_SIMILARITY: f32 = 0.25;
+
+/// Sentences of the reference and of the rendition which say the same, or one of them alone.
+#[derive(Clone, Debug, Serialize)]
+pub struct SentencePair {
+    /// the indices of the sentences of the reference, none when the rendition added the sentences
+    pub reference_sentences: Vec<usize>,
+    pub reference: String,
+    /// the indices of the sentences of the rendition, none when it left the sentences out
+    pub rendition_sentences: Vec<usize>,
+    pub rendition: String,
+    /// the cosine similarity of their embeddings, when there are both
+    pub similarity: Option<f32>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct SemanticComparison {
+    /// what the sentences were embedded with, see [crate::embeddings::backend]
+    pub embedding: String,
+    /// the mean similarity of the sentences of the reference, those left out counting as 0
+    pub similarity: f32,
+    pub pairs: Vec<SentencePair>,
+}
+
+/// How a step of [semantic_comparison]'s alignment takes sentences of the reference and the rendition.
+const SENTENCE_STEPS: [(usize, usize); 5] = [(1, 1), (2, 1), (1, 2), (1, 0), (0, 1)];
+
+/**
+ * Aligns the sentences of `hypothesis` with those of `reference` by
+ * meaning, so that paraphrases count as renditions. The sentences, and
+ * each two following each other, are embedded, and the sentences are
+ * paired in order so that the similarity of the reference's sentences is
+ * highest, a sentence of one side going with one of the other, or two
+ * being merged or split into one. Pairs less alike than
+ * MIN_SENTENCE_SIMILARITY are left unpaired. This blocks while the
+ * embeddings are made.
+ */
+pub fn semantic_comparison(reference: &str, hypothesis: &str) -> E<SemanticComparison> {
+    let sentences = |text: &str| -> Vec<String> {
+        crate::difficulty::sentences(text)
+            .into_iter()
+            .filter(|s| !words(s).is_empty())
+            .collect()
+    };
+    let (reference, hypothesis) = (sentences(reference), sentences(hypothesis));
+    let joined =
+        |sentences: &[String], start: usize, len: usize| sentences[start..start + len].join(" ");
+    // each sentence, then each two following each other
+    let spans = |sentences: &[String]| -> Vec<String> {
+        let singles = (0..sentences.len()).map(|i| joined(sentences, i, 1));
+        let doubles = (1..sentences.len()).map(|i| joined(sentences, i - 1, 2));
+        singles.chain(doubles).collect()
+    };
+    let texts: Vec<String> = spans(&reference)
+        .into_iter()
+        .chain(spans(&hypothesis))
+        .collect();
+    let embeddings: HashMap<String, Vec<f32>> = texts
+        .iter()
+        .cloned()
+        .zip(crate::embeddings::embed(&texts)?)
+        .collect();
+    let similarity = |r: &str, h: &str| crate::embeddings::cosine(&embeddings[r], &embeddings[h]);
+
+    // best[i][j] is the highest total similarity of the first i sentences of the reference aligned with the first j of the rendition
+    let (n, m) = (reference.len(), hypothesis.len());
+    let mut best = vec![vec![f32::NEG_INFINITY; m + 1]; n + 1];
+    let mut came_by = vec![vec![(0, 0, None); m + 1]; n + 1];
+    best[0][0] = 0.0;
+    for i in 0..=n {
+        for j in 0..=m {
+            if best[i][j] == f32::NEG_INFINITY {
+                continue;
+            }
+            for (di, dj) in SENTENCE_STEPS {
+                if i + di > n || j + dj > m {
+                    continue;
+                }
+                let pair = (di > 0 && dj > 0)
+                    .then(|| similarity(&joined(&reference, i, di), &joined(&hypothesis, j, dj)));
+                let gain = match pair {
+                    Some(s) if s >= MIN_SENTENCE_SIMILARITY => s * di as f32,
+                    Some(_) => continue,
+                    None => 0.0,
+                };
+                if best[i][j] + gain > best[i + di][j + dj] {
+                    best[i + di][j + dj] = best[i][j] + gain;
+                    came_by[i + di][j + dj] = (di, dj, pair);
+                }
+            }
+        }
+    }
+
+    let mut pairs = vec![];
+    let (mut i, mut j) = (n, m);
+    while i > 0 || j > 0 {
+        let (di, dj, similarity) = came_by[i][j];
+        i -= di;
+        j -= dj;
+        pairs.push(SentencePair {
+            reference_sentences: (i..i + di).collect(),
+            reference: joined(&reference, i, di),
+            rendition_sentences: (j..j + dj).collect(),
+            rendition: joined(&hypothesis, j, dj),
+            similarity,
+        });
+    }
+    pairs.reverse();
+    Ok(SemanticComparison {
+        embedding: crate::embeddings::backend().to_string(),
+        similarity: if n == 0 { 0.0 } else { best[n][m] / n as f32 },
+        pairs,
+    })
+}
+
+#[derive(Clone, Serialize)]
+pub struct ChangesReport {
+    pub changes: Vec<Change>,
+    pub score: Score,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bleu: Option<BleuScore>,
+    /// the long words of the transcript, by position, see [highlight_long_words]
+    pub attention_words: Vec<(usize, String)>,
+    /// see [time_alignment_score]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timing_score: Option<f32>,
+    /// the glossary's terms found in the transcript and missed, when the resource has one for the language
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub terminology: Option<crate::glossary::TerminologyReport>,
+    /// the sentences aligned by meaning, with `mode=semantic`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub semantic: Option<SemanticComparison>,
+}
+
+/**
+ * The word by word changes between the session's transcript and the
+ * reference, with the word error rate, the long words of the transcript,
+ * the timing score when there are word timestamps, the terminology when
+ * there is a glossary and, if asked for, the BLEU score and in the
+ * semantic `mode` the sentences aligned by meaning.
+ */
+pub async fn report(
+    resource_path: String,
+    uuid: String,
+    lang: String,
+    include_bleu: bool,
+    mode: ComparisonMode,
+) -> E<ChangesReport> {
+    let source = get_translation(&resource_path, &lang)?;
+    let dest = get_transcript(&uuid).await?;
+    let session = match find_session_with_uuid(&uuid).await {
+        Some(session_id) => crate::session::get_session(&session_id).await,
+        None => None,
+    };
+    let timing_score = match session {
+        Some(session) => {
+            let (resource_path, lang) = (resource_path.clone(), lang.clone());
+            match tokio::task::spawn_blocking(move || {
+                timing_score(&resource_path, &session, &lang).map_err(|e| e.to_string())
+            })
+            .await
+            {
+                Ok(Ok(score)) => score,
+                Ok(Err(e)) => {
+                    log::warn!("Couldn't score the timing of session {}: {}", uuid, e);
+                    None
+                }
+                Err(e) => {
+                    log::error!("Error scoring the timing of session {}: {:?}", uuid, e);
+                    None
+                }
+            }
+        }
+        None => None,
+    };
+    let terminology =
+        crate::glossary::terminology(&resource_path, &lang, &dest).unwrap_or_else(|e| {
+            log::warn!("Couldn't read the glossary of {}: {}", resource_path, e);
+            None
+        });
+    let semantic = match mode {
+        ComparisonMode::Words => None,
+        ComparisonMode::Semantic => {
+            let (source, dest) = (source.clone(), dest.clone());
+            match tokio::task::spawn_blocking(move || {
+                semantic_comparison(&source, &dest).map_err(|e| e.to_string())
+            })
+            .await
+            {
+                Ok(Ok(semantic)) => Some(semantic),
+                Ok(Err(e)) => {
+                    log::warn!("Couldn't compare the sentences of session {}: {}", uuid, e);
+                    None
+                }
+                Err(e) => {
+                    log::error!("Error comparing the sentences of session {}: {:?}", uuid, e);
+                    None
+                }
+            }
+        }
+    };
+    Ok(ChangesReport {
+        terminology,
+        semantic,
+        changes: changes(resource_path, uuid, lang).await?,
+        score: word_error_rate(&source, &dest),
+        bleu: include_bleu.then(|| n_gram_overlap(&source, &dest, BLEU_MAX_N)),
+        attention_words: highlight_long_words(&dest, LONG_WORD_SYLLABLES),
+        timing_score,
+    })
+}
+
+/// Pauses longer than this in a narration are taken to be a change of speaker.
+pub const TURN_GAP_SECONDS: f32 = 1.0;
+
+#[derive(Clone, Debug, Serialize)]
+pub struct SpeakerTurn {
+    pub speaker: String,
+    pub start_secs: f32,
+    pub text: String,
+}
+
+/**
+ * Stands in for real diarization of dialogues: splits the narration at
+ * every pause longer than TURN_GAP_SECONDS, and labels the turns as two
+ * speakers taking turns, A, B, A, B...
+ */
+pub fn speaker_diarization_stub(timestamps: &[WordTimestamp]) -> Vec<SpeakerTurn> {
+    let mut turns: Vec<SpeakerTurn> = vec![];
+    let mut last_end: Option<f32> = None;
+    for timestamp in timestamps {
+        match turns.last_mut() {
+            Some(turn) if timestamp.start - last_end.unwrap_or(0.0) <= TURN_GAP_SECONDS => {
+                turn.text.push(' ');
+                turn.text.push_str(&timestamp.word);
+            }
+            _ => turns.push(SpeakerTurn {
+                speaker: ["A", "B"][turns.len() % 2].to_string(),
+                start_secs: timestamp.start,
+                text: timestamp.word.clone(),
+            }),
+        }
+        last_end = Some(timestamp.end);
+    }
+    turns
+}
+
+pub fn speaker_turns(resource_path: &String, lang: &str) -> E<Option<Vec<SpeakerTurn>>> {
+    Ok(crate::alignment::word_timestamps(resource_path, lang)?
+        .map(|timestamps| speaker_diarization_stub(&timestamps)))
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct TurnScore {
+    pub speaker: String,
+    pub start_secs: f32,
+    pub score: Score,
+}
+
+#[derive(Clone, Default)]
+struct Errors {
+    insertions: usize,
+    deletions: usize,
+    substitutions: usize,
+    hypothesis_words: usize,
+}
+
+/**
+ * Scores each turn separately. The hypothesis is aligned with the whole
+ * reference, and each error is counted against the turn of the reference
+ * word it's at; insertions go to the turn of the word before them. Without
+ * turns there is nothing to score.
+ */
+pub fn score_turns(turns: &[SpeakerTurn], hypothesis: &str) -> Vec<TurnScore> {
+    if turns.is_empty() {
+        return vec![];
+    }
+    let mut turn_of: Vec<usize> = vec![];
+    let mut reference: Vec<String> = vec![];
+    for (i, turn) in turns.iter().enumerate() {
+        let mut turn_words = words(&turn.text);
+        turn_of.resize(turn_of.len() + turn_words.len(), i);
+        reference.append(&mut turn_words);
+    }
+    let hypothesis = words(hypothesis);
+    let turn_at = |index: usize| {
+        if turn_of.is_empty() {
+            0
+        } else {
+            turn_of[index.min(turn_of.len() - 1)]
+        }
+    };
+
+    let mut errors = vec![Errors::default(); turns.len()];
+    for op in capture_diff_slices(Algorithm::Myers, &reference, &hypothesis) {
+        match op {
+            DiffOp::Equal { old_index, len, .. } => {
+                for i in old_index..old_index + len {
+                    errors[turn_at(i)].hypothesis_words += 1;
+                }
+            }
+            DiffOp::Delete {
+                old_index, old_len, ..
+            } => {
+                for i in old_index..old_index + old_len {
+                    errors[turn_at(i)].deletions += 1;
+                }
+            }
+            DiffOp::Insert {
+                old_index, new_len, ..
+            } => {
+                let turn = &mut errors[turn_at(old_index.saturating_sub(1))];
+                turn.insertions += new_len;
+                turn.hypothesis_words += new_len;
+            }
+            DiffOp::Replace {
+                old_index,
+                old_len,
+                new_len,
+                ..
+            } => {
+                for k in 0..old_len {
+                    let turn = &mut errors[turn_at(old_index + k)];
+                    if k < new_len {
+                        turn.substitutions += 1;
+                        turn.hypothesis_words += 1;
+                    } else {
+                        turn.deletions += 1;
+                    }
+                }
+                let turn = &mut errors[turn_at(old_index + old_len - 1)];
+                turn.insertions += new_len.saturating_sub(old_len);
+                turn.hypothesis_words += new_len.saturating_sub(old_len);
+            }
+        }
+    }
+
+    turns
+        .iter()
+        .zip(errors)
+        .enumerate()
+        .map(|(i, (turn, errors))| {
+            let reference_words = turn_of.iter().filter(|t| **t == i).count();
+            let total = (errors.insertions + errors.deletions + errors.substitutions) as f32;
+            TurnScore {
+                speaker: turn.speaker.clone(),
+                start_secs: turn.start_secs,
+                score: Score {
+                    wer: if reference_words == 0 {
+                        total
+                    } else {
+                        total / reference_words as f32
+                    },
+                    insertions: errors.insertions,
+                    deletions: errors.deletions,
+                    substitutions: errors.substitutions,
+                    reference_words,
+                    hypothesis_words: errors.hypothesis_words,
+                },
+            }
+        })
+        .collect()
+}
+
+/// Scores a session turn by turn, if the resource has the word timestamps to find turns.
+pub async fn turn_scores(
+    resource_path: String,
+    uuid: String,
+    lang: String,
+) -> E<Option<Vec<TurnScore>>> {
+    // finding the turns may run the forced aligner
+    let turns = tokio::task::spawn_blocking(move || {
+        speaker_turns(&resource_path, &lang).map_err(|e| Er::from_error(&*e))
+    })
+    .await??;
+    let Some(turns) = turns else {
+        return Ok(None);
+    };
+    let dest = get_transcript(&uuid).await?;
+    Ok(Some(score_turns(&turns, &dest)))
+}
+
+/// Where the filler word lists are unless `FILLERS_DIR` says otherwise.
+pub const DEFAULT_FILLERS_DIR: &str = "fillers";
+
+/**
+ * Reads the filler words for `lang`, one per line, from
+ * `fillers_<lang>.txt` in `FILLERS_DIR` (by default `fillers`). A
+ * language without a list has no fillers.
+ */
+pub fn load_fillers(lang: &str) -> HashSet<String> {
+    let dir = std::env::var("FILLERS_DIR").unwrap_or(DEFAULT_FILLERS_DIR.to_string());
+    match fs::read_to_string(format!("{}/fillers_{}.txt", dir, lang)) {
+        Ok(contents) => words(&contents).into_iter().collect(),
+        Err(e) => {
+            log::debug!("No filler words for {}: {:?}", lang, e);
+            HashSet::new()
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct FillerReport {
+    pub lang: String,
+    pub total: usize,
+    /// fillers per minute, when the length of the recording is known
+    pub rate_per_minute: Option<f32>,
+    /// indices of the fillers among the words of the transcript
+    pub positions: Vec<usize>,
+}
+
+impl FillerReport {
+    pub fn with_duration(mut self, seconds: f32) -> Self {
+        if seconds > 0.0 {
+            self.rate_per_minute = Some(self.total as f32 * 60.0 / seconds);
+        }
+        self
+    }
+}
+
+/// Finds the words of a transcript which are fillers in `lang`, like "um" or "äh".
+pub fn count_filler_words(transcript: &str, lang: &str, fillers: &HashSet<String>) -> FillerReport {
+    let positions: Vec<usize> = words(transcript)
+        .iter()
+        .enumerate()
+        .filter(|(_, word)| fillers.contains(*word))
+        .map(|(i, _)| i)
+        .collect();
+    FillerReport {
+        lang: lang.to_string(),
+        total: positions.len(),
+        rate_per_minute: None,
+        positions,
+    }
+}
@@ -0,0 +1,415 @@
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::net::{IpAddr, SocketAddr};
+
+use crate::error::{Er, E};
+use crate::scoring::SCORING_CONFIG;
+
+/// The address the server listens on unless configured otherwise.
+pub const DEFAULT_LISTEN: &str = "127.0.0.1:3030";
+/// Where resource bundles are found unless configured otherwise.
+pub const DEFAULT_ASSETS_DIR: &str = "../assets";
+/// Where curricula are found unless configured otherwise.
+pub const DEFAULT_CURRICULA_DIR: &str = "../curricula";
+/// The whisper.cpp model used unless configured otherwise.
+pub const DEFAULT_WHISPER_MODEL: &str = "medium";
+/// Where whisper.cpp models are found unless configured otherwise.
+pub const DEFAULT_MODELS_DIR: &str = "../models";
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct TlsConfig {
+    /// PEM file of the certificate chain
+    pub cert: String,
+    /// PEM file of the private key
+    pub key: String,
+}
+
+/**
+ * How the server is set up, read from the TOML file given with
+ * `--config` or named by `CONFIG_FILE`. The environment variables in
+ * [ENV_OVERRIDES] override the file, and anything missing from both
+ * keeps its default.
+ */
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Config {
+    pub listen: SocketAddr,
+    pub assets_dir: String,
+    /// where trainers put curricula, the playlists of resources learners work through
+    pub curricula_dir: String,
+    /// where sessions are saved; without it nothing outlives the process
+    pub recordings_dir: Option<String>,
+    /// the SQLite file sessions are kept in and restored from, which `--persist` turns on
+    pub database: Option<String>,
+    /// the whisper.cpp model, found in `models_dir` unless `model_path` says where it is
+    pub whisper_model: String,
+    pub model_path: Option<String>,
+    /// where the models sessions can choose are, as `ggml-<size>.bin`
+    pub models_dir: String,
+    /// how many whisper.cpp models are kept in memory at once
+    pub max_loaded_models: usize,
+    /// sessions going on for longer are closed; by default there is no limit
+    pub max_session_seconds: Option<u64>,
+    /// the log filter used when `RUST_LOG` isn't set, for instance `info`
+    pub log_level: Option<String>,
+    pub tls: Option<TlsConfig>,
+    /// the addresses of proxies whose `X-Forwarded-For` is believed
+    pub trusted_proxies: Vec<IpAddr>,
+    /// the origins browsers may make requests from, exactly as they send them, or `"*"` for any
+    pub cors_allowed_origins: Vec<String>,
+    /// sent as `Content-Security-Policy` with every response, unless empty
+    pub content_security_policy: String,
+    /// how long after a session is closed its recording is deleted; recordings are kept if unset
+    pub delete_recordings_after_seconds: Option<u64>,
+    /// how many days after they were last updated finished sessions are deleted altogether
+    pub delete_sessions_after_days: Option<u64>,
+    /// the most the saved sessions may take up on disk before the oldest are deleted
+    pub max_recordings_bytes: Option<u64>,
+    /// what sessions are transcribed with unless they ask for another backend
+    pub transcription_backend: Option<crate::transcribe::Backend>,
+    /// how many pieces of audio are transcribed at once
+    pub transcription_workers: usize,
+    /// new sessions are refused while more pieces than this wait to be transcribed
+    pub max_queued_transcriptions: Option<usize>,
+    /// told about each session as it finishes
+    pub webhooks: Vec<crate::notify::Webhook>,
+    /// how long the sessions going on are given to be transcribed when the server is stopped
+    pub shutdown_timeout_seconds: u64,
+    /// how the audio is prepared before it is transcribed
+    pub preprocessing: crate::audio::Preprocessing,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            listen: DEFAULT_LISTEN.parse().unwrap(),
+            assets_dir: DEFAULT_ASSETS_DIR.to_string(),
+            curricula_dir: DEFAULT_CURRICULA_DIR.to_string(),
+            recordings_dir: None,
+            database: None,
+            whisper_model: DEFAULT_WHISPER_MODEL.to_string(),
+            model_path: None,
+            models_dir: DEFAULT_MODELS_DIR.to_string(),
+            max_loaded_models: crate::models::DEFAULT_MAX_LOADED_MODELS,
+            max_session_seconds: None,
+            log_level: None,
+            tls: None,
+            trusted_proxies: vec![],
+            cors_allowed_origins: vec![],
+            content_security_policy: crate::middleware::DEFAULT_CONTENT_SECURITY_POLICY.to_string(),
+            delete_recordings_after_seconds: None,
+            delete_sessions_after_days: None,
+            max_recordings_bytes: None,
+            transcription_backend: None,
+            transcription_workers: (num_cpus::get() / 4).max(1),
+            max_queued_transcriptions: None,
+            webhooks: vec![],
+            shutdown_timeout_seconds: crate::shutdown::DEFAULT_SHUTDOWN_TIMEOUT_SECONDS,
+            preprocessing: crate::audio::Preprocessing::default(),
+        }
+    }
+}
+
+/// The config file given with `--config <file>` or `--config=<file>`, or else by `CONFIG_FILE`.
+pub fn config_file() -> Option<String> {
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--config" {
+            return args.next();
+        }
+        if let Some(file) = arg.strip_prefix("--config=") {
+            return Some(file.to_string());
+        }
+    }
+    std::env::var("CONFIG_FILE").ok()
+}
+
+/// Whether the server was started with `--persist`, keeping sessions in a database.
+pub fn persist_flag() -> bool {
+    std::env::args().skip(1).any(|arg| arg == "--persist")
+}
+
+/// The contents of the config file as JSON, or null if there isn't one.
+fn file_values() -> Value {
+    config_file()
+        .and_then(|f| std::fs::read_to_string(f).ok())
+        .and_then(|contents| toml::from_str::<toml::Value>(&contents).ok())
+        .and_then(|contents| serde_json::to_value(contents).ok())
+        .unwrap_or(Value::Null)
+}
+
+impl Config {
+    pub fn load() -> E<Self> {
+        let mut config: Self = match config_file() {
+            Some(filename) => {
+                let contents = std::fs::read_to_string(&filename).map_err(|e| {
+                    Er::new(format!("Couldn't read config file {}: {}", filename, e))
+                })?;
+                toml::from_str(&contents)?
+            }
+            None => Self::default(),
+        };
+        let var = |name: &str| std::env::var(name).ok().filter(|v| !v.is_empty());
+        if let Some(listen) = var("LISTEN") {
+            config.listen = listen.parse().map_err(|_| {
+                Er::new(format!(
+                    "LISTEN must be an address and port, like {}, not {}",
+                    DEFAULT_LISTEN, listen
+                ))
+            })?;
+        }
+        if let Some(assets_dir) = var("ASSETS_DIR") {
+            config.assets_dir = assets_dir;
+        }
+        if let Some(recordings_dir) = var("RECORDINGS_DIR") {
+            config.recordings_dir = Some(recordings_dir);
+        }
+        if persist_flag() && config.database.is_none() {
+            config.database = Some(crate::store::DEFAULT_DATABASE.to_string());
+        }
+        if let Some(model) = var("WHISPER_MODEL") {
+            config.whisper_model = model;
+        }
+        if let Some(model_path) = var("WHISPER_MODEL_PATH") {
+            config.model_path = Some(model_path);
+        }
+        if let Some(seconds) = var("MAX_SESSION_SECONDS") {
+            config.max_session_seconds = Some(seconds.parse().map_err(|_| {
+                Er::new(format!(
+                    "MAX_SESSION_SECONDS must be a number of seconds, not {}",
+                    seconds
+                ))
+            })?);
+        }
+        if let Some(workers) = var("WHISPER_PROCESSES") {
+            config.transcription_workers = workers.parse().map_err(|_| {
+                Er::new(format!(
+                    "WHISPER_PROCESSES must be a number of workers, not {}",
+                    workers
+                ))
+            })?;
+        }
+        Ok(config)
+    }
+
+    /// The file of the whisper.cpp model.
+    pub fn model_path(&self) -> String {
+        self.model_path.clone().unwrap_or(format!(
+            "{}/ggml-{}.bin",
+            self.models_dir, self.whisper_model
+        ))
+    }
+}
+
+/// The environment variables which override settings of the config file, and those settings.
+pub const ENV_OVERRIDES: [(&str, &str); 7] = [
+    ("LISTEN", "listen"),
+    ("ASSETS_DIR", "assets_dir"),
+    ("RECORDINGS_DIR", "recordings_dir"),
+    ("WHISPER_MODEL", "whisper_model"),
+    ("WHISPER_MODEL_PATH", "model_path"),
+    ("MAX_SESSION_SECONDS", "max_session_seconds"),
+    ("WHISPER_PROCESSES", "transcription_workers"),
+];
+
+lazy_static! {
+    pub static ref CONFIG: Config = Config::load().unwrap();
+}
+
+/// Where a configuration value came from.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Source {
+    Env,
+    File,
+    Default,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Setting {
+    pub value: Value,
+    pub source: Source,
+}
+
+/// What secret values are shown as.
+pub const REDACTED: &str = "***";
+
+/**
+ * The environment variables the server reads, with their defaults, where
+ * they have one, and whether they are secret.
+ */
+fn variables() -> Vec<(&'static str, Option<String>, bool)> {
+    vec![
+        ("CONFIG_FILE", None, false),
+        ("EMBEDDING_API_KEY", None, true),
+        ("EMBEDDING_CMD", None, false),
+        ("EMBEDDING_MODEL", None, false),
+        ("EMBEDDING_URL", None, false),
+        (
+            "FFMPEG",
+            Some(crate::audio::DEFAULT_FFMPEG.to_string()),
+            false,
+        ),
+        (
+            "FILLERS_DIR",
+            Some(crate::compare::DEFAULT_FILLERS_DIR.to_string()),
+            false,
+        ),
+        ("FORCE_ALIGN_CMD", None, false),
+        (
+            "MAX_CONNECTIONS_PER_IP",
+            Some(crate::rate_limit::DEFAULT_MAX_CONNECTIONS_PER_IP.to_string()),
+            false,
+        ),
+        (
+            "METADATA_CACHE_SIZE",
+            Some(crate::metadata::DEFAULT_METADATA_CACHE_SIZE.to_string()),
+            false,
+        ),
+        (
+            "METADATA_CACHE_TTL_SECONDS",
+            Some(crate::metadata::DEFAULT_METADATA_CACHE_TTL_SECONDS.to_string()),
+            false,
+        ),
+        ("OPENAI_API_KEY", None, true),
+        ("OPENAI_API_URL", None, false),
+        (
+            "OPENAI_MODEL",
+            Some(crate::transcribe::DEFAULT_OPENAI_MODEL.to_string()),
+            false,
+        ),
+        ("RUST_LOG", None, false),
+        (
+            "SCORING_CONFIG",
+            Some(crate::scoring::DEFAULT_SCORING_CONFIG.to_string()),
+            false,
+        ),
+        (
+            "SENTIMENT_DIR",
+            Some(crate::sentiment::DEFAULT_SENTIMENT_DIR.to_string()),
+            false,
+        ),
+        ("SIGNED_URLS", None, false),
+        (
+            "STOPWORDS_DIR",
+            Some(crate::ngrams::DEFAULT_STOPWORDS_DIR.to_string()),
+            false,
+        ),
+        ("TERPLOUNGE_API_TOKEN", None, true),
+        (
+            "TOKIO_MAX_BLOCKING_THREADS",
+            Some(crate::DEFAULT_MAX_BLOCKING_THREADS.to_string()),
+            false,
+        ),
+        (
+            "TOKIO_WORKER_THREADS",
+            Some(num_cpus::get().to_string()),
+            false,
+        ),
+        ("TTS_CMD", None, false),
+        ("TTS_URL", None, false),
+        ("URL_SIGNING_KEY", None, true),
+        (
+            "URL_SIGNING_TTL_SECONDS",
+            Some(crate::auth::DEFAULT_URL_SIGNING_TTL_SECONDS.to_string()),
+            false,
+        ),
+        ("USERS_FILE", None, false),
+        ("VOSK_SERVER", None, false),
+        ("WHISPER_SERVER", None, false),
+    ]
+}
+
+/// Adds each leaf of `value` to `settings`, with its path from `prefix` as its key.
+fn flatten(prefix: &str, value: &Value, file: &Value, settings: &mut BTreeMap<String, Setting>) {
+    match value {
+        Value::Object(map) => {
+            for (key, value) in map {
+                let path = if prefix.is_empty() {
+                    key.to_string()
+                } else {
+                    format!("{}.{}", prefix, key)
+                };
+                flatten(
+                    &path,
+                    value,
+                    file.get(key).unwrap_or(&Value::Null),
+                    settings,
+                );
+            }
+        }
+        _ => {
+            let source = if file.is_null() {
+                Source::Default
+            } else {
+                Source::File
+            };
+            // the scoring config holds f32s, which are shown as they would be written
+            let value = match value.as_f64() {
+                Some(f) if value.is_f64() => {
+                    json!((f as f32).to_string().parse::<f64>().unwrap_or(f))
+                }
+                _ => value.clone(),
+            };
+            settings.insert(prefix.to_string(), Setting { value, source });
+        }
+    }
+}
+
+/**
+ * Every configuration value the server is running with, keyed by its
+ * environment variable, by its path in the config file, or for those
+ * from the scoring config file by `scoring.` and its path in the file.
+ * Secrets are shown as REDACTED when they are set.
+ */
+pub fn export() -> BTreeMap<String, Setting> {
+    let mut settings = BTreeMap::new();
+    let mut add = |key: &str, env: Option<String>, default: Option<String>, secret: bool| {
+        let (value, source) = match (env, default) {
+            (Some(value), _) => (Some(value), Source::Env),
+            (None, default) => (default, Source::Default),
+        };
+        let value = match value {
+            Some(_) if secret => json!(REDACTED),
+            Some(value) => json!(value),
+            None => Value::Null,
+        };
+        settings.insert(key.to_string(), Setting { value, source });
+    };
+    for (key, default, secret) in variables() {
+        add(key, std::env::var(key).ok(), default, secret);
+    }
+    for (key, value) in std::env::vars() {
+        if key.starts_with("POS_TAGGER_PATH_") {
+            add(&key, Some(value), None, false);
+        }
+    }
+
+    // the settings of the config file, some of which can be overridden
+    let file = file_values();
+    if let Ok(config) = serde_json::to_value(&*CONFIG) {
+        flatten("", &config, &file, &mut settings);
+    }
+    for (variable, key) in ENV_OVERRIDES {
+        if let Some(mut setting) = settings.remove(key) {
+            if std::env::var(variable).is_ok_and(|v| !v.is_empty()) {
+                setting.source = Source::Env;
+            }
+            settings.insert(variable.to_string(), setting);
+        }
+    }
+
+    let filename = std::env::var("SCORING_CONFIG")
+        .unwrap_or(crate::scoring::DEFAULT_SCORING_CONFIG.to_string());
+    let file: Value = std::fs::read_to_string(filename)
+        .ok()
+        .and_then(|contents| toml::from_str::<toml::Value>(&contents).ok())
+        .and_then(|contents| serde_json::to_value(contents).ok())
+        .unwrap_or(Value::Null);
+    if let Ok(scoring) = serde_json::to_value(&*SCORING_CONFIG) {
+        flatten("scoring", &scoring, &file, &mut settings);
+    }
+    settings
+}
@@ -0,0 +1,218 @@
+use serde::{Deserialize, Serialize};
+use similar::{capture_diff_slices, Algorithm, DiffOp};
+
+use crate::audio::Chapter;
+use crate::compare::{word_error_rate, words, Score};
+use crate::error::{Er, Kind, E};
+use crate::session::SessionData;
+
+/**
+ * How a session is practised: interpreting simultaneously while the
+ * whole resource plays, or consecutively, rendering each segment of it
+ * after it has been played.
+ */
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize, sqlx::Type)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(type_name = "TEXT", rename_all = "lowercase")]
+pub enum Mode {
+    #[default]
+    Simultaneous,
+    Consecutive,
+}
+
+impl Mode {
+    pub fn from_name(name: &str) -> E<Mode> {
+        match name {
+            "simultaneous" => Ok(Mode::Simultaneous),
+            "consecutive" => Ok(Mode::Consecutive),
+            _ => Err(Er::with_kind(
+                Kind::BadRequest,
+                format!(
+                    "There is no mode {}, only simultaneous and consecutive",
+                    name
+                ),
+            )),
+        }
+    }
+}
+
+/// When the learner rendered a segment of the resource, in seconds from the start of their recording.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Rendition {
+    pub segment: usize,
+    pub start_secs: f32,
+    /// until the next segment is played, or the end of the recording
+    pub end_secs: Option<f32>,
+}
+
+/// What the client sends over `/chat` as the learner starts rendering a segment, or stops.
+#[derive(Deserialize)]
+pub struct RenditionMarker {
+    pub rendition: Option<usize>,
+}
+
+/// A segment of the resource with the learner's rendition of it, and how it compares to the reference.
+#[derive(Clone, Debug, Serialize)]
+pub struct SegmentComparison {
+    pub index: usize,
+    pub start_secs: f32,
+    pub end_secs: f32,
+    /// the part of the reference translation the rendition is aligned with
+    pub reference: String,
+    pub rendition: String,
+    pub score: Score,
+}
+
+/**
+ * Starts, or with `None` stops, the learner's rendition of a segment at
+ * `at_secs` into the recording, ending the rendition before it.
+ */
+pub fn mark_rendition(renditions: &mut Vec<Rendition>, segment: Option<usize>, at_secs: f32) {
+    if let Some(last) = renditions.last_mut().filter(|r| r.end_secs.is_none()) {
+        last.end_secs = Some(at_secs);
+    }
+    if let Some(segment) = segment {
+        renditions.push(Rendition {
+            segment,
+            start_secs: at_secs,
+            end_secs: None,
+        });
+    }
+}
+
+/**
+ * The words the learner said rendering each of `count` segments. A word
+ * belongs to the rendition it was started in, words without a time to
+ * the rendition of the word before them, and the words said while
+ * listening to the source are left out.
+ */
+fn rendition_words(session: &SessionData, count: usize) -> E<Vec<Vec<String>>> {
+    let transcript = session.transcript()?;
+    let times = session.word_times();
+    let mut renditions = vec![vec![]; count];
+    let mut current: Option<usize> = None;
+    for (word, time) in transcript.split_whitespace().zip(times) {
+        if let Some((start, _)) = time {
+            current = session
+                .renditions
+                .iter()
+                .rev()
+                .find(|r| r.start_secs <= start && r.end_secs.is_none_or(|end| start < end))
+                .map(|r| r.segment)
+                .filter(|segment| *segment < count);
+        }
+        if let Some(segment) = current {
+            renditions[segment].extend(words(word));
+        }
+    }
+    Ok(renditions)
+}
+
+/**
+ * Divides the words of the reference between the segments as they line
+ * up with the learner's renditions. The renditions are aligned with the
+ * whole reference, so that each reference word goes to the segment of
+ * the rendered word it matches or was replaced by. Words left out go to
+ * the segment rendered before them, or are shared out between the
+ * segments between the two renditions around them when the learner
+ * skipped some.
+ */
+fn split_reference(reference: &[String], renditions: &[Vec<String>]) -> Vec<Vec<String>> {
+    let mut segment_of: Vec<usize> = vec![];
+    let mut hypothesis: Vec<String> = vec![];
+    for (i, rendition) in renditions.iter().enumerate() {
+        segment_of.resize(segment_of.len() + rendition.len(), i);
+        hypothesis.extend(rendition.iter().cloned());
+    }
+    let mut split = vec![vec![]; renditions.len()];
+    if renditions.is_empty() {
+        return split;
+    }
+    let last = renditions.len() - 1;
+    // the segments of the rendered words before and after the one at `index`
+    let before = |index: usize| index.checked_sub(1).map(|i| segment_of[i]);
+    let after = |index: usize| segment_of.get(index).copied();
+
+    for op in capture_diff_slices(Algorithm::Myers, reference, &hypothesis) {
+        match op {
+            DiffOp::Equal {
+                old_index,
+                new_index,
+                len,
+            } => {
+                for k in 0..len {
+                    split[segment_of[new_index + k]].push(reference[old_index + k].clone());
+                }
+            }
+            DiffOp::Replace {
+                old_index,
+                old_len,
+                new_index,
+                new_len,
+            } => {
+                for k in 0..old_len {
+                    let segment = segment_of[new_index + k.min(new_len - 1)];
+                    split[segment].push(reference[old_index + k].clone());
+                }
+            }
+            DiffOp::Delete {
+                old_index,
+                old_len,
+                new_index,
+            } => {
+                let first_skipped = before(new_index).map_or(0, |segment| segment + 1);
+                let next = after(new_index).unwrap_or(last + 1).max(first_skipped);
+                let left_out = &reference[old_index..old_index + old_len];
+                if next > first_skipped {
+                    // the segments in between weren't rendered, so the words are theirs
+                    let skipped = next - first_skipped;
+                    for (k, word) in left_out.iter().enumerate() {
+                        split[first_skipped + k * skipped / old_len].push(word.clone());
+                    }
+                } else {
+                    split[before(new_index).unwrap_or(0)].extend(left_out.iter().cloned());
+                }
+            }
+            DiffOp::Insert { .. } => (),
+        }
+    }
+    split
+}
+
+/**
+ * Compares a consecutive session with the reference translation in
+ * `lang` segment by segment, the segments being the chapters of the
+ * resource. Sessions practised simultaneously give 400.
+ */
+pub fn compare(
+    resource_path: &String,
+    session: &SessionData,
+    lang: &String,
+) -> E<Vec<SegmentComparison>> {
+    if session.mode != Mode::Consecutive {
+        return Err(Er::with_kind(
+            Kind::BadRequest,
+            format!("Session {} wasn't practised consecutively", session.uuid),
+        ));
+    }
+    let segments: Vec<Chapter> = crate::api::get_chapters(resource_path)?;
+    let reference = words(&crate::compare::get_translation(resource_path, lang)?);
+    let renditions = rendition_words(session, segments.len())?;
+    let references = split_reference(&reference, &renditions);
+    Ok(segments
+        .into_iter()
+        .zip(references.into_iter().zip(renditions))
+        .map(|(segment, (reference, rendition))| {
+            let reference = reference.join(" ");
+            let rendition = rendition.join(" ");
+            SegmentComparison {
+                index: segment.index,
+                start_secs: segment.start_secs,
+                end_secs: segment.end_secs,
+                score: word_error_rate(&reference, &rendition),
+                reference,
+                rendition,
+            }
+        })
+        .collect())
+}
@@ -0,0 +1,247 @@
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+use crate::config::CONFIG;
+use crate::error::{Er, Kind, E};
+use crate::metadata::Metadata;
+
+/// One resource of a curriculum, practised into `lang`.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Exercise {
+    pub resource: String,
+    /// the curriculum's `lang` unless the exercise says otherwise
+    #[serde(default)]
+    pub lang: Option<String>,
+    /// what the trainer wants the learner to look out for
+    #[serde(default)]
+    pub note: Option<String>,
+}
+
+/**
+ * An ordered set of exercises a trainer has assigned, read from
+ * `<id>.toml` or `<id>.json` in `curricula_dir`.
+ */
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Curriculum {
+    #[serde(skip_deserializing)]
+    pub id: String,
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+    /// the language the exercises are practised into, unless they say otherwise
+    #[serde(default)]
+    pub lang: Option<String>,
+    pub exercises: Vec<Exercise>,
+}
+
+/// An exercise with whether the learner has done it.
+#[derive(Clone, Debug, Serialize)]
+pub struct ExerciseProgress {
+    pub resource: String,
+    pub lang: String,
+    pub note: Option<String>,
+    /// the resource's name, or its path if its metadata can't be read
+    pub name: String,
+    pub practice_url: String,
+    /// how many sessions the learner has finished with it
+    pub sessions: usize,
+    pub completed: bool,
+}
+
+/// A curriculum as the learner has got on with it.
+#[derive(Clone, Debug, Serialize)]
+pub struct CurriculumProgress {
+    pub id: String,
+    pub name: String,
+    pub description: String,
+    pub exercises: Vec<ExerciseProgress>,
+    pub completed_count: usize,
+    /// the first exercise not completed yet, counting from 0
+    pub next: Option<usize>,
+}
+
+/// Where an exercise is in its curriculum, for the practice and compare pages.
+#[derive(Clone, Debug, Serialize)]
+pub struct CurriculumStep {
+    pub id: String,
+    pub name: String,
+    /// counting from 1
+    pub position: usize,
+    pub count: usize,
+    pub note: Option<String>,
+    /// the practice page of the exercise after this one
+    pub next_url: Option<String>,
+    pub next_name: Option<String>,
+}
+
+impl Exercise {
+    fn lang<'a>(&'a self, curriculum: &'a Curriculum) -> Option<&'a String> {
+        self.lang.as_ref().or(curriculum.lang.as_ref())
+    }
+}
+
+/// Curricula are named by their file, which can only have letters, digits, `-` and `_`.
+fn check_id(id: &str) -> E<()> {
+    if id.is_empty()
+        || !id
+            .chars()
+            .all(|c| c.is_alphanumeric() || c == '-' || c == '_')
+    {
+        return Err(Er::with_kind(
+            Kind::BadRequest,
+            format!("{} is not a valid curriculum", id),
+        ));
+    }
+    Ok(())
+}
+
+/// Reads a curriculum file, checking that each exercise has a language.
+fn read(file: &Path, id: &str) -> E<Curriculum> {
+    let contents = std::fs::read_to_string(file)?;
+    let mut curriculum: Curriculum = match file.extension().and_then(|e| e.to_str()) {
+        Some("toml") => toml::from_str(&contents)?,
+        _ => serde_json::from_str(&contents)?,
+    };
+    curriculum.id = id.to_string();
+    if let Some(exercise) = curriculum
+        .exercises
+        .iter()
+        .find(|e| e.lang(&curriculum).is_none())
+    {
+        return Err(Er::new(format!(
+            "Exercise {} of curriculum {} has no lang, and neither has the curriculum",
+            exercise.resource, id
+        )));
+    }
+    Ok(curriculum)
+}
+
+/// The curriculum called `id`.
+pub fn get(id: &str) -> E<Curriculum> {
+    check_id(id)?;
+    for extension in ["toml", "json"] {
+        let file = format!("{}/{}.{}", CONFIG.curricula_dir, id, extension);
+        if Path::new(&file).exists() {
+            return read(Path::new(&file), id);
+        }
+    }
+    Err(Er::with_kind(
+        Kind::NotFound,
+        format!("No curriculum {}", id),
+    ))
+}
+
+/**
+ * Every curriculum in `curricula_dir`, by name. Files which can't be read
+ * are left out with a warning, and without the directory there are none.
+ */
+pub fn list() -> E<Vec<Curriculum>> {
+    let Ok(entries) = std::fs::read_dir(&CONFIG.curricula_dir) else {
+        return Ok(vec![]);
+    };
+    let mut curricula = vec![];
+    for entry in entries {
+        let path = entry?.path();
+        let (Some(id), Some("toml" | "json")) = (
+            path.file_stem().and_then(|s| s.to_str()),
+            path.extension().and_then(|e| e.to_str()),
+        ) else {
+            continue;
+        };
+        if check_id(id).is_err() {
+            continue;
+        }
+        match read(&path, id) {
+            Ok(curriculum) => curricula.push(curriculum),
+            Err(e) => log::warn!("Leaving curriculum {} out: {}", path.display(), e),
+        }
+    }
+    curricula.sort_by(|a, b| a.name.cmp(&b.name).then(a.id.cmp(&b.id)));
+    Ok(curricula)
+}
+
+/// The practice page of a resource in `lang`, as part of the curriculum `id`.
+fn practice_url(resource: &str, lang: &str, id: &str) -> String {
+    format!(
+        "/practice/{}/{}?curriculum={}",
+        urlencoding::encode(resource),
+        lang,
+        id
+    )
+}
+
+/**
+ * How far the learner has got with the curriculum: an exercise is
+ * completed once they have finished a session with its resource in its
+ * language. Learners are told apart by their `user` when they are logged
+ * in, and otherwise by their address, `client`, as their practice
+ * history is; when neither is known no session counts as theirs.
+ */
+pub async fn progress(
+    curriculum: &Curriculum,
+    user: Option<&str>,
+    client: Option<&str>,
+) -> CurriculumProgress {
+    let mut exercises = vec![];
+    for exercise in &curriculum.exercises {
+        let lang = exercise.lang(curriculum).cloned().unwrap_or_default();
+        let sessions = crate::session::get_sessions_by_resource(&exercise.resource)
+            .await
+            .into_iter()
+            .filter(|s| !s.valid && s.language == lang)
+            .filter(|s| match user {
+                Some(user) => s.user.as_deref() == Some(user),
+                None => client.is_some() && s.client.as_deref() == client,
+            })
+            .count();
+        exercises.push(ExerciseProgress {
+            name: Metadata::from_resource_path(&exercise.resource)
+                .map(|m| m.name)
+                .unwrap_or(exercise.resource.clone()),
+            practice_url: practice_url(&exercise.resource, &lang, &curriculum.id),
+            resource: exercise.resource.clone(),
+            lang,
+            note: exercise.note.clone(),
+            sessions,
+            completed: sessions > 0,
+        });
+    }
+    CurriculumProgress {
+        id: curriculum.id.clone(),
+        name: curriculum.name.clone(),
+        description: curriculum.description.clone(),
+        completed_count: exercises.iter().filter(|e| e.completed).count(),
+        next: exercises.iter().position(|e| !e.completed),
+        exercises,
+    }
+}
+
+/**
+ * Where practising `resource` into `lang` is in the curriculum, and which
+ * exercise comes after it, if it is in it at all.
+ */
+pub fn step(curriculum: &Curriculum, resource: &str, lang: &str) -> Option<CurriculumStep> {
+    let index = curriculum.exercises.iter().position(|e| {
+        e.resource == resource && e.lang(curriculum).map(|l| l.as_str()) == Some(lang)
+    })?;
+    let next = curriculum.exercises.get(index + 1);
+    Some(CurriculumStep {
+        id: curriculum.id.clone(),
+        name: curriculum.name.clone(),
+        position: index + 1,
+        count: curriculum.exercises.len(),
+        note: curriculum.exercises[index].note.clone(),
+        next_url: next.map(|e| {
+            practice_url(
+                &e.resource,
+                e.lang(curriculum).map(|l| l.as_str()).unwrap_or_default(),
+                &curriculum.id,
+            )
+        }),
+        next_name: next.map(|e| {
+            Metadata::from_resource_path(&e.resource)
+                .map(|m| m.name)
+                .unwrap_or(e.resource.clone())
+        }),
+    })
+}
@@ -0,0 +1,130 @@
+use serde::Serialize;
+use std::collections::HashSet;
+
+use crate::compare::{syllables, words};
+use crate::error::{Er, E};
+use crate::metadata::Metadata;
+
+/// CEFR levels by the highest Flesch-Kincaid grade they cover; anything harder is C2.
+pub const CEFR_MAPPING: [(f32, &str); 5] = [
+    (3.0, "A1"),
+    (5.0, "A2"),
+    (7.0, "B1"),
+    (9.0, "B2"),
+    (12.0, "C1"),
+];
+
+#[derive(Clone, Debug, Serialize)]
+pub struct CefrBand {
+    pub max_grade: Option<f32>,
+    pub level: String,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Components {
+    pub lang: String,
+    pub sentence_count: usize,
+    pub mean_sentence_length: f32,
+    pub word_count: usize,
+    pub type_token_ratio: f32,
+    pub mean_syllables_per_word: f32,
+    pub flesch_kincaid: f32,
+    pub cefr: String,
+    pub cefr_mapping: Vec<CefrBand>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Estimate {
+    pub lang: String,
+    pub cefr: String,
+}
+
+/// Splits text after each run of `.`, `!` or `?`, dropping empty pieces.
+pub fn sentences(text: &str) -> Vec<String> {
+    let mut sentences = vec![];
+    let mut current = String::new();
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        current.push(c);
+        if ".!?".contains(c) && !chars.peek().map(|n| ".!?".contains(*n)).unwrap_or(false) {
+            sentences.push(std::mem::take(&mut current));
+        }
+    }
+    sentences.push(current);
+    sentences
+        .into_iter()
+        .map(|s| s.trim().to_string())
+        .filter(|s| !words(s).is_empty())
+        .collect()
+}
+
+fn cefr(grade: f32) -> String {
+    CEFR_MAPPING
+        .iter()
+        .find(|(max_grade, _)| grade <= *max_grade)
+        .map(|(_, level)| level)
+        .unwrap_or(&"C2")
+        .to_string()
+}
+
+/**
+ * Measures how hard a text is to interpret, mapping its Flesch-Kincaid
+ * grade level onto the CEFR levels.
+ */
+pub fn components(text: &str, lang: &str) -> Components {
+    let sentence_count = sentences(text).len();
+    let words = words(text);
+    let word_count = words.len();
+    let types: HashSet<&String> = words.iter().collect();
+    let syllable_count: usize = words.iter().map(|w| syllables(w)).sum();
+    let ratio = |a: usize, b: usize| if b == 0 { 0.0 } else { a as f32 / b as f32 };
+    let mean_sentence_length = ratio(word_count, sentence_count);
+    let mean_syllables_per_word = ratio(syllable_count, word_count);
+    let flesch_kincaid = if word_count == 0 {
+        0.0
+    } else {
+        0.39 * mean_sentence_length + 11.8 * mean_syllables_per_word - 15.59
+    };
+    let mut cefr_mapping: Vec<CefrBand> = CEFR_MAPPING
+        .iter()
+        .map(|(max_grade, level)| CefrBand {
+            max_grade: Some(*max_grade),
+            level: level.to_string(),
+        })
+        .collect();
+    cefr_mapping.push(CefrBand {
+        max_grade: None,
+        level: "C2".to_string(),
+    });
+    Components {
+        lang: lang.to_string(),
+        sentence_count,
+        mean_sentence_length,
+        word_count,
+        type_token_ratio: ratio(types.len(), word_count),
+        mean_syllables_per_word,
+        flesch_kincaid,
+        cefr: cefr(flesch_kincaid),
+        cefr_mapping,
+    }
+}
+
+/// Measures the text of a resource in `lang`, or by default its transcript.
+pub fn resource_components(resource_path: &String, lang: Option<&String>) -> E<Components> {
+    let metadata = Metadata::from_resource_path(resource_path)?;
+    let lang = lang.unwrap_or(&metadata.native);
+    let path = metadata.text_path(lang).ok_or(Er::new(format!(
+        "Resource {} has no text in {}",
+        resource_path, lang
+    )))?;
+    let text = std::fs::read_to_string(path)?;
+    Ok(components(&text, lang))
+}
+
+pub fn estimate(resource_path: &String, lang: Option<&String>) -> E<Estimate> {
+    let components = resource_components(resource_path, lang)?;
+    Ok(Estimate {
+        lang: components.lang,
+        cefr: components.cefr,
+    })
+}
@@ -0,0 +1,179 @@
+use lazy_static::lazy_static;
+use reqwest::blocking::Client;
+use serde_json::{json, Value};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::sync::Mutex;
+
+use crate::error::{Er, E};
+
+/// How many sentences' embeddings are kept in memory before they are all forgotten.
+pub const EMBEDDING_CACHE_SIZE: usize = 10000;
+/// How many dimensions the character trigrams of a sentence are counted in without a backend.
+pub const TRIGRAM_DIMENSIONS: usize = 1024;
+
+lazy_static! {
+    static ref CACHE: Mutex<HashMap<String, Vec<f32>>> = Mutex::new(HashMap::new());
+}
+
+fn env(name: &str) -> Option<String> {
+    std::env::var(name).ok().filter(|v| !v.is_empty())
+}
+
+/// What sentences are embedded with: `command` with `EMBEDDING_CMD`, `url` with `EMBEDDING_URL`, or else `trigrams`.
+pub fn backend() -> &'static str {
+    match (env("EMBEDDING_CMD"), env("EMBEDDING_URL")) {
+        (Some(_), _) => "command",
+        (None, Some(_)) => "url",
+        (None, None) => "trigrams",
+    }
+}
+
+/**
+ * Runs `EMBEDDING_CMD`, which is given the sentences as a JSON array of
+ * strings on its standard input and prints a JSON array of their
+ * embeddings, each an array of numbers. The command may contain extra
+ * arguments, separated by spaces.
+ */
+fn run_command(command: &str, texts: &[String]) -> E<Vec<Vec<f32>>> {
+    let mut args = command.split_whitespace();
+    let program = args
+        .next()
+        .ok_or(Er::new("EMBEDDING_CMD is empty".to_string()))?;
+    log::debug!("Running {} to embed {} sentences", command, texts.len());
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()?;
+    child
+        .stdin
+        .take()
+        .ok_or(Er::new(format!("Couldn't write to {}", command)))?
+        .write_all(serde_json::to_string(texts)?.as_bytes())?;
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        return Err(Er::new(format!(
+            "{} failed with {}",
+            command, output.status
+        )));
+    }
+    Ok(serde_json::from_slice(&output.stdout)?)
+}
+
+/**
+ * Posts `{"input":[...]}` to `EMBEDDING_URL`, with `EMBEDDING_MODEL` as
+ * `model` if it is set and `EMBEDDING_API_KEY` as a bearer token, and
+ * reads the embeddings from `data` as OpenAI-compatible endpoints answer.
+ */
+fn request(url: &str, texts: &[String]) -> E<Vec<Vec<f32>>> {
+    log::debug!(
+        "Requesting embeddings of {} sentences from {}",
+        texts.len(),
+        url
+    );
+    let mut body = json!({ "input": texts });
+    if let Some(model) = env("EMBEDDING_MODEL") {
+        body["model"] = json!(model);
+    }
+    let mut request = Client::new().post(url).json(&body);
+    if let Some(key) = env("EMBEDDING_API_KEY") {
+        request = request.bearer_auth(key);
+    }
+    let res = request.send()?;
+    if !res.status().is_success() {
+        return Err(Er::new(format!("{} answered {}", url, res.status())));
+    }
+    let mut data: Vec<Value> = serde_json::from_value(res.json::<Value>()?["data"].take())?;
+    data.sort_by_key(|d| d["index"].as_u64());
+    data.into_iter()
+        .map(|mut d| Ok(serde_json::from_value(d["embedding"].take())?))
+        .collect()
+}
+
+/**
+ * Counts the character trigrams of the lowercase words of `text`, hashed
+ * into TRIGRAM_DIMENSIONS. It stands in for a model when there is none,
+ * and only knows sentences are alike when they share words or their
+ * stems, not when they are paraphrases.
+ */
+fn trigrams(text: &str) -> Vec<f32> {
+    let mut vector = vec![0f32; TRIGRAM_DIMENSIONS];
+    for word in crate::compare::words(text) {
+        let chars: Vec<char> = format!(" {} ", word).chars().collect();
+        for trigram in chars.windows(3) {
+            let mut hasher = DefaultHasher::new();
+            trigram.hash(&mut hasher);
+            vector[hasher.finish() as usize % TRIGRAM_DIMENSIONS] += 1.0;
+        }
+    }
+    vector
+}
+
+/**
+ * The embeddings of `texts`, in the same order, made by the backend
+ * `backend` names. Those made before are taken from memory, and the rest
+ * are made together. This blocks while the backend works, so it should
+ * be called off the async runtime.
+ */
+pub fn embed(texts: &[String]) -> E<Vec<Vec<f32>>> {
+    let missing: Vec<String> = {
+        let cache = CACHE.lock().unwrap();
+        let mut missing: Vec<String> = texts
+            .iter()
+            .filter(|t| !cache.contains_key(*t))
+            .cloned()
+            .collect();
+        missing.sort();
+        missing.dedup();
+        missing
+    };
+    let mut made = HashMap::new();
+    if !missing.is_empty() {
+        let embeddings = match (env("EMBEDDING_CMD"), env("EMBEDDING_URL")) {
+            (Some(command), _) => run_command(&command, &missing)?,
+            (None, Some(url)) => request(&url, &missing)?,
+            (None, None) => missing.iter().map(|t| trigrams(t)).collect(),
+        };
+        if embeddings.len() != missing.len() {
+            return Err(Er::new(format!(
+                "Got {} embeddings for {} sentences",
+                embeddings.len(),
+                missing.len()
+            )));
+        }
+        made.extend(missing.into_iter().zip(embeddings));
+    }
+    let mut cache = CACHE.lock().unwrap();
+    let embeddings = texts
+        .iter()
+        .map(|t| {
+            made.get(t)
+                .or_else(|| cache.get(t))
+                .cloned()
+                .ok_or_else(|| Er::new(format!("No embedding of {}", t)).into())
+        })
+        .collect();
+    if cache.len() + made.len() > EMBEDDING_CACHE_SIZE {
+        cache.clear();
+    }
+    cache.extend(made);
+    embeddings
+}
+
+/// The cosine similarity of two embeddings, 0 if either is all zeros or they differ in length.
+pub fn cosine(a: &[f32], b: &[f32]) -> f32 {
+    if a.len() != b.len() {
+        return 0.0;
+    }
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norm = |v: &[f32]| v.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let norms = norm(a) * norm(b);
+    if norms == 0.0 {
+        return 0.0;
+    }
+    dot / norms
+}
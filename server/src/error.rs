@@ -1,23 +1,141 @@
 use std::error::Error;
 use std::fmt;
+use warp::http::StatusCode;
 
 pub type E<T> = Result<T, Box<dyn Error>>;
 
+/// The kinds of error API clients are told apart, each answered with its own status.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    NotFound,
+    SessionNotFound,
+    ResourceNotFound,
+    ResourceExists,
+    BadUuid,
+    BadRequest,
+    MetadataInvalid,
+    SessionNotReady,
+    SessionClosed,
+    RecordingDeleted,
+    NotOwner,
+    TooManyLogins,
+    ServerBusy,
+}
+
+impl Kind {
+    /// What API clients see in the `error` field of the response.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Kind::NotFound => "not_found",
+            Kind::SessionNotFound => "session_not_found",
+            Kind::ResourceNotFound => "resource_not_found",
+            Kind::ResourceExists => "resource_exists",
+            Kind::BadUuid => "bad_uuid",
+            Kind::BadRequest => "bad_request",
+            Kind::MetadataInvalid => "metadata_invalid",
+            Kind::SessionNotReady => "session_not_ready",
+            Kind::SessionClosed => "session_closed",
+            Kind::RecordingDeleted => "recording_deleted",
+            Kind::NotOwner => "not_owner",
+            Kind::TooManyLogins => "too_many_logins",
+            Kind::ServerBusy => "server_busy",
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Kind::NotFound | Kind::SessionNotFound | Kind::ResourceNotFound => {
+                StatusCode::NOT_FOUND
+            }
+            Kind::BadUuid | Kind::BadRequest => StatusCode::BAD_REQUEST,
+            Kind::MetadataInvalid => StatusCode::INTERNAL_SERVER_ERROR,
+            Kind::SessionNotReady | Kind::SessionClosed | Kind::ResourceExists => {
+                StatusCode::CONFLICT
+            }
+            Kind::RecordingDeleted => StatusCode::GONE,
+            Kind::NotOwner => StatusCode::FORBIDDEN,
+            Kind::TooManyLogins => StatusCode::TOO_MANY_REQUESTS,
+            Kind::ServerBusy => StatusCode::SERVICE_UNAVAILABLE,
+        }
+    }
+}
+
+/**
+ * An error with a message, and the kind it is when API clients should be
+ * told. Errors without a kind are answered as internal errors.
+ */
 #[derive(Debug, Clone)]
 pub struct Er {
     msg: String,
+    kind: Option<Kind>,
 }
 
 impl Er {
     pub fn new(msg: String) -> Box<Er> {
-        Box::new(Er { msg })
+        Box::new(Er { msg, kind: None })
+    }
+
+    pub fn with_kind(kind: Kind, msg: String) -> Box<Er> {
+        Box::new(Er {
+            msg,
+            kind: Some(kind),
+        })
+    }
+
+    /**
+     * A copy of any error which can be sent between threads, keeping its
+     * kind if it is an `Er`. Files which aren't there are `NotFound`.
+     */
+    pub fn from_error(e: &(dyn Error + 'static)) -> Er {
+        // a Box<Er> turned into an E with ? stays boxed inside it
+        if let Some(er) = e
+            .downcast_ref::<Er>()
+            .or_else(|| e.downcast_ref::<Box<Er>>().map(|b| &**b))
+        {
+            return er.clone();
+        }
+        let kind = e
+            .downcast_ref::<std::io::Error>()
+            .filter(|e| e.kind() == std::io::ErrorKind::NotFound)
+            .map(|_| Kind::NotFound);
+        Er {
+            msg: e.to_string(),
+            kind,
+        }
+    }
+
+    pub fn kind(&self) -> Option<Kind> {
+        self.kind
+    }
+
+    pub fn message(&self) -> &str {
+        &self.msg
     }
 }
 
 impl std::error::Error for Er {}
 
+impl warp::reject::Reject for Er {}
+
 impl fmt::Display for Er {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "{}", &self.msg)
     }
 }
+
+/// Rejects a request with an error of `kind`.
+pub fn reject(kind: Kind, msg: String) -> warp::Rejection {
+    warp::reject::custom(*Er::with_kind(kind, msg))
+}
+
+/// Rejects a request because of `e`, as an error of `kind` unless it has a kind of its own.
+pub fn reject_as(kind: Kind, e: &(dyn Error + 'static)) -> warp::Rejection {
+    let mut er = Er::from_error(e);
+    er.kind = er.kind.or(Some(kind));
+    warp::reject::custom(er)
+}
+
+/// Rejects a request because of `e`, as an internal error unless it has a kind of its own.
+pub fn reject_error(e: &(dyn Error + 'static)) -> warp::Rejection {
+    warp::reject::custom(Er::from_error(e))
+}
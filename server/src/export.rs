@@ -0,0 +1,84 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::error::{Er, Kind, E};
+use crate::session::SessionData;
+use crate::subtitles::{Cue, SubtitleFormat};
+
+/// The formats a session's transcript can be exported in.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExportFormat {
+    Txt,
+    Json,
+    Srt,
+    Vtt,
+}
+
+impl ExportFormat {
+    pub fn from_name(name: &str) -> E<Self> {
+        match name {
+            "txt" => Ok(Self::Txt),
+            "json" => Ok(Self::Json),
+            "srt" => Ok(Self::Srt),
+            "vtt" => Ok(Self::Vtt),
+            _ => Err(Er::with_kind(
+                Kind::BadRequest,
+                format!(
+                    "Transcripts can't be exported as {}, only txt, json, srt and vtt",
+                    name
+                ),
+            )),
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Self::Txt => "text/plain",
+            Self::Json => "application/json",
+            Self::Srt => SubtitleFormat::Srt.content_type(),
+            Self::Vtt => SubtitleFormat::Vtt.content_type(),
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::Txt => "txt",
+            Self::Json => "json",
+            Self::Srt => SubtitleFormat::Srt.extension(),
+            Self::Vtt => SubtitleFormat::Vtt.extension(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct TranscriptExport {
+    pub uuid: Uuid,
+    pub language: String,
+    pub resource: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub text: String,
+    pub segments: Vec<Cue>,
+}
+
+/**
+ * A session's transcript in `format`: its text with a line for each
+ * segment, a JSON document with the timed segments, or subtitles with a
+ * cue for each.
+ */
+pub fn export(session: &SessionData, format: ExportFormat) -> E<String> {
+    let cues = session.transcript_cues()?;
+    Ok(match format {
+        ExportFormat::Txt => cues.iter().map(|cue| format!("{}\n", cue.text)).collect(),
+        ExportFormat::Json => serde_json::to_string(&TranscriptExport {
+            uuid: session.uuid,
+            language: session.language.clone(),
+            resource: session.resource.clone(),
+            created_at: session.created_at,
+            text: session.transcript()?.trim().to_string(),
+            segments: cues,
+        })?,
+        ExportFormat::Srt => crate::subtitles::render(&cues, SubtitleFormat::Srt),
+        ExportFormat::Vtt => crate::subtitles::render(&cues, SubtitleFormat::Vtt),
+    })
+}
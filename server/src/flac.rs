@@ -0,0 +1,588 @@
+use crate::error::{Er, E};
+
+/// Samples per FLAC frame.
+pub const BLOCK_SIZE: usize = 4096;
+
+struct BitWriter {
+    bytes: Vec<u8>,
+    buffer: u64,
+    bits: u32,
+}
+
+impl BitWriter {
+    fn new() -> Self {
+        Self {
+            bytes: vec![],
+            buffer: 0,
+            bits: 0,
+        }
+    }
+
+    /// Writes the low `bits` bits of `value`, most significant first. At most 32 at a time.
+    fn write(&mut self, value: u64, bits: u32) {
+        if bits == 0 {
+            return;
+        }
+        self.buffer = (self.buffer << bits) | (value & ((1u64 << bits) - 1));
+        self.bits += bits;
+        while self.bits >= 8 {
+            self.bits -= 8;
+            self.bytes.push((self.buffer >> self.bits) as u8);
+        }
+        self.buffer &= (1u64 << self.bits) - 1;
+    }
+
+    fn write_unary(&mut self, mut zeros: u64) {
+        while zeros >= 32 {
+            self.write(0, 32);
+            zeros -= 32;
+        }
+        self.write(1, zeros as u32 + 1);
+    }
+
+    fn align(&mut self) {
+        if self.bits > 0 {
+            self.write(0, 8 - self.bits);
+        }
+    }
+}
+
+struct BitReader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn read(&mut self, bits: u32) -> E<u64> {
+        let mut value = 0u64;
+        for _ in 0..bits {
+            let byte = self
+                .bytes
+                .get(self.position / 8)
+                .ok_or(Er::new("Unexpected end of FLAC data".to_string()))?;
+            value = (value << 1) | ((byte >> (7 - self.position % 8)) & 1) as u64;
+            self.position += 1;
+        }
+        Ok(value)
+    }
+
+    fn read_signed(&mut self, bits: u32) -> E<i64> {
+        let value = self.read(bits)? as i64;
+        if bits > 0 && value >> (bits - 1) == 1 {
+            Ok(value - (1i64 << bits))
+        } else {
+            Ok(value)
+        }
+    }
+
+    fn read_unary(&mut self) -> E<u64> {
+        let mut zeros = 0;
+        while self.read(1)? == 0 {
+            zeros += 1;
+        }
+        Ok(zeros)
+    }
+
+    fn align(&mut self) {
+        self.position = self.position.div_ceil(8) * 8;
+    }
+
+    fn byte_position(&self) -> usize {
+        self.position / 8
+    }
+}
+
+fn crc8(bytes: &[u8]) -> u8 {
+    let mut crc = 0u8;
+    for byte in bytes {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 {
+                (crc << 1) ^ 0x07
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+fn crc16(bytes: &[u8]) -> u16 {
+    let mut crc = 0u16;
+    for byte in bytes {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x8005
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// Frame numbers are coded like UTF-8 characters.
+fn write_frame_number(writer: &mut BitWriter, number: u64) {
+    if number < 0x80 {
+        writer.write(number, 8);
+        return;
+    }
+    let continuation_bytes = match number {
+        n if n < 0x800 => 1,
+        n if n < 0x10000 => 2,
+        n if n < 0x200000 => 3,
+        n if n < 0x4000000 => 4,
+        _ => 5,
+    };
+    let lead_ones = (0xFF00u64 >> (continuation_bytes + 1)) & 0xFF;
+    writer.write(lead_ones | (number >> (6 * continuation_bytes)), 8);
+    for i in (0..continuation_bytes).rev() {
+        writer.write(0x80 | ((number >> (6 * i)) & 0x3F), 8);
+    }
+}
+
+fn fixed_residuals(samples: &[i64], order: usize) -> Vec<i64> {
+    (order..samples.len())
+        .map(|n| {
+            let s = |k: usize| samples[n - k];
+            let prediction = match order {
+                0 => 0,
+                1 => s(1),
+                2 => 2 * s(1) - s(2),
+                3 => 3 * s(1) - 3 * s(2) + s(3),
+                _ => 4 * s(1) - 6 * s(2) + 4 * s(3) - s(4),
+            };
+            samples[n] - prediction
+        })
+        .collect()
+}
+
+fn zigzag(residual: i64) -> u64 {
+    ((residual << 1) ^ (residual >> 63)) as u64
+}
+
+/// The Rice parameter giving the fewest bits for the residuals, and that number of bits.
+fn rice_parameter(residuals: &[i64]) -> (u32, u64) {
+    let cost = |k: u32| -> u64 {
+        residuals
+            .iter()
+            .map(|r| (zigzag(*r) >> k) + 1 + k as u64)
+            .sum()
+    };
+    let mean = residuals.iter().map(|r| zigzag(*r)).sum::<u64>() / residuals.len().max(1) as u64;
+    let estimate = (64 - mean.leading_zeros()).min(30);
+    (estimate.saturating_sub(1)..=(estimate + 1).min(30))
+        .map(|k| (k, cost(k)))
+        .min_by_key(|(_, bits)| *bits)
+        .unwrap()
+}
+
+fn write_subframe(writer: &mut BitWriter, samples: &[i64], bits_per_sample: u32) {
+    if samples.iter().all(|s| *s == samples[0]) {
+        writer.write(0, 8);
+        writer.write(samples[0] as u64, bits_per_sample);
+        return;
+    }
+    let verbatim_bits = samples.len() as u64 * bits_per_sample as u64;
+    let best = (0..=4usize.min(samples.len() - 1))
+        .map(|order| {
+            let residuals = fixed_residuals(samples, order);
+            let (parameter, bits) = rice_parameter(&residuals);
+            (
+                order,
+                residuals,
+                parameter,
+                bits + (order as u64 * bits_per_sample as u64),
+            )
+        })
+        .min_by_key(|(_, _, _, bits)| *bits)
+        .unwrap();
+    let (order, residuals, parameter, bits) = best;
+    if bits >= verbatim_bits {
+        writer.write(1 << 1, 8);
+        for sample in samples {
+            writer.write(*sample as u64, bits_per_sample);
+        }
+        return;
+    }
+    writer.write((0b001000 | order as u64) << 1, 8);
+    for sample in &samples[..order] {
+        writer.write(*sample as u64, bits_per_sample);
+    }
+    // 5 bit Rice parameters, a single partition
+    writer.write(0b01, 2);
+    writer.write(0, 4);
+    writer.write(parameter as u64, 5);
+    for residual in residuals {
+        let u = zigzag(residual);
+        writer.write_unary(u >> parameter);
+        writer.write(u, parameter);
+    }
+}
+
+/**
+ * Encodes mono samples of `bits_per_sample` bits as a FLAC stream, using
+ * the fixed predictors with Rice coded residuals, or storing a frame
+ * verbatim if prediction doesn't help. The MD5 signature is left unset.
+ */
+pub fn encode(samples: &[i32], sample_rate: u32, bits_per_sample: u32) -> Vec<u8> {
+    let mut writer = BitWriter::new();
+    writer.bytes.extend_from_slice(b"fLaC");
+    // STREAMINFO, the last metadata block
+    writer.write(1, 1);
+    writer.write(0, 7);
+    writer.write(34, 24);
+    let block = BLOCK_SIZE.min(samples.len().max(16)) as u64;
+    writer.write(block, 16);
+    writer.write(block, 16);
+    writer.write(0, 24);
+    writer.write(0, 24);
+    writer.write(sample_rate as u64, 20);
+    writer.write(0, 3);
+    writer.write(bits_per_sample as u64 - 1, 5);
+    writer.write(samples.len() as u64 >> 32, 4);
+    writer.write(samples.len() as u64 & 0xFFFFFFFF, 32);
+    writer.write(0, 32);
+    writer.write(0, 32);
+    writer.write(0, 32);
+    writer.write(0, 32);
+
+    for (number, block) in samples.chunks(BLOCK_SIZE).enumerate() {
+        let start = writer.bytes.len();
+        writer.write(0b11111111111110, 14);
+        writer.write(0, 2);
+        // block size in 16 bits at the end of the header, everything else from STREAMINFO
+        writer.write(0b0111, 4);
+        writer.write(0, 4);
+        writer.write(0, 4);
+        writer.write(0, 4);
+        write_frame_number(&mut writer, number as u64);
+        writer.write(block.len() as u64 - 1, 16);
+        let crc = crc8(&writer.bytes[start..]);
+        writer.write(crc as u64, 8);
+
+        let block: Vec<i64> = block.iter().map(|s| *s as i64).collect();
+        write_subframe(&mut writer, &block, bits_per_sample);
+        writer.align();
+        let crc = crc16(&writer.bytes[start..]);
+        writer.write(crc as u64, 16);
+    }
+    writer.bytes
+}
+
+fn read_residuals(
+    reader: &mut BitReader,
+    block_size: usize,
+    order: usize,
+    residuals: &mut Vec<i64>,
+) -> E<()> {
+    let (parameter_bits, escape) = match reader.read(2)? {
+        0 => (4, 0b1111),
+        1 => (5, 0b11111),
+        method => return Err(Er::new(format!("Unknown residual coding {}", method))),
+    };
+    let partition_order = reader.read(4)?;
+    let partitions = 1usize << partition_order;
+    for partition in 0..partitions {
+        let mut count = block_size >> partition_order;
+        if partition == 0 {
+            count = count
+                .checked_sub(order)
+                .ok_or(Er::new("FLAC partition smaller than predictor".to_string()))?;
+        }
+        let parameter = reader.read(parameter_bits)?;
+        if parameter == escape {
+            let bits = reader.read(5)? as u32;
+            for _ in 0..count {
+                residuals.push(reader.read_signed(bits)?);
+            }
+        } else {
+            for _ in 0..count {
+                let u = (reader.read_unary()? << parameter) | reader.read(parameter as u32)?;
+                residuals.push((u >> 1) as i64 ^ -((u & 1) as i64));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn read_subframe(reader: &mut BitReader, block_size: usize, bits_per_sample: u32) -> E<Vec<i64>> {
+    reader.read(1)?;
+    let kind = reader.read(6)?;
+    let wasted = if reader.read(1)? == 1 {
+        reader.read_unary()? as u32 + 1
+    } else {
+        0
+    };
+    let bits = bits_per_sample.checked_sub(wasted).ok_or(Er::new(format!(
+        "{} wasted bits in a FLAC subframe",
+        wasted
+    )))?;
+    let mut samples: Vec<i64> = Vec::with_capacity(block_size);
+    match kind {
+        0 => samples.resize(block_size, reader.read_signed(bits)?),
+        1 => {
+            for _ in 0..block_size {
+                samples.push(reader.read_signed(bits)?);
+            }
+        }
+        8..=12 => {
+            let order = (kind - 8) as usize;
+            for _ in 0..order {
+                samples.push(reader.read_signed(bits)?);
+            }
+            let mut residuals = vec![];
+            read_residuals(reader, block_size, order, &mut residuals)?;
+            for residual in residuals {
+                let n = samples.len();
+                let s = |k: usize| samples[n - k];
+                let prediction = match order {
+                    0 => 0,
+                    1 => s(1),
+                    2 => 2 * s(1) - s(2),
+                    3 => 3 * s(1) - 3 * s(2) + s(3),
+                    _ => 4 * s(1) - 6 * s(2) + 4 * s(3) - s(4),
+                };
+                samples.push(prediction + residual);
+            }
+        }
+        32..=63 => {
+            let order = (kind - 31) as usize;
+            for _ in 0..order {
+                samples.push(reader.read_signed(bits)?);
+            }
+            let precision = reader.read(4)? as u32 + 1;
+            let shift = reader.read_signed(5)?;
+            if shift < 0 {
+                return Err(Er::new(format!("Negative FLAC LPC shift {}", shift)));
+            }
+            let coefficients = (0..order)
+                .map(|_| reader.read_signed(precision))
+                .collect::<E<Vec<i64>>>()?;
+            let mut residuals = vec![];
+            read_residuals(reader, block_size, order, &mut residuals)?;
+            for residual in residuals {
+                let n = samples.len();
+                let prediction: i64 = coefficients
+                    .iter()
+                    .enumerate()
+                    .map(|(k, c)| c * samples[n - k - 1])
+                    .sum();
+                samples.push((prediction >> shift) + residual);
+            }
+        }
+        _ => return Err(Er::new(format!("Unknown FLAC subframe type {}", kind))),
+    }
+    Ok(samples.into_iter().map(|s| s << wasted).collect())
+}
+
+/// Reads the metadata blocks, returning the sample rate, bits per sample and number of samples.
+fn read_stream_info(reader: &mut BitReader) -> E<(u32, u32, u64)> {
+    if !reader.bytes.starts_with(b"fLaC") {
+        return Err(Er::new("Not a FLAC stream".to_string()));
+    }
+    reader.position = 32;
+    let mut stream_info = None;
+    loop {
+        let last = reader.read(1)? == 1;
+        let kind = reader.read(7)?;
+        let length = reader.read(24)? as usize;
+        let end = reader.position + 8 * length;
+        if kind == 0 {
+            reader.read(16 + 16 + 24)?;
+            reader.read(24)?;
+            let sample_rate = reader.read(20)? as u32;
+            let channels = reader.read(3)? + 1;
+            let bits_per_sample = reader.read(5)? as u32 + 1;
+            let total = reader.read(36)?;
+            if channels != 1 {
+                return Err(Er::new(format!(
+                    "Only mono FLAC, not {} channels",
+                    channels
+                )));
+            }
+            stream_info = Some((sample_rate, bits_per_sample, total));
+        }
+        reader.position = end;
+        if last {
+            break;
+        }
+    }
+    stream_info.ok_or(Er::new("FLAC stream without STREAMINFO".to_string()))
+}
+
+/// The sample rate, bits per sample and number of samples of a FLAC stream.
+pub fn stream_info(bytes: &[u8]) -> E<(u32, u32, u64)> {
+    read_stream_info(&mut BitReader { bytes, position: 0 })
+}
+
+/**
+ * Decodes a mono FLAC stream, returning its samples, sample rate and bits
+ * per sample. Frame checksums are verified.
+ */
+pub fn decode(bytes: &[u8]) -> E<(Vec<i32>, u32, u32)> {
+    let mut reader = BitReader { bytes, position: 0 };
+    let (sample_rate, bits_per_sample, total) = read_stream_info(&mut reader)?;
+
+    // the header's count isn't trusted with more room than the stream could fill
+    let mut samples: Vec<i32> = Vec::with_capacity((total as usize).min(bytes.len()));
+    while reader.byte_position() < bytes.len() {
+        let start = reader.byte_position();
+        if reader.read(14)? != 0b11111111111110 {
+            return Err(Er::new(format!("No FLAC frame at byte {}", start)));
+        }
+        reader.read(2)?;
+        let block_size_code = reader.read(4)?;
+        let sample_rate_code = reader.read(4)?;
+        if reader.read(4)? != 0 {
+            return Err(Er::new("Only mono FLAC frames are supported".to_string()));
+        }
+        let bits = match reader.read(3)? {
+            0 => bits_per_sample,
+            1 => 8,
+            2 => 12,
+            4 => 16,
+            5 => 20,
+            6 => 24,
+            _ => 32,
+        };
+        reader.read(1)?;
+        let first = reader.read(8)?;
+        for _ in 1..(first as u8).leading_ones().max(1) {
+            reader.read(8)?;
+        }
+        let block_size = match block_size_code {
+            1 => 192,
+            2..=5 => 576 << (block_size_code - 2),
+            6 => reader.read(8)? as usize + 1,
+            7 => reader.read(16)? as usize + 1,
+            8..=15 => 256 << (block_size_code - 8),
+            _ => return Err(Er::new("Reserved FLAC block size".to_string())),
+        };
+        match sample_rate_code {
+            12 => {
+                reader.read(8)?;
+            }
+            13 | 14 => {
+                reader.read(16)?;
+            }
+            _ => (),
+        }
+        let header_end = reader.byte_position();
+        if reader.read(8)? as u8 != crc8(&bytes[start..header_end]) {
+            return Err(Er::new(format!(
+                "Bad FLAC header checksum at byte {}",
+                start
+            )));
+        }
+        let block = read_subframe(&mut reader, block_size, bits)?;
+        reader.align();
+        let frame_end = reader.byte_position();
+        if reader.read(16)? as u16 != crc16(&bytes[start..frame_end]) {
+            return Err(Er::new(format!(
+                "Bad FLAC frame checksum at byte {}",
+                start
+            )));
+        }
+        samples.extend(block.into_iter().map(|s| s as i32));
+    }
+    Ok((samples, sample_rate, bits_per_sample))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(samples: &[i32], bits_per_sample: u32) {
+        let encoded = encode(samples, 44100, bits_per_sample);
+        assert_eq!(
+            stream_info(&encoded).unwrap(),
+            (44100, bits_per_sample, samples.len() as u64)
+        );
+        let (decoded, sample_rate, bits) = decode(&encoded).unwrap();
+        assert_eq!((sample_rate, bits), (44100, bits_per_sample));
+        assert_eq!(decoded, samples);
+    }
+
+    /// Repeatable noise from a linear congruential generator, spanning `bits_per_sample` bits.
+    fn noise(length: usize, bits_per_sample: u32) -> Vec<i32> {
+        let mut state = 12345u64;
+        (0..length)
+            .map(|_| {
+                state = state
+                    .wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407);
+                ((state >> 32) as i32) >> (32 - bits_per_sample)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn silence() {
+        round_trip(&vec![0; BLOCK_SIZE * 2], 16);
+        round_trip(&[0; 5], 24);
+    }
+
+    #[test]
+    fn full_scale() {
+        for bits in [8, 16, 24] {
+            let max = (1i32 << (bits - 1)) - 1;
+            let min = -(1i32 << (bits - 1));
+            let square: Vec<i32> = (0..BLOCK_SIZE + 100)
+                .map(|i| if i % 50 < 25 { max } else { min })
+                .collect();
+            round_trip(&square, bits);
+            let alternating: Vec<i32> = (0..1000)
+                .map(|i| if i % 2 == 0 { max } else { min })
+                .collect();
+            round_trip(&alternating, bits);
+        }
+    }
+
+    #[test]
+    fn odd_block_sizes() {
+        for length in [
+            1,
+            2,
+            3,
+            15,
+            17,
+            1001,
+            BLOCK_SIZE - 1,
+            BLOCK_SIZE + 1,
+            3 * BLOCK_SIZE - 7,
+        ] {
+            round_trip(&noise(length, 16), 16);
+        }
+        round_trip(&[], 16);
+    }
+
+    #[test]
+    fn implausible_sample_count() {
+        let samples = noise(1000, 16);
+        let mut encoded = encode(&samples, 44100, 16);
+        // the last 36 bits of STREAMINFO's fixed part count the samples
+        encoded[21] |= 0x0f;
+        encoded[22..26].fill(0xff);
+        assert_eq!(stream_info(&encoded).unwrap().2, (1 << 36) - 1);
+        assert_eq!(decode(&encoded).unwrap().0, samples);
+    }
+
+    #[test]
+    fn noise_and_tones() {
+        round_trip(&noise(BLOCK_SIZE * 3, 24), 24);
+        let tone: Vec<i32> = (0..BLOCK_SIZE * 2 + 333)
+            .map(|i| ((i as f32 * 0.05).sin() * 20000.0) as i32)
+            .collect();
+        round_trip(&tone, 16);
+    }
+
+    #[test]
+    fn corruption_is_noticed() {
+        let mut encoded = encode(&noise(1000, 16), 44100, 16);
+        let last = encoded.len() - 10;
+        encoded[last] ^= 0x10;
+        assert!(decode(&encoded).is_err());
+    }
+}
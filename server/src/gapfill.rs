@@ -0,0 +1,192 @@
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::error::{Er, E};
+use crate::metadata::Metadata;
+
+/// How many words a gap-fill exercise blanks out unless asked for another number.
+pub const DEFAULT_GAPS: usize = 5;
+/// The parts of speech blanked out unless others are asked for.
+pub const DEFAULT_PARTS_OF_SPEECH: &str = "NOUN,VERB";
+/// What a blanked out word is replaced with.
+pub const GAP: &str = "___";
+
+/**
+ * Reads the tagging model for `lang` from the file named by
+ * `POS_TAGGER_PATH_<LANG>`, for instance `POS_TAGGER_PATH_DE`. Each line
+ * holds a word and its part of speech, with Universal Dependencies tags
+ * like `NOUN` or `VERB`: words are tagged by looking them up, and words
+ * not in the model can't be blanked out.
+ */
+pub fn load_tagger(lang: &str) -> E<HashMap<String, String>> {
+    let variable = format!("POS_TAGGER_PATH_{}", lang.to_uppercase());
+    let path = std::env::var(&variable).map_err(|_| {
+        Er::new(format!(
+            "{} is not set, so {} can't be tagged",
+            variable, lang
+        ))
+    })?;
+    Ok(std::fs::read_to_string(path)?
+        .lines()
+        .filter_map(|line| {
+            let (word, tag) = line.trim().split_once(char::is_whitespace)?;
+            Some((word.to_lowercase(), tag.trim().to_uppercase()))
+        })
+        .collect())
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Gap {
+    /// the gap's position among the gaps, which is where its answer goes in a check
+    pub gap: usize,
+    pub pos: String,
+    pub answer: String,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct GapFill {
+    pub text: String,
+    pub gaps: Vec<Gap>,
+    /// generating the exercise again with this seed gives the same gaps
+    pub seed: u64,
+}
+
+/**
+ * Picks `count` of the words of `text` tagged with one of the parts of
+ * speech in `pos` at random, seeded by `seed`, and blanks them out,
+ * keeping any punctuation around them.
+ */
+pub fn gap_fill(
+    text: &str,
+    tagger: &HashMap<String, String>,
+    pos: &[String],
+    count: usize,
+    seed: u64,
+) -> GapFill {
+    // byte ranges of the words which could be blanked out, with their tags
+    let candidates: Vec<(usize, usize, &String)> = text
+        .split_whitespace()
+        .filter_map(|token| {
+            let word = token.trim_matches(|c: char| !c.is_alphanumeric());
+            let tag = tagger.get(&word.to_lowercase())?;
+            if word.is_empty() || !pos.contains(tag) {
+                return None;
+            }
+            let start = word.as_ptr() as usize - text.as_ptr() as usize;
+            Some((start, start + word.len(), tag))
+        })
+        .collect();
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut chosen: Vec<&(usize, usize, &String)> =
+        candidates.choose_multiple(&mut rng, count).collect();
+    chosen.sort_by_key(|(start, _, _)| *start);
+
+    let mut blanked = String::new();
+    let mut gaps = vec![];
+    let mut end_of_last = 0;
+    for (gap, (start, end, tag)) in chosen.into_iter().enumerate() {
+        blanked.push_str(&text[end_of_last..*start]);
+        blanked.push_str(GAP);
+        gaps.push(Gap {
+            gap,
+            pos: tag.to_string(),
+            answer: text[*start..*end].to_string(),
+        });
+        end_of_last = *end;
+    }
+    blanked.push_str(&text[end_of_last..]);
+    GapFill {
+        text: blanked,
+        gaps,
+        seed,
+    }
+}
+
+/// Splits a comma separated list of parts of speech.
+pub fn parts_of_speech(list: &str) -> Vec<String> {
+    list.split(',')
+        .map(|p| p.trim().to_uppercase())
+        .filter(|p| !p.is_empty())
+        .collect()
+}
+
+/// The gap-fill exercise for a resource's text in `lang`.
+pub fn resource_gap_fill(
+    resource_path: &String,
+    lang: &str,
+    pos: &[String],
+    count: usize,
+    seed: u64,
+) -> E<GapFill> {
+    let metadata = Metadata::from_resource_path(resource_path)?;
+    let path = metadata.text_path(lang).ok_or(Er::new(format!(
+        "No text for lang {} in resource {}",
+        lang, resource_path
+    )))?;
+    let text = std::fs::read_to_string(path)?;
+    Ok(gap_fill(&text, &load_tagger(lang)?, pos, count, seed))
+}
+
+/// A learner's answers to the exercise made with these parameters.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Answers {
+    pub pos: Option<String>,
+    pub count: Option<usize>,
+    pub seed: u64,
+    pub answers: Vec<String>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct MarkedAnswer {
+    pub gap: usize,
+    pub answer: Option<String>,
+    pub expected: String,
+    pub correct: bool,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct CheckResult {
+    pub correct: usize,
+    pub total: usize,
+    pub score: f32,
+    pub answers: Vec<MarkedAnswer>,
+}
+
+/**
+ * Marks the answers to an exercise, ignoring case and surrounding
+ * whitespace. Gaps left without an answer are wrong.
+ */
+pub fn check(exercise: &GapFill, answers: &[String]) -> CheckResult {
+    let answers: Vec<MarkedAnswer> = exercise
+        .gaps
+        .iter()
+        .map(|gap| {
+            let answer = answers.get(gap.gap).cloned();
+            let correct = answer
+                .as_ref()
+                .map(|a| a.trim().to_lowercase() == gap.answer.to_lowercase())
+                .unwrap_or(false);
+            MarkedAnswer {
+                gap: gap.gap,
+                answer,
+                expected: gap.answer.clone(),
+                correct,
+            }
+        })
+        .collect();
+    let correct = answers.iter().filter(|a| a.correct).count();
+    let total = answers.len();
+    CheckResult {
+        correct,
+        total,
+        score: if total == 0 {
+            0.0
+        } else {
+            correct as f32 / total as f32
+        },
+        answers,
+    }
+}
@@ -0,0 +1,134 @@
+use serde::{Deserialize, Serialize};
+
+use crate::compare::words;
+use crate::error::E;
+use crate::metadata::Metadata;
+
+/// A term of a resource, and the ways it may be rendered in one language.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Term {
+    /// the term as it is said in the resource
+    pub term: String,
+    /// what it may be translated as, any one of which will do
+    pub renderings: Vec<String>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct TermMatch {
+    #[serde(flatten)]
+    pub term: Term,
+    /// the words of the transcript taken for one of the renderings
+    pub found: String,
+    /// where they start among the words of the transcript
+    pub position: usize,
+    /// whether they are the rendering exactly, rather than close to it
+    pub exact: bool,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct TerminologyReport {
+    pub hits: Vec<TermMatch>,
+    pub missed: Vec<Term>,
+    /// the fraction of the terms which were rendered
+    pub coverage: f32,
+}
+
+/**
+ * The glossary of a resource in `lang`, from the JSON file its metadata
+ * names under `glossaries`: `[{"term":"victory","renderings":["Sieg"]}]`.
+ * Resources without one for `lang` have `None`.
+ */
+pub fn read_glossary(metadata: &Metadata, lang: &str) -> E<Option<Vec<Term>>> {
+    let Some(filename) = metadata.glossaries.get(lang) else {
+        return Ok(None);
+    };
+    let f = std::fs::File::open(format!("{}/{}", metadata.enclosing_directory, filename))?;
+    Ok(Some(serde_json::from_reader(std::io::BufReader::new(f))?))
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let above = row[j + 1];
+            row[j + 1] = (above + 1)
+                .min(row[j] + 1)
+                .min(diagonal + usize::from(ca != *cb));
+            diagonal = above;
+        }
+    }
+    row[b.len()]
+}
+
+/// Whether a heard word is close enough to the expected one: a letter off in every five.
+fn close(expected: &str, heard: &str) -> bool {
+    edit_distance(expected, heard) <= expected.chars().count() / 5
+}
+
+/**
+ * Looks for a rendering of `term` among the words of a transcript,
+ * preferring exact ones. Words may be misspelt by a letter in five, as
+ * transcription often gets inflections and compounds slightly wrong.
+ */
+fn find_term(term: &Term, transcript: &[String]) -> Option<TermMatch> {
+    let mut best: Option<TermMatch> = None;
+    for rendering in &term.renderings {
+        let rendering = words(rendering);
+        if rendering.is_empty() || rendering.len() > transcript.len() {
+            continue;
+        }
+        for (position, window) in transcript.windows(rendering.len()).enumerate() {
+            if !rendering.iter().zip(window).all(|(r, w)| close(r, w)) {
+                continue;
+            }
+            let exact = rendering.as_slice() == window;
+            if best.as_ref().map(|b| exact && !b.exact).unwrap_or(true) {
+                best = Some(TermMatch {
+                    term: term.clone(),
+                    found: window.join(" "),
+                    position,
+                    exact,
+                });
+            }
+            if exact {
+                return best;
+            }
+        }
+    }
+    best
+}
+
+/// Which of the glossary's terms were rendered in `transcript`, and which were missed.
+pub fn check_terms(glossary: &[Term], transcript: &str) -> TerminologyReport {
+    let transcript = words(transcript);
+    let mut hits = vec![];
+    let mut missed = vec![];
+    for term in glossary {
+        match find_term(term, &transcript) {
+            Some(hit) => hits.push(hit),
+            None => missed.push(term.clone()),
+        }
+    }
+    TerminologyReport {
+        coverage: if glossary.is_empty() {
+            1.0
+        } else {
+            hits.len() as f32 / glossary.len() as f32
+        },
+        hits,
+        missed,
+    }
+}
+
+/// The terminology of a transcript against the glossary of `resource_path` in `lang`, if it has one.
+pub fn terminology(
+    resource_path: &String,
+    lang: &str,
+    transcript: &str,
+) -> E<Option<TerminologyReport>> {
+    let metadata = Metadata::from_resource_path(resource_path)?;
+    Ok(read_glossary(&metadata, lang)?.map(|glossary| check_terms(&glossary, transcript)))
+}
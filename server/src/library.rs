@@ -0,0 +1,192 @@
+use lazy_static::lazy_static;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::error::{Er, Kind, E};
+use crate::metadata::{list_resources, LanguagePair, Metadata, ResourceSummary};
+
+/// How long the library index is used before the assets directory is scanned again.
+pub const LIBRARY_TTL: Duration = Duration::from_secs(60);
+
+/// The CEFR levels resources are filtered by, easiest first.
+pub const CEFR_LEVELS: [&str; 6] = ["A1", "A2", "B1", "B2", "C1", "C2"];
+
+/// A resource as the library lists it, with what it can be searched and filtered by.
+#[derive(Clone, Debug, Serialize)]
+pub struct LibraryEntry {
+    #[serde(flatten)]
+    pub summary: ResourceSummary,
+    pub description: String,
+    pub tags: Vec<String>,
+    /// the CEFR level of the metadata, or else estimated from the native text
+    pub difficulty: Option<String>,
+    pub duration_secs: Option<f32>,
+    pub language_pairs: Vec<LanguagePair>,
+}
+
+impl LibraryEntry {
+    fn new(resource_path: String, metadata: &Metadata) -> Self {
+        let difficulty = metadata.difficulty.clone().or_else(|| {
+            crate::difficulty::estimate(&resource_path, None)
+                .ok()
+                .map(|estimate| estimate.cefr)
+        });
+        let duration_secs = metadata.duration_secs.or_else(|| {
+            metadata
+                .audio_path(None)
+                .and_then(|audio| crate::audio::duration_seconds(&audio).ok())
+        });
+        Self {
+            description: metadata.description.clone(),
+            tags: metadata.tags.clone(),
+            difficulty,
+            duration_secs,
+            language_pairs: metadata.language_pairs(),
+            summary: metadata.to_summary(resource_path),
+        }
+    }
+
+    /// How long the audio is as minutes and seconds, like `4:05`.
+    pub fn duration(&self) -> Option<String> {
+        self.duration_secs.map(|secs| {
+            let secs = secs.round() as u64;
+            format!("{}:{:02}", secs / 60, secs % 60)
+        })
+    }
+}
+
+lazy_static! {
+    /// the library as last built, with when it was
+    static ref LIBRARY: Mutex<Option<(Instant, Vec<LibraryEntry>)>> = Mutex::new(None);
+}
+
+/**
+ * Every resource bundle in the assets directory, read again once the
+ * index is older than LIBRARY_TTL. Estimating difficulties and reading
+ * the length of audio takes a while for many resources, so this should
+ * be called off the async runtime. Bundles whose metadata can't be read
+ * are left out with a warning.
+ */
+pub fn entries() -> E<Vec<LibraryEntry>> {
+    if let Some((built_at, entries)) = LIBRARY.lock().unwrap().as_ref() {
+        if built_at.elapsed() < LIBRARY_TTL {
+            return Ok(entries.clone());
+        }
+    }
+    let entries: Vec<LibraryEntry> = list_resources()?
+        .into_iter()
+        .filter_map(|path| match Metadata::from_resource_path(&path) {
+            Ok(metadata) => Some(LibraryEntry::new(path, &metadata)),
+            Err(e) => {
+                log::warn!("Leaving resource {} out of the library: {:?}", path, e);
+                None
+            }
+        })
+        .collect();
+    *LIBRARY.lock().unwrap() = Some((Instant::now(), entries.clone()));
+    Ok(entries)
+}
+
+/// Forgets the library index, so that it is built again from the assets directory next time.
+pub fn invalidate() {
+    *LIBRARY.lock().unwrap() = None;
+}
+
+/// What the library is searched for, as `q`, `lang`, `tag` and `difficulty` ask.
+#[derive(Clone, Debug, Default)]
+pub struct LibraryQuery {
+    /// words which all have to be in the resource's name, description, path or tags
+    pub q: Option<String>,
+    /// a language the resource has a text in
+    pub lang: Option<String>,
+    pub tag: Option<String>,
+    /// one of CEFR_LEVELS
+    pub difficulty: Option<String>,
+}
+
+impl LibraryQuery {
+    /// The query the parameters make, any of which can be left out or empty.
+    pub fn from_params(params: &HashMap<String, String>) -> E<Self> {
+        let param = |name: &str| {
+            params
+                .get(name)
+                .map(|value| value.trim().to_string())
+                .filter(|value| !value.is_empty())
+        };
+        let difficulty = param("difficulty").map(|level| level.to_uppercase());
+        if let Some(level) = &difficulty {
+            if !CEFR_LEVELS.contains(&level.as_str()) {
+                return Err(Er::with_kind(
+                    Kind::BadRequest,
+                    format!(
+                        "difficulty must be one of {}, not {}",
+                        CEFR_LEVELS.join(", "),
+                        level
+                    ),
+                ));
+            }
+        }
+        Ok(Self {
+            q: param("q"),
+            lang: param("lang"),
+            tag: param("tag"),
+            difficulty,
+        })
+    }
+
+    /// Whether the resource is one the query is looking for.
+    pub fn matches(&self, entry: &LibraryEntry) -> bool {
+        if let Some(lang) = &self.lang {
+            if !entry.summary.languages.contains(lang) {
+                return false;
+            }
+        }
+        if let Some(tag) = &self.tag {
+            if !entry.tags.iter().any(|t| t.eq_ignore_ascii_case(tag)) {
+                return false;
+            }
+        }
+        if self.difficulty.is_some() && self.difficulty != entry.difficulty {
+            return false;
+        }
+        if let Some(q) = &self.q {
+            let haystack = format!(
+                "{} {} {} {}",
+                entry.summary.title,
+                entry.description,
+                entry.summary.resource_path,
+                entry.tags.join(" ")
+            )
+            .to_lowercase();
+            if !q
+                .to_lowercase()
+                .split_whitespace()
+                .all(|word| haystack.contains(word))
+            {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// Every tag of the resources, sorted, for filtering by.
+pub fn tags(entries: &[LibraryEntry]) -> Vec<String> {
+    let mut tags: Vec<String> = entries.iter().flat_map(|e| e.tags.clone()).collect();
+    tags.sort();
+    tags.dedup();
+    tags
+}
+
+/// Every language the resources have a text in, sorted, for filtering by.
+pub fn languages(entries: &[LibraryEntry]) -> Vec<String> {
+    let mut languages: Vec<String> = entries
+        .iter()
+        .flat_map(|e| e.summary.languages.clone())
+        .collect();
+    languages.sort();
+    languages.dedup();
+    languages
+}
@@ -1,50 +1,122 @@
+mod alignment;
 mod api;
+mod audio;
+mod auth;
+mod bundle;
 mod compare;
+mod config;
+mod consecutive;
+mod curriculum;
+mod difficulty;
+mod embeddings;
 mod error;
+mod export;
+mod flac;
+mod gapfill;
+mod glossary;
+mod library;
 mod metadata;
-mod queue;
+mod metrics;
+mod middleware;
+mod models;
+mod ngrams;
+mod notify;
+mod pronunciation;
+mod rate_limit;
+mod resources;
+mod scoring;
+mod sentiment;
 mod session;
+mod shutdown;
+mod stats;
+mod store;
+mod subtitles;
+mod tls;
+mod transcribe;
 mod translate;
+mod tts;
+mod users;
 mod whispercpp;
 mod whisperx;
 
-use crossbeam_channel::unbounded;
 use dotenv::dotenv;
-use thread_priority::*;
+use tokio::runtime::{Builder, Runtime};
 
 use crate::api::serve;
-use crate::whisperx::WhisperX;
+use crate::config::CONFIG;
 
 pub const LOWER_PRIORITY: u8 = 40;
-pub const HIGHER_PRIORITY: u8 = 60;
 
-#[tokio::main]
-async fn main() {
+/// The most threads kept for blocking work unless `TOKIO_MAX_BLOCKING_THREADS` says otherwise.
+pub const DEFAULT_MAX_BLOCKING_THREADS: usize = 512;
+
+/**
+ * The runtime the server runs on. `TOKIO_WORKER_THREADS` sets the number
+ * of threads running async tasks, by default one per core, and
+ * `TOKIO_MAX_BLOCKING_THREADS` the most threads kept for blocking work
+ * such as audio analysis, by default DEFAULT_MAX_BLOCKING_THREADS.
+ */
+fn runtime() -> std::io::Result<Runtime> {
+    let mut builder = Builder::new_multi_thread();
+    builder.enable_all();
+    if let Some(threads) = env_threads("TOKIO_WORKER_THREADS") {
+        builder.worker_threads(threads);
+    }
+    builder.max_blocking_threads(
+        env_threads("TOKIO_MAX_BLOCKING_THREADS").unwrap_or(DEFAULT_MAX_BLOCKING_THREADS),
+    );
+    builder.build()
+}
+
+fn env_threads(name: &str) -> Option<usize> {
+    let value = std::env::var(name).ok()?;
+    match value.parse() {
+        Ok(threads) if threads > 0 => Some(threads),
+        _ => {
+            log::warn!(
+                "Ignoring {}={}, it should be a positive number",
+                name,
+                value
+            );
+            None
+        }
+    }
+}
+
+fn main() {
     dotenv().ok();
 
-    env_logger::init();
-
-    let (_translate_tx, translate_rx) = unbounded();
-    log::debug!("Making transcription pool");
-    whispercpp::start_translate_pool().unwrap();
-    log::debug!("Made WhisperCpp pool");
-    if std::env::var("WHISPER_SERVER").is_ok() {
-        std::thread::spawn(move || async {
-            set_current_thread_priority(ThreadPriority::Crossplatform(
-                HIGHER_PRIORITY.try_into().unwrap(),
-            ))
-            .unwrap();
-            let mut queue = queue::get_queue();
-            let whisperx = WhisperX::new().unwrap();
-            log::debug!("Waiting for WhisperX job");
-            queue.subscribe::<WhisperX>(&whisperx).unwrap();
-        });
-        log::debug!("Started remote whisper process");
+    let mut logger = env_logger::Builder::from_default_env();
+    if std::env::var("RUST_LOG").is_err() {
+        if let Some(level) = &CONFIG.log_level {
+            logger.parse_filters(level);
+        }
+    }
+    logger.init();
+
+    // a scoring config which can't be read is better found now than when a learner is first graded
+    if let Err(e) = scoring::ScoringConfig::load() {
+        eprintln!("{}", e);
+        std::process::exit(2);
+    }
+
+    runtime()
+        .expect("Could not build the tokio runtime")
+        .block_on(run());
+}
+
+async fn run() {
+    transcribe::scheduler::start().unwrap();
+    if let Some(database) = &CONFIG.database {
+        if let Err(e) = store::open(database).await {
+            eprintln!("Couldn't open the database {}: {}", database, e);
+            std::process::exit(2);
+        }
     }
     log::info!("Restoring old sessions");
     crate::session::restore_sessions().await.unwrap();
+    crate::session::start_retention_task();
+    crate::notify::start();
 
-    std::thread::spawn(move || async { queue::get_queue().queue_process(translate_rx).await });
-    log::debug!("Made enqueuing process");
-    serve().await;
+    serve(&CONFIG).await;
 }
@@ -1,8 +1,28 @@
+use lazy_static::lazy_static;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
-use crate::error::E;
+use crate::error::{Er, Kind, E};
+
+/// One recording of a resource, for instance the speech in another language for relay interpreting.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct AudioTrack {
+    pub lang: String,
+    #[serde(default)]
+    pub speaker: Option<String>,
+    /// relative to the resource's directory, like `audio`
+    pub path: String,
+}
+
+/// A direction a resource is meant to be interpreted in, from one language into another.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct LanguagePair {
+    pub from: String,
+    pub to: String,
+}
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Metadata {
@@ -11,45 +31,536 @@ pub struct Metadata {
     pub description: String,
     pub url: String,
     pub license: String,
+    /// the track played unless another is asked for, by default the first of `audio_tracks`
+    #[serde(default)]
     pub audio: String,
     #[serde(default)]
+    pub audio_tracks: Vec<AudioTrack>,
+    #[serde(default)]
     pub skip: u32,
     pub native: String,
     pub transcript: Option<String>,
     pub translations: HashMap<String, String>,
+    /// files of the terms to be rendered in each language, see [crate::glossary]
+    #[serde(default)]
+    pub glossaries: HashMap<String, String>,
+    /// paths of resources to practise next, in order
+    #[serde(default)]
+    pub related_resources: Vec<String>,
+    /// what the resource is about or good for, like `politics` or `numbers`, for the library
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// its CEFR level, like `B2`, when it has been judged rather than estimated
+    #[serde(default)]
+    pub difficulty: Option<String>,
+    /// how long the audio is, for audio whose length can't be read from the file
+    #[serde(default)]
+    pub duration_secs: Option<f32>,
+    /// the directions it is meant to be interpreted in, by default from `native` into each translation
+    #[serde(default)]
+    pub language_pairs: Vec<LanguagePair>,
+    /// where the audio is divided into chapters, in seconds, instead of at its pauses
+    #[serde(default)]
+    pub cue_points: Vec<f32>,
     #[serde(skip_serializing)]
     #[serde(skip_deserializing)]
     pub enclosing_directory: String,
 }
 
+/// The MIME type of a resource's audio or video file, going by its extension.
+pub fn media_type(filename: &str) -> String {
+    mime_guess::from_path(filename)
+        .first_or_octet_stream()
+        .essence_str()
+        .to_string()
+}
+
+/// How many resources' metadata is kept in memory unless `METADATA_CACHE_SIZE` says otherwise.
+pub const DEFAULT_METADATA_CACHE_SIZE: usize = 256;
+/// How long metadata is kept in memory unless `METADATA_CACHE_TTL_SECONDS` says otherwise.
+pub const DEFAULT_METADATA_CACHE_TTL_SECONDS: u64 = 60;
+
+/**
+ * The metadata of recently used resources, keyed by the canonical path of
+ * their directory, with when each was read and last used. The least
+ * recently used is dropped when there are too many.
+ */
+#[derive(Default)]
+struct MetadataCache {
+    entries: HashMap<PathBuf, (Metadata, Instant, u64)>,
+    uses: u64,
+}
+
+lazy_static! {
+    static ref METADATA_CACHE: Mutex<MetadataCache> = Mutex::new(MetadataCache::default());
+    static ref METADATA_CACHE_SIZE: usize = std::env::var("METADATA_CACHE_SIZE")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(DEFAULT_METADATA_CACHE_SIZE);
+    static ref METADATA_CACHE_TTL: Duration = Duration::from_secs(
+        std::env::var("METADATA_CACHE_TTL_SECONDS")
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(DEFAULT_METADATA_CACHE_TTL_SECONDS)
+    );
+}
+
+impl MetadataCache {
+    fn get(&mut self, key: &Path) -> Option<Metadata> {
+        self.uses += 1;
+        let uses = self.uses;
+        match self.entries.get_mut(key) {
+            Some((metadata, read_at, last_used)) if read_at.elapsed() < *METADATA_CACHE_TTL => {
+                *last_used = uses;
+                Some(metadata.clone())
+            }
+            _ => None,
+        }
+    }
+
+    fn insert(&mut self, key: PathBuf, metadata: Metadata) {
+        self.uses += 1;
+        self.entries
+            .insert(key, (metadata, Instant::now(), self.uses));
+        while self.entries.len() > *METADATA_CACHE_SIZE {
+            let Some(oldest) = self
+                .entries
+                .iter()
+                .min_by_key(|(_, (_, _, last_used))| *last_used)
+                .map(|(key, _)| key.clone())
+            else {
+                break;
+            };
+            self.entries.remove(&oldest);
+        }
+    }
+}
+
+/**
+ * Forgets the cached metadata of the resource at `resource_path`, or of
+ * every resource if it's None, returning how many were forgotten. The
+ * library is built again either way.
+ */
+pub fn invalidate_cache(resource_path: Option<&String>) -> usize {
+    crate::library::invalidate();
+    let mut cache = METADATA_CACHE.lock().unwrap();
+    match resource_path {
+        Some(resource_path) => match std::fs::canonicalize(full_resource_path(resource_path)) {
+            Ok(key) => cache.entries.remove(&key).map(|_| 1).unwrap_or(0),
+            Err(_) => 0,
+        },
+        None => {
+            let count = cache.entries.len();
+            cache.entries.clear();
+            count
+        }
+    }
+}
+
+/// The directory of a resource, which is under the assets directory unless the path is absolute.
+fn full_resource_path(resource_path: &String) -> String {
+    if resource_path.starts_with('/') {
+        resource_path.clone()
+    } else {
+        format!("{}/{}", assets_dir(), resource_path)
+    }
+}
+
+pub fn assets_dir() -> String {
+    crate::config::CONFIG.assets_dir.clone()
+}
+
+fn find_resources(dir: &Path, prefix: &str, resources: &mut Vec<String>) -> E<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        if !entry.metadata()?.is_dir() {
+            continue;
+        }
+        let name = entry.file_name().to_string_lossy().to_string();
+        let resource_path = if prefix.is_empty() {
+            name
+        } else {
+            format!("{}/{}", prefix, name)
+        };
+        if entry.path().join("metadata.json").exists() {
+            resources.push(resource_path);
+        } else {
+            find_resources(&entry.path(), &resource_path, resources)?;
+        }
+    }
+    Ok(())
+}
+
+/**
+ * The resource paths of all the resource bundles in the assets
+ * directory, which are the directories with a metadata.json in them, at
+ * any depth.
+ */
+pub fn list_resources() -> E<Vec<String>> {
+    let mut resources = vec![];
+    find_resources(Path::new(&assets_dir()), "", &mut resources)?;
+    resources.sort();
+    Ok(resources)
+}
+
 impl Metadata {
     pub fn from_filename(filename: String) -> E<Self> {
-        let f = std::fs::File::open(&filename)?;
+        let f = std::fs::File::open(&filename).map_err(|e| {
+            let kind = match e.kind() {
+                std::io::ErrorKind::NotFound => Kind::ResourceNotFound,
+                _ => Kind::MetadataInvalid,
+            };
+            Er::with_kind(kind, format!("Couldn't open {}: {}", filename, e))
+        })?;
         let reader = std::io::BufReader::new(f);
-        let mut metadata: Self = serde_json::from_reader(reader)?;
+        let mut metadata: Self = serde_json::from_reader(reader).map_err(|e| {
+            Er::with_kind(
+                Kind::MetadataInvalid,
+                format!("Couldn't read {}: {}", filename, e),
+            )
+        })?;
+        if metadata.audio.is_empty() {
+            if let Some(track) = metadata.audio_tracks.first() {
+                metadata.audio = track.path.clone();
+            }
+        }
         log::debug!("metadata::from_filename: {:?}", metadata);
         metadata.enclosing_directory = Path::parent(Path::new(&filename))
             .unwrap()
             .to_str()
             .unwrap()
             .to_string();
+        if metadata.audio.is_empty() {
+            let synthesized = crate::tts::audio_filename(&metadata.native);
+            if Path::new(&format!("{}/{}", metadata.enclosing_directory, synthesized)).exists() {
+                metadata.audio = synthesized;
+            }
+        }
         Ok(metadata)
     }
 
+    /// Reads the metadata of a resource, or takes it from the cache if it was read recently.
     pub fn from_resource_path(resource_path: &String) -> E<Self> {
-        let full_path = if resource_path.starts_with('/') {
-            resource_path.clone()
-        } else {
-            format!(
-                "{}/{}",
-                std::env::var("ASSETS_DIR").unwrap_or("../assets".to_string()),
-                resource_path
-            )
-        };
+        let full_path = full_resource_path(resource_path);
+        let key = std::fs::canonicalize(&full_path).ok();
+        if let Some(key) = &key {
+            if let Some(mut metadata) = METADATA_CACHE.lock().unwrap().get(key) {
+                metadata.enclosing_directory = full_path;
+                return Ok(metadata);
+            }
+        }
         let metadata_path = format!("{}/metadata.json", full_path);
         log::debug!("Path is {}", metadata_path);
         let mut metadata = Metadata::from_filename(metadata_path)?;
         metadata.enclosing_directory = full_path;
+        if let Some(key) = key {
+            METADATA_CACHE.lock().unwrap().insert(key, metadata.clone());
+        }
         Ok(metadata)
     }
+
+    /// Languages with a transcript or translation, native first.
+    pub fn languages(&self) -> Vec<String> {
+        let mut languages = vec![];
+        if self.transcript.is_some() {
+            languages.push(self.native.clone());
+        }
+        let mut translations: Vec<String> = self.translations.keys().cloned().collect();
+        translations.sort();
+        languages.append(&mut translations);
+        languages
+    }
+
+    /// The directions the resource is meant to be interpreted in.
+    pub fn language_pairs(&self) -> Vec<LanguagePair> {
+        if !self.language_pairs.is_empty() {
+            return self.language_pairs.clone();
+        }
+        let mut translations: Vec<&String> = self.translations.keys().collect();
+        translations.sort();
+        translations
+            .into_iter()
+            .map(|lang| LanguagePair {
+                from: self.native.clone(),
+                to: lang.clone(),
+            })
+            .collect()
+    }
+
+    /**
+     * The resource's audio tracks, or a track of `audio` in the native
+     * language for resources which only name that.
+     */
+    pub fn tracks(&self) -> Vec<AudioTrack> {
+        if !self.audio_tracks.is_empty() {
+            return self.audio_tracks.clone();
+        }
+        vec![AudioTrack {
+            lang: self.native.clone(),
+            speaker: None,
+            path: self.audio.clone(),
+        }]
+    }
+
+    /// The full path to the first audio track in `lang`, or to `audio` when no track is asked for.
+    pub fn audio_path(&self, track: Option<&str>) -> Option<String> {
+        let filename = match track {
+            Some(lang) => self
+                .tracks()
+                .into_iter()
+                .find(|t| t.lang == lang)
+                .map(|t| t.path)?,
+            None => self.audio.clone(),
+        };
+        if filename.is_empty() {
+            return None;
+        }
+        Some(format!("{}/{}", self.enclosing_directory, filename))
+    }
+
+    /// The MIME type of the first audio track in `lang`, or of `audio` when no track is asked for.
+    pub fn media_type(&self, track: Option<&str>) -> Option<String> {
+        self.audio_path(track).map(|path| media_type(&path))
+    }
+
+    /// Whether the resource's `audio` is a video, which the practice page shows as it plays.
+    pub fn is_video(&self) -> bool {
+        self.media_type(None)
+            .is_some_and(|media_type| media_type.starts_with("video/"))
+    }
+
+    /// What the list of resources shows of this one.
+    pub fn to_summary(&self, resource_path: String) -> ResourceSummary {
+        ResourceSummary {
+            resource_path,
+            title: self.name.clone(),
+            native: self.native.clone(),
+            languages: self.languages(),
+            audio: self.audio.clone(),
+        }
+    }
+
+    /**
+     * Checks that the required fields are filled in and that the files
+     * they name exist, returning a description of each problem found.
+     */
+    pub fn validate(&self) -> Vec<String> {
+        let mut errors = vec![];
+        // text-only resources are given speech when they are first practised
+        let synthesizable = self.transcript.is_some() && crate::tts::configured();
+        for (field, value) in [
+            ("name", &self.name),
+            ("audio", &self.audio),
+            ("native", &self.native),
+        ] {
+            if value.is_empty() && !(field == "audio" && synthesizable) {
+                errors.push(format!("{} is missing", field));
+            }
+        }
+        for file in std::iter::once(&self.audio).chain(self.audio_tracks.iter().map(|t| &t.path)) {
+            let media_type = media_type(file);
+            if !file.is_empty()
+                && !media_type.starts_with("audio/")
+                && !media_type.starts_with("video/")
+            {
+                errors.push(format!("{} is neither audio nor video", file));
+            }
+        }
+        let mut files = vec![&self.audio];
+        files.extend(self.audio_tracks.iter().map(|t| &t.path));
+        files.extend(self.transcript.iter());
+        files.extend(self.translations.values());
+        files.extend(self.glossaries.values());
+        for file in files {
+            if !file.is_empty()
+                && !Path::new(&format!("{}/{}", self.enclosing_directory, file)).exists()
+            {
+                errors.push(format!("{} not found", file));
+            }
+        }
+        errors
+    }
+
+    /// The full path to the text for `lang`: the transcript when `lang`
+    /// is the native language of the resource, otherwise the translation.
+    pub fn text_path(&self, lang: &str) -> Option<String> {
+        let filename = if lang == self.native {
+            self.transcript.as_ref()
+        } else {
+            self.translations.get(lang)
+        }?;
+        Some(format!("{}/{}", self.enclosing_directory, filename))
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Validation {
+    pub valid: bool,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub errors: Vec<String>,
+}
+
+/**
+ * Checks that a resource is fit to be practised with: its metadata is
+ * valid, a WAV audio file can be decoded, there is at least one
+ * non-empty text, and every alignment sidecar has the same words as the
+ * text it belongs to.
+ */
+pub fn validate_resource(resource_path: &String) -> Validation {
+    let metadata = match Metadata::from_resource_path(resource_path) {
+        Ok(m) => m,
+        Err(e) => {
+            return Validation {
+                valid: false,
+                errors: vec![format!("metadata.json could not be loaded: {}", e)],
+            }
+        }
+    };
+    let mut errors = metadata.validate();
+
+    let audio = format!("{}/{}", metadata.enclosing_directory, metadata.audio);
+    if metadata.audio.to_lowercase().ends_with(".wav")
+        && Path::new(&audio).exists()
+        && crate::audio::read_wav(&audio).is_err()
+    {
+        errors.push("audio file corrupted".to_string());
+    }
+
+    let mut texts = 0;
+    for lang in metadata.languages() {
+        let Some(path) = metadata.text_path(&lang) else {
+            continue;
+        };
+        let Some(filename) = Path::new(&path).file_name().map(|f| f.to_string_lossy()) else {
+            errors.push(format!("the text in {} isn't named by a file", lang));
+            continue;
+        };
+        let Ok(text) = std::fs::read_to_string(&path) else {
+            continue;
+        };
+        if text.trim().is_empty() {
+            errors.push(format!("{} is empty", filename));
+            continue;
+        }
+        texts += 1;
+        match crate::alignment::read_alignment(&metadata, &lang) {
+            Ok(Some(timestamps)) => {
+                let aligned = timestamps
+                    .iter()
+                    .map(|t| t.word.as_str())
+                    .collect::<Vec<&str>>()
+                    .join(" ");
+                if crate::compare::words(&aligned) != crate::compare::words(&text) {
+                    errors.push(format!(
+                        "alignment_{}.json doesn't match {}",
+                        lang, filename
+                    ));
+                }
+            }
+            Ok(None) => (),
+            Err(e) => errors.push(format!("alignment_{}.json is invalid: {}", lang, e)),
+        }
+    }
+    if texts == 0 {
+        errors.push("no transcript or translation".to_string());
+    }
+
+    Validation {
+        valid: errors.is_empty(),
+        errors,
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct ResourceSummary {
+    pub resource_path: String,
+    pub title: String,
+    pub native: String,
+    pub languages: Vec<String>,
+    pub audio: String,
+}
+
+/**
+ * Summaries of all the resource bundles. A bundle whose metadata can't be
+ * read is left out with a warning, rather than hiding all the others.
+ */
+pub fn resource_summaries() -> E<Vec<ResourceSummary>> {
+    Ok(list_resources()?
+        .into_iter()
+        .filter_map(|path| match Metadata::from_resource_path(&path) {
+            Ok(metadata) => Some(metadata.to_summary(path)),
+            Err(e) => {
+                log::warn!("Leaving resource {} out of the list: {:?}", path, e);
+                None
+            }
+        })
+        .collect())
+}
+
+/// How many suggestions `related` makes on top of the hand-picked ones
+pub const RELATED_SUGGESTIONS: usize = 5;
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Related {
+    pub resource_path: String,
+    pub name: String,
+    pub languages: Vec<String>,
+}
+
+impl Related {
+    fn new(resource_path: String, metadata: &Metadata) -> Self {
+        Self {
+            resource_path,
+            name: metadata.name.clone(),
+            languages: metadata.languages(),
+        }
+    }
+}
+
+/**
+ * Resources to practise after this one: first those listed in
+ * `related_resources`, then the ones in the same native language sharing
+ * the most translation languages with it.
+ */
+pub fn related(resource_path: &String) -> E<Vec<Related>> {
+    let metadata = Metadata::from_resource_path(resource_path)?;
+    let mut related: Vec<Related> = vec![];
+    for path in &metadata.related_resources {
+        if path == resource_path || related.iter().any(|r| &r.resource_path == path) {
+            continue;
+        }
+        match Metadata::from_resource_path(path) {
+            Ok(m) => related.push(Related::new(path.clone(), &m)),
+            Err(e) => log::warn!("Related resource {} of {}: {:?}", path, resource_path, e),
+        }
+    }
+
+    let mut suggestions: Vec<(usize, Related)> = vec![];
+    for path in list_resources()? {
+        if &path == resource_path || related.iter().any(|r| r.resource_path == path) {
+            continue;
+        }
+        let Ok(candidate) = Metadata::from_resource_path(&path) else {
+            continue;
+        };
+        if candidate.native != metadata.native {
+            continue;
+        }
+        let shared = candidate
+            .translations
+            .keys()
+            .filter(|lang| metadata.translations.contains_key(*lang))
+            .count();
+        if shared > 0 {
+            suggestions.push((shared, Related::new(path, &candidate)));
+        }
+    }
+    suggestions.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.name.cmp(&b.1.name)));
+    related.extend(
+        suggestions
+            .into_iter()
+            .take(RELATED_SUGGESTIONS)
+            .map(|(_, r)| r),
+    );
+    Ok(related)
 }
@@ -0,0 +1,340 @@
+use lazy_static::lazy_static;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::Duration;
+use uuid::Uuid;
+
+/// The content type of the Prometheus text format `render` writes.
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+/// The upper bounds, in seconds, of the buckets HTTP requests are counted in.
+const HTTP_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+/// The upper bounds, in seconds, of the buckets transcriptions are counted in.
+const TRANSCRIPTION_BUCKETS: &[f64] = &[0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 20.0, 30.0, 60.0, 120.0];
+
+/// The name, type and help text of each metric, in the order they are rendered.
+const METRICS: &[(&str, &str, &str)] = &[
+    (
+        "terplounge_sessions_active",
+        "gauge",
+        "Sessions being recorded.",
+    ),
+    (
+        "terplounge_transcriptions_waiting",
+        "gauge",
+        "Pieces of audio waiting for a transcription worker.",
+    ),
+    (
+        "terplounge_websocket_connections_total",
+        "counter",
+        "Chat websockets opened, as new sessions or to resume one.",
+    ),
+    (
+        "terplounge_websocket_errors_total",
+        "counter",
+        "Chat websockets which failed receiving or sending, or whose audio couldn't be decoded.",
+    ),
+    (
+        "terplounge_transcriptions_total",
+        "counter",
+        "Pieces of audio transcribed, by backend and whether it succeeded.",
+    ),
+    (
+        "terplounge_transcription_duration_seconds",
+        "histogram",
+        "How long the backend took to transcribe a piece of audio.",
+    ),
+    (
+        "terplounge_http_requests_total",
+        "counter",
+        "HTTP requests answered, by method, route and status.",
+    ),
+    (
+        "terplounge_http_request_duration_seconds",
+        "histogram",
+        "How long HTTP requests took to answer, by method and route.",
+    ),
+];
+
+#[derive(Clone, Debug)]
+struct Histogram {
+    bounds: &'static [f64],
+    /// how many observations were at most each bound
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Self {
+        Self {
+            bounds,
+            counts: vec![0; bounds.len()],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    fn observe(&mut self, value: f64) {
+        for (count, bound) in self.counts.iter_mut().zip(self.bounds) {
+            if value <= *bound {
+                *count += 1;
+            }
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+/// The values of the metrics, each kept by its name and its labels as the text format writes them.
+#[derive(Default)]
+struct Registry {
+    counters: BTreeMap<(&'static str, String), u64>,
+    histograms: BTreeMap<(&'static str, String), Histogram>,
+}
+
+impl Registry {
+    fn count(&mut self, name: &'static str, labels: String) {
+        *self.counters.entry((name, labels)).or_default() += 1;
+    }
+
+    fn observe(
+        &mut self,
+        name: &'static str,
+        labels: String,
+        bounds: &'static [f64],
+        value: Duration,
+    ) {
+        self.histograms
+            .entry((name, labels))
+            .or_insert_with(|| Histogram::new(bounds))
+            .observe(value.as_secs_f64());
+    }
+}
+
+lazy_static! {
+    static ref REGISTRY: Mutex<Registry> = Mutex::new(Registry::default());
+}
+
+/// Labels as the text format writes them, with quotes, backslashes and newlines in their values escaped.
+fn labels(pairs: &[(&str, &str)]) -> String {
+    pairs
+        .iter()
+        .map(|(name, value)| {
+            let value = value
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+            format!("{}=\"{}\"", name, value)
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Counts a chat websocket opened, `resumed` when it takes up a session that was already going on.
+pub fn websocket_connected(resumed: bool) {
+    let kind = if resumed { "resumed" } else { "new" };
+    REGISTRY.lock().unwrap().count(
+        "terplounge_websocket_connections_total",
+        labels(&[("kind", kind)]),
+    );
+}
+
+/// Counts a chat websocket failing, `kind` being `receive`, `send` or `decode`.
+pub fn websocket_error(kind: &str) {
+    REGISTRY.lock().unwrap().count(
+        "terplounge_websocket_errors_total",
+        labels(&[("kind", kind)]),
+    );
+}
+
+/// Counts a piece of audio `backend` transcribed in `elapsed`, or failed to.
+pub fn transcribed(backend: &str, ok: bool, elapsed: Duration) {
+    let mut registry = REGISTRY.lock().unwrap();
+    let result = if ok { "ok" } else { "error" };
+    registry.count(
+        "terplounge_transcriptions_total",
+        labels(&[("backend", backend), ("result", result)]),
+    );
+    registry.observe(
+        "terplounge_transcription_duration_seconds",
+        labels(&[("backend", backend)]),
+        TRANSCRIPTION_BUCKETS,
+        elapsed,
+    );
+}
+
+/**
+ * The words of the server's routes, which are all a route label keeps of
+ * a path. A new route's words belong here, or the label of its requests
+ * is only coarser.
+ */
+const ROUTE_WORDS: &[&str] = &[
+    "admin",
+    "api",
+    "assets",
+    "audio",
+    "audio-chapters",
+    "audio-quality",
+    "audio-segments",
+    "audio-speed",
+    "cache",
+    "changes",
+    "chapters",
+    "chat",
+    "check",
+    "close",
+    "close-session",
+    "compare",
+    "compare_visual",
+    "comparisons",
+    "components",
+    "consecutive",
+    "curricula",
+    "dashboard",
+    "difficulty",
+    "estimate",
+    "events",
+    "export",
+    "export-config",
+    "gap-fill",
+    "history",
+    "invalidate",
+    "library",
+    "login",
+    "logout",
+    "metrics",
+    "narration",
+    "ngrams",
+    "practice",
+    "pronunciation-report",
+    "rate",
+    "recording",
+    "related",
+    "replay",
+    "reprocess-session",
+    "resource",
+    "resource-usage",
+    "resources",
+    "score",
+    "score-card",
+    "sentiment-arc",
+    "serve_resource",
+    "session",
+    "sessions",
+    "speaker-turns",
+    "spectrogram",
+    "stats",
+    "status",
+    "status-ws",
+    "transcribe",
+    "transcript",
+    "trim",
+    "upload",
+    "user",
+    "users",
+    "v1",
+    "validate",
+    "vacuum",
+    "word-timestamps",
+];
+
+/**
+ * The route a request for `path` took, with its words kept and the rest
+ * of it, like resource paths, languages and session UUIDs, replaced by
+ * `:uuid` and `:param`, so that the requests for each session and
+ * resource are counted together. The files under `/assets` are all
+ * `/assets/:path`. Requests answered with any 4xx are counted as
+ * `other`, so that probing for paths or sending bad ones doesn't add a
+ * route each time.
+ */
+fn route(path: &str, status: u16) -> String {
+    if (400..500).contains(&status) {
+        return "other".to_string();
+    }
+    if path.starts_with("/assets/") {
+        return "/assets/:path".to_string();
+    }
+    path.split('/')
+        .map(|segment| {
+            if segment.is_empty() || ROUTE_WORDS.contains(&segment) {
+                segment
+            } else if Uuid::parse_str(segment).is_ok() {
+                ":uuid"
+            } else {
+                ":param"
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Counts an HTTP request answered with `status` in `elapsed`.
+pub fn http_request(method: &str, path: &str, status: u16, elapsed: Duration) {
+    let route = route(path, status);
+    let mut registry = REGISTRY.lock().unwrap();
+    registry.count(
+        "terplounge_http_requests_total",
+        labels(&[
+            ("method", method),
+            ("route", &route),
+            ("status", &status.to_string()),
+        ]),
+    );
+    registry.observe(
+        "terplounge_http_request_duration_seconds",
+        labels(&[("method", method), ("route", &route)]),
+        HTTP_BUCKETS,
+        elapsed,
+    );
+}
+
+/// The metrics in the Prometheus text format, for `/metrics`.
+pub async fn render() -> String {
+    let active = crate::session::SESSIONS
+        .read()
+        .await
+        .values()
+        .filter(|s| s.valid && s.close_reason.is_none())
+        .count();
+    let waiting = crate::transcribe::scheduler::queue_length();
+    let registry = REGISTRY.lock().unwrap();
+    let mut out = String::new();
+    for (name, kind, help) in METRICS {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} {}", name, kind);
+        match *name {
+            "terplounge_sessions_active" => {
+                let _ = writeln!(out, "{} {}", name, active);
+            }
+            "terplounge_transcriptions_waiting" => {
+                let _ = writeln!(out, "{} {}", name, waiting);
+            }
+            _ => (),
+        }
+        for ((_, labels), value) in registry.counters.iter().filter(|((n, _), _)| n == name) {
+            let _ = writeln!(out, "{}{{{}}} {}", name, labels, value);
+        }
+        for ((_, labels), histogram) in registry.histograms.iter().filter(|((n, _), _)| n == name) {
+            let separator = if labels.is_empty() { "" } else { "," };
+            for (bound, count) in histogram.bounds.iter().zip(&histogram.counts) {
+                let _ = writeln!(
+                    out,
+                    "{}_bucket{{{}{}le=\"{}\"}} {}",
+                    name, labels, separator, bound, count
+                );
+            }
+            let _ = writeln!(
+                out,
+                "{}_bucket{{{}{}le=\"+Inf\"}} {}",
+                name, labels, separator, histogram.count
+            );
+            let _ = writeln!(out, "{}_sum{{{}}} {}", name, labels, histogram.sum);
+            let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, histogram.count);
+        }
+    }
+    out
+}
@@ -0,0 +1,188 @@
+use std::net::{IpAddr, SocketAddr};
+use warp::http::header::{HeaderMap, HeaderName, HeaderValue};
+use warp::http::uri::Authority;
+use warp::http::Uri;
+use warp::{Filter, Rejection};
+
+use crate::config::{Config, CONFIG};
+use crate::tls::PeerAddr;
+
+/// The policy sent unless the config file sets another, which lets the client's inline scripts and cdnjs through.
+pub const DEFAULT_CONTENT_SECURITY_POLICY: &str = "default-src 'self'; script-src 'self' 'unsafe-inline' https://cdnjs.cloudflare.com; style-src 'self' 'unsafe-inline' https://cdnjs.cloudflare.com; img-src 'self' data:; media-src 'self' blob:; connect-src 'self' ws: wss:";
+
+/// Whether `origin` is a scheme and a host, which is all an origin may be.
+fn valid_origin(origin: &str) -> bool {
+    match origin.parse::<Uri>() {
+        Ok(uri) => {
+            uri.scheme().is_some()
+                && uri.authority().is_some()
+                && uri.path_and_query().map(|p| p.as_str()).unwrap_or("/") == "/"
+                && !origin.ends_with('/')
+        }
+        Err(_) => false,
+    }
+}
+
+/**
+ * The CORS settings for the origins in `cors_allowed_origins`, any of them
+ * if it holds `"*"`, or None when it is empty and cross-origin requests
+ * are left to the browser's defaults. Origins which can't be one are
+ * left out.
+ */
+pub fn cors(config: &Config) -> Option<warp::cors::Builder> {
+    if config.cors_allowed_origins.is_empty() {
+        return None;
+    }
+    let cors = warp::cors()
+        .allow_methods(["GET", "POST", "PUT", "DELETE", "OPTIONS"])
+        .allow_headers(["authorization", "content-type"]);
+    if config.cors_allowed_origins.iter().any(|o| o == "*") {
+        return Some(cors.allow_any_origin());
+    }
+    let origins: Vec<&str> = config
+        .cors_allowed_origins
+        .iter()
+        .map(|o| o.as_str())
+        .filter(|o| {
+            let valid = valid_origin(o);
+            if !valid {
+                log::warn!("Ignoring CORS origin {}, which isn't a scheme and host", o);
+            }
+            valid
+        })
+        .collect();
+    Some(cors.allow_origins(origins))
+}
+
+/**
+ * Adds `X-Content-Type-Options: nosniff`, `X-Frame-Options: SAMEORIGIN`
+ * and the configured `Content-Security-Policy` to every response, unless
+ * the policy is empty or can't be a header.
+ */
+pub fn security_headers(config: &Config) -> warp::filters::reply::WithHeaders {
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        HeaderName::from_static("x-content-type-options"),
+        HeaderValue::from_static("nosniff"),
+    );
+    headers.insert(
+        HeaderName::from_static("x-frame-options"),
+        HeaderValue::from_static("SAMEORIGIN"),
+    );
+    if !config.content_security_policy.is_empty() {
+        match HeaderValue::from_str(&config.content_security_policy) {
+            Ok(policy) => {
+                headers.insert(HeaderName::from_static("content-security-policy"), policy);
+            }
+            Err(e) => log::warn!("Not sending content_security_policy: {:?}", e),
+        }
+    }
+    warp::reply::with::headers(headers)
+}
+
+/**
+ * The client behind `peer`, going back through `X-Forwarded-For` from
+ * its end for as long as the address the request came from is one of
+ * the `trusted` proxies, since each proxy adds the address it got the
+ * request from. Anything else may have been made up by the client.
+ */
+fn forwarded_client(peer: IpAddr, forwarded: Option<&str>, trusted: &[IpAddr]) -> IpAddr {
+    let mut client = peer;
+    for hop in forwarded.unwrap_or("").rsplit(',') {
+        if !trusted.contains(&client) {
+            break;
+        }
+        match hop.trim().parse() {
+            Ok(addr) => client = addr,
+            Err(_) => break,
+        }
+    }
+    client
+}
+
+/**
+ * The address of the client making a request, as told by the
+ * `trusted_proxies` of the config file when it comes through one of
+ * them, or None if it isn't known.
+ */
+pub fn client_addr() -> impl Filter<Extract = (Option<IpAddr>,), Error = Rejection> + Clone {
+    warp::addr::remote()
+        .and(warp::ext::optional::<PeerAddr>())
+        .and(warp::header::optional::<String>("x-forwarded-for"))
+        .map(
+            |remote: Option<SocketAddr>, peer: Option<PeerAddr>, forwarded: Option<String>| {
+                let peer = remote.or(peer.map(|p| p.0))?.ip();
+                Some(forwarded_client(
+                    peer,
+                    forwarded.as_deref(),
+                    &CONFIG.trusted_proxies,
+                ))
+            },
+        )
+}
+
+/**
+ * The scheme and host the client addressed, like `https://example.org`,
+ * from the `X-Forwarded-Proto` and `X-Forwarded-Host` headers of `trusted`
+ * proxies, which name what the first of them was asked for, and otherwise
+ * from `Host` and whether the server serves TLS. None without a host
+ * which can be one.
+ */
+fn public_base(
+    trusted: bool,
+    forwarded_proto: Option<&str>,
+    forwarded_host: Option<&str>,
+    host: Option<&str>,
+) -> Option<String> {
+    let first = |header: Option<&str>| {
+        header
+            .filter(|_| trusted)
+            .and_then(|h| h.split(',').next())
+            .map(|h| h.trim().to_string())
+    };
+    let scheme = first(forwarded_proto).unwrap_or(
+        if CONFIG.tls.is_some() {
+            "https"
+        } else {
+            "http"
+        }
+        .to_string(),
+    );
+    if scheme != "http" && scheme != "https" {
+        return None;
+    }
+    let host = first(forwarded_host).or(host.map(|h| h.to_string()))?;
+    let host: Authority = host.parse().ok()?;
+    // an authority may also hold a user, which a host can't
+    (!host.as_str().contains('@')).then(|| format!("{}://{}", scheme, host))
+}
+
+/**
+ * Where the server is reached from as the client sees it, for URLs which
+ * have to be absolute. Proxies are only believed if they are among the
+ * `trusted_proxies` of the config file, as with `client_addr`.
+ */
+pub fn base_url() -> impl Filter<Extract = (Option<String>,), Error = Rejection> + Clone {
+    warp::addr::remote()
+        .and(warp::ext::optional::<PeerAddr>())
+        .and(warp::header::optional::<String>("x-forwarded-proto"))
+        .and(warp::header::optional::<String>("x-forwarded-host"))
+        .and(warp::header::optional::<String>("host"))
+        .map(
+            |remote: Option<SocketAddr>,
+             peer: Option<PeerAddr>,
+             proto: Option<String>,
+             forwarded_host: Option<String>,
+             host: Option<String>| {
+                let trusted = remote
+                    .or(peer.map(|p| p.0))
+                    .is_some_and(|peer| CONFIG.trusted_proxies.contains(&peer.ip()));
+                public_base(
+                    trusted,
+                    proto.as_deref(),
+                    forwarded_host.as_deref(),
+                    host.as_deref(),
+                )
+            },
+        )
+}
@@ -0,0 +1,107 @@
+use lazy_static::lazy_static;
+use std::sync::{Arc, Mutex};
+use whisper_rs::WhisperContext;
+
+use crate::config::CONFIG;
+use crate::error::{Er, Kind, E};
+use crate::transcribe::Backend;
+
+/// The whisper.cpp model sizes sessions can ask for, smallest and fastest first.
+pub const MODEL_SIZES: [&str; 5] = ["tiny", "base", "small", "medium", "large"];
+
+/// How many models are kept loaded unless `max_loaded_models` says otherwise.
+pub const DEFAULT_MAX_LOADED_MODELS: usize = 2;
+
+lazy_static! {
+    /// the models loaded so far, the one used longest ago first
+    static ref LOADED: Mutex<Vec<(String, Arc<WhisperContext>)>> = Mutex::new(vec![]);
+    /// held while a model is loaded, without holding up the workers using the loaded ones
+    static ref LOADING: Mutex<()> = Mutex::new(());
+}
+
+/// The file of the model called `name`, `model_path` for the configured model if it is set.
+pub fn model_file(name: &str) -> String {
+    if name == CONFIG.whisper_model {
+        CONFIG.model_path()
+    } else {
+        format!("{}/ggml-{}.bin", CONFIG.models_dir, name)
+    }
+}
+
+/**
+ * Checks that a session transcribed with `backend` can be transcribed
+ * with the model called `name`: only whisper.cpp runs on the server's
+ * models, and those are the configured model and the ones of
+ * `MODEL_SIZES` whose file is on the server.
+ */
+pub fn check(name: &str, backend: Backend) -> E<()> {
+    if backend != Backend::WhisperCpp {
+        return Err(Er::with_kind(
+            Kind::BadRequest,
+            format!(
+                "Only {} sessions can choose a model, not {} ones",
+                Backend::WhisperCpp.name(),
+                backend.name()
+            ),
+        ));
+    }
+    if name != CONFIG.whisper_model && !MODEL_SIZES.contains(&name) {
+        return Err(Er::with_kind(
+            Kind::BadRequest,
+            format!(
+                "There is no model {}, only {}",
+                name,
+                MODEL_SIZES.join(", ")
+            ),
+        ));
+    }
+    if !std::path::Path::new(&model_file(name)).exists() {
+        return Err(Er::with_kind(
+            Kind::BadRequest,
+            format!("The model {} isn't installed on this server", name),
+        ));
+    }
+    Ok(())
+}
+
+/// The model called `name` if it is loaded, marking it as the one used last.
+fn loaded(name: &str) -> Option<Arc<WhisperContext>> {
+    let mut loaded = LOADED.lock().unwrap();
+    let i = loaded.iter().position(|(n, _)| n == name)?;
+    let model = loaded.remove(i);
+    let context = model.1.clone();
+    loaded.push(model);
+    Some(context)
+}
+
+/**
+ * The model called `name`, loading it the first time it is needed. Only
+ * `max_loaded_models` are kept in memory, so loading another unloads the
+ * one used longest ago once the transcriptions using it are done.
+ * Models are loaded one at a time, as each takes much of the memory,
+ * while the models already loaded go on being used.
+ */
+pub fn get(name: &str) -> E<Arc<WhisperContext>> {
+    if let Some(context) = loaded(name) {
+        return Ok(context);
+    }
+    let _loading = LOADING.lock().unwrap_or_else(|e| e.into_inner());
+    // another worker may have loaded it while this one waited
+    if let Some(context) = loaded(name) {
+        return Ok(context);
+    }
+    let file = model_file(name);
+    log::info!("Loading model {} from {}", name, file);
+    let context = Arc::new(
+        WhisperContext::new(&file)
+            .map_err(|e| Er::new(format!("Couldn't load model {}: {}", file, e)))?,
+    );
+    let mut loaded = LOADED.lock().unwrap();
+    loaded.push((name.to_string(), context.clone()));
+    let max = CONFIG.max_loaded_models.max(1);
+    while loaded.len() > max {
+        let (unloaded, _) = loaded.remove(0);
+        log::info!("Unloading model {}", unloaded);
+    }
+    Ok(context)
+}
@@ -0,0 +1,135 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+
+use crate::compare::words;
+use crate::difficulty::sentences;
+use crate::error::{Er, E};
+use crate::metadata::Metadata;
+
+/// The length of the n-grams counted unless asked for another.
+pub const DEFAULT_N: usize = 2;
+/// The longest n-grams which can be counted.
+pub const MAX_N: usize = 5;
+/// How many n-grams are returned unless asked for another number.
+pub const DEFAULT_TOP_K: usize = 20;
+/// N-grams found fewer times than this are left out unless asked otherwise.
+pub const DEFAULT_MIN_COUNT: usize = 2;
+
+/// Where the stop word lists are unless `STOPWORDS_DIR` says otherwise.
+pub const DEFAULT_STOPWORDS_DIR: &str = "stopwords";
+
+/**
+ * Reads the stop words for `lang`, one per line, from
+ * `stopwords_<lang>.txt` in `STOPWORDS_DIR` (by default `stopwords`). A
+ * language without a list has no stop words.
+ */
+pub fn load_stopwords(lang: &str) -> HashSet<String> {
+    let dir = std::env::var("STOPWORDS_DIR").unwrap_or(DEFAULT_STOPWORDS_DIR.to_string());
+    match std::fs::read_to_string(format!("{}/stopwords_{}.txt", dir, lang)) {
+        Ok(contents) => words(&contents).into_iter().collect(),
+        Err(e) => {
+            log::debug!("No stop words for {}: {:?}", lang, e);
+            HashSet::new()
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Ngram {
+    pub ngram: Vec<String>,
+    pub count: usize,
+}
+
+/**
+ * The `top_k` most frequent runs of `n` words, which must be at least
+ * one, within the sentences of `text` found at least `min_count` times,
+ * most frequent first. Runs starting or ending with one of `stopwords`
+ * are left out, so with stop words "Ende der Woche" is counted but "auf
+ * der" is not.
+ */
+pub fn top_ngrams(
+    text: &str,
+    n: usize,
+    top_k: usize,
+    min_count: usize,
+    stopwords: &HashSet<String>,
+) -> Vec<Ngram> {
+    let mut counts: HashMap<Vec<String>, usize> = HashMap::new();
+    for sentence in sentences(text) {
+        for ngram in words(&sentence).windows(n) {
+            if stopwords.contains(&ngram[0]) || stopwords.contains(&ngram[ngram.len() - 1]) {
+                continue;
+            }
+            *counts.entry(ngram.to_vec()).or_default() += 1;
+        }
+    }
+    let mut ngrams: Vec<Ngram> = counts
+        .into_iter()
+        .filter(|(_, count)| *count >= min_count)
+        .map(|(ngram, count)| Ngram { ngram, count })
+        .collect();
+    ngrams.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.ngram.cmp(&b.ngram)));
+    ngrams.truncate(top_k);
+    ngrams
+}
+
+/**
+ * The most frequent n-grams of a resource's text in `lang`. All of them
+ * are counted and kept in the resource directory as
+ * `ngrams_<lang>_<n>.json`, or `..._stopwords.json` without stop words,
+ * until the text changes, and `top_k` and `min_count` are applied to
+ * those, so that there are only so many files however they are asked for.
+ */
+pub fn resource_ngrams(
+    resource_path: &String,
+    lang: &str,
+    n: usize,
+    top_k: usize,
+    min_count: usize,
+    exclude_stopwords: bool,
+) -> E<Vec<Ngram>> {
+    let metadata = Metadata::from_resource_path(resource_path)?;
+    let path = metadata.text_path(lang).ok_or(Er::new(format!(
+        "No text for lang {} in resource {}",
+        lang, resource_path
+    )))?;
+    let filename = format!(
+        "{}/ngrams_{}_{}{}.json",
+        metadata.enclosing_directory,
+        lang,
+        n,
+        if exclude_stopwords { "_stopwords" } else { "" }
+    );
+    let modified = |f: &str| std::fs::metadata(f).and_then(|m| m.modified()).ok();
+    let cached = match std::fs::read_to_string(&filename) {
+        Ok(cached) if modified(&filename) >= modified(&path) => serde_json::from_str(&cached).ok(),
+        _ => None,
+    };
+    let ngrams: Vec<Ngram> = match cached {
+        Some(ngrams) => ngrams,
+        None => {
+            let stopwords = if exclude_stopwords {
+                load_stopwords(lang)
+            } else {
+                HashSet::new()
+            };
+            let ngrams = top_ngrams(
+                &std::fs::read_to_string(&path)?,
+                n,
+                usize::MAX,
+                1,
+                &stopwords,
+            );
+            let partial = format!("{}.partial", filename);
+            std::fs::write(&partial, serde_json::to_string(&ngrams)?)?;
+            std::fs::rename(partial, &filename)?;
+            ngrams
+        }
+    };
+    // most frequent first, so those counted too rarely are all at the end
+    Ok(ngrams
+        .into_iter()
+        .take_while(|ngram| ngram.count >= min_count)
+        .take(top_k)
+        .collect())
+}
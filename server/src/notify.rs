@@ -0,0 +1,189 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+use uuid::Uuid;
+
+use crate::compare::Score;
+use crate::config::CONFIG;
+use crate::error::{Er, E};
+use crate::session::{ReplayPayload, SessionEvent};
+
+/// How many times a payload is posted before the webhook is given up on.
+pub const WEBHOOK_ATTEMPTS: u32 = 5;
+/// How long to wait before posting again the first time, doubling with each attempt.
+pub const WEBHOOK_FIRST_RETRY_SECONDS: u64 = 2;
+/// How long a webhook has to answer.
+pub const WEBHOOK_TIMEOUT_SECONDS: u64 = 10;
+
+/// Where finished sessions are reported, as `[[webhooks]]` in the config file.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Webhook {
+    pub url: String,
+    /// what the payloads are signed with; it isn't shown with the configuration
+    #[serde(default, skip_serializing)]
+    pub secret: Option<String>,
+}
+
+/// What webhooks are sent when a session has been transcribed and can be compared.
+#[derive(Clone, Debug, Serialize)]
+pub struct SessionFinished {
+    /// always `session.finished`
+    pub event: &'static str,
+    pub uuid: Uuid,
+    pub resource: Option<String>,
+    pub lang: String,
+    pub user: Option<String>,
+    pub finished_at: DateTime<Utc>,
+    /// against the resource's reference in `lang`, if it has one
+    pub score: Option<Score>,
+    /// absolute when it is known where the learner reached the server, otherwise from its root
+    pub compare_url: Option<String>,
+}
+
+impl SessionFinished {
+    async fn new(event: &SessionEvent) -> Self {
+        let score = match &event.resource {
+            Some(resource) => crate::compare::score(
+                resource.clone(),
+                event.uuid.to_string(),
+                event.language.clone(),
+            )
+            .await
+            .ok(),
+            None => None,
+        };
+        Self {
+            event: "session.finished",
+            uuid: event.uuid,
+            resource: event.resource.clone(),
+            lang: event.language.clone(),
+            user: event.user.clone(),
+            finished_at: event.event.timestamp,
+            score,
+            compare_url: event.resource.as_ref().map(|resource| {
+                format!(
+                    "{}/compare/{}/{}/{}",
+                    event.base_url.as_deref().unwrap_or_default(),
+                    urlencoding::encode(resource),
+                    event.uuid,
+                    event.language
+                )
+            }),
+        }
+    }
+}
+
+/// The hex HMAC-SHA256 of `body` with `secret`, sent as `X-Terplounge-Signature: sha256=<signature>`.
+pub fn signature(secret: &str, body: &[u8]) -> String {
+    crate::auth::hmac_sha256(secret.as_bytes(), body)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+async fn post(client: &reqwest::Client, webhook: &Webhook, body: &str) -> E<()> {
+    let mut request = client
+        .post(&webhook.url)
+        .header("Content-Type", "application/json")
+        .body(body.to_string());
+    if let Some(secret) = &webhook.secret {
+        request = request.header(
+            "X-Terplounge-Signature",
+            format!("sha256={}", signature(secret, body.as_bytes())),
+        );
+    }
+    let response = request.send().await?;
+    if !response.status().is_success() {
+        return Err(Er::new(format!(
+            "{} answered {}",
+            webhook.url,
+            response.status()
+        )));
+    }
+    Ok(())
+}
+
+/**
+ * Posts `body` to the webhook, trying again after WEBHOOK_FIRST_RETRY_SECONDS,
+ * then twice as long each time, until it has been tried WEBHOOK_ATTEMPTS
+ * times. Answers other than 2xx count as failures.
+ */
+async fn deliver(client: reqwest::Client, webhook: Webhook, body: String) {
+    let mut wait = Duration::from_secs(WEBHOOK_FIRST_RETRY_SECONDS);
+    for attempt in 1..=WEBHOOK_ATTEMPTS {
+        let error = match post(&client, &webhook, &body).await {
+            Ok(()) => {
+                log::debug!("Notified {}", webhook.url);
+                return;
+            }
+            Err(e) => e.to_string(),
+        };
+        if attempt == WEBHOOK_ATTEMPTS {
+            log::error!(
+                "Giving up notifying {} after {} attempts: {}",
+                webhook.url,
+                attempt,
+                error
+            );
+            return;
+        }
+        log::warn!(
+            "Couldn't notify {}, trying again in {:?}: {}",
+            webhook.url,
+            wait,
+            error
+        );
+        tokio::time::sleep(wait).await;
+        wait *= 2;
+    }
+}
+
+/**
+ * Tells every webhook in the config file about each session as it
+ * finishes, which includes sessions finishing again after being
+ * reprocessed. Does nothing without webhooks.
+ */
+pub fn start() {
+    if CONFIG.webhooks.is_empty() {
+        return;
+    }
+    let client = match reqwest::Client::builder()
+        .timeout(Duration::from_secs(WEBHOOK_TIMEOUT_SECONDS))
+        .build()
+    {
+        Ok(client) => client,
+        Err(e) => {
+            log::error!("Couldn't make a client for the webhooks: {}", e);
+            return;
+        }
+    };
+    tokio::spawn(async move {
+        let (_, mut receiver) = crate::session::subscribe_events().await;
+        loop {
+            let event = match receiver.recv().await {
+                Ok(event) => event,
+                Err(RecvError::Lagged(missed)) => {
+                    log::warn!("The webhooks missed {} session events", missed);
+                    continue;
+                }
+                Err(RecvError::Closed) => return,
+            };
+            if !matches!(&event.event.payload, ReplayPayload::StatusChanged { status, .. } if status == "finished")
+            {
+                continue;
+            }
+            let payload = SessionFinished::new(&event).await;
+            let body = match serde_json::to_string(&payload) {
+                Ok(body) => body,
+                Err(e) => {
+                    log::error!("Couldn't make the webhook payload of {}: {}", event.uuid, e);
+                    continue;
+                }
+            };
+            for webhook in &CONFIG.webhooks {
+                tokio::spawn(deliver(client.clone(), webhook.clone(), body.clone()));
+            }
+        }
+    });
+}
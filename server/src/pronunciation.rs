@@ -0,0 +1,122 @@
+use serde::{Deserialize, Serialize};
+
+use crate::audio::AudioQuality;
+use crate::compare::{FillerReport, Score, Substitution};
+use crate::error::E;
+use crate::metadata::Metadata;
+use crate::scoring::ScoreCard;
+use crate::session::SessionData;
+
+/**
+ * Everything known about how a session was spoken. Sections are left out
+ * when there is nothing to compute them from: those comparing with the
+ * reference when the session has no resource with a text in the
+ * language, the filler count when there is no filler list for it, and
+ * those analysing audio when there is no recording.
+ */
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PronunciationReport {
+    pub uuid: String,
+    pub lang: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub accuracy: Option<Score>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub substitutions: Option<Vec<Substitution>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fillers: Option<FillerReport>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub audio_quality: Option<AudioQuality>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub score_card: Option<ScoreCard>,
+}
+
+fn cache_path(session: &SessionData, lang: &str) -> Option<String> {
+    Some(format!(
+        "{}/pronunciation_report_{}.json",
+        session.directory()?,
+        lang
+    ))
+}
+
+/// Removes the cached reports of a session whose transcript is about to change.
+pub fn clear_cache(session: &SessionData) {
+    let Some(directory) = session.directory() else {
+        return;
+    };
+    let Ok(entries) = std::fs::read_dir(directory) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let name = entry.file_name().to_string_lossy().to_string();
+        if name.starts_with("pronunciation_report_") && name.ends_with(".json") {
+            if let Err(e) = std::fs::remove_file(entry.path()) {
+                log::warn!("Couldn't remove {}: {:?}", name, e);
+            }
+        }
+    }
+}
+
+fn reference_text(session: &SessionData, lang: &str) -> Option<String> {
+    let metadata = Metadata::from_resource_path(session.resource.as_ref()?).ok()?;
+    std::fs::read_to_string(metadata.text_path(lang)?).ok()
+}
+
+/**
+ * Puts together the pronunciation report of a session in `lang`. Once the
+ * session has finished the report is kept as
+ * `pronunciation_report_<lang>.json` beside its recording.
+ */
+pub async fn pronunciation_report(session: &SessionData, lang: &str) -> E<PronunciationReport> {
+    let cache = cache_path(session, lang).filter(|_| !session.valid);
+    if let Some(contents) = cache.as_ref().and_then(|c| std::fs::read_to_string(c).ok()) {
+        return Ok(serde_json::from_str(&contents)?);
+    }
+
+    let transcript = session.transcript()?;
+    let reference = reference_text(session, lang);
+    // the length of the recording and its quality
+    let recording = match session.recording_file.clone() {
+        Some(filename) => tokio::task::spawn_blocking(move || {
+            let (samples, sample_rate) = crate::audio::read_audio(&filename).ok()?;
+            Some((
+                samples.len() as f32 / sample_rate as f32,
+                crate::audio::quality(&samples, sample_rate),
+            ))
+        })
+        .await
+        .ok()
+        .flatten(),
+        None => None,
+    };
+
+    let fillers = crate::compare::load_fillers(lang);
+    let report = PronunciationReport {
+        uuid: session.uuid.to_string(),
+        lang: lang.to_string(),
+        accuracy: reference
+            .as_ref()
+            .map(|r| crate::compare::word_error_rate(r, &transcript)),
+        substitutions: reference
+            .as_ref()
+            .map(|r| crate::compare::substitutions(r, &transcript)),
+        fillers: (!fillers.is_empty()).then(|| {
+            let report = crate::compare::count_filler_words(&transcript, lang, &fillers);
+            match &recording {
+                Some((seconds, _)) => report.with_duration(*seconds),
+                None => report,
+            }
+        }),
+        audio_quality: recording.map(|(_, quality)| quality),
+        score_card: match reference {
+            Some(_) => crate::scoring::score_card(session).await.ok(),
+            None => None,
+        },
+    };
+
+    if let Some(cache) = cache {
+        if let Err(e) = std::fs::write(&cache, serde_json::to_string(&report)?) {
+            log::warn!("Couldn't cache {}: {:?}", cache, e);
+        }
+    }
+    Ok(report)
+}
@@ -0,0 +1,126 @@
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use warp::{Filter, Rejection};
+
+/// How many connections one address may have open unless `MAX_CONNECTIONS_PER_IP` says otherwise.
+pub const DEFAULT_MAX_CONNECTIONS_PER_IP: usize = 5;
+
+lazy_static! {
+    static ref CONNECTIONS: Mutex<HashMap<IpAddr, usize>> = Mutex::new(HashMap::new());
+}
+
+#[derive(Debug)]
+pub struct TooManyConnections;
+
+impl warp::reject::Reject for TooManyConnections {}
+
+fn max_connections_per_ip() -> usize {
+    std::env::var("MAX_CONNECTIONS_PER_IP")
+        .ok()
+        .and_then(|m| m.parse().ok())
+        .unwrap_or(DEFAULT_MAX_CONNECTIONS_PER_IP)
+}
+
+/**
+ * A connection counted against its address until it is dropped, which
+ * happens however the connection ends, even when its task panics.
+ */
+#[derive(Debug)]
+pub struct ConnectionGuard {
+    ip: Option<IpAddr>,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let Some(ip) = self.ip else {
+            return;
+        };
+        let mut connections = CONNECTIONS.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(count) = connections.get_mut(&ip) {
+            *count -= 1;
+            if *count == 0 {
+                connections.remove(&ip);
+            }
+        }
+    }
+}
+
+/// Counts a new connection from `ip`, unless it already has `limit` open.
+pub fn acquire(ip: IpAddr, limit: usize) -> Option<ConnectionGuard> {
+    let mut connections = CONNECTIONS.lock().unwrap_or_else(|e| e.into_inner());
+    let count = connections.entry(ip).or_insert(0);
+    if *count >= limit {
+        return None;
+    }
+    *count += 1;
+    Some(ConnectionGuard { ip: Some(ip) })
+}
+
+/**
+ * Lets through requests from addresses with fewer than
+ * `MAX_CONNECTIONS_PER_IP` connections open, passing on a guard to keep
+ * for as long as the connection lasts. Requests whose address isn't known
+ * aren't counted.
+ */
+pub fn limit() -> impl Filter<Extract = (ConnectionGuard,), Error = Rejection> + Clone {
+    crate::middleware::client_addr().and_then(|addr: Option<IpAddr>| async move {
+        match addr {
+            Some(addr) => acquire(addr, max_connections_per_ip()).ok_or_else(|| {
+                log::warn!("Too many connections from {}", addr);
+                warp::reject::custom(TooManyConnections)
+            }),
+            None => Ok(ConnectionGuard { ip: None }),
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    // documentation addresses, each used by one test only, so that tests
+    // running at the same time don't count each other's connections
+    const ADDRESS_A: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+    const ADDRESS_B: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2));
+    const ADDRESS_C: IpAddr = IpAddr::V4(Ipv4Addr::new(198, 51, 100, 1));
+    const ADDRESS_D: IpAddr = IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1));
+
+    fn open(ip: IpAddr) -> usize {
+        CONNECTIONS.lock().unwrap().get(&ip).copied().unwrap_or(0)
+    }
+
+    #[test]
+    fn acquires_up_to_the_limit_and_refuses_the_next() {
+        let guards: Vec<ConnectionGuard> = (0..3).map(|_| acquire(ADDRESS_A, 3).unwrap()).collect();
+        assert_eq!(open(ADDRESS_A), 3);
+        assert!(acquire(ADDRESS_A, 3).is_none());
+        assert_eq!(open(ADDRESS_A), 3);
+        drop(guards);
+        assert_eq!(open(ADDRESS_A), 0);
+    }
+
+    #[test]
+    fn dropping_a_guard_frees_its_slot() {
+        let first = acquire(ADDRESS_B, 2).unwrap();
+        let _second = acquire(ADDRESS_B, 2).unwrap();
+        assert!(acquire(ADDRESS_B, 2).is_none());
+        drop(first);
+        assert_eq!(open(ADDRESS_B), 1);
+        let _third = acquire(ADDRESS_B, 2).unwrap();
+        assert!(acquire(ADDRESS_B, 2).is_none());
+    }
+
+    #[test]
+    fn addresses_are_counted_separately() {
+        let _c = acquire(ADDRESS_C, 1).unwrap();
+        assert!(acquire(ADDRESS_C, 1).is_none());
+        let d = acquire(ADDRESS_D, 1).unwrap();
+        assert_eq!((open(ADDRESS_C), open(ADDRESS_D)), (1, 1));
+        drop(d);
+        assert_eq!((open(ADDRESS_C), open(ADDRESS_D)), (1, 0));
+        assert!(acquire(ADDRESS_D, 1).is_some());
+    }
+}
@@ -0,0 +1,201 @@
+use serde::Serialize;
+use std::collections::HashMap;
+use std::path::Path;
+
+use crate::error::{Er, Kind, E};
+use crate::metadata::{assets_dir, list_resources, Metadata, ResourceSummary};
+
+#[derive(Clone, Debug, Serialize)]
+pub struct ResourceListing {
+    #[serde(flatten)]
+    pub summary: ResourceSummary,
+    /// what is wrong with the resource's metadata, if anything
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub errors: Vec<String>,
+}
+
+/**
+ * Every resource bundle, with the problems found in its metadata so they
+ * can be fixed. Bundles whose metadata can't be read at all are listed by
+ * their path alone.
+ */
+pub fn list() -> E<Vec<ResourceListing>> {
+    Ok(list_resources()?
+        .into_iter()
+        .map(|path| match Metadata::from_resource_path(&path) {
+            Ok(metadata) => ResourceListing {
+                errors: metadata.validate(),
+                summary: metadata.to_summary(path),
+            },
+            Err(e) => ResourceListing {
+                summary: ResourceSummary {
+                    resource_path: path,
+                    title: String::new(),
+                    native: String::new(),
+                    languages: vec![],
+                    audio: String::new(),
+                },
+                errors: vec![e.to_string()],
+            },
+        })
+        .collect())
+}
+
+/**
+ * Checks that a resource path names a directory inside the assets
+ * directory: relative, with no empty, `.` or `..` parts, and only
+ * letters, digits, `-`, `_` and `.` in its parts.
+ */
+fn check_resource_path(resource_path: &str) -> E<()> {
+    let valid = !resource_path.is_empty()
+        && resource_path.split('/').all(|part| {
+            !part.is_empty()
+                && part != "."
+                && part != ".."
+                && part
+                    .chars()
+                    .all(|c| c.is_alphanumeric() || c == '-' || c == '_' || c == '.')
+        });
+    if valid {
+        Ok(())
+    } else {
+        Err(Er::with_kind(
+            Kind::BadRequest,
+            format!("{} is not a valid resource path", resource_path),
+        ))
+    }
+}
+
+/// The files a resource's metadata names, which have to be uploaded with it.
+fn named_files(metadata: &Metadata) -> Vec<String> {
+    let mut files = vec![metadata.audio.clone()];
+    files.extend(metadata.audio_tracks.iter().map(|t| t.path.clone()));
+    files.extend(metadata.transcript.iter().cloned());
+    files.extend(metadata.translations.values().cloned());
+    files.extend(metadata.glossaries.values().cloned());
+    files.retain(|f| !f.is_empty());
+    files.sort();
+    files.dedup();
+    files
+}
+
+/**
+ * Makes a new resource bundle at `resource_path` from its metadata, as
+ * JSON, and the files it names, keyed by their names. Files the metadata
+ * doesn't name are refused, as are resources which already exist. If the
+ * bundle can't be written, or doesn't validate because for instance its
+ * audio can't be decoded, it is removed again.
+ */
+pub fn create(
+    resource_path: &str,
+    metadata: &[u8],
+    files: HashMap<String, Vec<u8>>,
+) -> E<Metadata> {
+    check_resource_path(resource_path)?;
+    let mut metadata: Metadata = serde_json::from_slice(metadata)
+        .map_err(|e| Er::with_kind(Kind::BadRequest, format!("The metadata isn't valid: {}", e)))?;
+    if metadata.audio.is_empty() {
+        if let Some(track) = metadata.audio_tracks.first() {
+            metadata.audio = track.path.clone();
+        }
+    }
+    let named = named_files(&metadata);
+    for name in files.keys() {
+        if name.contains('/') || !named.contains(name) {
+            return Err(Er::with_kind(
+                Kind::BadRequest,
+                format!("{} is not named by the metadata", name),
+            ));
+        }
+    }
+    if let Some(missing) = named.iter().find(|name| !files.contains_key(*name)) {
+        return Err(Er::with_kind(
+            Kind::BadRequest,
+            format!("{} was not uploaded", missing),
+        ));
+    }
+
+    // resources inside others aren't found, and existing directories aren't ours to remove
+    let directory = format!("{}/{}", assets_dir(), resource_path);
+    if Path::new(&directory).exists() {
+        return Err(Er::with_kind(
+            Kind::ResourceExists,
+            format!("There is already a resource or directory {}", resource_path),
+        ));
+    }
+    if let Some(parent) = Path::new(resource_path).ancestors().skip(1).find(|p| {
+        Path::new(&assets_dir())
+            .join(p)
+            .join("metadata.json")
+            .exists()
+    }) {
+        return Err(Er::with_kind(
+            Kind::ResourceExists,
+            format!(
+                "{} is inside the resource {}",
+                resource_path,
+                parent.display()
+            ),
+        ));
+    }
+    let write = || -> E<()> {
+        std::fs::create_dir_all(&directory)?;
+        for (name, data) in &files {
+            std::fs::write(format!("{}/{}", directory, name), data)?;
+        }
+        std::fs::write(
+            format!("{}/metadata.json", directory),
+            serde_json::to_string_pretty(&metadata)?,
+        )?;
+        let validation = crate::metadata::validate_resource(&resource_path.to_string());
+        if !validation.valid {
+            return Err(Er::with_kind(
+                Kind::BadRequest,
+                validation.errors.join(", "),
+            ));
+        }
+        Ok(())
+    };
+    if let Err(e) = write() {
+        if let Err(e) = remove_resource_directory(resource_path) {
+            log::warn!("Couldn't remove {}: {}", directory, e);
+        }
+        return Err(e);
+    }
+    crate::library::invalidate();
+    log::info!("Added resource {}", resource_path);
+    Metadata::from_resource_path(&resource_path.to_string())
+}
+
+/// Removes the directory of a resource, and the directories it was in if that leaves them empty.
+fn remove_resource_directory(resource_path: &str) -> E<()> {
+    let assets = assets_dir();
+    std::fs::remove_dir_all(format!("{}/{}", assets, resource_path))?;
+    for parent in Path::new(resource_path).ancestors().skip(1) {
+        if parent.as_os_str().is_empty()
+            || std::fs::remove_dir(Path::new(&assets).join(parent)).is_err()
+        {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/**
+ * Removes a resource bundle and everything in its directory. Sessions
+ * practising with it are kept, but can no longer be compared with it.
+ */
+pub fn delete(resource_path: &str) -> E<()> {
+    check_resource_path(resource_path)?;
+    let directory = format!("{}/{}", assets_dir(), resource_path);
+    if !Path::new(&directory).join("metadata.json").exists() {
+        return Err(Er::with_kind(
+            Kind::ResourceNotFound,
+            format!("No resource {}", resource_path),
+        ));
+    }
+    crate::metadata::invalidate_cache(Some(&resource_path.to_string()));
+    remove_resource_directory(resource_path)?;
+    log::info!("Deleted resource {}", resource_path);
+    Ok(())
+}
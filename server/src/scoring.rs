@@ -0,0 +1,333 @@
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use similar::{capture_diff_slices, Algorithm, DiffOp};
+
+use crate::compare::{words, Score};
+use crate::error::{Er, E};
+use crate::metadata::Metadata;
+use crate::session::SessionData;
+
+/**
+ * Upper bounds of a metric, where lower is better, for each letter
+ * grade. Anything above `d` is an F.
+ */
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Thresholds {
+    pub a: f32,
+    pub b: f32,
+    pub c: f32,
+    pub d: f32,
+}
+
+impl Thresholds {
+    fn check(&self, name: &str) -> E<()> {
+        if !(self.a <= self.b && self.b <= self.c && self.c <= self.d) {
+            return Err(Er::new(format!(
+                "The {} thresholds must go up from a to d, not {}, {}, {}, {}",
+                name, self.a, self.b, self.c, self.d
+            )));
+        }
+        Ok(())
+    }
+
+    fn grade(&self, value: f32) -> u8 {
+        if value <= self.a {
+            4
+        } else if value <= self.b {
+            3
+        } else if value <= self.c {
+            2
+        } else if value <= self.d {
+            1
+        } else {
+            0
+        }
+    }
+}
+
+/// The file the grade thresholds are read from unless `SCORING_CONFIG` names another.
+pub const DEFAULT_SCORING_CONFIG: &str = "scoring.toml";
+
+/**
+ * Grade thresholds, read from the TOML file named by `SCORING_CONFIG`,
+ * or `scoring.toml`. Categories missing from the file keep their
+ * defaults.
+ */
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ScoringConfig {
+    /// word error rate
+    pub accuracy: Thresholds,
+    /// fraction of the recording spent pausing, plus the filler penalty
+    pub fluency: Thresholds,
+    /// how much each filler word per minute adds to the fluency measure
+    pub filler_penalty: f32,
+    /// relative deviation from the pace of the reference, in words per minute
+    pub pace: Thresholds,
+}
+
+impl Default for ScoringConfig {
+    fn default() -> Self {
+        Self {
+            accuracy: Thresholds {
+                a: 0.1,
+                b: 0.2,
+                c: 0.35,
+                d: 0.5,
+            },
+            fluency: Thresholds {
+                a: 0.15,
+                b: 0.25,
+                c: 0.35,
+                d: 0.5,
+            },
+            filler_penalty: 0.02,
+            pace: Thresholds {
+                a: 0.1,
+                b: 0.2,
+                c: 0.3,
+                d: 0.5,
+            },
+        }
+    }
+}
+
+impl ScoringConfig {
+    /// Reads the config and checks its thresholds, which the server does when it starts.
+    pub fn load() -> E<Self> {
+        let filename =
+            std::env::var("SCORING_CONFIG").unwrap_or(DEFAULT_SCORING_CONFIG.to_string());
+        let config: Self = match std::fs::read_to_string(&filename) {
+            Ok(contents) => toml::from_str(&contents)
+                .map_err(|e| Er::new(format!("Couldn't read {}: {}", filename, e)))?,
+            Err(_) => {
+                log::debug!("No scoring config at {}, using defaults", filename);
+                return Ok(Self::default());
+            }
+        };
+        config.accuracy.check("accuracy")?;
+        config.fluency.check("fluency")?;
+        config.pace.check("pace")?;
+        if config.filler_penalty.is_nan() || config.filler_penalty < 0.0 {
+            return Err(Er::new(format!(
+                "filler_penalty must not be negative, not {}",
+                config.filler_penalty
+            )));
+        }
+        Ok(config)
+    }
+}
+
+lazy_static! {
+    pub static ref SCORING_CONFIG: ScoringConfig = ScoringConfig::load().unwrap_or_else(|e| {
+        log::warn!("Grading with the default thresholds: {}", e);
+        ScoringConfig::default()
+    });
+}
+
+fn letter(points: u8) -> &'static str {
+    match points {
+        4 => "A",
+        3 => "B",
+        2 => "C",
+        1 => "D",
+        _ => "F",
+    }
+}
+
+/// The mean of the grades, as a letter with a `+` or `-` where it falls between two.
+fn overall(grades: &[u8]) -> Option<String> {
+    if grades.is_empty() {
+        return None;
+    }
+    let mean = grades.iter().map(|g| *g as f32).sum::<f32>() / grades.len() as f32;
+    let points = mean.round();
+    let modifier = match mean - points {
+        d if points > 0.0 && d > 1.0 / 6.0 => "+",
+        d if points > 0.0 && d < -1.0 / 6.0 => "-",
+        _ => "",
+    };
+    Some(format!("{}{}", letter(points as u8), modifier))
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ScoreCard {
+    pub accuracy: Option<String>,
+    pub fluency: Option<String>,
+    /// There is no phoneme-level analysis yet, so this is never graded.
+    pub pronunciation: Option<String>,
+    pub pace: Option<String>,
+    pub overall: Option<String>,
+}
+
+/**
+ * Length of the reference narration in seconds, from the word
+ * timestamps of its native language, or else from the audio file itself
+ * if that is a WAV.
+ */
+fn reference_duration(resource_path: &String) -> Option<f32> {
+    let metadata = Metadata::from_resource_path(resource_path).ok()?;
+    if let Ok(Some(timestamps)) = crate::alignment::word_timestamps(resource_path, &metadata.native)
+    {
+        if let Some(last) = timestamps.last() {
+            return Some(last.end);
+        }
+    }
+    let audio = format!("{}/{}", metadata.enclosing_directory, metadata.audio);
+    crate::audio::duration_seconds(&audio).ok()
+}
+
+/**
+ * Grades a session in each category. Categories without the data to
+ * compute them, for instance pace when the reference audio's length is
+ * unknown, are left out of the card and the overall grade.
+ */
+pub async fn score_card(session: &SessionData) -> E<ScoreCard> {
+    let config = &*SCORING_CONFIG;
+    let resource = session
+        .resource
+        .clone()
+        .ok_or(Er::new(format!("Session {} has no resource", session.uuid)))?;
+    let score = crate::compare::score(
+        resource.clone(),
+        session.uuid.to_string(),
+        session.language.clone(),
+    )
+    .await?;
+
+    // the reference's length may take running the forced aligner, and both read files
+    let (recording, reference_seconds) = {
+        let (filename, resource) = (session.recording_file.clone(), resource.clone());
+        tokio::task::spawn_blocking(move || {
+            (
+                filename.and_then(|filename| crate::audio::read_audio(&filename).ok()),
+                reference_duration(&resource),
+            )
+        })
+        .await
+        .unwrap_or((None, None))
+    };
+
+    let accuracy = Some(config.accuracy.grade(score.wer));
+    let transcript = session.transcript()?;
+    let fillers = crate::compare::load_fillers(&session.language);
+    let fluency = recording.as_ref().map(|(samples, sample_rate)| {
+        let report = crate::compare::count_filler_words(&transcript, &session.language, &fillers)
+            .with_duration(samples.len() as f32 / *sample_rate as f32);
+        config.fluency.grade(
+            crate::audio::pause_ratio(samples, *sample_rate)
+                + config.filler_penalty * report.rate_per_minute.unwrap_or(0.0),
+        )
+    });
+    let pace = recording.as_ref().and_then(|(samples, sample_rate)| {
+        let minutes = samples.len() as f32 / *sample_rate as f32 / 60.0;
+        let reference_minutes = reference_seconds? / 60.0;
+        if minutes <= 0.0 || reference_minutes <= 0.0 || score.reference_words == 0 {
+            return None;
+        }
+        let wpm = score.hypothesis_words as f32 / minutes;
+        let reference_wpm = score.reference_words as f32 / reference_minutes;
+        Some(
+            config
+                .pace
+                .grade((wpm - reference_wpm).abs() / reference_wpm),
+        )
+    });
+
+    let grades: Vec<u8> = [accuracy, fluency, pace]
+        .iter()
+        .flatten()
+        .cloned()
+        .collect();
+    Ok(ScoreCard {
+        accuracy: accuracy.map(|g| letter(g).to_string()),
+        fluency: fluency.map(|g| letter(g).to_string()),
+        pronunciation: None,
+        pace: pace.map(|g| letter(g).to_string()),
+        overall: overall(&grades),
+    })
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct SentenceAccuracy {
+    pub sentence_index: usize,
+    pub text: String,
+    pub words: usize,
+    /// words of the sentence found in the transcript, in the same order
+    pub matched: usize,
+    pub accuracy: f32,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct AccuracyReport {
+    #[serde(flatten)]
+    pub counts: Score,
+    /// one minus the word error rate, and never below 0
+    pub accuracy: f32,
+    /// the accuracy out of 100
+    pub score: u32,
+    /// the accuracy's letter grade, by the thresholds in `SCORING_CONFIG`
+    pub grade: String,
+    pub sentences: Vec<SentenceAccuracy>,
+}
+
+/**
+ * How closely `hypothesis` follows `reference`: the word error rate with
+ * its counts, and for each sentence of the reference how many of its
+ * words were kept, so the passages which went wrong can be found.
+ */
+pub fn accuracy_report(reference: &str, hypothesis: &str) -> AccuracyReport {
+    let counts = crate::compare::word_error_rate(reference, hypothesis);
+    let reference_words = words(reference);
+    let mut matched = vec![false; reference_words.len()];
+    for op in capture_diff_slices(Algorithm::Myers, &reference_words, &words(hypothesis)) {
+        if let DiffOp::Equal { old_index, len, .. } = op {
+            matched[old_index..old_index + len].fill(true);
+        }
+    }
+
+    let mut first_word = 0;
+    let sentences = crate::difficulty::sentences(reference)
+        .into_iter()
+        .enumerate()
+        .map(|(sentence_index, text)| {
+            let count = words(&text).len();
+            let end = (first_word + count).min(matched.len());
+            let kept = matched[first_word.min(end)..end]
+                .iter()
+                .filter(|m| **m)
+                .count();
+            first_word += count;
+            SentenceAccuracy {
+                sentence_index,
+                text,
+                words: count,
+                matched: kept,
+                accuracy: if count == 0 {
+                    1.0
+                } else {
+                    kept as f32 / count as f32
+                },
+            }
+        })
+        .collect();
+
+    let accuracy = (1.0 - counts.wer).max(0.0);
+    AccuracyReport {
+        accuracy,
+        score: (accuracy * 100.0).round() as u32,
+        grade: letter(SCORING_CONFIG.accuracy.grade(counts.wer)).to_string(),
+        counts,
+        sentences,
+    }
+}
+
+/// Scores a session's transcript against the reference text in `lang` of `resource_path`.
+pub fn session_accuracy(
+    session: &SessionData,
+    resource_path: &String,
+    lang: &String,
+) -> E<AccuracyReport> {
+    let reference = crate::compare::get_translation(resource_path, lang)?;
+    Ok(accuracy_report(&reference, &session.transcript()?))
+}
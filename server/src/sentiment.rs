@@ -0,0 +1,97 @@
+use serde::Serialize;
+use std::collections::HashMap;
+
+use crate::compare::words;
+use crate::error::{Er, E};
+use crate::metadata::Metadata;
+
+/// How many segments a sentiment arc is divided into by default.
+pub const DEFAULT_SEGMENTS: usize = 10;
+
+/// Where the sentiment lexicons are unless `SENTIMENT_DIR` says otherwise.
+pub const DEFAULT_SENTIMENT_DIR: &str = "sentiment";
+
+/**
+ * Reads the sentiment lexicon for `lang` from `sentiment_<lang>.txt` in
+ * `SENTIMENT_DIR` (by default `sentiment`). Each line holds a word and its
+ * score, from -1 for the most negative to 1 for the most positive. A
+ * language without a lexicon has nothing but neutral words.
+ */
+pub fn load_lexicon(lang: &str) -> HashMap<String, f32> {
+    let dir = std::env::var("SENTIMENT_DIR").unwrap_or(DEFAULT_SENTIMENT_DIR.to_string());
+    match std::fs::read_to_string(format!("{}/sentiment_{}.txt", dir, lang)) {
+        Ok(contents) => contents
+            .lines()
+            .filter_map(|line| {
+                let (word, score) = line.trim().rsplit_once(char::is_whitespace)?;
+                Some((word.trim().to_lowercase(), score.parse().ok()?))
+            })
+            .collect(),
+        Err(e) => {
+            log::debug!("No sentiment lexicon for {}: {:?}", lang, e);
+            HashMap::new()
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct SentimentSegment {
+    pub segment: usize,
+    pub start_word: usize,
+    pub end_word: usize,
+    pub sentiment: f32,
+}
+
+/**
+ * Divides the words of `text` into `segments` runs of equal length, fewer
+ * if there are not that many words, and scores each with the mean score
+ * of its words found in the lexicon, or 0 if there are none.
+ */
+pub fn sentiment_arc(
+    text: &str,
+    lexicon: &HashMap<String, f32>,
+    segments: usize,
+) -> Vec<SentimentSegment> {
+    let words = words(text);
+    if words.is_empty() {
+        return vec![];
+    }
+    let segments = segments.clamp(1, words.len());
+    (0..segments)
+        .map(|segment| {
+            let start_word = segment * words.len() / segments;
+            let end_word = (segment + 1) * words.len() / segments - 1;
+            let scores: Vec<f32> = words[start_word..=end_word]
+                .iter()
+                .filter_map(|w| lexicon.get(w))
+                .cloned()
+                .collect();
+            let sentiment = if scores.is_empty() {
+                0.0
+            } else {
+                scores.iter().sum::<f32>() / scores.len() as f32
+            };
+            SentimentSegment {
+                segment,
+                start_word,
+                end_word,
+                sentiment,
+            }
+        })
+        .collect()
+}
+
+/// The sentiment arc of a resource's text in `lang`.
+pub fn resource_sentiment_arc(
+    resource_path: &String,
+    lang: &str,
+    segments: usize,
+) -> E<Vec<SentimentSegment>> {
+    let metadata = Metadata::from_resource_path(resource_path)?;
+    let path = metadata.text_path(lang).ok_or(Er::new(format!(
+        "No text for lang {} in resource {}",
+        lang, resource_path
+    )))?;
+    let text = std::fs::read_to_string(path)?;
+    Ok(sentiment_arc(&text, &load_lexicon(lang), segments))
+}
@@ -1,10 +1,11 @@
 use chrono::{DateTime, Utc};
 use crossbeam_channel::{unbounded, Sender};
+use futures_util::stream::{SplitSink, SplitStream};
 use futures_util::{SinkExt, StreamExt};
 use lazy_static::lazy_static;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::io::Write;
 use std::ops::Deref;
 use std::ops::DerefMut;
@@ -12,53 +13,260 @@ use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
 use tokio::runtime::{Builder, Runtime};
-use tokio::sync::RwLock;
+use tokio::sync::{broadcast, RwLock};
 use tokio::time::timeout;
 use uuid::Uuid;
 use warp::ws::{Message, WebSocket};
 
 const RECV_TIMEOUT_SECONDS: u64 = 15;
+/// How long a session whose learner dropped out waits to be resumed with `/chat?resume=<uuid>` before it is closed.
+pub const RESUME_GRACE_SECONDS: u64 = 60;
+/// How long `status_connected` waits between looks at a session, doubling from the first while nothing changes.
+const STATUS_POLL_MILLIS: (u64, u64) = (250, 4000);
 
-use crate::error::E;
-use crate::queue::{self};
+/// The longest a session may go on for; by default there is no limit.
+fn max_session_seconds() -> Option<i64> {
+    crate::config::CONFIG.max_session_seconds.map(|s| s as i64)
+}
+
+/// Where sessions are saved, if anywhere.
+fn recordings_dir() -> Option<String> {
+    crate::config::CONFIG.recordings_dir.clone()
+}
+
+use crate::audio::{Codec, StreamDecoder};
+use crate::consecutive::{Mode, Rendition, RenditionMarker};
+use crate::error::{Er, Kind, E};
+use crate::subtitles::Cue;
+use crate::transcribe::scheduler::{self, Priority};
+use crate::transcribe::Backend;
 use crate::translate::{self, TranslationResponse, TranslationResponses};
 
 pub type Sessions = HashMap<usize, SessionData>;
 
+/// Why a session was closed.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CloseReason {
+    /// the learner stopped, or disconnected
+    ClientRequested,
+    /// nothing came from the learner for `RECV_TIMEOUT_SECONDS`
+    IdleTimeout,
+    /// the session went on for longer than the configured `max_session_seconds`
+    MaxDuration,
+    ServerShutdown,
+    /// closed with `/admin/close-session`
+    AdminForced,
+    Error(String),
+}
+
+impl CloseReason {
+    /// The name of the reason without any details, for counting reasons.
+    pub fn label(&self) -> &'static str {
+        match self {
+            CloseReason::ClientRequested => "client_requested",
+            CloseReason::IdleTimeout => "idle_timeout",
+            CloseReason::MaxDuration => "max_duration",
+            CloseReason::ServerShutdown => "server_shutdown",
+            CloseReason::AdminForced => "admin_forced",
+            CloseReason::Error(_) => "error",
+        }
+    }
+
+    /// Whether the connection may just have dropped, so that the learner can resume the session.
+    fn resumable(&self) -> bool {
+        matches!(self, CloseReason::IdleTimeout | CloseReason::Error(_))
+    }
+}
+
+/// The lowest and highest ratings learners can give their own sessions.
+pub const SELF_RATING_RANGE: (u8, u8) = (1, 5);
+
+/// A learner's own view of how well a session went.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct SelfRating {
+    pub self_rating: u8,
+    #[serde(default)]
+    pub notes: Option<String>,
+}
+
+/// How many events a session's log holds before the audio chunks it receives stop being logged.
+pub const MAX_REPLAY_EVENTS: usize = 100_000;
+
+/// What happened in a session, as the `event_type` and `payload` of a `ReplayEvent`.
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "event_type", content = "payload", rename_all = "snake_case")]
+pub enum ReplayPayload {
+    AudioChunkReceived {
+        samples: usize,
+    },
+    TranscriptionRequested {
+        sequence_number: usize,
+        samples: usize,
+    },
+    TranscriptionResult {
+        sequence_number: usize,
+        segment_number: i32,
+        num_segments: i32,
+        text: String,
+    },
+    /// the language of a session given as `auto` has been detected
+    LanguageDetected {
+        language: String,
+    },
+    /// `status` is one of `open`, `resumed`, `closing`, `finished` and `reprocessing`
+    StatusChanged {
+        status: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        close_reason: Option<CloseReason>,
+    },
+}
+
+/// Something which happened in a session, kept for `/replay`.
+#[derive(Clone, Debug, Serialize)]
+pub struct ReplayEvent {
+    pub timestamp: DateTime<Utc>,
+    #[serde(flatten)]
+    pub payload: ReplayPayload,
+}
+
+/// How many events `/events` subscribers can fall behind by before they miss some.
+pub const SESSION_EVENTS_CAPACITY: usize = 1024;
+
+/// An event of a session as the admin dashboard is sent it, saying which session it is from.
+#[derive(Clone, Debug, Serialize)]
+pub struct SessionEvent {
+    pub uuid: Uuid,
+    pub resource: Option<String>,
+    pub language: String,
+    pub user: Option<String>,
+    /// where the learner reached the server, which webhooks make their URLs absolute with
+    #[serde(skip)]
+    pub base_url: Option<String>,
+    #[serde(flatten)]
+    pub event: ReplayEvent,
+}
+
+/// A session as the admin dashboard lists it before any of its events arrive.
+#[derive(Clone, Debug, Serialize)]
+pub struct SessionOverview {
+    pub uuid: Uuid,
+    pub resource: Option<String>,
+    pub language: String,
+    pub user: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub status: &'static str,
+    pub transcription_job_count: usize,
+    pub transcription_completed_count: usize,
+}
+
 /// Our global unique user id counter.
 static NEXT_USER_ID: AtomicUsize = AtomicUsize::new(1);
 
-#[derive(Clone, Debug, Serialize)]
+/**
+ * A session, as it is kept in memory while the server runs. The fields
+ * which are saved are read back from the database, when there is one,
+ * with `sqlx::FromRow`; the others start out empty.
+ */
+#[derive(Clone, Debug, Serialize, sqlx::FromRow)]
 pub struct SessionData {
     #[serde(skip_serializing)]
+    #[sqlx(skip)]
     pub id: usize,
     #[serde(skip_serializing)]
+    #[sqlx(skip)]
     pub transcription_sender_tx: Option<Sender<Message>>,
     pub language: String,
+    #[sqlx(try_from = "uuid::fmt::Hyphenated")]
     pub uuid: Uuid,
     pub resource: Option<String>,
     pub sample_rate: u32,
     #[serde(skip_serializing)]
+    #[sqlx(skip)]
     pub valid: bool,
     #[serde(skip_serializing)]
+    #[sqlx(skip)]
     pub buffer: Vec<f32>,
     #[serde(skip_serializing)]
+    #[sqlx(skip)]
     pub silence_length: usize,
+    #[sqlx(skip)]
     pub sequence_number: usize,
     #[serde(skip_serializing)]
+    #[sqlx(skip)]
     pub last_sequence: Option<usize>,
+    /// how many samples were sent for transcription as each sequence number
+    #[serde(skip_serializing)]
+    #[sqlx(json)]
+    pub sequence_lengths: Vec<usize>,
     #[serde(skip_serializing)]
+    #[allow(dead_code)]
+    #[sqlx(skip)]
     pub recording: bool,
     #[serde(skip_serializing)]
     pub recording_file: Option<String>,
     #[serde(skip_serializing)]
     pub transcript_file: Option<String>,
+    /// when the recording and the files made from it were deleted, after which they are gone for good
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub recording_deleted_at: Option<DateTime<Utc>>,
     #[serde(skip_serializing)]
+    #[sqlx(skip)]
     pub translations: Arc<Mutex<TranslationResponses>>,
+    /// the scores of the finished session in each language, as `/score` worked them out
+    #[serde(skip_serializing)]
+    #[sqlx(skip)]
+    pub scores: Arc<Mutex<HashMap<String, crate::compare::Score>>>,
+    /// what happened in the session while the server was running, oldest first
+    #[serde(skip_serializing)]
+    #[sqlx(skip)]
+    pub events: Arc<Mutex<Vec<ReplayEvent>>>,
+    /// IP address of the learner, kept out of the API but saved with the session
+    #[serde(skip_serializing)]
+    pub client: Option<String>,
+    /// the logged in user who made the session, who alone can see it
+    #[serde(skip_serializing)]
+    pub user: Option<String>,
+    /// the scheme and host the learner reached the server at, for absolute URLs; not kept over restarts
+    #[serde(skip_serializing)]
+    #[sqlx(skip)]
+    pub base_url: Option<String>,
+    /// whether `language` was detected from the audio rather than given
+    pub language_detected: bool,
+    /// whether the segments are labelled with who spoke them, for dialogues
+    pub diarize: bool,
+    /// whether the learner interprets simultaneously or segment by segment
+    pub mode: Mode,
+    /// when the learner rendered each segment, in consecutive sessions
+    #[sqlx(json)]
+    pub renditions: Vec<Rendition>,
+    /// what the session is transcribed with
+    pub backend: Backend,
+    /// the whisper.cpp model the session asked for instead of the configured one
+    pub model: Option<String>,
+    /// how the learner's audio is sent
+    pub codec: Codec,
+    /// how many times the learner has connected to the session, counting resumptions
+    #[serde(skip_serializing)]
+    #[sqlx(skip)]
+    pub connection: usize,
+    /// why the session was closed, if it has been
+    #[sqlx(json)]
+    pub close_reason: Option<CloseReason>,
+    #[sqlx(json)]
+    pub rating: Option<SelfRating>,
     pub updated_at: DateTime<Utc>,
     pub created_at: DateTime<Utc>,
 }
 
+/// A session as the JSON API shows it, with the URLs to fetch its data from.
+#[derive(Clone, Debug, Serialize)]
+pub struct SessionInfo {
+    #[serde(flatten)]
+    pub session: SessionData,
+    pub replay_url: Option<String>,
+}
+
 #[derive(Deserialize)]
 struct SavedSessionData {
     pub language: String,
@@ -68,16 +276,53 @@ struct SavedSessionData {
     pub updated_at: DateTime<Utc>,
     pub created_at: DateTime<Utc>,
     pub transcript: Option<String>,
+    #[serde(default)]
+    pub client: Option<String>,
+    #[serde(default)]
+    pub user: Option<String>,
+    #[serde(default)]
+    pub language_detected: bool,
+    #[serde(default)]
+    pub diarize: bool,
+    #[serde(default)]
+    pub mode: Mode,
+    #[serde(default)]
+    pub renditions: Vec<Rendition>,
+    #[serde(default)]
+    pub backend: Backend,
+    #[serde(default)]
+    pub model: Option<String>,
+    #[serde(default)]
+    pub codec: Codec,
+    #[serde(default)]
+    pub close_reason: Option<CloseReason>,
+    #[serde(default)]
+    pub rating: Option<SelfRating>,
+    #[serde(default)]
+    pub segments: Option<TranslationResponses>,
+    #[serde(default)]
+    pub sequence_lengths: Vec<usize>,
+    #[serde(default)]
+    pub recording_deleted_at: Option<DateTime<Utc>>,
 }
 
 #[derive(Clone, Debug, Serialize)]
 pub struct Status {
     pub language: String,
+    /// whether `language` was detected, or is still `auto` as it is yet to be
+    pub language_detected: bool,
     pub uuid: Uuid,
     pub resource: Option<String>,
     pub sample_rate: u32,
     pub transcription_job_count: usize,
     pub transcription_completed_count: usize,
+    pub close_reason: Option<CloseReason>,
+    /// the session's pieces waiting for a transcription worker
+    pub transcriptions_waiting: usize,
+    /// how many pieces of any session will be transcribed before the first of those
+    pub queue_position: Option<usize>,
+    /// the model the session is transcribed with, if the backend says
+    pub model: Option<String>,
 }
 
 impl SessionData {
@@ -96,7 +341,7 @@ impl SessionData {
         };
         let mut recording_file = None;
         let mut transcript_file = None;
-        if let Ok(dir) = std::env::var("RECORDINGS_DIR") {
+        if let Some(dir) = recordings_dir() {
             let new_dir = format!("{}/{}", dir, uuid);
             if std::fs::create_dir_all(new_dir.clone()).is_ok() {
                 recording_file = Some(format!("{}/{}.wav", new_dir, uuid));
@@ -114,11 +359,28 @@ impl SessionData {
             recording: recording_file.is_some(),
             recording_file,
             transcript_file,
+            recording_deleted_at: None,
             valid: true,
             buffer: Vec::new(),
             sequence_number: 0,
             last_sequence: None,
+            sequence_lengths: vec![],
             translations: Arc::new(Mutex::new(TranslationResponses::new())),
+            scores: Arc::new(Mutex::new(HashMap::new())),
+            events: Arc::new(Mutex::new(vec![])),
+            client: None,
+            user: None,
+            base_url: None,
+            language_detected: false,
+            diarize: false,
+            mode: Mode::default(),
+            renditions: vec![],
+            backend: Backend::configured(),
+            model: None,
+            codec: Codec::Pcm,
+            connection: 0,
+            close_reason: None,
+            rating: None,
             updated_at: Utc::now(),
             created_at: Utc::now(),
         }
@@ -137,21 +399,182 @@ impl SessionData {
             .as_ref()
             .ok_or("couldn't find sender")?
             .send(Message::text(
-                json!({ "uuid": self.uuid.to_string() }).to_string(),
+                json!({ "uuid": self.uuid.to_string(), "codec": self.codec }).to_string(),
             ))?;
         Ok(())
     }
 
+    /**
+     * Takes `language` as the session's, detected from its audio, and
+     * tells the learner, if they are connected, which it is.
+     */
+    pub fn set_detected_language(&mut self, language: &str) {
+        self.language = language.to_string();
+        self.language_detected = true;
+        self.record_event(ReplayPayload::LanguageDetected {
+            language: language.to_string(),
+        });
+        if let Some(sender) = &self.transcription_sender_tx {
+            let message = json!({
+                "uuid": self.uuid.to_string(),
+                "language": language,
+                "language_detected": true,
+            });
+            // the learner may have gone, and will see it in the status
+            let _ = sender.send(Message::text(message.to_string()));
+        }
+    }
+
+    /// What the learner is sent for a transcribed segment, timed within the whole recording for them to follow along.
+    fn segment_message(&self, response: &TranslationResponse) -> Message {
+        let (start, end) =
+            crate::subtitles::segment_times(response, &self.sequence_lengths, self.sample_rate);
+        let mut message = json!(response);
+        message["start"] = json!(start);
+        message["end"] = json!(end);
+        Message::text(message.to_string())
+    }
+
     pub fn transcript(&self) -> E<String> {
         let mutex = self.translations.lock().unwrap();
         let responses: &crate::translate::TranslationResponses = mutex.deref();
         Ok(responses.to_string())
     }
 
+    /**
+     * When each word of `transcript()`, as split at whitespace, was said,
+     * in seconds from the start of the recording. The gaps of pieces not
+     * transcribed yet, and all words of sessions restored from disk
+     * without their segments' timing, have no time.
+     */
+    pub fn word_times(&self) -> Vec<Option<(f32, f32)>> {
+        let translations = self.translations.lock().unwrap();
+        let mut times = vec![];
+        for piece in translations.pieces() {
+            match piece {
+                Some(segment) if !self.sequence_lengths.is_empty() => times.extend(
+                    crate::subtitles::word_times(segment, &self.sequence_lengths, self.sample_rate)
+                        .into_iter()
+                        .map(Some),
+                ),
+                Some(segment) => times.extend(segment.translation.split_whitespace().map(|_| None)),
+                None => times.push(None),
+            }
+        }
+        times
+    }
+
+    /// Who said each word of `transcript()`, as `word_times` times them, in diarized sessions.
+    pub fn word_speakers(&self) -> Vec<Option<String>> {
+        let translations = self.translations.lock().unwrap();
+        let mut speakers = vec![];
+        for piece in translations.pieces() {
+            match piece {
+                Some(segment) => speakers.extend(
+                    segment
+                        .translation
+                        .split_whitespace()
+                        .map(|_| segment.speaker.clone()),
+                ),
+                None => speakers.push(None),
+            }
+        }
+        speakers
+    }
+
+    /**
+     * The transcribed segments, timed from the start of the recording.
+     * Sessions restored from disk have kept only their text, without the
+     * timing of its segments, so it is one segment lasting the whole
+     * recording.
+     */
+    pub fn transcript_cues(&self) -> E<Vec<Cue>> {
+        Ok(if self.sequence_lengths.is_empty() {
+            let end_secs = match &self.recording_file {
+                Some(filename) => crate::audio::duration_seconds(filename).unwrap_or(0.0),
+                None => 0.0,
+            };
+            vec![Cue {
+                start_secs: 0.0,
+                end_secs,
+                text: self.transcript()?.trim().to_string(),
+                confidence: None,
+                speaker: None,
+            }]
+        } else {
+            let mutex = self.translations.lock().unwrap();
+            crate::subtitles::cues(mutex.deref(), &self.sequence_lengths, self.sample_rate)
+        })
+    }
+
+    /**
+     * Adds to the session's event log, unless it is full and `payload` is
+     * an audio chunk. Events other than audio chunks are also published
+     * to the subscribers of `/events`.
+     */
+    pub fn record_event(&self, payload: ReplayPayload) {
+        let audio = matches!(payload, ReplayPayload::AudioChunkReceived { .. });
+        let event = ReplayEvent {
+            timestamp: Utc::now(),
+            payload,
+        };
+        if !audio {
+            // this only fails when nobody is listening
+            let _ = SESSION_EVENTS.send(SessionEvent {
+                uuid: self.uuid,
+                resource: self.resource.clone(),
+                language: self.language.clone(),
+                user: self.user.clone(),
+                base_url: self.base_url.clone(),
+                event: event.clone(),
+            });
+        }
+        let mut events = self.events.lock().unwrap();
+        if events.len() >= MAX_REPLAY_EVENTS && audio {
+            return;
+        }
+        events.push(event);
+    }
+
+    /// The session's events from the `from`th on.
+    pub fn events_from(&self, from: usize) -> Vec<ReplayEvent> {
+        let events = self.events.lock().unwrap();
+        events.get(from..).unwrap_or_default().to_vec()
+    }
+
+    fn record_status(&self, status: &str) {
+        self.record_event(ReplayPayload::StatusChanged {
+            status: status.to_string(),
+            close_reason: self.close_reason.clone(),
+        });
+    }
+
     pub fn finalize_session(&mut self) {
-        self.record_transcript()
-            .expect("error recording transcript");
-        self.write_metadata().expect("error writing metadata");
+        if self.diarize {
+            crate::subtitles::label_speakers(
+                &mut self.translations.lock().unwrap(),
+                &self.sequence_lengths,
+                self.sample_rate,
+            );
+        }
+        if let Err(e) = self.record_transcript() {
+            log::error!(
+                "Couldn't record the transcript of session {}: {:?}",
+                self.id,
+                e
+            );
+        }
+        if let Err(e) = self.write_metadata() {
+            log::error!(
+                "Couldn't write the metadata of session {}: {:?}",
+                self.id,
+                e
+            );
+        }
+        if let Err(e) = crate::store::save_blocking(self) {
+            log::error!("Couldn't save session {}: {:?}", self.id, e);
+        }
+        self.record_status("finished");
         mutate_session_sync(&self.id, |session| {
             let sender = session.transcription_sender_tx.take();
             drop(sender);
@@ -161,10 +584,16 @@ impl SessionData {
     }
 
     fn write_metadata(&self) -> E<()> {
-        if let Ok(dir) = std::env::var("RECORDINGS_DIR") {
+        if let Some(dir) = recordings_dir() {
             let metadata_file = format!("{}/{}/metadata.json", dir, self.uuid);
             let mut file = std::fs::File::create(metadata_file)?;
-            let json = json!(self).to_string();
+            let mut json = json!(self);
+            json["client"] = json!(self.client);
+            json["user"] = json!(self.user);
+            // the segments with their timing, so subtitles and word timings survive restarts
+            json["segments"] = json!(self.translations.lock().unwrap().deref());
+            json["sequence_lengths"] = json!(self.sequence_lengths);
+            let json = json.to_string();
             file.write_all(json.as_bytes())?;
         }
         Ok(())
@@ -180,16 +609,83 @@ impl SessionData {
         Ok(())
     }
 
+    /// The directory the session's recording and the files made from it are kept in.
+    pub fn directory(&self) -> Option<String> {
+        let recording = std::path::Path::new(self.recording_file.as_ref()?);
+        Some(recording.parent()?.display().to_string())
+    }
+
+    /// Where to download the recording from, signed if URL signing is turned on.
+    pub fn replay_url(&self) -> Option<String> {
+        self.recording_file
+            .as_ref()
+            .filter(|_| self.recording_deleted_at.is_none())
+            .map(|_| crate::auth::signed_url(&format!("/recording/{}", self.uuid)))
+    }
+
+    pub fn info(&self) -> SessionInfo {
+        SessionInfo {
+            session: self.clone(),
+            replay_url: self.replay_url(),
+        }
+    }
+
+    /// Whether the session is `open`, `closing` while the last of it is transcribed, or `finished`.
+    pub fn status_name(&self) -> &'static str {
+        if !self.valid {
+            "finished"
+        } else if self.close_reason.is_some() {
+            "closing"
+        } else {
+            "open"
+        }
+    }
+
+    pub fn overview(&self) -> SessionOverview {
+        SessionOverview {
+            uuid: self.uuid,
+            resource: self.resource.clone(),
+            language: self.language.clone(),
+            user: self.user.clone(),
+            created_at: self.created_at,
+            status: self.status_name(),
+            transcription_job_count: self.sequence_number,
+            transcription_completed_count: self.get_translation_count().unwrap_or(0),
+        }
+    }
+
     pub fn status(&self) -> E<Status> {
+        let (transcriptions_waiting, queue_position) = scheduler::queue_position(self.id);
         Ok(Status {
             language: self.language.clone(),
+            language_detected: self.language_detected,
             uuid: self.uuid,
             resource: self.resource.clone(),
             sample_rate: self.sample_rate,
             transcription_job_count: self.sequence_number,
             transcription_completed_count: self.get_translation_count()?,
+            close_reason: self.close_reason.clone(),
+            transcriptions_waiting,
+            queue_position,
+            model: self.active_model(),
         })
     }
+
+    /// The model the session is transcribed with, as far as the server knows which.
+    pub fn active_model(&self) -> Option<String> {
+        match self.backend {
+            Backend::WhisperCpp => Some(
+                self.model
+                    .clone()
+                    .unwrap_or(crate::config::CONFIG.whisper_model.clone()),
+            ),
+            Backend::OpenAi => Some(
+                std::env::var("OPENAI_MODEL")
+                    .unwrap_or(crate::transcribe::DEFAULT_OPENAI_MODEL.to_string()),
+            ),
+            Backend::WhisperX | Backend::Vosk => None,
+        }
+    }
 }
 
 lazy_static! {
@@ -206,6 +702,26 @@ lazy_static! {
         .build()
         .unwrap();
     pub static ref SESSIONS: RwLock<Sessions> = RwLock::new(Sessions::default());
+    static ref SESSION_EVENTS: broadcast::Sender<SessionEvent> =
+        broadcast::channel(SESSION_EVENTS_CAPACITY).0;
+}
+
+/**
+ * The sessions still being recorded or transcribed, oldest first, and a
+ * receiver of the events of every session from then on.
+ */
+pub async fn subscribe_events() -> (Vec<SessionOverview>, broadcast::Receiver<SessionEvent>) {
+    // subscribed first, so that an event may repeat what the overview says but none is missed
+    let receiver = SESSION_EVENTS.subscribe();
+    let mut overviews: Vec<SessionOverview> = SESSIONS
+        .read()
+        .await
+        .values()
+        .filter(|s| s.valid)
+        .map(SessionData::overview)
+        .collect();
+    overviews.sort_by_key(|s| s.created_at);
+    (overviews, receiver)
 }
 
 pub fn process_transcription(session_id: usize, response: &TranslationResponse) -> E<()> {
@@ -213,11 +729,11 @@ pub fn process_transcription(session_id: usize, response: &TranslationResponse)
     log::debug!(
         "Sending {:?} to user\nSessionData is {}, last_sequence = {:?}",
         response,
-        json!(session).to_string(),
+        json!(session),
         session.last_sequence,
     );
     match session.transcription_sender_tx.as_ref() {
-        Some(sender) => match sender.send(Message::text(json!(response).to_string())) {
+        Some(sender) => match sender.send(session.segment_message(response)) {
             Ok(_) => (),
             Err(e) => log::error!("Couldn't send {:?}", e),
         },
@@ -229,6 +745,12 @@ pub fn process_transcription(session_id: usize, response: &TranslationResponse)
         .unwrap()
         .deref_mut()
         .add_translation(&response.clone())?;
+    session.record_event(ReplayPayload::TranscriptionResult {
+        sequence_number: response.sequence_number,
+        segment_number: response.segment_number,
+        num_segments: response.num_segments,
+        text: response.translation.clone(),
+    });
 
     if let Some(last) = session.last_sequence {
         if session.sequence_number >= last && response.segment_number == response.num_segments - 1 {
@@ -252,8 +774,206 @@ pub async fn get_session(id: &usize) -> Option<SessionData> {
     SESSIONS.read().await.get(id).cloned()
 }
 
-pub async fn get_sessions() -> Option<Vec<SessionData>> {
-    Some(SESSIONS.read().await.iter().map(|x| x.1.clone()).collect())
+/// Which sessions `get_sessions` returns; each field left out lets every session through.
+#[derive(Clone, Debug, Default)]
+pub struct SessionFilter {
+    pub lang: Option<String>,
+    pub resource: Option<String>,
+    /// `open`, `closing` or `finished`
+    pub status: Option<String>,
+    /// the user who made the session, None for those made without logging in
+    pub user: Option<Option<String>>,
+}
+
+impl SessionFilter {
+    fn matches(&self, session: &SessionData) -> bool {
+        self.lang.as_ref().is_none_or(|l| &session.language == l)
+            && self
+                .resource
+                .as_ref()
+                .is_none_or(|r| session.resource.as_ref() == Some(r))
+            && self
+                .status
+                .as_ref()
+                .is_none_or(|s| session.status_name() == s)
+            && self.user.as_ref().is_none_or(|u| &session.user == u)
+    }
+}
+
+/// A page of sessions, counting from 1.
+#[derive(Clone, Copy, Debug)]
+pub struct Page {
+    pub page: usize,
+    pub per_page: usize,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct SessionPage<T> {
+    pub sessions: Vec<T>,
+    pub page: usize,
+    pub per_page: usize,
+    /// how many sessions there are on all the pages
+    pub total: usize,
+    pub pages: usize,
+}
+
+/**
+ * The sessions let through by `filter`, oldest first, and of those only
+ * the ones on `page` if one is asked for.
+ */
+pub async fn get_sessions(filter: &SessionFilter, page: Option<Page>) -> SessionPage<SessionData> {
+    let mut sessions: Vec<SessionData> = SESSIONS
+        .read()
+        .await
+        .values()
+        .filter(|s| filter.matches(s))
+        .cloned()
+        .collect();
+    sessions.sort_by_key(|s| s.created_at);
+    let total = sessions.len();
+    let page = page.unwrap_or(Page {
+        page: 1,
+        per_page: total.max(1),
+    });
+    let sessions = sessions
+        .into_iter()
+        .skip((page.page - 1) * page.per_page)
+        .take(page.per_page)
+        .collect();
+    SessionPage {
+        sessions,
+        page: page.page,
+        per_page: page.per_page,
+        total,
+        pages: total.div_ceil(page.per_page).max(1),
+    }
+}
+
+/// The sessions practising with `resource`, oldest first.
+pub async fn get_sessions_by_resource(resource: &str) -> Vec<SessionData> {
+    let mut sessions: Vec<SessionData> = SESSIONS
+        .read()
+        .await
+        .values()
+        .filter(|s| s.resource.as_deref() == Some(resource))
+        .cloned()
+        .collect();
+    sessions.sort_by_key(|s| s.created_at);
+    sessions
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Attempt {
+    pub uuid: Uuid,
+    pub created_at: DateTime<Utc>,
+    pub wer: Option<f32>,
+    pub wpm: Option<f32>,
+    pub self_rating: Option<u8>,
+}
+
+/**
+ * A learner's finished attempts at a resource in `lang`, newest first.
+ * Learners are told apart by their `user` when they are logged in, and
+ * otherwise by their address, `client`, among the anonymous sessions; if
+ * neither is known there is no telling whose attempts are whose, so none
+ * are returned. Scoring reads each transcript and recording, so it is
+ * done off the async runtime.
+ */
+pub async fn history(
+    resource: &str,
+    lang: &str,
+    user: Option<&str>,
+    client: Option<&str>,
+    limit: usize,
+) -> E<Vec<Attempt>> {
+    if user.is_none() && client.is_none() {
+        return Ok(vec![]);
+    }
+    let mut sessions: Vec<SessionData> = get_sessions_by_resource(resource)
+        .await
+        .into_iter()
+        .filter(|s| !s.valid && s.language == lang)
+        .filter(|s| match user {
+            Some(user) => s.user.as_deref() == Some(user),
+            None => s.user.is_none() && s.client.as_deref() == client,
+        })
+        .collect();
+    sessions.reverse();
+    sessions.truncate(limit);
+
+    let lang = lang.to_string();
+    Ok(tokio::task::spawn_blocking(move || {
+        sessions
+            .iter()
+            .map(|session| {
+                let score = crate::compare::session_score(session, &lang).ok();
+                let minutes = session
+                    .recording_file
+                    .as_ref()
+                    .filter(|_| session.recording_deleted_at.is_none())
+                    .and_then(|f| crate::audio::duration_seconds(f).ok())
+                    .map(|seconds| seconds / 60.0)
+                    .filter(|minutes| *minutes > 0.0);
+                Attempt {
+                    uuid: session.uuid,
+                    created_at: session.created_at,
+                    wer: score.as_ref().map(|s| s.wer),
+                    wpm: score
+                        .zip(minutes)
+                        .map(|(s, minutes)| s.hypothesis_words as f32 / minutes),
+                    self_rating: session.rating.as_ref().map(|r| r.self_rating),
+                }
+            })
+            .collect()
+    })
+    .await?)
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Progress {
+    pub uuid: Uuid,
+    pub created_at: DateTime<Utc>,
+    pub wer: Option<f32>,
+}
+
+/**
+ * The word error rates of every finished session at a resource in
+ * `lang`, oldest first, made `since` if given, and only those of `user`
+ * if there is one (`Some(None)` being the anonymous sessions). With a
+ * `limit` only the newest that many are kept. Sessions which can't be
+ * scored have no `wer`. Scoring reads each transcript, so it is done off
+ * the async runtime.
+ */
+pub async fn progress(
+    resource: &str,
+    lang: &String,
+    since: Option<DateTime<Utc>>,
+    limit: Option<usize>,
+    user: Option<Option<String>>,
+) -> E<Vec<Progress>> {
+    let mut sessions: Vec<SessionData> = get_sessions_by_resource(resource)
+        .await
+        .into_iter()
+        .filter(|s| !s.valid && &s.language == lang)
+        .filter(|s| since.map(|since| s.created_at >= since).unwrap_or(true))
+        .filter(|s| user.as_ref().map(|user| &s.user == user).unwrap_or(true))
+        .collect();
+    let skip = limit.map(|l| sessions.len().saturating_sub(l)).unwrap_or(0);
+    sessions.drain(..skip);
+    let lang = lang.clone();
+    Ok(tokio::task::spawn_blocking(move || {
+        sessions
+            .iter()
+            .map(|session| Progress {
+                uuid: session.uuid,
+                created_at: session.created_at,
+                wer: crate::compare::session_score(session, &lang)
+                    .map(|s| s.wer)
+                    .ok(),
+            })
+            .collect()
+    })
+    .await?)
 }
 
 pub fn get_session_sync(id: &usize) -> Option<SessionData> {
@@ -303,17 +1023,78 @@ async fn remove_session(id: &usize) {
 }
 
 pub async fn user_message(session_id: usize, msg: Message) -> E<()> {
+    if let Ok(text) = msg.to_str() {
+        if let Ok(marker) = serde_json::from_str::<RenditionMarker>(text) {
+            mark_rendition(session_id, marker.rendition).await;
+        }
+        return Ok(());
+    }
     if !msg.is_binary() {
-        // TODO: handle this
         return Ok(());
     }
     let data = msg.into_bytes();
+    let samples: Vec<f32> = data
+        .chunks_exact(4)
+        .map(|a| f32::from_le_bytes([a[0], a[1], a[2], a[3]]))
+        .collect();
+    user_audio(session_id, samples).await
+}
+
+/**
+ * Starts the learner's rendition of a segment of a consecutive session
+ * at the audio received so far, or stops it. Other sessions ignore this.
+ */
+async fn mark_rendition(session_id: usize, segment: Option<usize>) {
+    mutate_session(&session_id, |session| {
+        if session.mode != Mode::Consecutive {
+            return;
+        }
+        let received = session.sequence_lengths.iter().sum::<usize>() + session.buffer.len();
+        let at_secs = received as f32 / session.sample_rate as f32;
+        crate::consecutive::mark_rendition(&mut session.renditions, segment, at_secs);
+    })
+    .await;
+}
+
+/**
+ * Reads the samples a session's decoder gives as it decodes, passing them
+ * on as they come. A sample split between two reads is put back together.
+ */
+async fn decoded_audio(session_id: usize, mut output: tokio::process::ChildStdout) {
+    use tokio::io::AsyncReadExt;
+    let mut buffer = vec![0u8; 64 * 1024];
+    let mut pending: Vec<u8> = vec![];
+    loop {
+        let read = match output.read(&mut buffer).await {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(e) => {
+                log::warn!(
+                    "Error reading decoded audio of session {}: {}",
+                    session_id,
+                    e
+                );
+                break;
+            }
+        };
+        pending.extend_from_slice(&buffer[..read]);
+        let whole = pending.len() - pending.len() % 4;
+        let samples: Vec<f32> = pending[..whole]
+            .chunks_exact(4)
+            .map(|a| f32::from_le_bytes([a[0], a[1], a[2], a[3]]))
+            .collect();
+        pending.drain(..whole);
+        if user_audio(session_id, samples).await.is_err() {
+            log::warn!("Couldn't add decoded audio to session {}", session_id);
+        }
+    }
+}
+
+/// Adds samples of the learner's audio to the session, sending it to be transcribed at pauses.
+pub async fn user_audio(session_id: usize, mut v: Vec<f32>) -> E<()> {
     if let Some(session) = get_session(&session_id).await {
         if let Some(ref _transcription_sender_tx) = session.transcription_sender_tx {
-            let mut v: Vec<f32> = data
-                .chunks_exact(4)
-                .map(|a| f32::from_le_bytes([a[0], a[1], a[2], a[3]]))
-                .collect();
+            session.record_event(ReplayPayload::AudioChunkReceived { samples: v.len() });
 
             mutate_session(&session_id, |session| session.buffer.append(&mut v)).await;
 
@@ -339,20 +1120,28 @@ pub async fn user_message(session_id: usize, msg: Message) -> E<()> {
                 let payload = session.buffer[..pivot].to_vec();
                 let lang = session.language.clone();
                 persist_session_data(&session, pivot)?;
-                let result = queue::get_queue().enqueue(translate::TranslationRequest {
-                    session_id,
-                    sequence_number,
-                    payload,
-                    lang,
-                });
+                let result = scheduler::enqueue(
+                    translate::TranslationRequest {
+                        session_id,
+                        sequence_number,
+                        payload,
+                        lang,
+                    },
+                    Priority::Live,
+                );
 
                 match result {
                     Ok(_) => {
                         drop(result);
+                        session.record_event(ReplayPayload::TranscriptionRequested {
+                            sequence_number,
+                            samples: pivot,
+                        });
                         mutate_session(&session_id, |session| {
                             session.silence_length = silence_length;
                             session.buffer = session.buffer[pivot..].to_vec();
                             session.sequence_number += 1;
+                            session.sequence_lengths.push(pivot);
                         })
                         .await;
                     }
@@ -371,50 +1160,188 @@ pub async fn user_message(session_id: usize, msg: Message) -> E<()> {
     Ok(())
 }
 
-pub async fn user_connected(
-    ws: WebSocket,
-    lang: String,
-    sample_rate: u32,
-    resource: Option<String>,
-) {
-    let session_id = NEXT_USER_ID.fetch_add(1, Ordering::Relaxed);
-
-    log::debug!("new chat user: {}", session_id);
+/**
+ * Sends the status of a session over `ws` whenever it changes, then
+ * `{"done": true}` once the session has been closed and its transcription
+ * has finished. The session store has no notifications, so it is looked at
+ * every so often, less often while nothing happens.
+ */
+pub async fn status_connected(ws: WebSocket, session_id: usize) {
+    let (mut ws_tx, mut ws_rx) = ws.split();
+    let mut last_status = None;
+    let mut wait = STATUS_POLL_MILLIS.0;
+    loop {
+        let Some(session) = get_session(&session_id).await else {
+            break;
+        };
+        let status = match session
+            .status()
+            .and_then(|s| Ok(serde_json::to_string(&s)?))
+        {
+            Ok(status) => status,
+            Err(e) => {
+                log::error!("Error in status of session {}: {:?}", session_id, e);
+                break;
+            }
+        };
+        if last_status.as_ref() != Some(&status) {
+            if let Err(e) = ws_tx.send(Message::text(status.clone())).await {
+                log::debug!("status websocket send error: {}", e);
+                return;
+            }
+            last_status = Some(status);
+            wait = STATUS_POLL_MILLIS.0;
+        } else {
+            wait = (wait * 2).min(STATUS_POLL_MILLIS.1);
+        }
+        if !session.valid {
+            break;
+        }
+        // waiting on the client notices it going away
+        match timeout(Duration::from_millis(wait), ws_rx.next()).await {
+            Ok(None) | Ok(Some(Err(_))) => return,
+            Ok(Some(Ok(message))) if message.is_close() => return,
+            _ => (),
+        }
+    }
+    if let Err(e) = ws_tx
+        .send(Message::text(json!({ "done": true }).to_string()))
+        .await
+    {
+        log::debug!("status websocket send error: {}", e);
+        return;
+    }
+    let _ = ws_tx.close().await;
+}
 
-    let (mut user_ws_tx, mut user_ws_rx) = ws.split();
+/**
+ * A session's events as newline-delimited JSON, those already logged and
+ * then each new one, until the session has been closed and its
+ * transcription has finished. The session is looked at as often as
+ * `status_connected` does.
+ */
+pub fn replay_stream(
+    session_id: usize,
+) -> impl futures_util::Stream<Item = Result<String, std::convert::Infallible>> {
+    futures_util::stream::unfold(
+        (0, STATUS_POLL_MILLIS.0, false),
+        move |(sent, mut wait, done)| async move {
+            if done {
+                return None;
+            }
+            loop {
+                let session = get_session(&session_id).await?;
+                let events = session.events_from(sent);
+                if !events.is_empty() {
+                    let lines: String = events.iter().map(|e| format!("{}\n", json!(e))).collect();
+                    let state = (sent + events.len(), STATUS_POLL_MILLIS.0, !session.valid);
+                    return Some((Ok(lines), state));
+                }
+                if !session.valid {
+                    return None;
+                }
+                tokio::time::sleep(Duration::from_millis(wait)).await;
+                wait = (wait * 2).min(STATUS_POLL_MILLIS.1);
+            }
+        },
+    )
+}
 
+/**
+ * Sends what the session's transcription sender is given over the
+ * `connection`th websocket of the learner. Once the learner has gone the
+ * rest is dropped, until the sender is dropped too when the session is
+ * finished or a resumption takes over, and the buffered audio is flushed
+ * unless the session was resumed.
+ */
+fn start_sending(
+    session_id: usize,
+    connection: usize,
+    mut user_ws_tx: SplitSink<WebSocket, Message>,
+) -> Sender<Message> {
     let (transcription_send_tx, transcript_receive_rx) = unbounded();
     (*WEBSOCKET_SEND_RUNTIME).spawn(async move {
+        let mut connected = true;
         for message in transcript_receive_rx.iter() {
+            if !connected {
+                continue;
+            }
             log::debug!("Sending message");
-            match user_ws_tx.send(message).await {
-                Ok(_) => (),
-                Err(e) => {
-                    log::debug!("websocket send error: {}", e);
-                    break;
-                }
+            if let Err(e) = user_ws_tx.send(message).await {
+                log::debug!("websocket send error: {}", e);
+                crate::metrics::websocket_error("send");
+                connected = false;
             }
         }
         log::debug!("Exiting loop");
-        if let Some(session) = get_session(&session_id).await {
-            match queue::get_queue().enqueue(translate::TranslationRequest {
-                session_id,
-                sequence_number: session.sequence_number,
-                payload: session.buffer.clone(),
-                lang: session.language.clone(),
-            }) {
-                Ok(_) => log::debug!("Flushed session data"),
+        if let Some(session) = get_session(&session_id)
+            .await
+            .filter(|s| s.connection == connection)
+        {
+            match scheduler::enqueue(
+                translate::TranslationRequest {
+                    session_id,
+                    sequence_number: session.sequence_number,
+                    payload: session.buffer.clone(),
+                    lang: session.language.clone(),
+                },
+                Priority::Live,
+            ) {
+                Ok(_) => {
+                    log::debug!("Flushed session data");
+                    session.record_event(ReplayPayload::TranscriptionRequested {
+                        sequence_number: session.sequence_number,
+                        samples: session.buffer.len(),
+                    });
+                }
                 Err(e) => log::error!("Error flushing session buffer: {:?}", e),
             }
-            mutate_session(&session_id, |session| session.sequence_number += 1).await;
+            let length = session.buffer.len();
+            mutate_session(&session_id, |session| {
+                session.sequence_number += 1;
+                session.sequence_lengths.push(length);
+            })
+            .await;
             match persist_session_data(&session, session.buffer.len()) {
                 Ok(_) => (),
-                Err(e) => log::error!("Error in final session data persist"),
+                Err(e) => log::error!("Error in final session data persist: {:?}", e),
             }
         }
 
-        user_ws_tx.close().await.unwrap();
+        // the learner may have gone already
+        if let Err(e) = user_ws_tx.close().await {
+            log::debug!(
+                "Couldn't close the websocket of session {}: {}",
+                session_id,
+                e
+            );
+        }
     });
+    transcription_send_tx
+}
+
+#[allow(clippy::too_many_arguments)]
+pub async fn user_connected(
+    ws: WebSocket,
+    lang: String,
+    sample_rate: u32,
+    resource: Option<String>,
+    client: Option<String>,
+    user: Option<String>,
+    base_url: Option<String>,
+    backend: Backend,
+    model: Option<String>,
+    diarize: bool,
+    mode: Mode,
+    decoder: Option<StreamDecoder>,
+) {
+    let session_id = NEXT_USER_ID.fetch_add(1, Ordering::Relaxed);
+
+    log::debug!("new chat user: {}", session_id);
+    crate::metrics::websocket_connected(false);
+
+    let (user_ws_tx, user_ws_rx) = ws.split();
+    let transcription_send_tx = start_sending(session_id, 1, user_ws_tx);
 
     let mut session = SessionData::new(
         session_id,
@@ -424,16 +1351,97 @@ pub async fn user_connected(
         resource,
         None,
     );
+    session.client = client;
+    session.user = user;
+    session.base_url = base_url;
+    session.backend = backend;
+    session.model = model;
+    session.diarize = diarize;
+    session.mode = mode;
+    session.connection = 1;
+    if decoder.is_some() {
+        session.codec = Codec::Opus;
+    }
     session.send_uuid().unwrap();
+    session.record_status("open");
     set_session(session_id, session).await;
+    receive_audio(session_id, 1, user_ws_rx, decoder).await;
+}
 
-    while let Ok(Some(result)) =
-        timeout(Duration::from_secs(RECV_TIMEOUT_SECONDS), user_ws_rx.next()).await
-    {
-        let msg = match result {
-            Ok(msg) => msg,
-            Err(e) => {
+/**
+ * Takes the learner back into an open session after their connection
+ * dropped, or before the server noticed it had. The segments transcribed
+ * so far are sent again, as some may have been sent while they were
+ * away, and their audio goes on where it stopped.
+ */
+pub async fn user_resumed(ws: WebSocket, session_id: usize, decoder: Option<StreamDecoder>) {
+    log::debug!("chat user {} resumed", session_id);
+    crate::metrics::websocket_connected(true);
+    let (user_ws_tx, user_ws_rx) = ws.split();
+    let mut connection = 0;
+    mutate_session(&session_id, |session| {
+        session.connection += 1;
+        connection = session.connection;
+    })
+    .await;
+    let transcription_send_tx = start_sending(session_id, connection, user_ws_tx);
+    mutate_session(&session_id, |session| {
+        session.transcription_sender_tx = Some(transcription_send_tx.clone());
+    })
+    .await;
+    let Some(mut session) = get_session(&session_id).await else {
+        return;
+    };
+    if let Err(e) = session.send_uuid() {
+        log::warn!("Couldn't resume session {}: {:?}", session_id, e);
+        return;
+    }
+    let segments: Vec<Message> = session
+        .translations
+        .lock()
+        .unwrap()
+        .segments()
+        .map(|response| session.segment_message(response))
+        .collect();
+    for message in segments {
+        let _ = transcription_send_tx.send(message);
+    }
+    session.record_status("resumed");
+    receive_audio(session_id, connection, user_ws_rx, decoder).await;
+}
+
+/**
+ * Feeds the audio coming over the learner's `connection`th websocket to
+ * the session until it ends, and then closes the session, unless the
+ * connection seems to have dropped: then the learner has
+ * `RESUME_GRACE_SECONDS` to resume it first. Nothing is closed when a
+ * resumption has taken over the session already.
+ */
+async fn receive_audio(
+    session_id: usize,
+    connection: usize,
+    mut user_ws_rx: SplitStream<WebSocket>,
+    mut decoder: Option<StreamDecoder>,
+) {
+    let reader = decoder
+        .as_mut()
+        .and_then(|d| d.output())
+        .map(|output| tokio::spawn(decoded_audio(session_id, output)));
+
+    let mut reason = CloseReason::ClientRequested;
+    loop {
+        let msg = match timeout(Duration::from_secs(RECV_TIMEOUT_SECONDS), user_ws_rx.next()).await
+        {
+            Ok(Some(Ok(msg))) => msg,
+            Ok(Some(Err(e))) => {
                 log::debug!("websocket error(uid={}): {}", session_id, e);
+                crate::metrics::websocket_error("receive");
+                reason = CloseReason::Error(e.to_string());
+                break;
+            }
+            Ok(None) => break,
+            Err(_) => {
+                reason = CloseReason::IdleTimeout;
                 break;
             }
         };
@@ -441,26 +1449,125 @@ pub async fn user_connected(
         let session = get_session(&session_id).await;
         match session {
             Some(s) => {
+                if s.connection != connection {
+                    break;
+                }
                 if !s.valid && s.get_translation_count().unwrap() == s.last_sequence.unwrap() {
                     break;
                 }
+                if max_session_seconds()
+                    .is_some_and(|max| (Utc::now() - s.created_at).num_seconds() > max)
+                {
+                    reason = CloseReason::MaxDuration;
+                    break;
+                }
             }
             None => {
                 log::warn!("Error getting session {}, bailing", session_id);
                 break;
             }
         }
-        let _ = user_message(session_id, msg).await;
+        match decoder.as_mut() {
+            Some(decoder) if msg.is_binary() => {
+                if let Err(e) = decoder.write(msg.as_bytes()).await {
+                    log::warn!("Decoding session {} failed: {}", session_id, e);
+                    crate::metrics::websocket_error("decode");
+                    reason = CloseReason::Error(e.to_string());
+                    break;
+                }
+            }
+            _ => {
+                let _ = user_message(session_id, msg).await;
+            }
+        }
+    }
+    // the last of the compressed audio is decoded before the session is closed
+    if let Some(decoder) = decoder {
+        if let Err(e) = decoder.finish().await {
+            log::warn!("Decoding session {} failed: {}", session_id, e);
+        }
+    }
+    if let Some(reader) = reader {
+        let _ = reader.await;
     }
-    log::debug!("Marking session {} for closure", session_id);
-    mark_session_for_closure(session_id).await;
     drop(user_ws_rx);
+    // whether this is still the learner's connection to the session, which hasn't been closed
+    let current = move || async move {
+        get_session(&session_id)
+            .await
+            .is_some_and(|s| s.connection == connection && s.close_reason.is_none())
+    };
+    if !current().await {
+        log::debug!("Exiting user_connected event loop of a replaced connection");
+        return;
+    }
+    if reason.resumable() {
+        // waiting in a task of its own lets go of the connection, which counts against the address
+        tokio::spawn(async move {
+            log::debug!(
+                "Waiting {}s for session {} to be resumed after {:?}",
+                RESUME_GRACE_SECONDS,
+                session_id,
+                reason
+            );
+            tokio::time::sleep(Duration::from_secs(RESUME_GRACE_SECONDS)).await;
+            if current().await {
+                mark_session_for_closure(session_id, reason).await;
+            } else {
+                log::debug!("Session {} was resumed or closed meanwhile", session_id);
+            }
+        });
+    } else {
+        log::debug!("Marking session {} for closure: {:?}", session_id, reason);
+        mark_session_for_closure(session_id, reason).await;
+    }
     log::debug!("Exiting user_connected event loop");
 }
 
-pub async fn mark_session_for_closure_uuid(uuid: String) {
+pub async fn mark_session_for_closure_uuid(uuid: String, reason: CloseReason) {
     if let Some(session_id) = find_session_with_uuid(&uuid).await {
-        mark_session_for_closure(session_id).await;
+        mark_session_for_closure(session_id, reason).await;
+    }
+}
+
+/**
+ * Closes the sessions still going on when the server shuts down, sending
+ * the last of their audio to be transcribed, and saves what has been
+ * transcribed of them so far in case that doesn't finish.
+ */
+pub async fn close_open_sessions(reason: CloseReason) {
+    let open: Vec<usize> = SESSIONS
+        .read()
+        .await
+        .values()
+        .filter(|s| s.valid && s.close_reason.is_none())
+        .map(|s| s.id)
+        .collect();
+    for session_id in open {
+        mark_session_for_closure(session_id, reason.clone()).await;
+    }
+    save_unfinished_sessions().await;
+}
+
+/// Saves the transcript and metadata of the sessions which haven't finished, as they are.
+pub async fn save_unfinished_sessions() {
+    let unfinished: Vec<SessionData> = SESSIONS
+        .read()
+        .await
+        .values()
+        .filter(|s| s.valid)
+        .cloned()
+        .collect();
+    for session in unfinished {
+        if let Err(e) = session
+            .record_transcript()
+            .and_then(|_| session.write_metadata())
+        {
+            log::error!("Couldn't save session {}: {:?}", session.id, e);
+        }
+        if let Err(e) = crate::store::save(&session).await {
+            log::error!("Couldn't save session {}: {:?}", session.id, e);
+        }
     }
 }
 
@@ -470,18 +1577,37 @@ There will be no more audio coming in. So:
 - send the rest of the buffered audio for translation
 - set session.last_sequence to session.sequence_number
 - increment session.sequence_number, in case one day we do restartable sessions
+
+The session's close reason is set unless an earlier closure set it.
 */
-pub async fn mark_session_for_closure(session_id: usize) {
+pub async fn mark_session_for_closure(session_id: usize, reason: CloseReason) {
+    let first_closure = get_session(&session_id)
+        .await
+        .is_some_and(|s| s.close_reason.is_none());
+    // the first reason is the one which counts
+    mutate_session(&session_id, |session| {
+        session.close_reason.get_or_insert(reason.clone());
+    })
+    .await;
+    if first_closure {
+        if let Some(seconds) = crate::config::CONFIG.delete_recordings_after_seconds {
+            delete_recording_after(session_id, Duration::from_secs(seconds));
+        }
+    }
     let session = get_session(&session_id).await.unwrap();
+    session.record_status("closing");
     if session.sequence_number == 0 {
-        // session was never used.
+        // session was never used, or too little to have sent a piece, which
+        // is flushed as the last when the sender is dropped
         mutate_session(&session_id, |session| {
             session.transcription_sender_tx = None;
+            session.last_sequence = Some(0);
         })
         .await;
         return;
     }
     let payload = session.buffer.to_vec();
+    let length = payload.len();
     let lang = session.language.clone();
     match persist_session_data(&session, payload.len()) {
         Ok(_) => (),
@@ -492,13 +1618,19 @@ pub async fn mark_session_for_closure(session_id: usize) {
         session.buffer.len(),
         session_id
     );
-    match queue::get_queue().enqueue(translate::TranslationRequest {
-        session_id,
-        sequence_number: session.sequence_number,
-        payload,
-        lang,
-    }) {
-        Ok(_) => (),
+    match scheduler::enqueue(
+        translate::TranslationRequest {
+            session_id,
+            sequence_number: session.sequence_number,
+            payload,
+            lang,
+        },
+        Priority::Live,
+    ) {
+        Ok(_) => session.record_event(ReplayPayload::TranscriptionRequested {
+            sequence_number: session.sequence_number,
+            samples: length,
+        }),
         Err(e) => log::error!("Error enqueuing final audio: {:?}", e),
     };
     let last_sequence = session.sequence_number;
@@ -511,27 +1643,685 @@ pub async fn mark_session_for_closure(session_id: usize) {
         session.buffer = vec![];
         session.last_sequence = Some(last_sequence);
         session.sequence_number = last_sequence + 1;
+        session.sequence_lengths.push(length);
     })
     .await;
 }
 
-pub async fn expire_sessions() -> E<()> {
-    let now = Utc::now().timestamp();
-    for (session_id, session) in (*SESSIONS).read().await.iter() {
-        if now - session.updated_at.timestamp() > 86400 {
-            remove_session(session_id).await;
+/**
+ * Transcribes a finished session again from its recording, for instance
+ * after the transcription backend has been improved. The recording is cut
+ * at silences as it would have been when it was streamed, and the session
+ * is finalized again, overwriting its transcript, when the last piece has
+ * been transcribed.
+ */
+pub async fn reprocess_session(session_id: usize) -> E<()> {
+    let session = get_session(&session_id)
+        .await
+        .ok_or(Er::new(format!("Session {} not found", session_id)))?;
+    let filename = session
+        .recording_file
+        .clone()
+        .ok_or(Er::new(format!("Session {} has no recording", session_id)))?;
+    let (samples, _) = crate::audio::read_audio(&filename)?;
+    if samples.is_empty() {
+        return Err(Er::new(format!("Recording {} is empty", filename)));
+    }
+    let payloads = split_at_silences(&samples, session.sample_rate);
+    let count = payloads.len();
+    let lengths: Vec<usize> = payloads.iter().map(|p| p.len()).collect();
+    log::debug!("Reprocessing session {} in {} pieces", session_id, count);
+    crate::pronunciation::clear_cache(&session);
+    session.record_status("reprocessing");
+    mutate_session(&session_id, |session| {
+        session.translations = Arc::new(Mutex::new(TranslationResponses::new()));
+        session.scores = Arc::new(Mutex::new(HashMap::new()));
+        session.valid = true;
+        session.sequence_number = count;
+        session.last_sequence = Some(count - 1);
+        session.sequence_lengths = lengths.clone();
+    })
+    .await;
+    for (sequence_number, payload) in payloads.into_iter().enumerate() {
+        scheduler::enqueue(
+            translate::TranslationRequest {
+                session_id,
+                sequence_number,
+                payload,
+                lang: session.language.clone(),
+            },
+            Priority::Batch,
+        )?;
+    }
+    Ok(())
+}
+
+/**
+ * Deletes a session's recording and everything made from it, keeping only
+ * its metadata and transcript so that it can still be listed and restored.
+ * Files which can't be deleted are tried after the others, and the first
+ * error is returned once all have been. The recordings of sessions still
+ * being recorded or transcribed can't be deleted.
+ */
+pub async fn delete_recording(session_id: usize) -> E<()> {
+    let session = get_session(&session_id)
+        .await
+        .ok_or(Er::new(format!("Session {} not found", session_id)))?;
+    if session.recording_deleted_at.is_some() {
+        return Ok(());
+    }
+    if session.valid {
+        return Err(Er::with_kind(
+            Kind::SessionNotReady,
+            "The session is still being recorded".to_string(),
+        ));
+    }
+    let deleted_at = Utc::now();
+    mutate_session(&session_id, |session| {
+        session.recording_deleted_at = Some(deleted_at);
+    })
+    .await;
+    let session = SessionData {
+        recording_deleted_at: Some(deleted_at),
+        ..session
+    };
+    // so the recording is still known to be gone after a restart
+    session.write_metadata()?;
+    crate::store::save(&session).await?;
+    let Some(directory) = session.directory() else {
+        return Ok(());
+    };
+    let transcript = session
+        .transcript_file
+        .as_ref()
+        .and_then(|t| std::path::Path::new(t).file_name())
+        .map(|t| t.to_os_string());
+    let mut first_error: Option<Box<dyn std::error::Error>> = None;
+    for entry in std::fs::read_dir(&directory)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        if name == "metadata.json" || Some(&name) == transcript.as_ref() {
+            continue;
+        }
+        let removed = if entry.file_type()?.is_dir() {
+            std::fs::remove_dir_all(entry.path())
+        } else {
+            std::fs::remove_file(entry.path())
+        };
+        if let Err(e) = removed {
+            log::warn!("Couldn't delete {:?}: {:?}", entry.path(), e);
+            first_error.get_or_insert(e.into());
         }
     }
+    log::info!("Deleted the recording of session {}", session.uuid);
+    match first_error {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+/// Deletes a session's recording once `delay` has passed and the session has finished.
+fn delete_recording_after(session_id: usize, delay: Duration) {
+    tokio::spawn(async move {
+        tokio::time::sleep(delay).await;
+        // the recording is written to until the last piece is transcribed
+        while get_session(&session_id).await.is_some_and(|s| s.valid) {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+        if let Err(e) = delete_recording(session_id).await {
+            log::warn!(
+                "Couldn't delete the recording of session {}: {:?}",
+                session_id,
+                e
+            );
+        }
+    });
+}
+
+/**
+ * Deletes the recordings of the restored sessions which were closed more
+ * than `delete_recordings_after_seconds` ago, as their deletion was lost
+ * with the server which was to do it, and schedules it for the others.
+ */
+async fn schedule_recording_deletions() {
+    let Some(seconds) = crate::config::CONFIG.delete_recordings_after_seconds else {
+        return;
+    };
+    let restored: Vec<(usize, DateTime<Utc>)> = SESSIONS
+        .read()
+        .await
+        .values()
+        .filter(|s| !s.valid && s.recording_deleted_at.is_none())
+        .map(|s| (s.id, s.updated_at))
+        .collect();
+    for (session_id, updated_at) in restored {
+        // sessions are last updated when they finish
+        let closed_for = (Utc::now() - updated_at).to_std().unwrap_or_default();
+        delete_recording_after(
+            session_id,
+            Duration::from_secs(seconds).saturating_sub(closed_for),
+        );
+    }
+}
+
+/// Cuts a recording at silences, as it would have been when it was streamed.
+fn split_at_silences(samples: &[f32], sample_rate: u32) -> Vec<Vec<f32>> {
+    let mut payloads = vec![];
+    let mut rest = samples;
+    while let Some(pivot) = translate::find_silence(rest, sample_rate) {
+        payloads.push(rest[..pivot].to_vec());
+        rest = &rest[pivot..];
+    }
+    if !rest.is_empty() {
+        payloads.push(rest.to_vec());
+    }
+    payloads
+}
+
+/// The sample rate the transcription backends expect the audio sent to them in.
+pub const UPLOAD_SAMPLE_RATE: u32 = 44100;
+
+/**
+ * Makes a session from a recording made elsewhere, for instance on a phone,
+ * as if it had been streamed in: the audio is saved as the session's
+ * recording, cut at silences and transcribed, and the session is finalized
+ * when the last piece has been. WAV and FLAC files are read, and are
+ * resampled to `UPLOAD_SAMPLE_RATE`, while other formats such as MP3 and
+ * Opus are decoded to it with ffmpeg. Returns the new session's UUID.
+ */
+#[allow(clippy::too_many_arguments)]
+pub async fn upload_session(
+    audio: Vec<u8>,
+    lang: String,
+    resource: Option<String>,
+    client: Option<String>,
+    user: Option<String>,
+    base_url: Option<String>,
+    backend: Backend,
+    model: Option<String>,
+    diarize: bool,
+) -> E<Uuid> {
+    if recordings_dir().is_none() {
+        return Err(Er::new(
+            "Uploads are kept with the recordings, and there is no recordings_dir".to_string(),
+        ));
+    }
+    let session_id = NEXT_USER_ID.fetch_add(1, Ordering::Relaxed);
+    let (transcription_send_tx, _) = unbounded();
+    let mut session = SessionData::new(
+        session_id,
+        transcription_send_tx,
+        lang,
+        UPLOAD_SAMPLE_RATE,
+        resource,
+        None,
+    );
+    // nobody is listening for the transcription as it comes in
+    session.transcription_sender_tx = None;
+    session.client = client;
+    session.user = user;
+    session.base_url = base_url;
+    session.backend = backend;
+    session.model = model;
+    session.diarize = diarize;
+    let directory = session.directory().ok_or(Er::new(format!(
+        "No directory for session {}",
+        session.uuid
+    )))?;
+    let upload_directory = directory.clone();
+    let prepared = tokio::task::spawn_blocking(move || {
+        prepare_upload(&mut session, &upload_directory, audio).map(|payloads| (session, payloads))
+    })
+    .await
+    .map_err(|e| Er::from_error(&e))
+    .and_then(|prepared| prepared);
+    let (mut session, payloads) = match prepared {
+        Ok(prepared) => prepared,
+        Err(e) => {
+            // nothing is kept of an upload which can't be transcribed
+            if let Err(removed) = std::fs::remove_dir_all(&directory) {
+                log::warn!("Couldn't delete {}: {:?}", directory, removed);
+            }
+            return Err(Box::new(e));
+        }
+    };
+    let count = payloads.len();
+    log::debug!("Transcribing upload {} in {} pieces", session.uuid, count);
+    let uuid = session.uuid;
+    let lang = session.language.clone();
+    session.buffer = vec![];
+    session.sequence_number = count;
+    session.last_sequence = Some(count - 1);
+    session.sequence_lengths = payloads.iter().map(|p| p.len()).collect();
+    session.record_status("open");
+    for (sequence_number, payload) in payloads.iter().enumerate() {
+        session.record_event(ReplayPayload::TranscriptionRequested {
+            sequence_number,
+            samples: payload.len(),
+        });
+    }
+    set_session(session_id, session).await;
+    let requests = payloads
+        .into_iter()
+        .enumerate()
+        .map(|(sequence_number, payload)| translate::TranslationRequest {
+            session_id,
+            sequence_number,
+            payload,
+            lang: lang.clone(),
+        })
+        .collect();
+    if let Err(e) =
+        scheduler::enqueue_all(requests, Priority::Batch).map_err(|e| Er::from_error(&*e))
+    {
+        // none of it was queued, so the session would never finish
+        remove_session(&session_id).await;
+        if let Err(removed) = std::fs::remove_dir_all(&directory) {
+            log::warn!("Couldn't delete {}: {:?}", directory, removed);
+        }
+        return Err(Box::new(e));
+    }
+    Ok(uuid)
+}
+
+/**
+ * Decodes an upload into the session's recording, resampled to
+ * UPLOAD_SAMPLE_RATE, and cuts it at silences to be transcribed. It
+ * writes, decodes and resamples the whole upload, so it is run off the
+ * async runtime.
+ */
+fn prepare_upload(
+    session: &mut SessionData,
+    directory: &str,
+    audio: Vec<u8>,
+) -> Result<Vec<Vec<f32>>, Er> {
+    // anything but WAV and FLAC is left to ffmpeg
+    let extension = if audio.starts_with(b"fLaC") {
+        "flac"
+    } else if audio.starts_with(b"RIFF") {
+        "wav"
+    } else {
+        "audio"
+    };
+    let upload = format!("{}/upload.{}", directory, extension);
+    std::fs::write(&upload, audio).map_err(|e| Er::from_error(&e))?;
+    let decoded = match extension {
+        "audio" => crate::audio::decode_file(&upload, UPLOAD_SAMPLE_RATE)
+            .map(|samples| (samples, UPLOAD_SAMPLE_RATE)),
+        _ => crate::audio::read_audio(&upload),
+    };
+    std::fs::remove_file(&upload).map_err(|e| Er::from_error(&e))?;
+    let (samples, sample_rate) = match decoded {
+        Ok(decoded) if !decoded.0.is_empty() => decoded,
+        Ok(_) => {
+            return Err(*Er::with_kind(
+                Kind::BadRequest,
+                "The audio is empty".to_string(),
+            ))
+        }
+        Err(e) => {
+            return Err(*Er::with_kind(
+                Kind::BadRequest,
+                format!("Couldn't read the audio: {}", e),
+            ))
+        }
+    };
+    session.buffer = if sample_rate == UPLOAD_SAMPLE_RATE {
+        samples
+    } else {
+        translate::resample_to(&samples, sample_rate as f64, UPLOAD_SAMPLE_RATE as f64).map_err(
+            |e| {
+                *Er::with_kind(
+                    Kind::BadRequest,
+                    format!("Couldn't resample audio at {}Hz: {}", sample_rate, e),
+                )
+            },
+        )?
+    };
+    persist_session_data(session, session.buffer.len()).map_err(|e| Er::from_error(&*e))?;
+    Ok(split_at_silences(&session.buffer, UPLOAD_SAMPLE_RATE))
+}
+
+/// The session's recording, which is a FLAC file once it has been vacuumed.
+fn recording_filename(dir: &str, uuid: &Uuid) -> String {
+    let flac = format!("{}/{}/{}.flac", dir, uuid, uuid);
+    if std::path::Path::new(&flac).exists() {
+        flac
+    } else {
+        format!("{}/{}/{}.wav", dir, uuid, uuid)
+    }
+}
+
+/**
+ * Keeps the learner's rating of a session with it. The session's metadata
+ * is written again if it has already finished, and otherwise will be when
+ * it does.
+ */
+pub async fn rate_session(session_id: usize, rating: SelfRating) -> E<()> {
+    mutate_session(&session_id, |session| session.rating = Some(rating.clone())).await;
+    let session = get_session(&session_id)
+        .await
+        .ok_or(Er::new(format!("Session {} not found", session_id)))?;
+    if !session.valid {
+        session.write_metadata()?;
+        crate::store::save(&session).await?;
+    }
     Ok(())
 }
 
+/// A session whose recording `vacuum` left as it was, and why.
+#[derive(Clone, Debug, Serialize)]
+pub struct VacuumSkipped {
+    pub uuid: Uuid,
+    pub reason: String,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct VacuumReport {
+    pub converted: usize,
+    pub bytes_saved: u64,
+    pub skipped: Vec<VacuumSkipped>,
+}
+
+/**
+ * Compresses the WAV recordings of closed sessions last updated before
+ * `cutoff` to FLAC. A recording which can't be converted is left as it is,
+ * and listed in the report with the reason.
+ */
+pub async fn vacuum(cutoff: DateTime<Utc>) -> VacuumReport {
+    let candidates: Vec<(usize, Uuid, String)> = SESSIONS
+        .read()
+        .await
+        .values()
+        .filter(|s| !s.valid && s.updated_at < cutoff)
+        .filter_map(|s| Some((s.id, s.uuid, s.recording_file.clone()?)))
+        .filter(|(_, _, f)| f.ends_with(".wav") && std::path::Path::new(f).exists())
+        .collect();
+
+    let mut report = VacuumReport {
+        converted: 0,
+        bytes_saved: 0,
+        skipped: vec![],
+    };
+    for (session_id, uuid, wav) in candidates {
+        let flac = format!("{}.flac", wav.trim_end_matches(".wav"));
+        let converted = flac.clone();
+        match tokio::task::spawn_blocking(move || {
+            crate::audio::wav_to_flac(&wav, &converted).map_err(|e| e.to_string())
+        })
+        .await
+        {
+            Ok(Ok(saved)) => {
+                mutate_session(&session_id, |session| {
+                    session.recording_file = Some(flac.clone())
+                })
+                .await;
+                if let Some(session) = get_session(&session_id).await {
+                    if let Err(e) = crate::store::save(&session).await {
+                        log::error!("Couldn't save session {}: {:?}", session_id, e);
+                    }
+                }
+                report.converted += 1;
+                report.bytes_saved += saved;
+            }
+            Ok(Err(reason)) => {
+                log::warn!("Not vacuuming session {}: {}", uuid, reason);
+                report.skipped.push(VacuumSkipped { uuid, reason });
+            }
+            Err(e) => {
+                log::error!("Error vacuuming session {}: {:?}", uuid, e);
+                report.skipped.push(VacuumSkipped {
+                    uuid,
+                    reason: e.to_string(),
+                });
+            }
+        }
+    }
+    report
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct ResourceUsage {
+    pub resource: String,
+    pub sessions: usize,
+    pub recording_minutes: f32,
+    /// the mean word error rate of the finished sessions which could be scored
+    pub mean_wer: Option<f32>,
+    /// the mean of the ratings learners gave their sessions, see [SelfRating]
+    pub mean_self_rating: Option<f32>,
+    /// how many of the sessions were closed for each reason, see [CloseReason::label]
+    pub close_reasons: BTreeMap<String, usize>,
+}
+
+/**
+ * How much each resource has been practiced in sessions started since
+ * `cutoff`, most practiced first. Resources nobody has practiced are at
+ * the end with no sessions.
+ */
+pub async fn resource_usage(cutoff: DateTime<Utc>) -> Vec<ResourceUsage> {
+    let sessions: Vec<SessionData> = SESSIONS
+        .read()
+        .await
+        .values()
+        .filter(|s| s.created_at >= cutoff && s.resource.is_some())
+        .cloned()
+        .collect();
+
+    // the usage of each resource, with the word error rates and ratings of its sessions
+    let mut usage: HashMap<String, (ResourceUsage, Vec<f32>, Vec<f32>)> = HashMap::new();
+    for resource in crate::metadata::list_resources().unwrap_or_default() {
+        usage.insert(
+            resource.clone(),
+            (
+                ResourceUsage {
+                    resource,
+                    sessions: 0,
+                    recording_minutes: 0.0,
+                    mean_wer: None,
+                    mean_self_rating: None,
+                    close_reasons: BTreeMap::new(),
+                },
+                vec![],
+                vec![],
+            ),
+        );
+    }
+    for session in sessions {
+        let resource = session.resource.clone().unwrap_or_default();
+        let wer = if session.valid {
+            None
+        } else {
+            crate::compare::score(
+                resource.clone(),
+                session.uuid.to_string(),
+                session.language.clone(),
+            )
+            .await
+            .ok()
+            .map(|s| s.wer)
+        };
+        let (entry, wers, ratings) = usage.entry(resource.clone()).or_insert((
+            ResourceUsage {
+                resource,
+                sessions: 0,
+                recording_minutes: 0.0,
+                mean_wer: None,
+                mean_self_rating: None,
+                close_reasons: BTreeMap::new(),
+            },
+            vec![],
+            vec![],
+        ));
+        entry.sessions += 1;
+        entry.recording_minutes += session
+            .recording_file
+            .as_ref()
+            .and_then(|f| crate::audio::duration_seconds(f).ok())
+            .unwrap_or(0.0)
+            / 60.0;
+        wers.extend(wer);
+        ratings.extend(session.rating.as_ref().map(|r| r.self_rating as f32));
+        if let Some(reason) = &session.close_reason {
+            *entry
+                .close_reasons
+                .entry(reason.label().to_string())
+                .or_default() += 1;
+        }
+    }
+
+    let mut usage: Vec<ResourceUsage> = usage
+        .into_values()
+        .map(|(mut entry, wers, ratings)| {
+            let mean = |values: Vec<f32>| {
+                (!values.is_empty()).then(|| values.iter().sum::<f32>() / values.len() as f32)
+            };
+            entry.mean_wer = mean(wers);
+            entry.mean_self_rating = mean(ratings);
+            entry
+        })
+        .collect();
+    usage.sort_by(|a, b| {
+        b.sessions
+            .cmp(&a.sessions)
+            .then_with(|| a.resource.cmp(&b.resource))
+    });
+    usage
+}
+
+/**
+ * Deletes a finished session for good: its directory with the recording,
+ * transcript and metadata, and its entry, so it isn't restored either.
+ * Sessions still being recorded or transcribed can't be deleted.
+ */
+pub async fn delete_session(session_id: usize) -> E<()> {
+    let session = get_session(&session_id)
+        .await
+        .ok_or(Er::new(format!("Session {} not found", session_id)))?;
+    if session.valid {
+        return Err(Er::with_kind(
+            Kind::SessionNotReady,
+            "The session is still being recorded".to_string(),
+        ));
+    }
+    if let Some(directory) = session.directory() {
+        match std::fs::remove_dir_all(&directory) {
+            Ok(_) => (),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e.into()),
+        }
+    }
+    crate::store::delete(&session).await?;
+    crate::pronunciation::clear_cache(&session);
+    remove_session(&session_id).await;
+    log::info!("Deleted session {}", session.uuid);
+    Ok(())
+}
+
+/// How many bytes the files in `directory` take up, with those of its subdirectories.
+fn directory_size(directory: &str) -> u64 {
+    let Ok(entries) = std::fs::read_dir(directory) else {
+        return 0;
+    };
+    entries
+        .flatten()
+        .map(|entry| match entry.metadata() {
+            Ok(m) if m.is_dir() => directory_size(&entry.path().display().to_string()),
+            Ok(m) => m.len(),
+            Err(_) => 0,
+        })
+        .sum()
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct RetentionReport {
+    pub deleted: usize,
+    pub bytes_freed: u64,
+}
+
+/**
+ * Deletes the finished sessions last updated more than
+ * `delete_sessions_after_days` ago, and then the oldest of the others
+ * until the sessions take up no more than `max_recordings_bytes` on disk.
+ * Sessions which can't be deleted are left for the next time.
+ */
+pub async fn expire_sessions() -> RetentionReport {
+    let config = &crate::config::CONFIG;
+    let saved: Vec<(usize, DateTime<Utc>, bool, Option<String>)> = SESSIONS
+        .read()
+        .await
+        .values()
+        .map(|s| (s.id, s.updated_at, s.valid, s.directory()))
+        .collect();
+    let mut sessions: Vec<(usize, DateTime<Utc>, bool, u64)> = saved
+        .into_iter()
+        .map(|(id, updated_at, valid, directory)| {
+            let size = directory.map(|d| directory_size(&d)).unwrap_or(0);
+            (id, updated_at, valid, size)
+        })
+        .collect();
+    sessions.sort_by_key(|(_, updated_at, _, _)| *updated_at);
+    let mut total: u64 = sessions.iter().map(|(_, _, _, size)| size).sum();
+    let cutoff = config
+        .delete_sessions_after_days
+        .map(|days| Utc::now() - chrono::Duration::days(days as i64));
+
+    let mut report = RetentionReport::default();
+    for (session_id, updated_at, valid, size) in sessions {
+        let expired = cutoff.is_some_and(|cutoff| updated_at < cutoff);
+        let too_big = config.max_recordings_bytes.is_some_and(|max| total > max);
+        if valid || !(expired || too_big) {
+            continue;
+        }
+        match delete_session(session_id).await {
+            Ok(_) => {
+                total -= size;
+                report.deleted += 1;
+                report.bytes_freed += size;
+            }
+            Err(e) => log::warn!("Couldn't delete session {}: {:?}", session_id, e),
+        }
+    }
+    report
+}
+
+/// How often `expire_sessions` runs when the config file asks for sessions to be deleted.
+pub const RETENTION_INTERVAL_SECONDS: u64 = 3600;
+
+/// Runs `expire_sessions` every `RETENTION_INTERVAL_SECONDS`, if there is a limit for it to keep to.
+pub fn start_retention_task() {
+    let config = &crate::config::CONFIG;
+    if config.delete_sessions_after_days.is_none() && config.max_recordings_bytes.is_none() {
+        return;
+    }
+    tokio::spawn(async {
+        let mut interval = tokio::time::interval(Duration::from_secs(RETENTION_INTERVAL_SECONDS));
+        loop {
+            interval.tick().await;
+            let report = expire_sessions().await;
+            if report.deleted > 0 {
+                log::info!(
+                    "Deleted {} sessions, freeing {} bytes",
+                    report.deleted,
+                    report.bytes_freed
+                );
+            }
+        }
+    });
+}
+
+/**
+ * Appends the first `length` samples of the session's buffer to its
+ * recording. New recordings are RECORDING_BITS bit integer WAV files,
+ * which `vacuum` can compress without losing anything, while those begun
+ * as 32 bit float files before stay so.
+ */
 fn persist_session_data(session: &SessionData, length: usize) -> E<()> {
     if let Some(filename) = &session.recording_file {
         let spec = hound::WavSpec {
             channels: 1,
             sample_rate: session.sample_rate,
-            bits_per_sample: 32,
-            sample_format: hound::SampleFormat::Float,
+            bits_per_sample: crate::audio::RECORDING_BITS,
+            sample_format: hound::SampleFormat::Int,
         };
 
         let mut writer = if std::path::Path::exists(std::path::Path::new(&filename)) {
@@ -539,17 +2329,47 @@ fn persist_session_data(session: &SessionData, length: usize) -> E<()> {
         } else {
             hound::WavWriter::create(filename, spec)?
         };
+        let spec = writer.spec();
         for sample in &session.buffer[..length] {
-            writer.write_sample(*sample).unwrap();
+            match spec.sample_format {
+                hound::SampleFormat::Float => writer.write_sample(*sample)?,
+                hound::SampleFormat::Int => {
+                    writer.write_sample(crate::audio::int_sample(*sample, spec.bits_per_sample))?
+                }
+            }
         }
+        // the header says how long the recording is, so it has to be written before a restart
+        writer.finalize()?;
     }
 
     Ok(())
 }
 
+/**
+ * Restores the sessions saved before the server was last stopped: from
+ * the database when there is one, and otherwise from the metadata and
+ * transcripts in the recordings directory.
+ */
 pub async fn restore_sessions() -> E<()> {
+    if crate::store::enabled() {
+        let restored_sessions = crate::store::load()
+            .await?
+            .into_iter()
+            .map(|stored| {
+                let sequences = stored.session.sequence_lengths.len().max(1);
+                SessionData {
+                    translations: Arc::new(Mutex::new(stored.segments)),
+                    sequence_number: sequences,
+                    last_sequence: Some(sequences),
+                    ..stored.session
+                }
+            })
+            .collect();
+        insert_restored_sessions(restored_sessions).await;
+        return Ok(());
+    }
     let mut saved_sessions: Vec<SavedSessionData> = vec![];
-    if let Ok(dir) = std::env::var("RECORDINGS_DIR") {
+    if let Some(dir) = recordings_dir() {
         for entry in std::fs::read_dir(dir.clone())? {
             let entry = entry?;
             if entry.metadata()?.is_dir() {
@@ -571,16 +2391,10 @@ pub async fn restore_sessions() -> E<()> {
                 }
             }
         }
-        let mut next_id: usize = 0;
-        let mut get_id = move || {
-            let id = next_id;
-            next_id += 1;
-            id
-        };
         let restored_sessions: Vec<SessionData> = saved_sessions
             .iter()
             .map(|s| SessionData {
-                id: get_id(),
+                id: 0,
                 transcription_sender_tx: None,
                 language: s.language.clone(),
                 uuid: s.uuid,
@@ -589,29 +2403,59 @@ pub async fn restore_sessions() -> E<()> {
                 valid: false,
                 buffer: vec![],
                 silence_length: 0,
-                sequence_number: 1,
-                last_sequence: Some(1),
+                sequence_number: s.sequence_lengths.len().max(1),
+                last_sequence: Some(s.sequence_lengths.len().max(1)),
+                sequence_lengths: s.sequence_lengths.clone(),
                 recording: false,
-                recording_file: Some(format!("{}/{}/{}.wav", dir, s.uuid, s.uuid)),
+                recording_file: Some(recording_filename(&dir, &s.uuid)),
                 transcript_file: Some(format!("{}/{}/{}.txt", dir, s.uuid, s.uuid)),
-                translations: Arc::new(Mutex::new(TranslationResponses::new_from_string(
-                    match &s.transcript {
-                        Some(s) => s.clone(),
-                        None => "transcript not found! This is probably a bug.".to_string(),
-                    },
-                    s.uuid.to_string(),
-                ))),
+                recording_deleted_at: s.recording_deleted_at,
+                translations: Arc::new(Mutex::new(match &s.segments {
+                    Some(segments) => segments.clone(),
+                    None => TranslationResponses::new_from_string(
+                        match &s.transcript {
+                            Some(s) => s.clone(),
+                            None => "transcript not found! This is probably a bug.".to_string(),
+                        },
+                        s.uuid.to_string(),
+                    ),
+                })),
+                scores: Arc::new(Mutex::new(HashMap::new())),
+                events: Arc::new(Mutex::new(vec![])),
+                client: s.client.clone(),
+                user: s.user.clone(),
+                base_url: None,
+                language_detected: s.language_detected,
+                diarize: s.diarize,
+                mode: s.mode,
+                renditions: s.renditions.clone(),
+                backend: s.backend,
+                model: s.model.clone(),
+                codec: s.codec,
+                connection: 0,
+                close_reason: s.close_reason.clone(),
+                rating: s.rating.clone(),
                 updated_at: s.updated_at,
                 created_at: s.created_at,
             })
             .collect();
-        for restored_session in restored_sessions {
-            SESSIONS
-                .write()
-                .await
-                .insert(restored_session.id, restored_session);
-        }
-        NEXT_USER_ID.store(get_id(), Ordering::Relaxed);
+        insert_restored_sessions(restored_sessions).await;
     }
     Ok(())
 }
+
+/// Numbers the restored sessions from 0 and keeps them, new sessions being numbered after them.
+async fn insert_restored_sessions(restored_sessions: Vec<SessionData>) {
+    let count = restored_sessions.len();
+    for (id, restored_session) in restored_sessions.into_iter().enumerate() {
+        SESSIONS.write().await.insert(
+            id,
+            SessionData {
+                id,
+                ..restored_session
+            },
+        );
+    }
+    NEXT_USER_ID.store(count, Ordering::Relaxed);
+    schedule_recording_deletions().await;
+}
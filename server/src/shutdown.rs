@@ -0,0 +1,87 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::config::CONFIG;
+use crate::error::{Er, Kind, E};
+use crate::session::CloseReason;
+
+/// How long the sessions being closed are given to be transcribed unless configured otherwise.
+pub const DEFAULT_SHUTDOWN_TIMEOUT_SECONDS: u64 = 30;
+/// How long the connections still open, like idle websockets, are waited for once the sessions are saved.
+pub const SHUTDOWN_LINGER_SECONDS: u64 = 5;
+/// How often the sessions being closed are looked at while waiting for them.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+static SHUTTING_DOWN: AtomicBool = AtomicBool::new(false);
+
+/// Whether the server has been told to stop.
+pub fn is_shutting_down() -> bool {
+    SHUTTING_DOWN.load(Ordering::SeqCst)
+}
+
+/// Refuses to start or resume sessions once the server is stopping, with 503 `server_busy`.
+pub fn check() -> E<()> {
+    if is_shutting_down() {
+        return Err(Er::with_kind(
+            Kind::ServerBusy,
+            "The server is shutting down, try again later".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// Waits for Ctrl-C, or SIGTERM as service managers and container runtimes send it.
+async fn signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => (),
+                    _ = terminate.recv() => (),
+                }
+                return;
+            }
+            Err(e) => log::warn!("Couldn't listen for SIGTERM: {}", e),
+        }
+    }
+    tokio::signal::ctrl_c().await.ok();
+}
+
+/**
+ * Waits for the server to be told to stop, then stops it in order. New
+ * sessions and uploads are refused, the sessions going on are closed,
+ * writing the last of their audio to their recordings and sending it to
+ * be transcribed, and the transcription workers are given
+ * `shutdown_timeout_seconds` to finish the pieces waiting for them. The
+ * sessions which didn't finish in time are saved with what has been
+ * transcribed of them.
+ */
+pub async fn shut_down() {
+    signal().await;
+    log::info!("Shutting down");
+    SHUTTING_DOWN.store(true, Ordering::SeqCst);
+    crate::session::close_open_sessions(CloseReason::ServerShutdown).await;
+
+    let timeout = Duration::from_secs(CONFIG.shutdown_timeout_seconds);
+    let started = Instant::now();
+    loop {
+        // the sessions' senders flush the last of their audio as they go, so it is looked for after a moment
+        tokio::time::sleep(POLL_INTERVAL).await;
+        let pending = crate::transcribe::scheduler::pending();
+        if pending == 0 {
+            break;
+        }
+        if started.elapsed() >= timeout {
+            log::warn!(
+                "{} pieces of audio weren't transcribed within {:?}",
+                pending,
+                timeout
+            );
+            break;
+        }
+    }
+    crate::session::save_unfinished_sessions().await;
+    log::info!("Sessions saved");
+}
@@ -0,0 +1,249 @@
+use chrono::{DateTime, Datelike, Duration, NaiveDate, TimeDelta, Utc};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use uuid::Uuid;
+
+use crate::error::{Er, Kind, E};
+use crate::session::SessionData;
+
+/// How the learner did in one finished session.
+#[derive(Clone, Debug, Serialize)]
+pub struct SessionStats {
+    pub uuid: Uuid,
+    pub created_at: DateTime<Utc>,
+    pub resource: String,
+    pub lang: String,
+    /// against the reference translation, when the session could be scored
+    pub wer: Option<f32>,
+    pub wpm: Option<f32>,
+    /// stops of `audio::PAUSE_SECONDS` or more, when the recording is kept
+    pub pauses: Option<usize>,
+    pub pauses_per_minute: Option<f32>,
+    pub duration_secs: Option<f32>,
+}
+
+/// The means of the sessions of a week, which starts on `week_start`, a Monday.
+#[derive(Clone, Debug, Serialize)]
+pub struct WeekStats {
+    pub week_start: NaiveDate,
+    pub sessions: usize,
+    pub wer: Option<f32>,
+    pub wpm: Option<f32>,
+    pub pauses_per_minute: Option<f32>,
+}
+
+/// How the learner has done practising a resource into `lang`.
+#[derive(Clone, Debug, Serialize)]
+pub struct ResourceStats {
+    pub resource: String,
+    pub lang: String,
+    pub sessions: usize,
+    pub first_wer: Option<f32>,
+    pub latest_wer: Option<f32>,
+    pub best_wer: Option<f32>,
+}
+
+/**
+ * How much each measure has changed from the first week with a value for
+ * it to the last: a falling `wer` and `pauses_per_minute` and a rising
+ * `wpm` are improvements.
+ */
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Trend {
+    pub wer: Option<f32>,
+    pub wpm: Option<f32>,
+    pub pauses_per_minute: Option<f32>,
+}
+
+/// A learner's finished sessions, newest first, with their weekly means and trend.
+#[derive(Clone, Debug, Serialize)]
+pub struct Stats {
+    pub user: Option<String>,
+    pub sessions: Vec<SessionStats>,
+    /// oldest first, leaving out weeks without sessions
+    pub weeks: Vec<WeekStats>,
+    pub resources: Vec<ResourceStats>,
+    pub trend: Trend,
+}
+
+/// Which sessions are counted, as `resource`, `lang` and `since_days` ask.
+#[derive(Clone, Debug, Default)]
+pub struct StatsQuery {
+    pub resource: Option<String>,
+    pub lang: Option<String>,
+    pub since: Option<DateTime<Utc>>,
+}
+
+impl StatsQuery {
+    /// The query the parameters make, any of which can be left out; a `since_days` which isn't a number of days gives 400.
+    pub fn from_params(params: &HashMap<String, String>) -> E<Self> {
+        let param = |name: &str| params.get(name).filter(|v| !v.is_empty()).cloned();
+        let since = match param("since_days") {
+            Some(days) => {
+                let bad = || {
+                    Er::with_kind(
+                        Kind::BadRequest,
+                        format!("since_days must be a number of days, not {}", days),
+                    )
+                };
+                let since = days
+                    .parse()
+                    .ok()
+                    .and_then(TimeDelta::try_days)
+                    .and_then(|days| Utc::now().checked_sub_signed(days))
+                    .ok_or_else(bad)?;
+                Some(since)
+            }
+            None => None,
+        };
+        Ok(Self {
+            resource: param("resource"),
+            lang: param("lang"),
+            since,
+        })
+    }
+
+    fn matches(&self, session: &SessionData) -> bool {
+        (self.resource.is_none() || session.resource == self.resource)
+            && self.lang.as_ref().is_none_or(|l| &session.language == l)
+            && self.since.is_none_or(|since| session.created_at >= since)
+    }
+}
+
+fn mean(values: impl Iterator<Item = Option<f32>>) -> Option<f32> {
+    let values: Vec<f32> = values.flatten().collect();
+    if values.is_empty() {
+        return None;
+    }
+    Some(values.iter().sum::<f32>() / values.len() as f32)
+}
+
+/// The change from the first to the last of the weeks with a value.
+fn change(weeks: &[WeekStats], value: impl Fn(&WeekStats) -> Option<f32>) -> Option<f32> {
+    let values: Vec<f32> = weeks.iter().filter_map(value).collect();
+    match (values.first(), values.last()) {
+        (Some(first), Some(last)) if values.len() > 1 => Some(last - first),
+        _ => None,
+    }
+}
+
+/// Scores a session and measures its recording, which is read off the async runtime.
+async fn session_stats(session: &SessionData) -> SessionStats {
+    let resource = session.resource.clone().unwrap_or_default();
+    let score = crate::compare::score(
+        resource.clone(),
+        session.uuid.to_string(),
+        session.language.clone(),
+    )
+    .await
+    .ok();
+    let recording = match session.recording_file.clone() {
+        Some(filename) => tokio::task::spawn_blocking(move || {
+            crate::audio::read_audio(&filename)
+                .ok()
+                .map(|(samples, sample_rate)| {
+                    (
+                        crate::audio::pause_count(&samples, sample_rate),
+                        samples.len() as f32 / sample_rate as f32,
+                    )
+                })
+        })
+        .await
+        .ok()
+        .flatten(),
+        None => None,
+    };
+    let minutes = recording
+        .map(|(_, seconds)| seconds / 60.0)
+        .filter(|minutes| *minutes > 0.0);
+    SessionStats {
+        uuid: session.uuid,
+        created_at: session.created_at,
+        resource,
+        lang: session.language.clone(),
+        wer: score.as_ref().map(|s| s.wer),
+        wpm: score
+            .zip(minutes)
+            .map(|(s, minutes)| s.hypothesis_words as f32 / minutes),
+        pauses: recording.map(|(pauses, _)| pauses),
+        pauses_per_minute: recording
+            .zip(minutes)
+            .map(|((pauses, _), minutes)| pauses as f32 / minutes),
+        duration_secs: recording.map(|(_, seconds)| seconds),
+    }
+}
+
+/**
+ * The statistics of the finished sessions of a learner matching `query`.
+ * Learners are told apart by their `user` when they are logged in, and
+ * otherwise by their address, `client`, as their practice history is;
+ * when neither is known no session is theirs.
+ */
+pub async fn stats(user: Option<&str>, client: Option<&str>, query: &StatsQuery) -> Stats {
+    let mut sessions: Vec<SessionData> = crate::session::SESSIONS
+        .read()
+        .await
+        .values()
+        .filter(|s| !s.valid && s.resource.is_some() && query.matches(s))
+        .filter(|s| match user {
+            Some(user) => s.user.as_deref() == Some(user),
+            None => client.is_some() && s.client.as_deref() == client,
+        })
+        .cloned()
+        .collect();
+    sessions.sort_by_key(|s| s.created_at);
+
+    let mut measured = vec![];
+    for session in &sessions {
+        measured.push(session_stats(session).await);
+    }
+
+    let mut by_week: BTreeMap<NaiveDate, Vec<&SessionStats>> = BTreeMap::new();
+    let mut by_resource: BTreeMap<(String, String), Vec<&SessionStats>> = BTreeMap::new();
+    for s in &measured {
+        let day = s.created_at.date_naive();
+        let week_start = day - Duration::days(day.weekday().num_days_from_monday() as i64);
+        by_week.entry(week_start).or_default().push(s);
+        by_resource
+            .entry((s.resource.clone(), s.lang.clone()))
+            .or_default()
+            .push(s);
+    }
+    let weeks: Vec<WeekStats> = by_week
+        .into_iter()
+        .map(|(week_start, sessions)| WeekStats {
+            week_start,
+            sessions: sessions.len(),
+            wer: mean(sessions.iter().map(|s| s.wer)),
+            wpm: mean(sessions.iter().map(|s| s.wpm)),
+            pauses_per_minute: mean(sessions.iter().map(|s| s.pauses_per_minute)),
+        })
+        .collect();
+    let resources = by_resource
+        .into_iter()
+        .map(|((resource, lang), sessions)| {
+            let wers: Vec<f32> = sessions.iter().filter_map(|s| s.wer).collect();
+            ResourceStats {
+                resource,
+                lang,
+                sessions: sessions.len(),
+                first_wer: wers.first().copied(),
+                latest_wer: wers.last().copied(),
+                best_wer: wers.iter().copied().reduce(f32::min),
+            }
+        })
+        .collect();
+    let trend = Trend {
+        wer: change(&weeks, |w| w.wer),
+        wpm: change(&weeks, |w| w.wpm),
+        pauses_per_minute: change(&weeks, |w| w.pauses_per_minute),
+    };
+    measured.reverse();
+    Stats {
+        user: user.map(String::from),
+        sessions: measured,
+        weeks,
+        resources,
+        trend,
+    }
+}
@@ -0,0 +1,174 @@
+use lazy_static::lazy_static;
+use sqlx::sqlite::{SqliteConnectOptions, SqlitePool, SqlitePoolOptions};
+use sqlx::types::Json;
+use std::sync::Mutex;
+use tokio::runtime::Handle;
+
+use crate::error::{Er, E};
+use crate::session::SessionData;
+use crate::translate::TranslationResponses;
+
+/// The SQLite file `--persist` keeps sessions in unless the config file names another.
+pub const DEFAULT_DATABASE: &str = "terplounge.db";
+
+#[derive(Clone)]
+struct Store {
+    pool: SqlitePool,
+    /// the runtime the pool was opened on, which the transcription workers save through
+    runtime: Handle,
+}
+
+lazy_static! {
+    static ref STORE: Mutex<Option<Store>> = Mutex::new(None);
+}
+
+/// A session as it is kept in the database, with its transcript.
+#[derive(sqlx::FromRow)]
+pub struct StoredSession {
+    #[sqlx(flatten)]
+    pub session: SessionData,
+    #[sqlx(json)]
+    pub segments: TranslationResponses,
+}
+
+fn store() -> Option<Store> {
+    STORE.lock().unwrap().clone()
+}
+
+/// Whether sessions are kept in a database, as `--persist` or the config file's `database` ask.
+pub fn enabled() -> bool {
+    store().is_some()
+}
+
+/**
+ * Opens the SQLite database sessions are kept in, creating it if it isn't
+ * there, and brings its tables up to date. It has to be opened on the
+ * runtime the server runs on.
+ */
+pub async fn open(filename: &str) -> E<()> {
+    let options = SqliteConnectOptions::new()
+        .filename(filename)
+        .create_if_missing(true);
+    let pool = SqlitePoolOptions::new().connect_with(options).await?;
+    sqlx::migrate!().run(&pool).await?;
+    *STORE.lock().unwrap() = Some(Store {
+        pool,
+        runtime: Handle::current(),
+    });
+    log::info!("Keeping sessions in {}", filename);
+    Ok(())
+}
+
+/// The sessions in the database, to be restored when the server starts.
+pub async fn load() -> E<Vec<StoredSession>> {
+    let Some(store) = store() else {
+        return Ok(vec![]);
+    };
+    Ok(sqlx::query_as::<_, StoredSession>(
+        "SELECT uuid, language, resource, sample_rate, recording_file, transcript_file,
+            recording_deleted_at, client, user, language_detected, diarize, mode,
+            renditions, backend, model, codec, close_reason, rating, sequence_lengths,
+            segments, updated_at, created_at
+        FROM sessions",
+    )
+    .fetch_all(&store.pool)
+    .await?)
+}
+
+/**
+ * Saves the metadata and transcript of a session, replacing what was
+ * saved of it before. Its recording stays where it is on disk.
+ */
+pub async fn save(session: &SessionData) -> E<()> {
+    let Some(store) = store() else {
+        return Ok(());
+    };
+    let uuid = session.uuid.hyphenated();
+    let segments = Json(session.translations.lock().unwrap().clone());
+    let transcript = session.transcript().ok();
+    let renditions = Json(&session.renditions);
+    let close_reason = Json(&session.close_reason);
+    let rating = Json(&session.rating);
+    let sequence_lengths = Json(&session.sequence_lengths);
+    sqlx::query!(
+        "INSERT INTO sessions (uuid, language, resource, sample_rate, recording_file,
+            transcript_file, recording_deleted_at, client, user, language_detected, diarize,
+            mode, renditions, backend, model, codec, close_reason, rating, sequence_lengths,
+            segments, transcript, updated_at, created_at)
+        VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+        ON CONFLICT (uuid) DO UPDATE SET
+            language = excluded.language,
+            resource = excluded.resource,
+            sample_rate = excluded.sample_rate,
+            recording_file = excluded.recording_file,
+            transcript_file = excluded.transcript_file,
+            recording_deleted_at = excluded.recording_deleted_at,
+            client = excluded.client,
+            user = excluded.user,
+            language_detected = excluded.language_detected,
+            diarize = excluded.diarize,
+            mode = excluded.mode,
+            renditions = excluded.renditions,
+            backend = excluded.backend,
+            model = excluded.model,
+            codec = excluded.codec,
+            close_reason = excluded.close_reason,
+            rating = excluded.rating,
+            sequence_lengths = excluded.sequence_lengths,
+            segments = excluded.segments,
+            transcript = excluded.transcript,
+            updated_at = excluded.updated_at",
+        uuid,
+        session.language,
+        session.resource,
+        session.sample_rate,
+        session.recording_file,
+        session.transcript_file,
+        session.recording_deleted_at,
+        session.client,
+        session.user,
+        session.language_detected,
+        session.diarize,
+        session.mode,
+        renditions,
+        session.backend,
+        session.model,
+        session.codec,
+        close_reason,
+        rating,
+        sequence_lengths,
+        segments,
+        transcript,
+        session.updated_at,
+        session.created_at,
+    )
+    .execute(&store.pool)
+    .await?;
+    Ok(())
+}
+
+/**
+ * Saves a session from a thread outside the async runtime, such as a
+ * transcription worker's, waiting until it is saved.
+ */
+pub fn save_blocking(session: &SessionData) -> E<()> {
+    let Some(store) = store() else {
+        return Ok(());
+    };
+    store
+        .runtime
+        .block_on(save(session))
+        .map_err(|e| Er::new(format!("Couldn't save session {}: {}", session.uuid, e)).into())
+}
+
+/// Deletes what was saved of a session.
+pub async fn delete(session: &SessionData) -> E<()> {
+    let Some(store) = store() else {
+        return Ok(());
+    };
+    let uuid = session.uuid.hyphenated();
+    sqlx::query!("DELETE FROM sessions WHERE uuid = ?", uuid)
+        .execute(&store.pool)
+        .await?;
+    Ok(())
+}
@@ -0,0 +1,203 @@
+use serde::Serialize;
+
+use crate::compare::TURN_GAP_SECONDS;
+use crate::translate::{TranslationResponse, TranslationResponses, SEGMENT_TIME_UNITS_PER_SECOND};
+
+/// The subtitle formats a transcript can be had in.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SubtitleFormat {
+    Srt,
+    Vtt,
+}
+
+impl SubtitleFormat {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            SubtitleFormat::Srt => "application/x-subrip",
+            SubtitleFormat::Vtt => "text/vtt",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            SubtitleFormat::Srt => "srt",
+            SubtitleFormat::Vtt => "vtt",
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Cue {
+    pub start_secs: f32,
+    pub end_secs: f32,
+    pub text: String,
+    /// how sure the transcription backend was of the text, when it says
+    pub confidence: Option<f32>,
+    /// who said it, in diarized sessions
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub speaker: Option<String>,
+}
+
+/**
+ * The transcribed segments as cues timed from the start of the recording.
+ * The times of a segment are from the start of its sequence, which comes
+ * after the audio of the sequences before it, `sequence_lengths` samples
+ * each.
+ */
+pub fn cues(
+    responses: &TranslationResponses,
+    sequence_lengths: &[usize],
+    sample_rate: u32,
+) -> Vec<Cue> {
+    responses
+        .segments()
+        .filter(|segment| !segment.translation.trim().is_empty())
+        .map(|segment| {
+            let (start_secs, end_secs) = segment_times(segment, sequence_lengths, sample_rate);
+            Cue {
+                start_secs,
+                end_secs,
+                text: segment.translation.trim().to_string(),
+                confidence: segment.confidence,
+                speaker: segment.speaker.clone(),
+            }
+        })
+        .collect()
+}
+
+/**
+ * Labels the segments of a dialogue whose backend didn't say who spoke
+ * them the way `speaker_diarization_stub` labels a resource's narration:
+ * each pause longer than TURN_GAP_SECONDS is a change of speaker, A, B,
+ * A... Transcripts the backend diarized are left as they are.
+ */
+pub fn label_speakers(
+    responses: &mut TranslationResponses,
+    sequence_lengths: &[usize],
+    sample_rate: u32,
+) {
+    if responses
+        .segments()
+        .any(|segment| segment.speaker.is_some())
+    {
+        return;
+    }
+    let mut turn = 0;
+    let mut last_end: Option<f32> = None;
+    for segment in responses
+        .segments_mut()
+        .filter(|segment| !segment.translation.trim().is_empty())
+    {
+        let (start, end) = segment_times(segment, sequence_lengths, sample_rate);
+        if last_end.is_some_and(|last_end| start - last_end > TURN_GAP_SECONDS) {
+            turn += 1;
+        }
+        segment.speaker = Some(["A", "B"][turn % 2].to_string());
+        last_end = Some(end);
+    }
+}
+
+/**
+ * When a segment starts and ends, in seconds from the start of the
+ * recording, after the `sequence_lengths` samples of the sequences
+ * before its own.
+ */
+pub fn segment_times(
+    segment: &TranslationResponse,
+    sequence_lengths: &[usize],
+    sample_rate: u32,
+) -> (f32, f32) {
+    let offset = sequence_lengths
+        .iter()
+        .take(segment.sequence_number)
+        .sum::<usize>() as f32
+        / sample_rate as f32;
+    (
+        offset + segment.segment_start as f32 / SEGMENT_TIME_UNITS_PER_SECOND,
+        offset + segment.segment_end as f32 / SEGMENT_TIME_UNITS_PER_SECOND,
+    )
+}
+
+/**
+ * When each word of a segment's text starts and ends, like
+ * `segment_times`. The transcriber's own word times are used if it gave
+ * one for each word, otherwise the segment's time is shared out between
+ * its words by their length.
+ */
+pub fn word_times(
+    segment: &TranslationResponse,
+    sequence_lengths: &[usize],
+    sample_rate: u32,
+) -> Vec<(f32, f32)> {
+    let (start, end) = segment_times(segment, sequence_lengths, sample_rate);
+    let words: Vec<&str> = segment.translation.split_whitespace().collect();
+    if !segment.words.is_empty() && segment.words.len() == words.len() {
+        let offset = start - segment.segment_start as f32 / SEGMENT_TIME_UNITS_PER_SECOND;
+        return segment
+            .words
+            .iter()
+            .map(|w| {
+                (
+                    offset + w.start as f32 / SEGMENT_TIME_UNITS_PER_SECOND,
+                    offset + w.end as f32 / SEGMENT_TIME_UNITS_PER_SECOND,
+                )
+            })
+            .collect();
+    }
+    let total = words
+        .iter()
+        .map(|w| w.chars().count())
+        .sum::<usize>()
+        .max(1) as f32;
+    let mut said = 0;
+    words
+        .iter()
+        .map(|w| {
+            let word_start = start + (end - start) * said as f32 / total;
+            said += w.chars().count();
+            (word_start, start + (end - start) * said as f32 / total)
+        })
+        .collect()
+}
+
+/// A time as `hh:mm:ss` followed by the milliseconds after `separator`.
+fn timestamp(secs: f32, separator: char) -> String {
+    let millis = (secs.max(0.0) * 1000.0).round() as u64;
+    format!(
+        "{:02}:{:02}:{:02}{}{:03}",
+        millis / 3_600_000,
+        millis / 60_000 % 60,
+        millis / 1000 % 60,
+        separator,
+        millis % 1000
+    )
+}
+
+/**
+ * Writes out the cues as a subtitle file. SubRip numbers its cues and has
+ * a comma before the milliseconds. The speakers of diarized cues are
+ * WebVTT voices, and in SubRip start the text.
+ */
+pub fn render(cues: &[Cue], format: SubtitleFormat) -> String {
+    let (mut subtitles, separator) = match format {
+        SubtitleFormat::Srt => (String::new(), ','),
+        SubtitleFormat::Vtt => ("WEBVTT\n\n".to_string(), '.'),
+    };
+    for (index, cue) in cues.iter().enumerate() {
+        if format == SubtitleFormat::Srt {
+            subtitles.push_str(&format!("{}\n", index + 1));
+        }
+        let text = match (&cue.speaker, format) {
+            (None, _) => cue.text.clone(),
+            (Some(speaker), SubtitleFormat::Srt) => format!("{}: {}", speaker, cue.text),
+            (Some(speaker), SubtitleFormat::Vtt) => format!("<v {}>{}", speaker, cue.text),
+        };
+        subtitles.push_str(&format!(
+            "{} --> {}\n{}\n\n",
+            timestamp(cue.start_secs, separator),
+            timestamp(cue.end_secs, separator),
+            text
+        ));
+    }
+    subtitles
+}
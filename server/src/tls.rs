@@ -0,0 +1,87 @@
+use std::future::Future;
+use std::net::SocketAddr;
+
+use tokio::net::TcpListener;
+use warp::filters::BoxedFilter;
+use warp::hyper::server::conn::Http;
+use warp::hyper::service::{service_fn, Service};
+use warp::reply::Response;
+
+use crate::config::TlsConfig;
+use crate::error::{Er, E};
+
+/**
+ * The address a TLS connection comes from. warp only knows it for the
+ * connections it accepts itself, so `serve` puts it in the extensions of
+ * each request for `middleware::client_addr`.
+ */
+#[derive(Clone, Copy, Debug)]
+pub struct PeerAddr(pub SocketAddr);
+
+/// The acceptor for the certificate chain and PKCS #8 private key in the PEM files of `tls`.
+fn acceptor(tls: &TlsConfig) -> E<tokio_native_tls::TlsAcceptor> {
+    let read = |filename: &String| {
+        std::fs::read(filename).map_err(|e| Er::new(format!("Couldn't read {}: {}", filename, e)))
+    };
+    let identity =
+        native_tls::Identity::from_pkcs8(&read(&tls.cert)?, &read(&tls.key)?).map_err(|e| {
+            Er::new(format!(
+                "Couldn't use {} and {} for TLS: {}",
+                tls.cert, tls.key, e
+            ))
+        })?;
+    Ok(native_tls::TlsAcceptor::new(identity)?.into())
+}
+
+/**
+ * Serves `routes` over TLS on `listen` until `shutdown` completes. Each
+ * connection is handed to hyper once its handshake is done, with
+ * upgrades for the chat websocket.
+ */
+pub async fn serve(
+    routes: BoxedFilter<(Response,)>,
+    listen: SocketAddr,
+    tls: &TlsConfig,
+    shutdown: impl Future<Output = ()>,
+) -> E<()> {
+    let acceptor = acceptor(tls)?;
+    let listener = TcpListener::bind(listen)
+        .await
+        .map_err(|e| Er::new(format!("Couldn't listen on {}: {}", listen, e)))?;
+    let service = warp::service(routes);
+    tokio::pin!(shutdown);
+    loop {
+        let (stream, peer) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    log::warn!("Couldn't accept a connection: {}", e);
+                    continue;
+                }
+            },
+            _ = &mut shutdown => return Ok(()),
+        };
+        let acceptor = acceptor.clone();
+        let service = service.clone();
+        tokio::spawn(async move {
+            let stream = match acceptor.accept(stream).await {
+                Ok(stream) => stream,
+                Err(e) => {
+                    log::debug!("TLS handshake with {} failed: {}", peer, e);
+                    return;
+                }
+            };
+            let service = service_fn(move |mut request| {
+                request.extensions_mut().insert(PeerAddr(peer));
+                service.clone().call(request)
+            });
+            if let Err(e) = Http::new()
+                .serve_connection(stream, service)
+                .with_upgrades()
+                .await
+            {
+                log::debug!("Connection from {} ended with {}", peer, e);
+            }
+        });
+    }
+}
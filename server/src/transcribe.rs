@@ -0,0 +1,572 @@
+use reqwest::blocking::{multipart, Client};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::env;
+
+use crate::error::{Er, Kind, E};
+use crate::session::{process_transcription, SessionData};
+use crate::translate::{
+    TranslationRequest, TranslationResponse, WordTime, SEGMENT_TIME_UNITS_PER_SECOND,
+};
+use crate::whispercpp::WhisperCpp;
+use crate::whisperx::WhisperX;
+
+pub mod scheduler;
+
+/// The sample rate audio is handed to transcribers at.
+pub const TRANSCRIBE_SAMPLE_RATE: u32 = 16000;
+/// The model asked for from an OpenAI-compatible endpoint unless `OPENAI_MODEL` says otherwise.
+pub const DEFAULT_OPENAI_MODEL: &str = "whisper-1";
+/// What sessions give as their language for it to be detected from their first piece of audio.
+pub const AUTO_LANGUAGE: &str = "auto";
+/// How many seconds at the start of a session's first piece its language is detected from.
+pub const LANGUAGE_DETECTION_SECONDS: usize = 10;
+/// What a segment which couldn't be transcribed says instead.
+pub const TRANSCRIPTION_ERROR: &str = "<b>error transcribing</b>";
+
+/// A piece of transcribed speech, timed in hundredths of a second from the start of its audio.
+#[derive(Clone, Debug)]
+pub struct Segment {
+    pub text: String,
+    pub start: i64,
+    pub end: i64,
+    /// from 0 to 1, for transcribers which say how sure they are
+    pub confidence: Option<f32>,
+    /// its words in the same units, for transcribers which time them
+    pub words: Vec<WordTime>,
+    /// who said it, for transcribers which tell speakers apart
+    pub speaker: Option<String>,
+}
+
+pub trait Transcriber {
+    /// Transcribes speech in `lang`, sampled at `TRANSCRIBE_SAMPLE_RATE`, with `model` if the backend can switch models.
+    fn transcribe(&self, audio: &[f32], lang: &str, model: Option<&str>) -> E<Vec<Segment>>;
+
+    /// Transcribes a dialogue, labelling each segment with its speaker if the backend can.
+    fn transcribe_dialogue(
+        &self,
+        audio: &[f32],
+        lang: &str,
+        model: Option<&str>,
+    ) -> E<Vec<Segment>> {
+        self.transcribe(audio, lang, model)
+    }
+
+    /// The language spoken in `audio`, for backends which can tell.
+    fn detect_language(&self, _audio: &[f32], _model: Option<&str>) -> E<Option<String>> {
+        Ok(None)
+    }
+}
+
+/// The engines sessions can be transcribed with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, sqlx::Type)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(type_name = "TEXT", rename_all = "lowercase")]
+pub enum Backend {
+    /// whisper.cpp, run in the server
+    WhisperCpp,
+    /// the whisperx server at `WHISPER_SERVER`
+    WhisperX,
+    /// an OpenAI-compatible transcription API at `OPENAI_API_URL`
+    OpenAi,
+    /// the Vosk server at `VOSK_SERVER`
+    Vosk,
+}
+
+impl Backend {
+    pub const ALL: [Backend; 4] = [
+        Backend::WhisperCpp,
+        Backend::WhisperX,
+        Backend::OpenAi,
+        Backend::Vosk,
+    ];
+
+    /// What the backend is called in `/chat?backend=` and the config file.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Backend::WhisperCpp => "whispercpp",
+            Backend::WhisperX => "whisperx",
+            Backend::OpenAi => "openai",
+            Backend::Vosk => "vosk",
+        }
+    }
+
+    /// Whether the server is set up to use the backend.
+    pub fn available(&self) -> bool {
+        let set = |name| env::var(name).is_ok_and(|v| !v.is_empty());
+        match self {
+            Backend::WhisperCpp => true,
+            Backend::WhisperX => set("WHISPER_SERVER"),
+            Backend::OpenAi => set("OPENAI_API_URL"),
+            Backend::Vosk => set("VOSK_SERVER"),
+        }
+    }
+
+    /// Whether the backend can tell the language of a session given as `AUTO_LANGUAGE`.
+    pub fn detects_language(&self) -> bool {
+        matches!(self, Backend::WhisperCpp)
+    }
+
+    /// Checks that sessions with `backend` can be in `lang`, which they can unless it is to be detected.
+    pub fn check_language(&self, lang: &str) -> E<()> {
+        if lang == AUTO_LANGUAGE && !self.detects_language() {
+            return Err(Er::with_kind(
+                Kind::BadRequest,
+                format!(
+                    "The {} backend can't detect languages, so lang has to be given",
+                    self.name()
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    /// The backend called `name`, if the server can use it.
+    pub fn from_name(name: &str) -> E<Backend> {
+        let backend = Backend::ALL
+            .into_iter()
+            .find(|b| b.name() == name)
+            .ok_or_else(|| {
+                Er::with_kind(
+                    Kind::BadRequest,
+                    format!(
+                        "There is no transcription backend {}, only {}",
+                        name,
+                        Backend::ALL.map(|b| b.name()).join(", ")
+                    ),
+                )
+            })?;
+        if !backend.available() {
+            return Err(Er::with_kind(
+                Kind::BadRequest,
+                format!("The {} backend is not set up on this server", name),
+            ));
+        }
+        Ok(backend)
+    }
+
+    /**
+     * The backend sessions use unless they ask for another: the config
+     * file's `transcription_backend`, or else whisperx if `WHISPER_SERVER`
+     * is set and whisper.cpp if it isn't.
+     */
+    pub fn configured() -> Backend {
+        match crate::config::CONFIG.transcription_backend {
+            Some(backend) => backend,
+            None if Backend::WhisperX.available() => Backend::WhisperX,
+            None => Backend::WhisperCpp,
+        }
+    }
+}
+
+impl Default for Backend {
+    fn default() -> Self {
+        Backend::configured()
+    }
+}
+
+/**
+ * Transcribes with an OpenAI-compatible API, posting each piece of
+ * audio as a WAV file to `OPENAI_API_URL/audio/transcriptions` with the
+ * key in `OPENAI_API_KEY`, if there is one.
+ */
+pub struct OpenAi {
+    client: Client,
+    url: String,
+    key: Option<String>,
+    model: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct OpenAiSegment {
+    text: String,
+    start: f32,
+    end: f32,
+    avg_logprob: Option<f32>,
+}
+
+#[derive(Deserialize, Debug)]
+struct OpenAiWord {
+    word: String,
+    start: f32,
+    end: f32,
+}
+
+#[derive(Deserialize, Debug)]
+struct OpenAiResponse {
+    text: String,
+    #[serde(default)]
+    segments: Vec<OpenAiSegment>,
+    /// the words of all segments, when asked for
+    #[serde(default)]
+    words: Vec<OpenAiWord>,
+}
+
+impl OpenAi {
+    pub fn new() -> E<Self> {
+        let url = env::var("OPENAI_API_URL")
+            .map_err(|_| Er::new("OPENAI_API_URL is not set".to_string()))?;
+        Ok(Self {
+            client: Client::new(),
+            url: url.trim_end_matches('/').to_string(),
+            key: env::var("OPENAI_API_KEY").ok().filter(|k| !k.is_empty()),
+            model: env::var("OPENAI_MODEL").unwrap_or(DEFAULT_OPENAI_MODEL.to_string()),
+        })
+    }
+}
+
+impl Transcriber for OpenAi {
+    fn transcribe(&self, audio: &[f32], lang: &str, _model: Option<&str>) -> E<Vec<Segment>> {
+        let wav = crate::audio::wav_bytes(audio, TRANSCRIBE_SAMPLE_RATE)?;
+        let form = multipart::Form::new()
+            .text("model", self.model.clone())
+            .text("language", lang.to_string())
+            .text("response_format", "verbose_json")
+            .text("timestamp_granularities[]", "segment")
+            .text("timestamp_granularities[]", "word")
+            .part(
+                "file",
+                multipart::Part::bytes(wav)
+                    .file_name("audio.wav")
+                    .mime_str("audio/wav")?,
+            );
+        let mut request = self
+            .client
+            .post(format!("{}/audio/transcriptions", self.url))
+            .multipart(form);
+        if let Some(key) = &self.key {
+            request = request.bearer_auth(key);
+        }
+        let response = request
+            .send()?
+            .error_for_status()?
+            .json::<OpenAiResponse>()?;
+        let time = |t: f32| (t * SEGMENT_TIME_UNITS_PER_SECOND) as i64;
+        let mut words: Vec<WordTime> = response
+            .words
+            .into_iter()
+            .map(|w| WordTime {
+                word: w.word.trim().to_string(),
+                start: time(w.start),
+                end: time(w.end),
+            })
+            .collect();
+        if response.segments.is_empty() {
+            // some servers only give the text
+            let end = audio.len() as f32 / TRANSCRIBE_SAMPLE_RATE as f32;
+            return Ok(vec![Segment {
+                text: response.text,
+                start: 0,
+                end: time(end),
+                confidence: None,
+                words,
+                speaker: None,
+            }]);
+        }
+        // the words are given for the whole audio, so each segment takes those that start in it
+        Ok(response
+            .segments
+            .into_iter()
+            .map(|s| {
+                let (start, end) = (time(s.start), time(s.end));
+                let count = words.iter().take_while(|w| w.start < end).count();
+                Segment {
+                    text: s.text,
+                    start,
+                    end,
+                    confidence: s.avg_logprob.map(f32::exp),
+                    words: words.drain(..count).collect(),
+                    speaker: None,
+                }
+            })
+            .collect())
+    }
+}
+
+/**
+ * Transcribes with a Vosk server, streaming the audio to the websocket at
+ * `VOSK_SERVER`, for instance `ws://localhost:2700`. Vosk servers serve
+ * one language each, so `lang` isn't sent.
+ */
+pub struct Vosk {
+    url: String,
+}
+
+/// How many samples are sent to Vosk at a time.
+const VOSK_CHUNK_SAMPLES: usize = 8000;
+
+impl Vosk {
+    pub fn new() -> E<Self> {
+        let url =
+            env::var("VOSK_SERVER").map_err(|_| Er::new("VOSK_SERVER is not set".to_string()))?;
+        Ok(Self { url })
+    }
+}
+
+/// The words of a Vosk result, as one segment, if it has any.
+fn vosk_segment(result: &Value) -> Option<Segment> {
+    let text = result["text"].as_str().filter(|t| !t.is_empty())?;
+    let words = result["result"].as_array();
+    let time = |word: Option<&Value>, field: &str| {
+        word.and_then(|w| w[field].as_f64())
+            .map(|t| (t as f32 * SEGMENT_TIME_UNITS_PER_SECOND) as i64)
+            .unwrap_or(0)
+    };
+    let word_times = words
+        .into_iter()
+        .flatten()
+        .filter_map(|w| {
+            Some(WordTime {
+                word: w["word"].as_str()?.to_string(),
+                start: time(Some(w), "start"),
+                end: time(Some(w), "end"),
+            })
+        })
+        .collect();
+    // whisper's segments start with the space between them, Vosk's don't
+    Some(Segment {
+        text: format!(" {}", text),
+        start: time(words.and_then(|w| w.first()), "start"),
+        end: time(words.and_then(|w| w.last()), "end"),
+        confidence: words.filter(|w| !w.is_empty()).map(|w| {
+            w.iter().filter_map(|w| w["conf"].as_f64()).sum::<f64>() as f32 / w.len() as f32
+        }),
+        words: word_times,
+        speaker: None,
+    })
+}
+
+impl Transcriber for Vosk {
+    fn transcribe(&self, audio: &[f32], _lang: &str, _model: Option<&str>) -> E<Vec<Segment>> {
+        use tungstenite::Message;
+        let (mut socket, _) = tungstenite::connect(self.url.as_str())?;
+        socket.send(Message::Text(
+            json!({"config": {"sample_rate": TRANSCRIBE_SAMPLE_RATE, "words": 1}}).to_string(),
+        ))?;
+        let mut segments = vec![];
+        let mut add_result = |message: Message| -> E<()> {
+            if let Message::Text(text) = message {
+                segments.extend(vosk_segment(&serde_json::from_str(&text)?));
+            }
+            Ok(())
+        };
+        for chunk in audio.chunks(VOSK_CHUNK_SAMPLES) {
+            let mut bytes = Vec::with_capacity(2 * chunk.len());
+            for sample in chunk {
+                bytes.extend(((sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16).to_le_bytes());
+            }
+            socket.send(Message::Binary(bytes))?;
+            // Vosk answers each chunk, with a result when it has heard a pause
+            add_result(socket.read()?)?;
+        }
+        socket.send(Message::Text(json!({"eof": 1}).to_string()))?;
+        loop {
+            match socket.read() {
+                Ok(Message::Close(_))
+                | Err(tungstenite::Error::ConnectionClosed)
+                | Err(tungstenite::Error::AlreadyClosed) => break,
+                Ok(message) => add_result(message)?,
+                Err(e) => return Err(Box::new(e)),
+            }
+        }
+        Ok(segments)
+    }
+}
+
+/// One of each backend the server is set up to use, for a transcription thread to use.
+pub struct Transcribers {
+    whispercpp: WhisperCpp,
+    whisperx: Option<WhisperX>,
+    openai: Option<OpenAi>,
+    vosk: Option<Vosk>,
+}
+
+impl Transcribers {
+    pub fn new() -> E<Self> {
+        Ok(Self {
+            whispercpp: WhisperCpp {},
+            whisperx: match Backend::WhisperX.available() {
+                true => Some(WhisperX::new()?),
+                false => None,
+            },
+            openai: match Backend::OpenAi.available() {
+                true => Some(OpenAi::new()?),
+                false => None,
+            },
+            vosk: match Backend::Vosk.available() {
+                true => Some(Vosk::new()?),
+                false => None,
+            },
+        })
+    }
+
+    fn get(&self, backend: Backend) -> E<&dyn Transcriber> {
+        let transcriber: Option<&dyn Transcriber> = match backend {
+            Backend::WhisperCpp => Some(&self.whispercpp),
+            Backend::WhisperX => self.whisperx.as_ref().map(|t| t as &dyn Transcriber),
+            Backend::OpenAi => self.openai.as_ref().map(|t| t as &dyn Transcriber),
+            Backend::Vosk => self.vosk.as_ref().map(|t| t as &dyn Transcriber),
+        };
+        transcriber.ok_or_else(|| {
+            Er::new(format!(
+                "The {} backend is not set up on this server",
+                backend.name()
+            )) as Box<dyn std::error::Error>
+        })
+    }
+
+    /**
+     * The language of a session which gave `AUTO_LANGUAGE`, detected from
+     * the first `LANGUAGE_DETECTION_SECONDS` of `audio` unless it was from
+     * an earlier piece. The detected language is kept as the session's and
+     * the learner is told it; if it can't be detected, whisper.cpp guesses
+     * again for each piece.
+     */
+    fn language(&self, session: &SessionData, audio: &[f32]) -> E<String> {
+        if let Some(session) = crate::session::get_session_sync(&session.id) {
+            if session.language != AUTO_LANGUAGE {
+                return Ok(session.language);
+            }
+        }
+        let start = &audio[..audio
+            .len()
+            .min(LANGUAGE_DETECTION_SECONDS * TRANSCRIBE_SAMPLE_RATE as usize)];
+        let Some(language) = self
+            .get(session.backend)?
+            .detect_language(start, session.model.as_deref())?
+        else {
+            log::warn!("Couldn't detect the language of session {}", session.uuid);
+            return Ok(AUTO_LANGUAGE.to_string());
+        };
+        log::info!(
+            "Detected {} as the language of session {}",
+            language,
+            session.uuid
+        );
+        crate::session::mutate_session_sync(&session.id, |session| {
+            session.set_detected_language(&language)
+        });
+        Ok(language)
+    }
+
+    /**
+     * Transcribes a piece of a session with the session's backend and
+     * passes on the segments. The audio is preprocessed as the config
+     * file's `[preprocessing]` says first, and the segments are timed from
+     * the start of the piece as it was sent. A piece which couldn't be
+     * transcribed, even because its backend panicked, becomes one segment
+     * saying so, and one with no speech in an empty segment, so that the
+     * session still finishes.
+     */
+    pub fn transcribe(&self, session: &SessionData, request: TranslationRequest) {
+        log::debug!(
+            "Transcribing sequence {} of session {} with {}",
+            request.sequence_number,
+            request.session_id,
+            session.backend.name()
+        );
+        let started = std::time::Instant::now();
+        // a backend which panics leaves the piece untranscribed, not the worker gone
+        let transcribed = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            crate::audio::preprocess(
+                &request.payload,
+                session.sample_rate,
+                &crate::config::CONFIG.preprocessing,
+            )
+            .and_then(|audio| {
+                if audio.samples.is_empty() {
+                    return Ok(vec![]);
+                }
+                let lang = match request.lang.as_str() {
+                    AUTO_LANGUAGE => self.language(session, &audio.samples)?,
+                    lang => lang.to_string(),
+                };
+                let transcriber = self.get(session.backend)?;
+                let segments = match session.diarize {
+                    true => transcriber.transcribe_dialogue(
+                        &audio.samples,
+                        &lang,
+                        session.model.as_deref(),
+                    ),
+                    false => {
+                        transcriber.transcribe(&audio.samples, &lang, session.model.as_deref())
+                    }
+                }?;
+                Ok(shift(segments, audio.trimmed_secs))
+            })
+        }))
+        .unwrap_or_else(|_| Err(Er::new("the transcription panicked".to_string()).into()));
+        crate::metrics::transcribed(
+            session.backend.name(),
+            transcribed.is_ok(),
+            started.elapsed(),
+        );
+        let segments = match transcribed {
+            Ok(segments) if segments.is_empty() => vec![Segment {
+                text: String::new(),
+                start: 0,
+                end: 0,
+                confidence: None,
+                words: vec![],
+                speaker: None,
+            }],
+            Ok(segments) => segments,
+            Err(e) => {
+                log::error!(
+                    "Couldn't transcribe sequence {} of session {} with {}: {}",
+                    request.sequence_number,
+                    session.uuid,
+                    session.backend.name(),
+                    e
+                );
+                vec![Segment {
+                    text: TRANSCRIPTION_ERROR.to_string(),
+                    start: 0,
+                    end: (request.payload.len() as f32 / session.sample_rate as f32
+                        * SEGMENT_TIME_UNITS_PER_SECOND) as i64,
+                    confidence: None,
+                    words: vec![],
+                    speaker: None,
+                }]
+            }
+        };
+        let num_segments = segments.len() as i32;
+        for (i, segment) in segments.into_iter().enumerate() {
+            let response = TranslationResponse {
+                sequence_number: request.sequence_number,
+                translation: segment.text,
+                num_segments,
+                segment_number: i as i32,
+                segment_start: segment.start,
+                segment_end: segment.end,
+                uuid: session.uuid.to_string(),
+                confidence: segment.confidence,
+                words: segment.words,
+                speaker: segment.speaker,
+            };
+            if let Err(e) = process_transcription(request.session_id, &response) {
+                log::warn!("Processing translation failed with error {}", e);
+                crate::session::mutate_session_sync(&request.session_id, |session| {
+                    session.valid = false
+                });
+            }
+        }
+    }
+}
+
+/// Moves the segments and their words `secs` later, to make up for the silence trimmed before them.
+fn shift(mut segments: Vec<Segment>, secs: f32) -> Vec<Segment> {
+    let offset = (secs * SEGMENT_TIME_UNITS_PER_SECOND) as i64;
+    if offset == 0 {
+        return segments;
+    }
+    for segment in &mut segments {
+        segment.start += offset;
+        segment.end += offset;
+        for word in &mut segment.words {
+            word.start += offset;
+            word.end += offset;
+        }
+    }
+    segments
+}
@@ -0,0 +1,291 @@
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::panic::AssertUnwindSafe;
+use std::sync::{Condvar, Mutex};
+use thread_priority::set_current_thread_priority;
+use thread_priority::ThreadPriority::Crossplatform;
+
+use crate::error::{Er, Kind, E};
+use crate::transcribe::Transcribers;
+use crate::translate::TranslationRequest;
+
+/**
+ * Which pieces are transcribed first. Learners recording now are
+ * following their transcript as it comes, so their pieces go before
+ * those of uploads and reprocessing, which are only looked at once they
+ * are done.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    Live,
+    Batch,
+}
+
+struct Job {
+    request: TranslationRequest,
+    priority: Priority,
+    /// the order the jobs were enqueued in
+    order: u64,
+}
+
+#[derive(Default)]
+struct State {
+    waiting: Vec<Job>,
+    next_order: u64,
+    /// how many workers have set up their backends and are still running
+    workers: usize,
+    /// how many pieces the workers are transcribing now
+    transcribing: usize,
+}
+
+impl State {
+    /**
+     * The waiting jobs in the order they will be taken: by priority, then
+     * a piece from each session in turn, so that a long upload doesn't
+     * hold up the sessions after it, and then first come first served.
+     */
+    fn schedule(&self) -> Vec<usize> {
+        let mut ahead: HashMap<usize, usize> = HashMap::new();
+        let mut keys: Vec<(Priority, usize, u64, usize)> = vec![];
+        let mut by_order: Vec<usize> = (0..self.waiting.len()).collect();
+        by_order.sort_by_key(|&i| self.waiting[i].order);
+        for i in by_order {
+            let job = &self.waiting[i];
+            let turn = ahead.entry(job.request.session_id).or_default();
+            keys.push((job.priority, *turn, job.order, i));
+            *turn += 1;
+        }
+        keys.sort();
+        keys.into_iter().map(|(_, _, _, i)| i).collect()
+    }
+}
+
+lazy_static! {
+    static ref STATE: Mutex<State> = Mutex::new(State::default());
+    static ref JOB_ADDED: Condvar = Condvar::new();
+}
+
+/// Adds a piece of a session to be transcribed once a worker is free, failing if no worker is running.
+pub fn enqueue(request: TranslationRequest, priority: Priority) -> E<()> {
+    enqueue_all(vec![request], priority)
+}
+
+/**
+ * Adds pieces to be transcribed once a worker is free, all of them or,
+ * when no worker is running, none, so that a session isn't left waiting
+ * for pieces which were never queued.
+ */
+pub fn enqueue_all(requests: Vec<TranslationRequest>, priority: Priority) -> E<()> {
+    let mut state = STATE.lock().unwrap();
+    if state.workers == 0 {
+        return Err(Er::new("No transcription workers are running".to_string()));
+    }
+    for request in requests {
+        log::debug!(
+            "Enqueuing sequence {} of session {}, {} waiting",
+            request.sequence_number,
+            request.session_id,
+            state.waiting.len()
+        );
+        let order = state.next_order;
+        state.next_order += 1;
+        state.waiting.push(Job {
+            request,
+            priority,
+            order,
+        });
+        JOB_ADDED.notify_one();
+    }
+    Ok(())
+}
+
+/// How many pieces are waiting for a worker.
+pub fn queue_length() -> usize {
+    STATE.lock().unwrap().waiting.len()
+}
+
+/// How many pieces are waiting or being transcribed, which the server waits for when it stops.
+pub fn pending() -> usize {
+    let state = STATE.lock().unwrap();
+    state.waiting.len() + state.transcribing
+}
+
+/**
+ * Refuses new sessions while more than `max_queued_transcriptions`
+ * pieces are waiting, as their learners would wait longer and longer for
+ * their transcripts, and slow down everyone already practicing, and
+ * while the server is shutting down.
+ */
+pub fn check_capacity() -> E<()> {
+    crate::shutdown::check()?;
+    let Some(max) = crate::config::CONFIG.max_queued_transcriptions else {
+        return Ok(());
+    };
+    if queue_length() > max {
+        return Err(Er::with_kind(
+            Kind::ServerBusy,
+            "Too much audio is waiting to be transcribed, try again later".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/**
+ * How many of the session's pieces are waiting, and how many pieces of
+ * any session will be taken before the first of them, if there is one.
+ */
+pub fn queue_position(session_id: usize) -> (usize, Option<usize>) {
+    let state = STATE.lock().unwrap();
+    let count = state
+        .waiting
+        .iter()
+        .filter(|job| job.request.session_id == session_id)
+        .count();
+    let position = state
+        .schedule()
+        .into_iter()
+        .position(|i| state.waiting[i].request.session_id == session_id);
+    (count, position)
+}
+
+fn lock_state() -> std::sync::MutexGuard<'static, State> {
+    STATE.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/**
+ * A running worker, counted for as long as its thread lives, even when
+ * it panics. When the last one stops the pieces still waiting are
+ * dropped, as there's no one left to transcribe them.
+ */
+struct Worker;
+
+impl Worker {
+    fn register() -> Self {
+        lock_state().workers += 1;
+        Self
+    }
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        let mut state = lock_state();
+        state.workers -= 1;
+        log::error!(
+            "A transcription worker stopped, {} still running",
+            state.workers
+        );
+        if state.workers == 0 && !state.waiting.is_empty() {
+            log::error!(
+                "Dropping {} pieces no worker is left to transcribe",
+                state.waiting.len()
+            );
+            state.waiting.clear();
+        }
+    }
+}
+
+/// A piece being transcribed, counted until it is done with however that ends.
+struct Transcribing;
+
+impl Drop for Transcribing {
+    fn drop(&mut self) {
+        lock_state().transcribing -= 1;
+    }
+}
+
+/// Waits for the next piece to transcribe.
+fn take() -> (TranslationRequest, Transcribing) {
+    let mut state = lock_state();
+    loop {
+        if let Some(&next) = state.schedule().first() {
+            state.transcribing += 1;
+            return (state.waiting.swap_remove(next).request, Transcribing);
+        }
+        state = JOB_ADDED.wait(state).unwrap_or_else(|e| e.into_inner());
+    }
+}
+
+/**
+ * Transcribes pieces as they come, skipping those of sessions which have
+ * gone. A piece whose handling panics is given up on, so that the worker
+ * goes on with the others.
+ */
+fn work(transcribers: Transcribers) {
+    loop {
+        let (request, _transcribing) = take();
+        let session_id = request.session_id;
+        match crate::session::get_session_sync(&session_id) {
+            Some(session) if session.valid => {
+                log::debug!(
+                    "Transcribing sequence {} of session {}, {} waiting",
+                    request.sequence_number,
+                    session_id,
+                    queue_length()
+                );
+                let transcribe = AssertUnwindSafe(|| transcribers.transcribe(&session, request));
+                if std::panic::catch_unwind(transcribe).is_err() {
+                    log::error!(
+                        "Transcribing a piece of session {} panicked, skipping it",
+                        session_id
+                    );
+                }
+            }
+            Some(_) => log::debug!("Skipping no longer valid session {}", session_id),
+            None => log::warn!("Couldn't load session with id {}", session_id),
+        }
+    }
+}
+
+/**
+ * Starts the threads transcribing the pieces of sessions,
+ * `transcription_workers` of them, which is as many pieces as are
+ * transcribed at once, and waits for them to set up their backends.
+ * Fails if none of them could.
+ */
+pub fn start() -> E<()> {
+    let workers = crate::config::CONFIG.transcription_workers.max(1);
+    log::debug!("Starting {} transcription workers", workers);
+    let (started, results) = std::sync::mpsc::channel();
+    for i in 0..workers {
+        let started = started.clone();
+        std::thread::Builder::new()
+            .name(format!("transcriber-{}", i))
+            .spawn(move || {
+                if let Err(e) = set_current_thread_priority(Crossplatform(
+                    crate::LOWER_PRIORITY.try_into().unwrap(),
+                )) {
+                    log::debug!("Couldn't lower the priority of a transcriber: {:?}", e);
+                }
+                // the HTTP clients are blocking, so can't be made on the async runtime
+                match Transcribers::new() {
+                    Ok(transcribers) => {
+                        // counted before the server takes sessions, so that they aren't refused
+                        let _worker = Worker::register();
+                        let _ = started.send(true);
+                        drop(started);
+                        work(transcribers);
+                    }
+                    Err(e) => {
+                        log::error!("Couldn't set up the transcription backends: {}", e);
+                        let _ = started.send(false);
+                    }
+                }
+            })?;
+    }
+    drop(started);
+    // each thread drops its sender once it has answered, or when it panics before, ending the results
+    let running = results.iter().filter(|ok| *ok).count();
+    if running == 0 {
+        return Err(Er::new(
+            "None of the transcription workers could set up their backends".to_string(),
+        ));
+    }
+    if running < workers {
+        log::warn!(
+            "Only {} of {} transcription workers started",
+            running,
+            workers
+        );
+    }
+    Ok(())
+}
@@ -2,13 +2,10 @@ use rubato::{
     Resampler, SincFixedIn, SincInterpolationParameters, SincInterpolationType, WindowFunction,
 };
 use serde::{Deserialize, Serialize};
+use std::fmt;
 
 use crate::error::E;
 
-pub trait Translator {
-    fn translate(&self, req: TranslationRequest) -> E<()>;
-}
-
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct TranslationRequest {
     pub session_id: usize,
@@ -17,7 +14,18 @@ pub struct TranslationRequest {
     pub lang: String,
 }
 
-#[derive(Clone, Debug, Serialize)]
+/// `segment_start` and `segment_end` count hundredths of a second from the start of their sequence's audio.
+pub const SEGMENT_TIME_UNITS_PER_SECOND: f32 = 100.0;
+
+/// When a word of a segment was said, in the same units as the segment's times.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct WordTime {
+    pub word: String,
+    pub start: i64,
+    pub end: i64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct TranslationResponse {
     pub sequence_number: usize,
     pub translation: String,
@@ -26,15 +34,24 @@ pub struct TranslationResponse {
     pub segment_start: i64,
     pub segment_end: i64,
     pub uuid: String,
+    /// how sure the transcriber was of the segment, from 0 to 1, if it says
+    #[serde(default)]
+    pub confidence: Option<f32>,
+    /// the segment's words with their times, for transcribers which give them
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub words: Vec<WordTime>,
+    /// who said the segment, in sessions which are diarized
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub speaker: Option<String>,
 }
 
-impl ToString for TranslationResponse {
-    fn to_string(&self) -> String {
-        self.translation.clone()
+impl fmt::Display for TranslationResponse {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.translation)
     }
 }
 
-#[derive(Clone, Debug, Serialize)]
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
 pub struct TranslationResponses(Vec<Option<Vec<Option<TranslationResponse>>>>);
 
 impl TranslationResponses {
@@ -51,6 +68,9 @@ impl TranslationResponses {
             segment_start: 0,
             segment_end: 1,
             uuid: u,
+            confidence: None,
+            words: vec![],
+            speaker: None,
         })])])
     }
 
@@ -78,14 +98,40 @@ impl TranslationResponses {
         Ok(())
     }
 
+    /// The segments transcribed so far, in order.
+    pub fn segments(&self) -> impl Iterator<Item = &TranslationResponse> {
+        self.0.iter().flatten().flatten().flatten()
+    }
+
+    /// The segments transcribed so far, in order, to change.
+    pub fn segments_mut(&mut self) -> impl Iterator<Item = &mut TranslationResponse> {
+        self.0.iter_mut().flatten().flatten().flatten()
+    }
+
+    /**
+     * The pieces the transcript is made of, in the order `Display` writes
+     * them, with `None` for the gaps of segments and sequences not
+     * transcribed yet.
+     */
+    pub fn pieces(&self) -> Vec<Option<&TranslationResponse>> {
+        let mut pieces = vec![];
+        for sequence in self.0.iter() {
+            match sequence {
+                Some(segments) => pieces.extend(segments.iter().map(Option::as_ref)),
+                None => pieces.push(None),
+            }
+        }
+        pieces
+    }
+
     pub fn translation_count(&self) -> E<usize> {
         let count = self.0.iter().filter(|x| !x.is_none()).count();
         Ok(count)
     }
 }
 
-impl ToString for TranslationResponses {
-    fn to_string(&self) -> String {
+impl fmt::Display for TranslationResponses {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let mut result = String::new();
 
         for responses in self.0.iter() {
@@ -101,7 +147,7 @@ impl ToString for TranslationResponses {
                 None => result.push_str(" .... "),
             }
         }
-        result
+        write!(f, "{}", result)
     }
 }
 
@@ -110,12 +156,10 @@ pub const SILENCE_TIME_MILLISECONDS: usize = 200;
 pub const SILENCE_AMPLITUDE_THRESHOLD: f32 = 0.005;
 //pub const SAMPLE_RATE: f64 = 44100f64;
 
-/**
- * does what is says.
- */
-pub fn resample(audio_data: &Vec<f32>, from_rate: f64) -> Vec<f32> {
+/// Resamples audio from `from_rate` to `to_rate`, which fails for rates too far apart.
+pub fn resample_to(audio_data: &[f32], from_rate: f64, to_rate: f64) -> E<Vec<f32>> {
     let mut resampler = SincFixedIn::<f32>::new(
-        16000_f64 / from_rate,
+        to_rate / from_rate,
         10.0,
         SincInterpolationParameters {
             sinc_len: 256,
@@ -126,12 +170,9 @@ pub fn resample(audio_data: &Vec<f32>, from_rate: f64) -> Vec<f32> {
         },
         audio_data.len(),
         1,
-    )
-    .unwrap(); // TODO: handle properly
-
-    let resampled = resampler.process(&[audio_data], None).unwrap();
-    let data = &resampled[0];
-    data.clone()
+    )?;
+    let mut resampled = resampler.process(&[audio_data], None)?;
+    Ok(resampled.swap_remove(0))
 }
 
 /**
@@ -0,0 +1,123 @@
+use lazy_static::lazy_static;
+use reqwest::blocking::Client;
+use serde_json::json;
+use std::path::Path;
+use std::process::Command;
+use std::sync::Mutex;
+
+use crate::error::{Er, Kind, E};
+use crate::metadata::Metadata;
+
+lazy_static! {
+    /// held while speech is synthesized, so that a resource's audio is only made once
+    static ref SYNTHESIZING: Mutex<()> = Mutex::new(());
+}
+
+/// The file in the bundle of a resource without audio that its synthesized speech is kept in.
+pub fn audio_filename(lang: &str) -> String {
+    format!("tts_{}.wav", lang)
+}
+
+fn env(name: &str) -> Option<String> {
+    std::env::var(name).ok().filter(|v| !v.is_empty())
+}
+
+/// Whether `TTS_CMD` or `TTS_URL` is set, so that resources can do without audio.
+pub fn configured() -> bool {
+    env("TTS_CMD").is_some() || env("TTS_URL").is_some()
+}
+
+/**
+ * Runs `TTS_CMD <lang> <text> <output>` to write the speech. The command
+ * may contain extra arguments, separated by spaces.
+ */
+fn run_command(command: &str, lang: &str, text: &str, output: &str) -> E<()> {
+    let mut args = command.split_whitespace();
+    let program = args.next().ok_or(Er::new("TTS_CMD is empty".to_string()))?;
+    log::debug!("Running {} to synthesize {}", command, text);
+    let status = Command::new(program)
+        .args(args)
+        .arg(lang)
+        .arg(text)
+        .arg(output)
+        .status()?;
+    if !status.success() {
+        return Err(Er::new(format!("{} failed with {}", command, status)));
+    }
+    Ok(())
+}
+
+/// Posts `{"text":...,"lang":...}` to `TTS_URL`, which answers with the speech as WAV.
+fn request(url: &str, lang: &str, text: &str, output: &str) -> E<()> {
+    log::debug!("Requesting speech in {} from {}", lang, url);
+    let res = Client::new()
+        .post(url)
+        .json(&json!({ "text": std::fs::read_to_string(text)?, "lang": lang }))
+        .send()?;
+    if !res.status().is_success() {
+        return Err(Er::new(format!("{} answered {}", url, res.status())));
+    }
+    std::fs::write(output, res.bytes()?)?;
+    Ok(())
+}
+
+/// Synthesizes the native text of the resource into `output`, which only appears once it is complete.
+fn synthesize(metadata: &Metadata, output: &str) -> E<()> {
+    let text = metadata.text_path(&metadata.native).ok_or(Er::with_kind(
+        Kind::NotFound,
+        format!(
+            "Resource {} has neither audio nor a transcript to synthesize it from",
+            metadata.enclosing_directory
+        ),
+    ))?;
+    let partial = format!("{}.partial", output);
+    let result = match (env("TTS_CMD"), env("TTS_URL")) {
+        (Some(command), _) => run_command(&command, &metadata.native, &text, &partial),
+        (None, Some(url)) => request(&url, &metadata.native, &text, &partial),
+        (None, None) => return Ok(()),
+    };
+    if let Err(e) = result {
+        let _ = std::fs::remove_file(&partial);
+        return Err(e);
+    }
+    log::info!("Synthesized {}", output);
+    std::fs::rename(&partial, output)?;
+    Ok(())
+}
+
+/**
+ * Gives a resource whose metadata names no audio the speech of its
+ * transcript, made with `TTS_CMD` or `TTS_URL` the first time it is
+ * needed and kept in its bundle as `tts_<native>.wav`. Resources with
+ * audio, and all of them when neither is set, are left as they are. This
+ * blocks until the speech is made, so it should be called off the async
+ * runtime.
+ */
+pub fn ensure_audio(resource_path: &String) -> E<()> {
+    let metadata = Metadata::from_resource_path(resource_path)?;
+    if !metadata.audio.is_empty() || !configured() {
+        return Ok(());
+    }
+    let _synthesizing = SYNTHESIZING.lock().unwrap();
+    let output = format!(
+        "{}/{}",
+        metadata.enclosing_directory,
+        audio_filename(&metadata.native)
+    );
+    if !Path::new(&output).exists() {
+        synthesize(&metadata, &output)?;
+    }
+    // the metadata read before the speech was there has no audio
+    crate::metadata::invalidate_cache(Some(resource_path));
+    Ok(())
+}
+
+/// `ensure_audio` run off the async runtime.
+pub async fn ensure_audio_blocking(resource_path: &str) -> Result<(), Er> {
+    let resource_path = resource_path.to_string();
+    tokio::task::spawn_blocking(move || {
+        ensure_audio(&resource_path).map_err(|e| Er::from_error(&*e))
+    })
+    .await
+    .map_err(|e| Er::from_error(&e))?
+}
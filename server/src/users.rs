@@ -0,0 +1,318 @@
+use lazy_static::lazy_static;
+use rand::RngCore;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use warp::{Filter, Rejection};
+
+use crate::error::{reject, Er, Kind, E};
+use crate::session::SessionData;
+
+/// The cookie a login is kept in.
+pub const LOGIN_COOKIE: &str = "terplounge_login";
+/// How long a login lasts.
+pub const LOGIN_SECONDS: u64 = 7 * 24 * 3600;
+/// How many rounds of PBKDF2 passwords are hashed with, to make guessing them slow.
+const PBKDF2_ROUNDS: u32 = 600_000;
+/// How many times passwords were hashed with SHA-256 before PBKDF2, as older lines still are.
+const SHA256_ROUNDS: usize = 100_000;
+/// How many wrong passwords an address can give before it has to wait.
+const MAX_FAILED_LOGINS: usize = 5;
+/// How long wrong passwords count against an address.
+const FAILED_LOGIN_SECONDS: u64 = 15 * 60;
+
+#[derive(Debug)]
+pub struct LoginFailed;
+
+impl warp::reject::Reject for LoginFailed {}
+
+lazy_static! {
+    /// the user each login token belongs to, and when they logged in
+    static ref LOGINS: Mutex<HashMap<String, (String, Instant)>> = Mutex::new(HashMap::new());
+    /// when each address gave the wrong password in the last `FAILED_LOGIN_SECONDS`
+    static ref FAILED_LOGINS: Mutex<HashMap<IpAddr, Vec<Instant>>> = Mutex::new(HashMap::new());
+}
+
+/// The file user accounts are kept in, named by `USERS_FILE`; without one there are no accounts.
+fn users_file() -> Option<String> {
+    std::env::var("USERS_FILE").ok().filter(|f| !f.is_empty())
+}
+
+/**
+ * Whether there are user accounts. Without them every session is
+ * anonymous and visible to everyone, as it always was.
+ */
+pub fn enabled() -> bool {
+    users_file().is_some()
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// How a password is hashed in the users file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Scheme {
+    /// PBKDF2 with HMAC-SHA256, in lines of `name:pbkdf2:salt:hash`
+    Pbkdf2,
+    /// SHA-256 over and over, in the `name:salt:hash` lines from before PBKDF2
+    Sha256,
+}
+
+fn hash_password(scheme: Scheme, salt: &str, password: &str) -> String {
+    match scheme {
+        Scheme::Pbkdf2 => {
+            let mut hash = [0u8; 32];
+            pbkdf2::pbkdf2_hmac::<Sha256>(
+                password.as_bytes(),
+                salt.as_bytes(),
+                PBKDF2_ROUNDS,
+                &mut hash,
+            );
+            to_hex(&hash)
+        }
+        Scheme::Sha256 => {
+            let mut hash = Sha256::new()
+                .chain_update(salt)
+                .chain_update(password)
+                .finalize();
+            for _ in 1..SHA256_ROUNDS {
+                hash = Sha256::digest(hash);
+            }
+            to_hex(&hash)
+        }
+    }
+}
+
+fn random_hex(bytes: usize) -> String {
+    let mut buffer = vec![0u8; bytes];
+    rand::thread_rng().fill_bytes(&mut buffer);
+    to_hex(&buffer)
+}
+
+/// A line of the users file.
+struct Account {
+    name: String,
+    scheme: Scheme,
+    salt: String,
+    hash: String,
+}
+
+impl Account {
+    /// The account of a user with a new salt, hashing their password with PBKDF2.
+    fn new(credentials: &Credentials) -> Self {
+        let salt = random_hex(16);
+        Self {
+            name: credentials.name.clone(),
+            scheme: Scheme::Pbkdf2,
+            hash: hash_password(Scheme::Pbkdf2, &salt, &credentials.password),
+            salt,
+        }
+    }
+
+    fn parse(line: &str) -> Option<Self> {
+        let fields: Vec<&str> = line.trim().split(':').collect();
+        let (name, scheme, salt, hash) = match fields.as_slice() {
+            [name, "pbkdf2", salt, hash] => (name, Scheme::Pbkdf2, salt, hash),
+            [name, salt, hash] => (name, Scheme::Sha256, salt, hash),
+            _ => return None,
+        };
+        Some(Self {
+            name: name.to_string(),
+            scheme,
+            salt: salt.to_string(),
+            hash: hash.to_string(),
+        })
+    }
+
+    fn line(&self) -> String {
+        match self.scheme {
+            Scheme::Pbkdf2 => format!("{}:pbkdf2:{}:{}", self.name, self.salt, self.hash),
+            Scheme::Sha256 => format!("{}:{}:{}", self.name, self.salt, self.hash),
+        }
+    }
+
+    /// Whether `password` is this user's, comparing the hashes in constant time.
+    fn checks(&self, password: &str) -> bool {
+        let given = hash_password(self.scheme, &self.salt, password);
+        given.len() == self.hash.len()
+            && given
+                .bytes()
+                .zip(self.hash.bytes())
+                .fold(0, |acc, (a, b)| acc | (a ^ b))
+                == 0
+    }
+}
+
+/**
+ * The users in the users file, each line of which is `name:pbkdf2:salt:hash`,
+ * or `name:salt:hash` for passwords set before PBKDF2.
+ */
+fn read_users() -> E<Vec<Account>> {
+    let Some(filename) = users_file() else {
+        return Ok(vec![]);
+    };
+    let contents = match std::fs::read_to_string(&filename) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(Er::new(format!("Couldn't read {}: {}", filename, e))),
+    };
+    Ok(contents.lines().filter_map(Account::parse).collect())
+}
+
+/// Writes `account` to the users file, replacing the line of the user it is for.
+fn write_user(filename: &str, account: Account) -> E<()> {
+    let mut lines: Vec<String> = read_users()?
+        .into_iter()
+        .filter(|a| a.name != account.name)
+        .map(|a| a.line())
+        .collect();
+    lines.push(account.line());
+    std::fs::write(filename, lines.join("\n") + "\n")?;
+    Ok(())
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Credentials {
+    pub name: String,
+    pub password: String,
+}
+
+/// Adds a user to the users file, or changes their password if they are already in it.
+pub fn set_user(credentials: &Credentials) -> E<()> {
+    let filename = users_file().ok_or(Er::with_kind(
+        Kind::BadRequest,
+        "There are no user accounts, as USERS_FILE is not set".to_string(),
+    ))?;
+    if credentials.name.is_empty()
+        || credentials.name.contains(':')
+        || credentials.name.contains('\n')
+        || credentials.password.is_empty()
+    {
+        return Err(Er::with_kind(
+            Kind::BadRequest,
+            "Names can't be empty or contain colons, and passwords can't be empty".to_string(),
+        ));
+    }
+    write_user(&filename, Account::new(credentials))
+}
+
+/// Whether `addr` has given too many wrong passwords lately to be let try again.
+fn too_many_failures(addr: &IpAddr) -> bool {
+    let mut failed = FAILED_LOGINS.lock().unwrap();
+    failed.retain(|_, times| {
+        times.retain(|at| at.elapsed() < Duration::from_secs(FAILED_LOGIN_SECONDS));
+        !times.is_empty()
+    });
+    failed
+        .get(addr)
+        .is_some_and(|times| times.len() >= MAX_FAILED_LOGINS)
+}
+
+/**
+ * Checks a user's password, giving a new login token if it is right.
+ * Addresses which give `MAX_FAILED_LOGINS` wrong passwords within
+ * `FAILED_LOGIN_SECONDS` are refused until the oldest of them is that old.
+ * A password hashed before PBKDF2 is hashed again with it once it has been
+ * given right. Hashing is slow, so this is run off the async runtime.
+ */
+pub fn login(credentials: &Credentials, addr: Option<IpAddr>) -> E<Option<String>> {
+    if addr.as_ref().is_some_and(too_many_failures) {
+        return Err(Er::with_kind(
+            Kind::TooManyLogins,
+            "Too many wrong passwords were given from this address, try again later".to_string(),
+        ));
+    }
+    let account = read_users()?
+        .into_iter()
+        .find(|a| a.name == credentials.name);
+    let Some(account) = account.filter(|a| a.checks(&credentials.password)) else {
+        if let Some(addr) = addr {
+            log::warn!("Wrong name or password from {}", addr);
+            FAILED_LOGINS
+                .lock()
+                .unwrap()
+                .entry(addr)
+                .or_default()
+                .push(Instant::now());
+        }
+        return Ok(None);
+    };
+    if account.scheme != Scheme::Pbkdf2 {
+        if let Some(filename) = users_file() {
+            if let Err(e) = write_user(&filename, Account::new(credentials)) {
+                log::error!(
+                    "Couldn't rehash the password of {}: {}",
+                    credentials.name,
+                    e
+                );
+            }
+        }
+    }
+    let token = random_hex(32);
+    let mut logins = LOGINS.lock().unwrap();
+    logins.retain(|_, (_, at)| at.elapsed() < Duration::from_secs(LOGIN_SECONDS));
+    logins.insert(token.clone(), (credentials.name.clone(), Instant::now()));
+    Ok(Some(token))
+}
+
+pub fn logout(token: &str) {
+    LOGINS.lock().unwrap().remove(token);
+}
+
+/// The user a login token belongs to, if it hasn't expired.
+fn user_for_token(token: &str) -> Option<String> {
+    let logins = LOGINS.lock().unwrap();
+    let (user, at) = logins.get(token)?;
+    (at.elapsed() < Duration::from_secs(LOGIN_SECONDS)).then(|| user.clone())
+}
+
+/// The logged in user making a request, from its login cookie.
+pub fn current_user(
+) -> impl Filter<Extract = (Option<String>,), Error = std::convert::Infallible> + Clone {
+    warp::cookie::optional::<String>(LOGIN_COOKIE)
+        .map(|token: Option<String>| token.and_then(|t| user_for_token(&t)))
+}
+
+/**
+ * Rejects requests for the data of a session made by someone other than
+ * `user`. Sessions made without logging in are anyone's.
+ */
+pub fn check_owner(session: &SessionData, user: &Option<String>) -> Result<(), Rejection> {
+    match &session.user {
+        Some(owner) if Some(owner) != user.as_ref() => Err(reject(
+            Kind::NotOwner,
+            format!("Session {} belongs to another user", session.uuid),
+        )),
+        _ => Ok(()),
+    }
+}
+
+/**
+ * Rejects deleting a session, or its recording, unless it is asked for
+ * with the admin token or by the logged in user whose session it is.
+ * Sessions made without logging in are anyone's to see but only the
+ * admin's to delete.
+ */
+pub fn check_can_delete(
+    session: &SessionData,
+    user: &Option<String>,
+    admin: bool,
+) -> Result<(), Rejection> {
+    if admin {
+        return Ok(());
+    }
+    match &session.user {
+        None => Err(reject(
+            Kind::NotOwner,
+            format!(
+                "Session {} was made without logging in, so only the admin can delete it",
+                session.uuid
+            ),
+        )),
+        Some(_) => check_owner(session, user),
+    }
+}
@@ -1,70 +1,66 @@
-use crate::error::{Er, E};
-use crate::queue;
-use crate::session::process_transcription;
-use crate::translate::{resample, TranslationRequest, TranslationResponse, Translator};
-use lazy_static::lazy_static;
-use std::env;
-use std::sync::OnceLock;
-use thread_priority::set_current_thread_priority;
-use thread_priority::ThreadPriority::Crossplatform;
-use whisper_rs::{FullParams, SamplingStrategy, WhisperContext};
+use crate::error::E;
+use crate::transcribe::{Segment, Transcriber};
+use crate::translate::WordTime;
+use whisper_rs::{FullParams, SamplingStrategy};
 
-lazy_static! {
-    static ref CTX: OnceLock<WhisperContext> = {
-        let model = env::var("WHISPER_MODEL").unwrap_or("medium".to_string());
-        let ctx = WhisperContext::new(&format!("../models/ggml-{}.bin", model)).unwrap();
-        let lock = OnceLock::new();
-        lock.set(ctx).unwrap();
-        lock
-    };
+/**
+ * The words of segment `i`, joined from its tokens: a token starting with
+ * a space starts a word, any other continues the one before. Special
+ * tokens such as `[_BEG_]` and `<|endoftext|>` are left out.
+ */
+fn words(state: &whisper_rs::WhisperState<'_>, i: i32, tokens: i32) -> Vec<WordTime> {
+    let mut words: Vec<WordTime> = vec![];
+    for t in 0..tokens {
+        let (Ok(text), Ok(data)) = (
+            state.full_get_token_text(i, t),
+            state.full_get_token_data(i, t),
+        ) else {
+            continue;
+        };
+        if text.starts_with("[_") || text.starts_with("<|") {
+            continue;
+        }
+        match words.last_mut() {
+            Some(word) if !text.starts_with(' ') => {
+                word.word.push_str(&text);
+                word.end = data.t1;
+            }
+            _ if text.trim().is_empty() => {}
+            _ => words.push(WordTime {
+                word: text.trim().to_string(),
+                start: data.t0,
+                end: data.t1,
+            }),
+        }
+    }
+    words
 }
 
 pub struct WhisperCpp {}
 
 impl WhisperCpp {}
 
-impl Translator for WhisperCpp {
-    fn translate(&self, translation_request: TranslationRequest) -> E<()> {
-        log::debug!(
-            "Sending job {} to translate",
-            &translation_request.session_id
-        );
-        let session = match crate::session::get_session_sync(&translation_request.session_id) {
-            Some(x) => x,
-            None => {
-                return Err(Er::new(format!(
-                    "Couldn't get session for request {:?}",
-                    translation_request
-                )))
-            }
-        };
-
-        let audio_data = translation_request.payload;
-
-        let data = resample(&audio_data, 44100_f64);
-
-        let mut bytes: Vec<u8> = Vec::with_capacity(4 * data.len());
-        for val in &data {
-            bytes.extend(&val.to_le_bytes());
-        }
-
-        let context = CTX.get().expect("Couldn't get context");
-        let mut state = context.create_state().expect("failed to create state");
+impl Transcriber for WhisperCpp {
+    fn transcribe(&self, data: &[f32], lang: &str, model: Option<&str>) -> E<Vec<Segment>> {
+        let context = crate::models::get(model.unwrap_or(&crate::config::CONFIG.whisper_model))?;
+        let mut state = context.create_state()?;
         let mut whisper_params = FullParams::new(SamplingStrategy::Greedy { best_of: 1 });
-        log::debug!("Setting language to {}", translation_request.lang);
-        whisper_params.set_language(Some(&translation_request.lang));
+        log::debug!("Setting language to {}", lang);
+        whisper_params.set_language(Some(lang));
+        whisper_params.set_token_timestamps(true);
         state
-            .full(whisper_params, &data)
+            .full(whisper_params, data)
             .expect("failed to run model");
 
         let num_segments = state
             .full_n_segments()
             .expect("failed to get number of segments");
         log::debug!("{} segments", num_segments);
+        let mut segments = vec![];
         for i in 0..num_segments {
             let segment = match state.full_get_segment_text(i) {
                 Ok(text) => text,
-                Err(_) => "<b>error transcribing</b>".to_string(),
+                Err(_) => crate::transcribe::TRANSCRIPTION_ERROR.to_string(),
             };
             let start_timestamp = state
                 .full_get_segment_t0(i)
@@ -75,59 +71,40 @@ impl Translator for WhisperCpp {
 
             log::debug!("[{} - {}]: {}", start_timestamp, end_timestamp, segment);
 
-            let response = TranslationResponse {
-                sequence_number: translation_request.sequence_number,
-                translation: segment,
-                num_segments,
-                segment_number: i,
-                segment_start: start_timestamp,
-                segment_end: end_timestamp,
-                uuid: session.uuid.to_string(),
-            };
+            // the mean probability of the segment's tokens
+            let tokens = state.full_n_tokens(i).unwrap_or(0);
+            let confidence = (tokens > 0).then(|| {
+                (0..tokens)
+                    .filter_map(|t| state.full_get_token_prob(i, t).ok())
+                    .sum::<f32>()
+                    / tokens as f32
+            });
 
-            let result = process_transcription(translation_request.session_id, &response);
-            match result {
-                Ok(_) => (),
-                Err(e) => {
-                    log::warn!("Processing translation failed with error {}", e);
-                    crate::session::mutate_session_sync(
-                        &translation_request.session_id,
-                        |session| session.valid = false,
-                    );
-                }
-            };
+            segments.push(Segment {
+                text: segment,
+                start: start_timestamp,
+                end: end_timestamp,
+                confidence,
+                words: words(&state, i, tokens),
+                speaker: None,
+            });
         }
-        Ok(())
+        Ok(segments)
     }
-}
-
-pub fn start_translate_pool() -> E<()> {
-    let num_whisper_processes: usize = match env::var("WHISPER_PROCESSES") {
-        Ok(num) => num.parse().expect("WHISPER_PROCESSES must be an integer"),
-        Err(_) => {
-            let num_cpus = num_cpus::get();
-            num_cpus / 4
-        }
-    };
-    log::debug!("Making thread pool with {} threads.", num_whisper_processes);
-    let pool = rayon::ThreadPoolBuilder::new()
-        .num_threads(num_whisper_processes)
-        .build()
-        .expect("Failed to create thread pool");
-    let queue = queue::get_queue();
 
-    for i in 0..num_whisper_processes {
-        log::debug!("Installing {}", i);
-        let mut queue = queue.clone();
-        set_current_thread_priority(Crossplatform(crate::LOWER_PRIORITY.try_into().unwrap()))
-            .unwrap();
-        pool.spawn(move || {
-            queue
-                .subscribe::<WhisperCpp>(&WhisperCpp {})
-                .unwrap_or_else(|_| {
-                    log::warn!("Exiting thread");
-                });
-        });
+    /// The language whisper.cpp finds most likely in the first 30 seconds of `data`.
+    fn detect_language(&self, data: &[f32], model: Option<&str>) -> E<Option<String>> {
+        let context = crate::models::get(model.unwrap_or(&crate::config::CONFIG.whisper_model))?;
+        let mut state = context.create_state()?;
+        state.pcm_to_mel(data, 1)?;
+        let probabilities = state.lang_detect(0, 1)?;
+        let best = probabilities
+            .iter()
+            .enumerate()
+            .max_by(|a, b| a.1.total_cmp(b.1))
+            .map(|(id, _)| id as i32);
+        Ok(best
+            .and_then(whisper_rs::get_lang_str)
+            .map(|lang| lang.to_string()))
     }
-    Ok(())
 }
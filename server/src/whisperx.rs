@@ -2,16 +2,29 @@ use log::debug;
 use reqwest::blocking::Client;
 use serde::Deserialize;
 use serde_json::json;
-use warp::ws::Message;
 
-use crate::error::{Er, E};
-use crate::translate::{resample, TranslationRequest, TranslationResponse, Translator};
+use crate::error::E;
+use crate::transcribe::{Segment, Transcriber};
+use crate::translate::{WordTime, SEGMENT_TIME_UNITS_PER_SECOND};
+
+#[derive(Deserialize, Debug)]
+struct RemoteWhisperWord {
+    word: String,
+    /// missing for words WhisperX couldn't align, such as numbers
+    start: Option<f32>,
+    end: Option<f32>,
+}
 
 #[derive(Deserialize, Debug)]
 struct RemoteWhisperSegment {
     text: String,
     start: f32,
     end: f32,
+    #[serde(default)]
+    words: Vec<RemoteWhisperWord>,
+    /// only given when diarization was asked for
+    #[serde(default)]
+    speaker: Option<String>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -30,57 +43,65 @@ impl WhisperX {
         let client = Client::new();
         Ok(Self { client })
     }
-}
 
-impl Translator for WhisperX {
-    fn translate(&self, translation_request: TranslationRequest) -> E<()> {
-        let audio_data = translation_request.payload;
-        if audio_data.is_empty() {
-            return Ok(());
+    /// Transcribes `data` on the whisperx server, `diarize` asking it to say who spoke each segment.
+    fn request(&self, data: &[f32], lang: &str, diarize: bool) -> E<Vec<Segment>> {
+        if data.is_empty() {
+            return Ok(vec![]);
+        }
+        let mut url = format!("{}?lang={}", std::env::var("WHISPER_SERVER").unwrap(), lang);
+        if diarize {
+            url.push_str("&diarize=true");
         }
-        let data = resample(&audio_data, 44100_f64);
-
-        let url = format!(
-            "{}?lang={}",
-            std::env::var("WHISPER_SERVER").unwrap(),
-            translation_request.lang
-        );
-        let session_id = translation_request.session_id;
-        let session = crate::session::get_session_sync(&translation_request.session_id).ok_or(
-            Er::new(format!("Couldn't get session for request {:?}", session_id)),
-        )?;
         debug!("Making request for translation to {}", url);
 
         let res = self.client.post(url).json(&json!(data)).send()?;
         let response = res.json::<RemoteWhisperResponse>()?;
 
-        for segment in response.segments {
-            let response = TranslationResponse {
-                sequence_number: translation_request.sequence_number,
-                translation: segment.text,
-                num_segments: 1,
-                segment_number: 0,
-                segment_start: (segment.start * 1000f32) as i64,
-                segment_end: (segment.end * 1000f32) as i64,
-                uuid: session.uuid.to_string(),
-            };
-
-            match session
-                .transcription_sender_tx
-                .as_ref()
-                .expect("Couldn't find sender!")
-                .send(Message::text(json!(response).to_string()))
-            {
-                Ok(_) => (),
-                Err(e) => {
-                    log::warn!("Sending failed with error {}", e);
-                    crate::session::mutate_session_sync(
-                        &translation_request.session_id,
-                        |session| session.valid = false,
-                    );
+        let time = |t: f32| (t * SEGMENT_TIME_UNITS_PER_SECOND) as i64;
+        Ok(response
+            .segments
+            .into_iter()
+            .map(|segment| {
+                // unaligned words take the segment's start, so the count still matches its text
+                let mut last = segment.start;
+                let words = segment
+                    .words
+                    .into_iter()
+                    .map(|w| {
+                        let start = w.start.unwrap_or(last);
+                        last = w.end.unwrap_or(start);
+                        WordTime {
+                            word: w.word,
+                            start: time(start),
+                            end: time(last),
+                        }
+                    })
+                    .collect();
+                Segment {
+                    text: segment.text,
+                    start: time(segment.start),
+                    end: time(segment.end),
+                    confidence: None,
+                    words,
+                    speaker: segment.speaker,
                 }
-            };
-        }
-        Ok(())
+            })
+            .collect())
+    }
+}
+
+impl Transcriber for WhisperX {
+    fn transcribe(&self, data: &[f32], lang: &str, _model: Option<&str>) -> E<Vec<Segment>> {
+        self.request(data, lang, false)
+    }
+
+    fn transcribe_dialogue(
+        &self,
+        data: &[f32],
+        lang: &str,
+        _model: Option<&str>,
+    ) -> E<Vec<Segment>> {
+        self.request(data, lang, true)
     }
 }